@@ -17,7 +17,6 @@ namespace_interface="$namespace-ns"
 
 ip link add $host_interface type veth peer name $namespace_interface
 ip link set dev $host_interface master $bridge_interface
-ip link set dev $namespace_interface master $bridge_interface
 
 ip link set dev $host_interface up
 
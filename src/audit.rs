@@ -0,0 +1,224 @@
+use std::ffi::c_ulong;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tracing::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::model::ContainerRuntimeResult;
+use crate::sys::{is_query_command, SysApi, SysCall};
+
+/// The audit log in the container root, which is only kept after the container has exited if undoing a change failed.
+pub const AUDIT_LOG: &str = "audit.log";
+
+/// A change made to the host.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// When the change was made, in RFC 3339 format
+    pub time: String,
+    /// The change as a command line, such as `iptables -A FORWARD ...`
+    pub operation: String,
+    /// Why the change failed, in which case it might only have been made in part
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub error: Option<String>
+}
+
+/// An append-only log (JSON lines) of the changes made to the host, so that what was changed can be found
+/// (and undone) if the runtime crashes or fails to undo it.
+pub struct AuditLog {
+    path: PathBuf,
+    file: Mutex<Option<File>>,
+    has_failures: AtomicBool
+}
+
+impl AuditLog {
+    /// The file is created on the first entry.
+    pub fn new(path: PathBuf) -> AuditLog {
+        AuditLog {
+            path,
+            file: Mutex::new(None),
+            has_failures: AtomicBool::new(false)
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Opens the file now, such as before the container changes its root, after which the path can no longer be reached.
+    pub fn open(&self) -> ContainerRuntimeResult<()> {
+        let mut file = self.file.lock().unwrap();
+        if file.is_none() {
+            *file = Some(OpenOptions::new().create(true).append(true).open(&self.path)?);
+        }
+
+        Ok(())
+    }
+
+    /// Records the change with its result, which is passed on.
+    pub fn record<T>(&self, operation: &str, result: ContainerRuntimeResult<T>) -> ContainerRuntimeResult<T> {
+        if result.is_err() {
+            self.has_failures.store(true, Ordering::SeqCst);
+        }
+
+        let entry = AuditEntry {
+            time: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
+            operation: operation.to_owned(),
+            error: result.as_ref().err().map(|err| err.to_string())
+        };
+
+        // The change has been made either way, so failing to audit it must not fail (or undo) it
+        if let Err(err) = self.append(&entry) {
+            warn!("Failed to write to the audit log {}: {}", self.path.to_str().unwrap(), err);
+        }
+
+        result
+    }
+
+    /// If any change failed, which is when the log is worth keeping.
+    pub fn has_failures(&self) -> bool {
+        self.has_failures.load(Ordering::SeqCst)
+    }
+
+    fn append(&self, entry: &AuditEntry) -> ContainerRuntimeResult<()> {
+        self.open()?;
+
+        // A single write, as the container appends to the same file from its own process
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.file.lock().unwrap().as_mut().unwrap().write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Reads the entries of the log at the path, oldest first, where a missing log has none.
+    pub fn read(path: &Path) -> ContainerRuntimeResult<Vec<AuditEntry>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into())
+        };
+
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                entries.push(serde_json::from_str(&line)?);
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+/// Records the calls that change the host in the audit log, where the calls that only query it (such as `ip link show`) are not recorded.
+pub struct AuditedSysApi {
+    inner: Arc<dyn SysApi>,
+    log: Arc<AuditLog>
+}
+
+impl AuditedSysApi {
+    /// Audits the calls of the API, unless it does not change the host (such as on a dry run).
+    pub fn wrap(inner: &Arc<dyn SysApi>, log: Arc<AuditLog>) -> Arc<dyn SysApi> {
+        if !inner.changes_host() {
+            return inner.clone();
+        }
+
+        Arc::new(
+            AuditedSysApi {
+                inner: inner.clone(),
+                log
+            }
+        )
+    }
+
+    fn command(&self, program: &str, args: &[&str], call: impl FnOnce() -> ContainerRuntimeResult<String>) -> ContainerRuntimeResult<String> {
+        if is_query_command(args) {
+            return call();
+        }
+
+        let operation = SysCall::Command { program: program.to_owned(), args: args.iter().map(|arg| arg.to_string()).collect() };
+        self.log.record(&operation.to_string(), call())
+    }
+}
+
+impl SysApi for AuditedSysApi {
+    fn mount(&self, src: Option<&str>, target: &Path, fstype: Option<&str>, flags: c_ulong, data: Option<&str>) -> ContainerRuntimeResult<()> {
+        let operation = SysCall::Mount {
+            src: src.map(|src| src.to_owned()),
+            target: target.to_owned(),
+            fstype: fstype.map(|fstype| fstype.to_owned()),
+            flags,
+            data: data.map(|data| data.to_owned())
+        };
+
+        self.log.record(&operation.to_string(), self.inner.mount(src, target, fstype, flags, data))
+    }
+
+    fn unmount(&self, target: &Path) -> ContainerRuntimeResult<()> {
+        let operation = SysCall::Unmount { target: target.to_owned() };
+        self.log.record(&operation.to_string(), self.inner.unmount(target))
+    }
+
+    fn pivot_root(&self, new_root: &Path, old_root: &Path) -> ContainerRuntimeResult<()> {
+        let operation = SysCall::PivotRoot { new_root: new_root.to_owned(), old_root: old_root.to_owned() };
+        self.log.record(&operation.to_string(), self.inner.pivot_root(new_root, old_root))
+    }
+
+    fn mknod(&self, path: &Path, mode: u32, device: u64) -> ContainerRuntimeResult<()> {
+        let operation = SysCall::Mknod { path: path.to_owned(), mode, device };
+        self.log.record(&operation.to_string(), self.inner.mknod(path, mode, device))
+    }
+
+    fn set_hostname(&self, hostname: &str) -> ContainerRuntimeResult<()> {
+        let operation = SysCall::SetHostname { hostname: hostname.to_owned() };
+        self.log.record(&operation.to_string(), self.inner.set_hostname(hostname))
+    }
+
+    fn write_sysctl(&self, name: &str, value: &str) -> ContainerRuntimeResult<()> {
+        let operation = SysCall::WriteSysctl { name: name.to_owned(), value: value.to_owned() };
+        self.log.record(&operation.to_string(), self.inner.write_sysctl(name, value))
+    }
+
+    fn ip(&self, args: &[&str]) -> ContainerRuntimeResult<String> {
+        self.command("ip", args, || self.inner.ip(args))
+    }
+
+    fn tc(&self, args: &[&str]) -> ContainerRuntimeResult<String> {
+        self.command("tc", args, || self.inner.tc(args))
+    }
+
+    fn iptables(&self, args: &[&str]) -> ContainerRuntimeResult<String> {
+        self.command("iptables", args, || self.inner.iptables(args))
+    }
+}
+
+#[test]
+fn test_audited_sys_api() {
+    let path = std::env::temp_dir().join(format!("cort-audit-{}.log", uuid::Uuid::new_v4()));
+    let log = Arc::new(AuditLog::new(path.clone()));
+
+    // Fakes do not change the host, so they are not audited
+    let recording: Arc<dyn SysApi> = Arc::new(crate::sys::RecordingSysApi::new());
+    assert!(Arc::ptr_eq(&recording, &AuditedSysApi::wrap(&recording, log.clone())));
+
+    let sys = AuditedSysApi {
+        inner: Arc::new(crate::sys::RecordingSysApi::new().fail("iptables -D", "Bad rule")),
+        log: log.clone()
+    };
+    sys.ip(&["link", "show", "cort0"]).unwrap();
+    sys.ip(&["link", "add", "name", "cort0", "type", "bridge"]).unwrap();
+    assert!(sys.iptables(&["-D", "FORWARD", "-i", "cort0", "-j", "ACCEPT"]).is_err());
+
+    let entries = AuditLog::read(log.path()).unwrap();
+    assert_eq!(2, entries.len());
+    assert_eq!("ip link add name cort0 type bridge", entries[0].operation);
+    assert_eq!(None, entries[0].error);
+    assert_eq!("iptables -D FORWARD -i cort0 -j ACCEPT", entries[1].operation);
+    assert!(entries[1].error.is_some());
+    assert!(log.has_failures());
+
+    std::fs::remove_file(&path).unwrap();
+}
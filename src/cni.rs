@@ -0,0 +1,235 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+use tracing::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::helpers::{COMMAND_TIMEOUT, wait_with_output};
+use crate::model::{ContainerRuntimeError, ContainerRuntimeResult};
+use crate::network::{CONTAINER_INTERFACE, Ipv4Net};
+use crate::spec::CniNetworkSpec;
+
+/// A network configuration, where a single plugin configuration (.conf) is treated as a list with one plugin.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CniNetworkConfig {
+    pub name: String,
+    pub cni_version: String,
+    pub plugins: Vec<Value>
+}
+
+impl CniNetworkConfig {
+    /// Finds the configuration with the name, in the order of the file names as CNI specifies.
+    pub fn load(config_dir: &Path, name: &str) -> ContainerRuntimeResult<CniNetworkConfig> {
+        let mut paths = match std::fs::read_dir(config_dir) {
+            Ok(entries) => entries.map(|entry| entry.map(|entry| entry.path())).collect::<Result<Vec<_>, _>>()?,
+            Err(err) => return Err(ContainerRuntimeError::Cni(format!("Failed to read {}: {}", config_dir.to_str().unwrap(), err)))
+        };
+        paths.sort();
+
+        for path in paths {
+            let is_config = matches!(path.extension().and_then(|extension| extension.to_str()), Some("conf" | "conflist" | "json"));
+            if !is_config {
+                continue;
+            }
+
+            match CniNetworkConfig::from_json(&std::fs::read_to_string(&path)?) {
+                Ok(config) if config.name == name => return Ok(config),
+                Ok(_) => {}
+                Err(err) => warn!("Skipping CNI configuration {}: {}", path.to_str().unwrap(), err)
+            }
+        }
+
+        Err(ContainerRuntimeError::NetworkNotFound(format!("{} (no CNI configuration in {})", name, config_dir.to_str().unwrap())))
+    }
+
+    pub fn from_json(content: &str) -> ContainerRuntimeResult<CniNetworkConfig> {
+        let config: Value = serde_json::from_str(content)?;
+        let field = |name: &str| -> ContainerRuntimeResult<String> {
+            config[name].as_str()
+                .map(|value| value.to_owned())
+                .ok_or_else(|| ContainerRuntimeError::Cni(format!("Expected '{}' in network configuration", name)))
+        };
+
+        let plugins = match config.get("plugins") {
+            Some(Value::Array(plugins)) if !plugins.is_empty() => plugins.clone(),
+            Some(_) => return Err(ContainerRuntimeError::Cni("Expected a non-empty list of plugins".to_owned())),
+            None => vec![config.clone()]
+        };
+
+        if plugins.iter().any(|plugin| !plugin["type"].is_string()) {
+            return Err(ContainerRuntimeError::Cni("Expected 'type' for each plugin".to_owned()));
+        }
+
+        Ok(
+            CniNetworkConfig {
+                name: field("name")?,
+                cni_version: field("cniVersion")?,
+                plugins
+            }
+        )
+    }
+
+    /// The configuration given to a plugin, which includes the name and version of the network and the result of the previous plugin.
+    fn plugin_config(&self, plugin: &Value, previous_result: Option<&Value>) -> Value {
+        let mut plugin_config = plugin.clone();
+        plugin_config["name"] = json!(self.name);
+        plugin_config["cniVersion"] = json!(self.cni_version);
+        if let Some(previous_result) = previous_result {
+            plugin_config["prevResult"] = previous_result.clone();
+        }
+
+        plugin_config
+    }
+}
+
+/// The result of adding the container to a network.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CniResult(pub Value);
+
+impl CniResult {
+    /// The first IPv4 address assigned to the container.
+    pub fn ip_address(&self) -> Option<Ipv4Net> {
+        self.0["ips"]
+            .as_array()?
+            .iter()
+            .filter_map(|ip| ip["address"].as_str())
+            .find_map(|address| Ipv4Net::from_str(address).ok())
+    }
+}
+
+/// Adds the container to the network by running the plugins in order, where each gets the result of the previous.
+pub fn add(spec: &CniNetworkSpec, container_id: &str, pid: i32) -> ContainerRuntimeResult<CniResult> {
+    let config = CniNetworkConfig::load(&spec.config_dir, &spec.name)?;
+    let netns = network_namespace_path(pid);
+
+    let mut result: Option<Value> = None;
+    for plugin in &config.plugins {
+        let plugin_config = config.plugin_config(plugin, result.as_ref());
+        match invoke(spec, "ADD", container_id, &netns, &plugin_config) {
+            Ok(plugin_result) => result = Some(plugin_result),
+            Err(err) => {
+                // The plugins that succeeded might have allocated resources
+                if let Err(delete_err) = delete_with_config(spec, &config, container_id, pid, result.as_ref()) {
+                    warn!("Failed to remove container from CNI network {}: {}", config.name, delete_err);
+                }
+                return Err(err);
+            }
+        }
+    }
+
+    let result = CniResult(result.unwrap_or(Value::Null));
+    info!("Added container to CNI network {} with address {:?}.", config.name, result.ip_address());
+    Ok(result)
+}
+
+/// Removes the container from the network by running the plugins in reverse order.
+pub fn delete(spec: &CniNetworkSpec, container_id: &str, pid: i32, result: &CniResult) -> ContainerRuntimeResult<()> {
+    let config = CniNetworkConfig::load(&spec.config_dir, &spec.name)?;
+    delete_with_config(spec, &config, container_id, pid, Some(&result.0))
+}
+
+fn delete_with_config(spec: &CniNetworkSpec, config: &CniNetworkConfig, container_id: &str, pid: i32, result: Option<&Value>) -> ContainerRuntimeResult<()> {
+    // The namespace is gone once the container has exited, which plugins must accept when deleting
+    let netns = network_namespace_path(pid);
+    let netns = if Path::new(&netns).exists() { netns } else { String::new() };
+
+    let mut first_error = None;
+    for plugin in config.plugins.iter().rev() {
+        let plugin_config = config.plugin_config(plugin, result);
+        if let Err(err) = invoke(spec, "DEL", container_id, &netns, &plugin_config) {
+            first_error.get_or_insert(err);
+        }
+    }
+
+    match first_error {
+        Some(err) => Err(err),
+        None => Ok(())
+    }
+}
+
+fn network_namespace_path(pid: i32) -> String {
+    format!("/proc/{}/ns/net", pid)
+}
+
+fn invoke(spec: &CniNetworkSpec, command: &str, container_id: &str, netns: &str, plugin_config: &Value) -> ContainerRuntimeResult<Value> {
+    let plugin_type = plugin_config["type"].as_str().unwrap_or_default();
+    let plugin_path = find_plugin(&spec.plugin_dirs, plugin_type)?;
+    let plugin_search_path = spec.plugin_dirs
+        .iter()
+        .map(|dir| dir.to_str().unwrap())
+        .collect::<Vec<_>>()
+        .join(":");
+
+    let mut child = Command::new(&plugin_path)
+        .env("CNI_COMMAND", command)
+        .env("CNI_CONTAINERID", container_id)
+        .env("CNI_NETNS", netns)
+        .env("CNI_IFNAME", CONTAINER_INTERFACE)
+        .env("CNI_PATH", plugin_search_path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| ContainerRuntimeError::Cni(format!("Failed to run plugin {}: {}", plugin_type, err)))?;
+
+    child.stdin.take().unwrap().write_all(&serde_json::to_vec(plugin_config)?)?;
+    let output = wait_with_output(child, plugin_path.to_str().unwrap(), COMMAND_TIMEOUT)
+        .map_err(|err| ContainerRuntimeError::Cni(format!("Plugin {} failed to {}: {}", plugin_type, command, err)))?;
+
+    if !output.status.success() {
+        // Plugins report errors as JSON on stdout
+        let message = serde_json::from_slice::<Value>(&output.stdout)
+            .ok()
+            .and_then(|error| error["msg"].as_str().map(|message| message.to_owned()))
+            .unwrap_or_else(|| String::from_utf8_lossy(&output.stderr).trim().to_owned());
+        return Err(ContainerRuntimeError::Cni(format!("Plugin {} failed to {}: {}", plugin_type, command, message)));
+    }
+
+    if output.stdout.iter().all(|byte| byte.is_ascii_whitespace()) {
+        return Ok(Value::Null);
+    }
+
+    serde_json::from_slice(&output.stdout)
+        .map_err(|err| ContainerRuntimeError::Cni(format!("Invalid result from plugin {}: {}", plugin_type, err)))
+}
+
+fn find_plugin(plugin_dirs: &[PathBuf], plugin_type: &str) -> ContainerRuntimeResult<PathBuf> {
+    if plugin_type.is_empty() || plugin_type.contains('/') {
+        return Err(ContainerRuntimeError::Cni(format!("Invalid plugin type '{}'", plugin_type)));
+    }
+
+    plugin_dirs
+        .iter()
+        .map(|dir| dir.join(plugin_type))
+        .find(|path| path.is_file())
+        .ok_or_else(|| ContainerRuntimeError::Cni(format!("Plugin {} not found in {:?}", plugin_type, plugin_dirs)))
+}
+
+#[test]
+fn test_network_config_from_json() {
+    let config = CniNetworkConfig::from_json(r#"{
+        "cniVersion": "1.0.0",
+        "name": "dbnet",
+        "plugins": [
+            { "type": "bridge", "bridge": "cni0", "ipam": { "type": "host-local", "subnet": "10.1.0.0/16" } },
+            { "type": "portmap", "capabilities": { "portMappings": true } }
+        ]
+    }"#).unwrap();
+    assert_eq!("dbnet", config.name);
+    assert_eq!(2, config.plugins.len());
+
+    let previous_result = json!({ "ips": [{ "address": "10.1.0.5/16" }] });
+    let plugin_config = config.plugin_config(&config.plugins[1], Some(&previous_result));
+    assert_eq!("dbnet", plugin_config["name"]);
+    assert_eq!("1.0.0", plugin_config["cniVersion"]);
+    assert_eq!(previous_result, plugin_config["prevResult"]);
+    assert_eq!(Some(Ipv4Net::from_str("10.1.0.5/16").unwrap()), CniResult(previous_result).ip_address());
+
+    let config = CniNetworkConfig::from_json(r#"{ "cniVersion": "0.4.0", "name": "macnet", "type": "macvlan", "master": "eth0" }"#).unwrap();
+    assert_eq!("macvlan", config.plugins[0]["type"]);
+
+    assert!(CniNetworkConfig::from_json(r#"{ "cniVersion": "1.0.0", "name": "empty", "plugins": [] }"#).is_err());
+}
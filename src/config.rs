@@ -0,0 +1,401 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::model::{ContainerRuntimeError, ContainerRuntimeResult};
+use crate::network::Ipv4Net;
+use crate::spec::{CgroupDriverSpec, HooksSpec, HostsFileSpec, IpamSpec, LogDriverSpec, OverlayOptionsSpec, PullPolicy, StorageDriverSpec};
+
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/cort/config.toml";
+pub const DEFAULT_NETWORK: &str = "default";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ContainerRuntimeConfig {
+    pub data_dir: PathBuf,
+    /// The log level, or filter directives such as `info,container_runtime::network=trace` (overridden by `RUST_LOG`)
+    pub log_level: Option<String>,
+    /// The format of the log written to stderr or the log file
+    pub log_format: LogFormat,
+    /// Writes the log to this file instead of stderr
+    pub log_file: Option<PathBuf>,
+    /// Also sends the log to syslog or journald
+    pub log_sink: Option<LogSink>,
+    pub storage_driver: StorageDriverSpec,
+    pub overlay: OverlayOptionsSpec,
+    /// Whether the cgroups of containers are written by the runtime or created by systemd
+    pub cgroup_driver: CgroupDriverSpec,
+    /// Keeps the names of running containers in this hosts file, so that the host can reach them by name
+    pub hosts_file: Option<HostsFileSpec>,
+    /// The log driver of containers that are run without --log-driver
+    pub log_driver: LogDriverSpec,
+    pub network: NetworkConfig,
+    /// Additional networks that containers can be connected to, by name
+    pub networks: HashMap<String, NetworkConfig>,
+    pub limits: LimitsConfig,
+    pub cni: CniConfig,
+    pub signatures: SignaturesConfig,
+    pub images: ImagesConfig,
+    /// The settings of registries by name (such as `docker.io` or `registry.internal:5000`)
+    pub registries: HashMap<String, RegistryConfig>,
+    /// Hooks run for every container, before those given when running it
+    pub hooks: HooksSpec
+}
+
+impl ContainerRuntimeConfig {
+    pub fn from_base_dir(base_dir: &Path) -> ContainerRuntimeConfig {
+        ContainerRuntimeConfig {
+            data_dir: base_dir.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    pub fn load(path: &Path) -> ContainerRuntimeResult<ContainerRuntimeConfig> {
+        let content = std::fs::read_to_string(path)?;
+        toml::from_str(&content).map_err(|err| ContainerRuntimeError::Config(format!("{}: {}", path.to_str().unwrap(), err)))
+    }
+
+    pub fn load_or_default(path: &Path) -> ContainerRuntimeResult<ContainerRuntimeConfig> {
+        if path.exists() {
+            ContainerRuntimeConfig::load(path)
+        } else {
+            Ok(ContainerRuntimeConfig::default())
+        }
+    }
+
+    /// Returns the network with the given name, where the default network is the one used by `run`.
+    pub fn find_network(&self, name: &str) -> ContainerRuntimeResult<&NetworkConfig> {
+        if name == DEFAULT_NETWORK {
+            return Ok(&self.network);
+        }
+
+        let network = self.networks
+            .get(name)
+            .ok_or_else(|| ContainerRuntimeError::NetworkNotFound(name.to_owned()))?;
+
+        let shares_bridge = network.bridge_interface == self.network.bridge_interface
+            || self.networks.iter().any(|(other_name, other)| other_name != name && other.bridge_interface == network.bridge_interface);
+        if shares_bridge {
+            return Err(ContainerRuntimeError::Config(format!("Network '{}' must have its own bridge interface", name)));
+        }
+
+        Ok(network)
+    }
+
+    pub fn image_base_dir(&self) -> PathBuf {
+        self.data_dir.join("images")
+    }
+
+    pub fn containers_base_dir(&self) -> PathBuf {
+        self.data_dir.join("containers")
+    }
+
+    pub fn names_dir(&self) -> PathBuf {
+        self.data_dir.join("names")
+    }
+
+    pub fn addresses_dir(&self) -> PathBuf {
+        self.data_dir.join("addresses")
+    }
+
+    /// Where the state of each pod is kept, along with the root and /dev/shm of its infra container.
+    pub fn pods_dir(&self) -> PathBuf {
+        self.data_dir.join("pods")
+    }
+
+    /// Where the specs of containers run by generated systemd units are kept.
+    pub fn units_dir(&self) -> PathBuf {
+        self.data_dir.join("units")
+    }
+
+    /// Held while bridges, iptables rules and addresses are changed, as concurrent runs would otherwise race.
+    pub fn network_lock_path(&self) -> PathBuf {
+        self.data_dir.join("network.lock")
+    }
+
+    /// The changes made to the host outside of containers, such as creating bridges and pruning what crashed containers left.
+    pub fn audit_log_path(&self) -> PathBuf {
+        self.data_dir.join("audit.log")
+    }
+}
+
+impl Default for ContainerRuntimeConfig {
+    fn default() -> Self {
+        ContainerRuntimeConfig {
+            data_dir: Path::new("/var/lib/cort").to_owned(),
+            log_level: None,
+            log_format: LogFormat::default(),
+            log_file: None,
+            log_sink: None,
+            storage_driver: StorageDriverSpec::default(),
+            overlay: OverlayOptionsSpec::default(),
+            cgroup_driver: CgroupDriverSpec::default(),
+            hosts_file: None,
+            log_driver: LogDriverSpec::default(),
+            network: NetworkConfig::default(),
+            networks: HashMap::new(),
+            limits: LimitsConfig::default(),
+            cni: CniConfig::default(),
+            signatures: SignaturesConfig::default(),
+            images: ImagesConfig::default(),
+            registries: HashMap::new(),
+            hooks: HooksSpec::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkConfig {
+    pub bridge_interface: String,
+    pub bridge_ip_address: Ipv4Net,
+    /// Allow containers on the bridge to communicate with each other
+    pub inter_container_communication: bool,
+    /// Allow a container to reach itself through the host IP
+    pub hairpin: bool,
+    /// How container addresses are assigned
+    pub ipam: IpamSpec
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            bridge_interface: "cort0".to_owned(),
+            bridge_ip_address: Ipv4Net::from_str("10.10.1.1/16").unwrap(),
+            inter_container_communication: true,
+            hairpin: false,
+            ipam: IpamSpec::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    pub cpu_shares: Option<i64>,
+    pub cpus: Option<f64>,
+    pub memory: Option<i64>,
+    pub memory_swap: Option<i64>,
+    pub memory_reservation: Option<i64>,
+    pub memory_swappiness: Option<u8>,
+    pub kernel_memory: Option<i64>
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            cpu_shares: Some(256),
+            cpus: None,
+            memory: Some(1024 * 1024 * 1024),
+            memory_swap: None,
+            memory_reservation: None,
+            memory_swappiness: None,
+            kernel_memory: None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CniConfig {
+    /// The directory with the network configurations (.conf and .conflist files)
+    pub config_dir: PathBuf,
+    /// The directories searched for plugin executables
+    pub plugin_dirs: Vec<PathBuf>
+}
+
+impl Default for CniConfig {
+    fn default() -> Self {
+        CniConfig {
+            config_dir: PathBuf::from("/etc/cni/net.d"),
+            plugin_dirs: vec![PathBuf::from("/opt/cni/bin")]
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SignaturesConfig {
+    pub public_keys: Vec<PathBuf>,
+    pub policy: SignaturePolicy
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImagesConfig {
+    /// How many layers are downloaded (and extracted) at once when pulling
+    pub max_concurrent_downloads: usize,
+    /// When the image of a container is pulled before it is run, unless given for the container
+    pub pull_policy: PullPolicy,
+    /// Runs images of other architectures than the one of the host, by the binfmt_misc handlers of the host (such as of qemu-user-static)
+    pub emulation: bool,
+    /// Where the QEMU user mode emulators (qemu-<architecture>-static) are found when running containers with emulation
+    pub qemu_dir: PathBuf
+}
+
+impl Default for ImagesConfig {
+    fn default() -> Self {
+        ImagesConfig {
+            max_concurrent_downloads: 3,
+            pull_policy: PullPolicy::Missing,
+            emulation: false,
+            qemu_dir: PathBuf::from("/usr/bin")
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RegistryConfig {
+    /// Registries that serve the same repositories, tried in order before this one when pulling
+    pub mirrors: Vec<String>,
+    /// Connects over HTTP rather than HTTPS
+    pub insecure: bool,
+    /// Used instead of the stored credentials (such as by `cort login`) or those from the Docker config
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Stores and reads the credentials with `docker-credential-<name>` (such as `pass` or `secretservice`) rather than the credentials file
+    pub credential_helper: Option<String>,
+    /// A PEM file with certificate authorities trusted in addition to the public ones
+    pub ca_cert: Option<PathBuf>,
+    /// An HTTP proxy to reach the registry through, such as `http://proxy.internal:3128`
+    pub proxy: Option<String>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum LogSink {
+    Syslog,
+    Journald
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// A JSON object per line, with the fields of the event and of the container it belongs to
+    Json
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("Invalid log format (expected text or json).".to_owned())
+        }
+    }
+}
+
+impl FromStr for LogSink {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "syslog" => Ok(LogSink::Syslog),
+            "journald" => Ok(LogSink::Journald),
+            _ => Err("Invalid log sink.".to_owned())
+        }
+    }
+}
+
+/// Whether images must have a valid signature to be pulled and run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum SignaturePolicy {
+    /// Signatures are only verified when requested with --verify
+    #[default]
+    Optional,
+    /// All images are verified, and unverified images are refused
+    Required
+}
+
+#[test]
+fn test_config_from_toml() {
+    let config: ContainerRuntimeConfig = toml::from_str(r#"
+        data_dir = "/data/cort"
+        log_level = "info"
+        log_format = "json"
+        log_sink = "journald"
+        storage_driver = "vfs"
+        cgroup_driver = "systemd"
+        log_driver = "json-file"
+
+        [overlay]
+        volatile = true
+
+        [hosts_file]
+        path = "/etc/hosts"
+        domain = "cort"
+
+        [network]
+        bridge_ip_address = "10.20.0.1/24"
+        inter_container_communication = false
+        ipam = "dhcp"
+
+        [networks.backend]
+        bridge_interface = "cort1"
+        bridge_ip_address = "10.30.0.1/24"
+
+        [limits]
+        memory = 536870912
+        memory_swappiness = 10
+
+        [signatures]
+        public_keys = ["/etc/cort/cosign.pub"]
+        policy = "required"
+
+        [[hooks.prestart]]
+        path = "/usr/local/bin/provision-volumes"
+        args = ["--verbose"]
+        timeout = 10
+
+        [images]
+        pull_policy = "never"
+
+        [registries."docker.io"]
+        mirrors = ["mirror.internal:5000"]
+
+        [registries."mirror.internal:5000"]
+        insecure = true
+    "#).unwrap();
+
+    assert_eq!(Path::new("/data/cort/images"), config.image_base_dir());
+    assert_eq!(Some("info"), config.log_level.as_deref());
+    assert_eq!(LogFormat::Json, config.log_format);
+    assert_eq!(Some(LogSink::Journald), config.log_sink);
+    assert_eq!(StorageDriverSpec::Vfs, config.storage_driver);
+    assert_eq!(CgroupDriverSpec::Systemd, config.cgroup_driver);
+    assert_eq!(Some(HostsFileSpec { path: PathBuf::from("/etc/hosts"), domain: Some("cort".to_owned()) }), config.hosts_file);
+    assert_eq!(LogDriverSpec::JsonFile, config.log_driver);
+    assert!(config.overlay.volatile && !config.overlay.metacopy);
+    assert_eq!("cort0", config.network.bridge_interface);
+    assert_eq!(Ipv4Net::from_str("10.20.0.1/24").unwrap(), config.network.bridge_ip_address);
+    assert!(!config.network.inter_container_communication);
+    assert!(!config.network.hairpin);
+    assert_eq!(IpamSpec::Dhcp, config.network.ipam);
+    assert_eq!("cort0", config.find_network(DEFAULT_NETWORK).unwrap().bridge_interface);
+    assert_eq!("cort1", config.find_network("backend").unwrap().bridge_interface);
+    assert!(config.find_network("frontend").is_err());
+    assert_eq!(Some(256), config.limits.cpu_shares);
+    assert_eq!(Some(536870912), config.limits.memory);
+    assert_eq!(Some(10), config.limits.memory_swappiness);
+    assert_eq!(None, config.limits.memory_reservation);
+    assert_eq!(vec![Path::new("/etc/cort/cosign.pub").to_owned()], config.signatures.public_keys);
+    assert_eq!(SignaturePolicy::Required, config.signatures.policy);
+    assert_eq!(Path::new("/usr/local/bin/provision-volumes"), config.hooks.prestart[0].path);
+    assert_eq!(vec!["--verbose".to_owned()], config.hooks.prestart[0].args);
+    assert_eq!(Some(10), config.hooks.prestart[0].timeout);
+    assert!(config.hooks.poststop.is_empty());
+    assert_eq!(PullPolicy::Never, config.images.pull_policy);
+    assert_eq!(3, config.images.max_concurrent_downloads);
+    assert!(!config.images.emulation);
+    assert_eq!(vec!["mirror.internal:5000".to_owned()], config.registries["docker.io"].mirrors);
+    assert!(config.registries["mirror.internal:5000"].insecure);
+    assert_eq!(None, config.registries["mirror.internal:5000"].ca_cert);
+}
@@ -1,307 +1,2391 @@
-use std::ffi::{c_int, c_void, CString};
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Write;
-use std::os::unix::io::AsRawFd;
+use std::net::Ipv4Addr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::AssertUnwindSafe;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, mpsc, Mutex};
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use tracing::{error, field, info, info_span, Span, trace, warn};
+use tracing::span::EnteredSpan;
+use serde::Serialize;
+
+use crate::audit::{AUDIT_LOG, AuditedSysApi, AuditLog};
+use crate::dhcp::DhcpLease;
+use crate::helpers::{RemoveDirGuard, Rollback};
+use crate::cni::{self, CniResult};
+use crate::emulation::{self, Emulator};
+use crate::gpu::{self, GpuDevice, GpuPassthrough};
+use crate::hooks::{self, HookStage};
+use crate::hosts;
+use crate::fuse::FuseMount;
+use crate::image::{find_in_layers, ImageLayer, ImageStore, validate_rootfs};
+use crate::init;
+use crate::linux::{change_dir, clone_process, exec, unshare, waitpid, wrap_libc_error};
+use crate::linux;
+use crate::logs::{self, LOGS_DIR, LogStream};
+use crate::model::{ContainerExitStatus, ContainerRuntimeError, ContainerRuntimeResult, ErrorReport, RunPlan, signal_name, User, USER_PATH};
+use crate::netwatch::HostNetworkWatcher;
+use crate::network;
+use crate::registry::Platform;
+use crate::spec::{BindMountSpec, BridgedNetworkSpec, BridgeSpec, CapabilitySpec, CgroupDriverSpec, CniNetworkSpec, DNSSpec, HostsFileSpec, ListenFdsSpec, MountPropagation, NamespaceMode, NetworkSpec, RunContainerSpec, SchedulingSpec, SECRETS_DIR, SecretSpec, SecuritySpec, SPEC_FILE, StorageDriverSpec, TimeNamespaceSpec, UlimitSpec};
+use crate::state::{ContainerState, ExecSession, ResourceLimits, ResourceUsage, StartupTiming};
+use crate::storage;
+use crate::sync::{SyncChannel, SyncMessage};
+use crate::sys::{DryRunSysApi, RecordingSysApi, SysApi};
+use crate::systemd;
+
+struct ExecuteContext<'a> {
+    sys: &'a dyn SysApi,
+    spec: &'a RunContainerSpec,
+    image_layers: Vec<PathBuf>,
+    localtime: Option<PathBuf>,
+    gpus: Option<GpuPassthrough>,
+    emulator: Option<Emulator>,
+    /// The content of each secret along with where it is written in the container, read before the root is changed
+    secrets: Vec<(PathBuf, Vec<u8>)>,
+    storage_driver: StorageDriverSpec,
+    /// The read end of the pipe that the stdin of the runtime is copied to, otherwise stdin is /dev/null
+    stdin: Option<RawFd>,
+    /// The write ends of the pipes capturing stdout and stderr, when the output is logged
+    output: Option<(RawFd, RawFd)>,
+    /// The namespaces of the pod that the container enters, along with their types
+    pod_namespaces: Vec<(File, libc::c_int)>
+}
+
+/// The time allowed for each setup step in the container, which is generous as the vfs storage driver copies the image layers.
+const SETUP_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The phases of starting a container (such as setting up its cgroups), each of which runs in a `startup` span of its own within the span
+/// of the container, so that subscribers see how the phases nest and how long they take (recorded as `elapsed_ms` when they end).
+/// The durations recorded by the spans are kept as the startup timings of the container, which are logged with --time-startup.
+#[derive(Default)]
+struct StartupPhases {
+    phases: Vec<(String, Duration)>
+}
+
+/// A phase that has started, whose span is entered until the phase is ended (or dropped, such as when it fails).
+struct StartupPhase {
+    name: String,
+    span: EnteredSpan,
+    start: Instant
+}
+
+impl StartupPhases {
+    fn start(name: &str) -> StartupPhase {
+        StartupPhase {
+            name: name.to_owned(),
+            span: info_span!("startup", phase = %name, elapsed_ms = field::Empty).entered(),
+            start: Instant::now()
+        }
+    }
+
+    fn end(&mut self, phase: StartupPhase) {
+        let elapsed = phase.start.elapsed();
+        phase.span.record("elapsed_ms", elapsed.as_secs_f64() * 1000.0);
+        trace!("Startup phase {} took {:.3} ms", phase.name, elapsed.as_secs_f64() * 1000.0);
+        self.phases.push((phase.name, elapsed));
+    }
+
+    /// Adds the phases that ran in another process (the container), where they were recorded by spans of their own.
+    fn extend(&mut self, phases: Vec<(String, Duration)>) {
+        self.phases.extend(phases);
+    }
+
+    fn timings(&self) -> Vec<StartupTiming> {
+        self.phases
+            .iter()
+            .map(|(phase, duration)| StartupTiming { phase: phase.clone(), milliseconds: duration.as_secs_f64() * 1000.0 })
+            .collect()
+    }
+
+    /// The total time of the phases, and the time of each phase.
+    fn report(&self) -> String {
+        let phases = self.phases
+            .iter()
+            .map(|(phase, duration)| format!("{} {:.1} ms", phase, duration.as_secs_f64() * 1000.0))
+            .collect::<Vec<_>>();
+        let total = self.phases.iter().map(|(_, duration)| *duration).sum::<Duration>();
+        format!("{:.1} ms ({})", total.as_secs_f64() * 1000.0, phases.join(", "))
+    }
+}
+
+/// The socket in the container root where the supervisor of a created container waits for it to be started.
+const START_SOCKET: &str = "start.sock";
+
+/// The PID of the runtime of the container, written into its root when created, as the container is set up before its state is saved.
+const RUNTIME_PID_FILE: &str = "runtime.pid";
+
+/// The named pipes in the container root that the output of a logged container goes through.
+const STDOUT_FIFO: &str = "stdout.fifo";
+const STDERR_FIFO: &str = "stderr.fifo";
+
+/// The span of what is done for a container, so that the log of concurrent containers can be told apart.
+pub fn span(container_id: &str, name: &str) -> Span {
+    info_span!("container", container_id = %container_id, container_name = %name)
+}
+
+pub fn run(sys: &Arc<dyn SysApi>, run_container_spec: &RunContainerSpec) -> ContainerRuntimeResult<ContainerExitStatus> {
+    run_container(sys, run_container_spec, &mut None)
+}
+
+/// Creates the container in a supervisor process that keeps running in the background, returning once the container is ready to execute its command.
+/// The supervisor executes the command when the container is started, and runs the cleanup once the container has exited.
+/// Returns the PID of the supervisor.
+pub fn create(sys: &Arc<dyn SysApi>, run_container_spec: &RunContainerSpec, cleanup: Rollback<'static>) -> ContainerRuntimeResult<i32> {
+    let (mut channel, supervisor_channel) = SyncChannel::pair()?;
+    let pid = linux::fork()?;
+    if pid == 0 {
+        drop(channel);
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            // Not part of the session of `cort create`, so that it is not killed along with it, and without its stdio,
+            // so that nothing waiting for its output to end (such as a shell capturing the id) waits for the container
+            unsafe {
+                libc::setsid();
+            }
+            let dev_null = std::fs::OpenOptions::new().read(true).write(true).open("/dev/null")?;
+            for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+                linux::dup2(dev_null.as_raw_fd(), fd)?;
+            }
+
+            let mut creator = Some(supervisor_channel);
+            let result = run_container(sys, run_container_spec, &mut creator);
+            match (&result, creator.as_mut()) {
+                (Err(err), Some(creator)) => {
+                    creator.send(&SyncMessage::Error(relayed_report(err))).ok();
+                }
+                (Err(err), None) => error!("Container {} failed: {}", run_container_spec.name, err),
+                _ => {}
+            }
+
+            drop(cleanup);
+            result
+        }));
+
+        unsafe {
+            libc::_exit(if matches!(result, Ok(Ok(_))) { 0 } else { 1 });
+        }
+    }
+
+    drop(supervisor_channel);
+    cleanup.commit();
+
+    match channel.receive()? {
+        Some(SyncMessage::Created) => Ok(pid),
+        Some(SyncMessage::Error(report)) => Err(ContainerRuntimeError::ContainerProcess(report)),
+        message => Err(ContainerRuntimeError::ContainerSetup(format!("Expected the container to be created but got {:?}", message)))
+    }
+}
+
+/// Starts a created container, returning once it has executed its command.
+pub fn start(container_root: &Path) -> ContainerRuntimeResult<()> {
+    let stream = UnixStream::connect(container_root.join(START_SOCKET))
+        .map_err(|err| ContainerRuntimeError::ContainerSetup(format!("Failed to connect to the supervisor of the container: {}", err)))?;
+
+    match SyncChannel::from_stream(stream).receive()? {
+        Some(SyncMessage::Started) => Ok(()),
+        Some(SyncMessage::Error(report)) => Err(ContainerRuntimeError::ContainerProcess(report)),
+        message => Err(ContainerRuntimeError::ContainerSetup(format!("Expected the container to be started but got {:?}", message)))
+    }
+}
+
+/// Stands for the PID of the container process when planning, which is only known once it is created.
+const PLAN_PID: i32 = i32::MAX;
+
+/// What running the container would do to the host and within the container, without doing it. The setup steps record their calls
+/// instead of making them, but the calls that only query the host are made, so that the plan follows what a run would find.
+/// The bridge is the one the container is connected to, which is created first if missing.
+pub fn plan(run_container_spec: &RunContainerSpec, bridge: Option<&BridgeSpec>) -> ContainerRuntimeResult<RunPlan> {
+    let spec = run_container_spec;
+    let layers = match &spec.rootfs {
+        Some(rootfs) => vec![ImageLayer::Extracted(validate_rootfs(rootfs)?)],
+        None => ImageStore::new(&spec.image_base_dir).layers(&spec.image)?
+    };
+
+    // Lazily pulled layers are not mounted, so what is in them is not known
+    let mut lazy_mounts = Vec::new();
+    let mut image_layers = Vec::new();
+    for (index, layer) in layers.into_iter().enumerate() {
+        match layer {
+            ImageLayer::Extracted(layer_dir) => image_layers.push(layer_dir),
+            ImageLayer::Lazy(layer) => {
+                let mount_point = spec.container_root().join("lazy").join(index.to_string());
+                lazy_mounts.push(format!("{} (layer {}, read from the registry as used)", mount_point.to_str().unwrap(), layer.digest));
+                image_layers.push(mount_point);
+            }
+        }
+    }
+
+    let gpus = spec.gpus.as_ref().map(gpu::discover).transpose()?;
+    let emulator = find_emulator(spec, &ImageStore::new(&spec.image_base_dir))?;
+    if lazy_mounts.is_empty() {
+        validate_command(spec, &image_layers, gpus.as_ref())?;
+    }
+    validate_security(&spec.security)?;
+    let localtime = spec.timezone.as_ref().map(|timezone| timezone.localtime_path()).transpose()?;
+
+    let new_root = spec.container_root().join("rootfs");
+    let storage_driver = storage::create_storage_driver(
+        &storage::select_storage_driver(spec.storage_driver, &spec.containers_base_dir),
+        &spec.overlay,
+        &spec.image_base_dir
+    );
+
+    let mut plan = RunPlan::default();
+    plan.add("Container", vec![
+        format!("name: {} (id: {})", spec.name, spec.id),
+        format!("command: {}", spec.command.join(" ")),
+        format!("root: {} ({} storage of {} layers)", new_root.to_str().unwrap(), storage_driver.name(), image_layers.len())
+    ]);
+    plan.add("Lazy layers", lazy_mounts);
+    if let Some(emulator) = &emulator {
+        let handler = emulator.handler.to_str().unwrap();
+        plan.add("Emulation", vec![
+            format!("emulator: {}", emulator.interpreter.to_str().unwrap()),
+            if emulator.registered { format!("binfmt_misc handler: {}", handler) } else { format!("register binfmt_misc handler: {}", handler) }
+        ]);
+    }
+
+    let clone_flags = clone_namespaces(spec);
+    let mut namespaces = [
+        (libc::CLONE_NEWPID, "pid"), (libc::CLONE_NEWNS, "mount"), (libc::CLONE_NEWUTS, "uts"), (libc::CLONE_NEWNET, "network"), (libc::CLONE_NEWIPC, "ipc")
+    ]
+        .iter()
+        .filter(|(flag, _)| clone_flags & flag != 0)
+        .map(|(_, name)| format!("new {} namespace", name))
+        .collect::<Vec<_>>();
+    if spec.cgroup_namespace == NamespaceMode::Private {
+        namespaces.push("new cgroup namespace".to_owned());
+    }
+    if let NetworkSpec::Pod(pod) = &spec.network {
+        namespaces.push(format!("network, uts and ipc namespaces of pod {}", pod.pod));
+    }
+    if spec.time_namespace.is_some() {
+        namespaces.push("new time namespace".to_owned());
+    }
+    plan.add("Namespaces", namespaces);
+
+    let mut cgroup_writes = Vec::new();
+    let is_systemd = spec.cgroup_driver == CgroupDriverSpec::Systemd;
+    if is_systemd {
+        cgroup_writes.push(format!("start systemd scope {} in {} with <pid>", systemd::scope_name(&spec.id), systemd::SLICE));
+    }
+    let mut add_cgroup = |controller: &str, limits: Vec<(&str, String)>| {
+        let cgroup_dir = if is_systemd && systemd::CONTROLLERS.contains(&controller) {
+            scope_cgroup_dir(controller, &spec.id)
+        } else {
+            cgroup_base_dir(controller).join(&spec.id)
+        };
+        cgroup_writes.push(format!("{} = <pid>", cgroup_dir.join("cgroup.procs").to_str().unwrap()));
+        for (file, value) in limits {
+            cgroup_writes.push(format!("{} = {}", cgroup_dir.join(file).to_str().unwrap(), value));
+        }
+    };
+
+    let limits = ResourceLimits::from_spec(spec);
+    let mut cpu_limits = cpu_cgroup_limits(&limits);
+    if spec.scheduling.rt_priority.is_some() {
+        cpu_limits.push(("cpu.rt_runtime_us", RT_RUNTIME_US.to_string()));
+    }
+    add_cgroup("cpu", cpu_limits);
+
+    let mut memory_limits = memory_cgroup_limits(&limits);
+    if let Some(kernel_memory) = spec.kernel_memory {
+        memory_limits.push(("memory.kmem.limit_in_bytes", kernel_memory.to_string()));
+    }
+    add_cgroup("memory", memory_limits);
+
+    for controller in OPTIONAL_CGROUP_CONTROLLERS {
+        if Path::new("/sys/fs/cgroup").join(controller).exists() {
+            add_cgroup(controller, Vec::new());
+        }
+    }
+    if let Some(gpus) = &gpus {
+        if Path::new("/sys/fs/cgroup/devices").exists() {
+            add_cgroup("devices", devices_cgroup_rules(&gpus.devices));
+        }
+    }
+    plan.add("Cgroups", cgroup_writes);
+
+    let host_sys = DryRunSysApi::new();
+    if let Some(bridge) = bridge {
+        network::create_bridge(&host_sys, bridge)?;
+    }
+    if let NetworkSpec::Bridged(bridged) = &spec.network {
+        network::create_container_network(&host_sys, bridged, &spec.id, PLAN_PID)?;
+    }
+    let mut host_steps = host_sys.command_lines();
+    if let NetworkSpec::Cni(cni_network) = &spec.network {
+        host_steps.push(format!("add to the CNI network {} (configuration in {})", cni_network.name, cni_network.config_dir.to_str().unwrap()));
+    }
+    for hook in &spec.hooks.prestart {
+        host_steps.push(format!("prestart hook: {} {}", hook.path.to_str().unwrap(), hook.args.join(" ")).trim_end().to_owned());
+    }
+    plan.add("Host", host_steps.iter().map(|step| step.replace(&PLAN_PID.to_string(), "<pid>")).collect());
+
+    let network_sys = RecordingSysApi::new();
+    let mut network_steps = Vec::new();
+    match &spec.network {
+        NetworkSpec::Bridged(bridged) if bridged.container_ip_address.is_some() => {
+            network::setup_container_interface(&network_sys, bridged, &spec.id)?;
+        }
+        NetworkSpec::Bridged(_) => network_steps.push(format!("configure {} with an address leased with DHCP", network::CONTAINER_INTERFACE)),
+        NetworkSpec::Cni(_) => network::setup_loopback_interface(&network_sys)?,
+        NetworkSpec::Host => {}
+        NetworkSpec::Pod(pod) => network_steps.push(format!("use the network of pod {} (PID {})", pod.pod, pod.pid))
+    }
+    // The hostname of a pod is set by the container that created its namespaces
+    if let Some(hostname) = spec.hostname().filter(|_| !matches!(spec.network, NetworkSpec::Pod(_))) {
+        network_sys.set_hostname(&hostname)?;
+    }
+    network_steps.splice(0..0, network_sys.command_lines());
+    plan.add("Container network", network_steps);
+
+    // The mounts are set up in a scratch directory standing in for the root, so that the steps creating mount points are harmless
+    let scratch_root = std::env::temp_dir().join(format!("cort-plan-{}", uuid::Uuid::new_v4()));
+    let _remove_scratch_root = RemoveDirGuard::new(scratch_root.clone());
+    std::fs::create_dir_all(&scratch_root)?;
+
+    let mount_sys = RecordingSysApi::new();
+    let bind_mounts = container_bind_mounts(spec, localtime.as_deref(), gpus.as_ref(), emulator.as_ref());
+    let gpu_devices = gpus.as_ref().map(|gpus| gpus.devices.as_slice()).unwrap_or_default();
+    mount_sys.mount(None, Path::new("/"), None, root_propagation(spec) | libc::MS_REC, None)?;
+    setup_mounts(&mount_sys, &scratch_root)?;
+    setup_shm(&mount_sys, &scratch_root, spec)?;
+    setup_devices(&mount_sys, &scratch_root, gpu_devices)?;
+    setup_bind_mounts(&mount_sys, &scratch_root, &bind_mounts)?;
+    mount_sys.pivot_root(&scratch_root, &scratch_root.join("old_root"))?;
+    mount_sys.unmount(Path::new("/old_root"))?;
+    if spec.read_only {
+        make_root_read_only(&mount_sys)?;
+    }
+    let scratch_root = scratch_root.to_str().unwrap();
+    plan.add("Mounts", mount_sys.command_lines().iter().map(|step| step.replace(scratch_root, new_root.to_str().unwrap())).collect());
+
+    let mut files = Vec::new();
+    if spec.prewarm {
+        files.push("read ahead the executable files of the root".to_owned());
+    }
+    files.extend(["write /etc/resolv.conf".to_owned(), "write /etc/hosts".to_owned()]);
+    if find_in_layers(&image_layers, Path::new("/etc/nsswitch.conf"))?.is_none() {
+        files.push("write /etc/nsswitch.conf".to_owned());
+    }
+    files.extend(spec.secrets.iter().map(|secret| format!("write {} (mode 0400 on a tmpfs, from {})", secret.target().to_str().unwrap(), secret.source.to_str().unwrap())));
+    plan.add("Files", files);
+
+    let mut process_steps = Vec::new();
+    if let Some(profile) = &spec.security.apparmor_profile {
+        process_steps.push(format!("AppArmor profile: {}", profile));
+    }
+    if let Some(label) = &spec.security.selinux_label {
+        process_steps.push(format!("SELinux label: {}", label));
+    }
+    process_steps.extend(spec.sysctls.iter().map(|(name, value)| format!("{} = {}", sysctl_path(name).to_str().unwrap(), value)));
+    process_steps.extend(spec.ulimits.iter().map(|ulimit| format!("ulimit: {}", ulimit)));
+    if let Some(oom_score_adj) = spec.scheduling.oom_score_adj {
+        process_steps.push(format!("/proc/<pid>/oom_score_adj = {}", oom_score_adj));
+    }
+    if let Some(nice) = spec.scheduling.nice {
+        process_steps.push(format!("nice: {}", nice));
+    }
+    if let Some(rt_priority) = spec.scheduling.rt_priority {
+        process_steps.push(format!("real-time priority: {} (SCHED_RR)", rt_priority));
+    }
+
+    let passwd_path = find_in_layers(&image_layers, Path::new("/etc/passwd"))?;
+    let users = passwd_path.map(|passwd_path| User::from_passwd_file(&passwd_path)).transpose()?.unwrap_or_default();
+    let group_path = find_in_layers(&image_layers, Path::new("/etc/group"))?;
+    let groups = group_path.map(|group_path| User::groups_from_group_file(&group_path)).transpose()?.unwrap_or_default();
+    if let Some(user) = spec.user(&users, &groups) {
+        let user = user?;
+        let group_id = user.group_id.map(|group_id| group_id.to_string()).unwrap_or_else(|| "unchanged".to_owned());
+        process_steps.push(format!("user: {} (uid: {}, gid: {}, home: {})", user.username, user.id, group_id, user.home_folder.to_str().unwrap()));
+        process_steps.extend(user.environment().iter().map(|(name, value)| format!("environment: {}={}", name, value)));
+        if user.id != 0 {
+            let kept = spec.security.keep_capabilities.iter().map(|capability| capability.name).collect::<Vec<_>>();
+            process_steps.push(format!("drop capabilities, keeping: {}", if kept.is_empty() { "none".to_owned() } else { kept.join(", ") }));
+        }
+    }
+    if let Some(gpus) = &gpus {
+        process_steps.extend(gpus.environment.iter().map(|(name, value)| format!("environment: {}={}", name, value)));
+    }
+    if spec.init {
+        process_steps.push("init as PID 1".to_owned());
+    }
+    if let Some(listen_fds) = &spec.listen_fds {
+        process_steps.push(format!("socket activation: fds {} to {} (LISTEN_FDS={})", listen_fds.fds().start, listen_fds.fds().end - 1, listen_fds.count));
+    }
+    process_steps.push(format!("exec {}", spec.command.join(" ")));
+    plan.add("Process", process_steps);
+
+    Ok(plan)
+}
+
+/// Passes the sockets from systemd on to the command, which is the process that they are for once it is executed (rather than the init).
+fn pass_listen_fds(listen_fds: &ListenFdsSpec) -> ContainerRuntimeResult<()> {
+    for fd in listen_fds.fds() {
+        linux::set_inheritable(fd)?;
+    }
+
+    std::env::set_var("LISTEN_FDS", listen_fds.count.to_string());
+    std::env::set_var("LISTEN_PID", std::process::id().to_string());
+    if let Some(names) = &listen_fds.names {
+        std::env::set_var("LISTEN_FDNAMES", names);
+    }
+
+    Ok(())
+}
+
+/// The namespaces that the container process is created in, where the cgroup and time namespaces are created by the process itself.
+fn clone_namespaces(spec: &RunContainerSpec) -> libc::c_int {
+    // The containers of a pod enter the network, UTS and IPC namespaces of the pod instead
+    let joins_pod = matches!(spec.network, NetworkSpec::Pod(_));
+    let clone_network_namespace = if spec.network.is_host() || joins_pod {0} else {libc::CLONE_NEWNET};
+    let clone_uts_namespace = if joins_pod {0} else {libc::CLONE_NEWUTS};
+    let clone_ipc_namespace = if spec.ipc_namespace == NamespaceMode::Host || joins_pod {0} else {libc::CLONE_NEWIPC};
+    libc::CLONE_NEWPID | libc::CLONE_NEWNS | clone_uts_namespace | clone_network_namespace | clone_ipc_namespace
+}
+
+/// Opens the namespaces of the pod that the container joins, before the container is cloned.
+fn open_pod_namespaces(spec: &RunContainerSpec) -> ContainerRuntimeResult<Vec<(File, libc::c_int)>> {
+    let NetworkSpec::Pod(pod) = &spec.network else {
+        return Ok(Vec::new());
+    };
+
+    [(libc::CLONE_NEWNET, "net"), (libc::CLONE_NEWUTS, "uts"), (libc::CLONE_NEWIPC, "ipc")]
+        .iter()
+        .map(|(namespace_type, name)| {
+            let namespace = linux::open_namespace(pod.pid, name).map_err(|err| {
+                ContainerRuntimeError::ContainerSetup(format!("Failed to open the {} namespace of pod {} (PID {}): {}", name, pod.pod, pod.pid, err))
+            })?;
+            Ok((namespace, *namespace_type))
+        })
+        .collect()
+}
+
+/// Runs the container, where the command is only executed once the container is started if there is a creator waiting for it to be created.
+fn run_container(sys: &Arc<dyn SysApi>, run_container_spec: &RunContainerSpec, creator: &mut Option<SyncChannel>) -> ContainerRuntimeResult<ContainerExitStatus> {
+    let _span = span(&run_container_spec.id, &run_container_spec.name).entered();
+    let mut phases = StartupPhases::default();
+    let phase = StartupPhases::start("image");
+    let image_store = ImageStore::new(&run_container_spec.image_base_dir);
+    let (layer_digests, layers): (Vec<_>, Vec<_>) = match &run_container_spec.rootfs {
+        Some(rootfs) => (Vec::new(), vec![ImageLayer::Extracted(validate_rootfs(rootfs)?)]),
+        None => {
+            let layers = image_store.layers_with_digests(&run_container_spec.image)?;
+            if run_container_spec.verify_rootfs {
+                image_store.verify_layers(&run_container_spec.image)?;
+            }
+
+            layers.into_iter().unzip()
+        }
+    };
+    phases.end(phase);
+
+    let phase = StartupPhases::start("preparation");
+    let gpus = run_container_spec.gpus.as_ref().map(gpu::discover).transpose()?;
+    let mut emulator = find_emulator(run_container_spec, &image_store)?;
+    if let Some(emulator) = &mut emulator {
+        // The handler is kept when the container exits, like the ones registered by qemu-user-static
+        emulator.register()?;
+    }
+    let secrets = read_secrets(&run_container_spec.secrets)?;
+    validate_security(&run_container_spec.security)?;
+    let localtime = run_container_spec.timezone.as_ref().map(|timezone| timezone.localtime_path()).transpose()?;
+
+    create_container_root(run_container_spec)?;
+    let mut remove_container_root = RemoveDirGuard::new(run_container_spec.container_root());
+    run_container_spec.save(&run_container_spec.container_root())?;
+
+    // Opened before the container is cloned, so that it can audit its mounts after changing its root
+    let audit_log = Arc::new(AuditLog::new(run_container_spec.container_root().join(AUDIT_LOG)));
+    audit_log.open()?;
+    let sys = &AuditedSysApi::wrap(sys, audit_log.clone());
+
+    // Lazily pulled layers are served from this process, and must be unmounted before the container root is removed
+    let mut lazy_mounts = Vec::new();
+    let mut image_layers = Vec::new();
+    for (index, layer) in layers.into_iter().enumerate() {
+        match layer {
+            ImageLayer::Extracted(layer_dir) => image_layers.push(layer_dir),
+            ImageLayer::Lazy(layer) => {
+                let mount_point = run_container_spec.container_root().join("lazy").join(index.to_string());
+                std::fs::create_dir_all(&mount_point)?;
+                let lazy_layer = Arc::new(image_store.open_lazy_layer(&layer, &run_container_spec.registries)?);
+                let lazy_mount = FuseMount::mount(sys.clone(), lazy_layer, &layer.digest, &mount_point)?;
+                image_layers.push(lazy_mount.mount_point().to_owned());
+                lazy_mounts.push(lazy_mount);
+            }
+        }
+    }
+
+    // The infra container of a pod never executes its command
+    if !run_container_spec.infra {
+        validate_command(run_container_spec, &image_layers, gpus.as_ref())?;
+    }
+
+    let storage_driver = storage::select_storage_driver(run_container_spec.storage_driver, &run_container_spec.container_root());
+
+    let log_driver = logs::create_log_driver(run_container_spec)?;
+    let output = match log_driver {
+        Some(_) => Some((create_output_fifo(&run_container_spec.container_root(), STDOUT_FIFO)?, create_output_fifo(&run_container_spec.container_root(), STDERR_FIFO)?)),
+        None => None
+    };
+
+    // The container never shares the stdin of the runtime (such as its terminal), but gets a copy of it when interactive
+    let (stdin, stdin_writer) = if run_container_spec.interactive {
+        let (stdin, stdin_writer) = linux::pipe()?;
+        (Some(stdin), Some(stdin_writer))
+    } else {
+        (None, None)
+    };
+
+    let (mut channel, child_channel) = SyncChannel::pair()?;
+    let context = ExecuteContext {
+        sys: sys.as_ref(),
+        spec: run_container_spec,
+        image_layers,
+        localtime,
+        gpus,
+        emulator,
+        secrets,
+        storage_driver,
+        stdin: stdin.as_ref().map(|stdin| stdin.as_raw_fd()),
+        output: output.as_ref().map(|((_, stdout), (_, stderr))| (stdout.as_raw_fd(), stderr.as_raw_fd())),
+        pod_namespaces: open_pod_namespaces(run_container_spec)?
+    };
+
+    let mut cleanup = Rollback::new();
+    add_cgroups_cleanup(&mut cleanup, &audit_log, &run_container_spec.id);
+
+    phases.end(phase);
+
+    let phase = StartupPhases::start("clone");
+    let (pid, process) = clone_process(clone_namespaces(run_container_spec))?;
+    if pid == 0 {
+        // The phases of the container are spans of their own, rather than of cloning it
+        drop(phase);
+        // The child has its own copy of everything in scope, but must never return (or unwind) from here, as the cleanup belongs to the parent.
+        // The parent end must be closed for the container to notice if the parent exits, and the write end of stdin for it to get EOF.
+        drop(channel);
+        drop(stdin_writer);
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| execute(&context, child_channel)));
+        unsafe {
+            libc::_exit(if matches!(result, Ok(Ok(()))) { 0 } else { 1 });
+        }
+    }
+
+    drop(child_channel);
+    drop(stdin);
+
+    // The container is signaled through its pidfd, so that a signal sent after it has been waited for never reaches another process
+    let process = Arc::new(process.unwrap());
+
+    // Only the container may hold the write ends, so that the output reaches the end when it exits
+    let output = output.map(|((stdout, _), (stderr, _))| (stdout, stderr));
+
+    info!("Running container as PID {}.", pid);
+    phases.end(phase);
+
+    // A setup step in the container that hangs, such as a mount, fails the run instead of blocking it
+    channel.set_receive_timeout(Some(SETUP_TIMEOUT))?;
+    let mut starter = None;
+    let mut setup = || -> ContainerRuntimeResult<()> {
+        let phase = StartupPhases::start("cgroups");
+        let mut state = ContainerState::new(run_container_spec, pid);
+        state.layers = layer_digests.clone();
+        state.save(&run_container_spec.container_root())?;
+        if run_container_spec.cgroup_driver == CgroupDriverSpec::Systemd {
+            systemd::start_scope(&run_container_spec.id, &run_container_spec.name, pid, &state.limits)?;
+        }
+        setup_cpu_cgroup(&audit_log, &run_container_spec.id, pid, &state.limits, run_container_spec.scheduling.rt_priority.is_some())?;
+        setup_memory_cgroup(&audit_log, run_container_spec, pid)?;
+        setup_optional_cgroups(&audit_log, &run_container_spec.id, pid)?;
+        if let Some(gpus) = &context.gpus {
+            setup_devices_cgroup(&audit_log, &run_container_spec.id, pid, &gpus.devices)?;
+        }
+        setup_scheduling(pid, &run_container_spec.scheduling)?;
+        phases.end(phase);
+
+        let phase = StartupPhases::start("host netns");
+        if let NetworkSpec::Bridged(bridged) = &run_container_spec.network {
+            network::create_container_network(sys.as_ref(), bridged, &run_container_spec.id, pid)?;
+            add_container_network_cleanup(&mut cleanup, sys, run_container_spec);
+        }
+
+        if let NetworkSpec::Cni(cni_network) = &run_container_spec.network {
+            let result = cni::add(cni_network, &run_container_spec.id, pid)?;
+            state.ip_address = result.ip_address();
+            state.cni_result = Some(result.clone());
+            state.save(&run_container_spec.container_root())?;
+            channel.send(&SyncMessage::CniAdded(state.ip_address))?;
+            add_cni_cleanup(&mut cleanup, cni_network, &run_container_spec.id, pid, result);
+        }
+
+        phases.end(phase);
+
+        let phase = StartupPhases::start("prestart hooks");
+        hooks::run_hooks(HookStage::Prestart, &run_container_spec.hooks.prestart, &state, &run_container_spec.container_root())?;
+        phases.end(phase);
+        channel.send(&SyncMessage::ParentReady)?;
+
+        if let NetworkSpec::Bridged(BridgedNetworkSpec { container_ip_address: None, .. }) = &run_container_spec.network {
+            match channel.receive()? {
+                Some(SyncMessage::Leased(lease)) => {
+                    state.ip_address = Some(lease.address);
+                    state.dhcp_lease = Some(lease);
+                    state.save(&run_container_spec.container_root())?;
+                }
+                Some(SyncMessage::Error(report)) => return Err(ContainerRuntimeError::ContainerProcess(report)),
+                message => return Err(ContainerRuntimeError::ContainerSetup(format!("Expected a DHCP lease but got {:?}", message)))
+            }
+        }
+
+        // Once the address is known, where the container is removed again once it has exited
+        if let Some(hosts_file) = &run_container_spec.hosts_file {
+            if let Err(err) = hosts::update_hosts_file(hosts_file, &run_container_spec.containers_base_dir) {
+                warn!("Failed to add the container to the hosts file {}: {}", hosts_file.path.to_str().unwrap(), err);
+            }
+
+            add_hosts_file_cleanup(&mut cleanup, hosts_file, &run_container_spec.containers_base_dir);
+        }
+
+        match channel.receive()? {
+            Some(SyncMessage::StartupTimes(container_phases)) => phases.extend(container_phases),
+            Some(SyncMessage::Error(report)) => return Err(ContainerRuntimeError::ContainerProcess(report)),
+            message => return Err(ContainerRuntimeError::ContainerSetup(format!("Expected the startup times but got {:?}", message)))
+        }
+        channel.wait_for(SyncMessage::ChildReady)?;
+
+        if let Some(creator_channel) = creator.as_mut() {
+            let listener = UnixListener::bind(run_container_spec.container_root().join(START_SOCKET))?;
+            state.created = true;
+            state.save(&run_container_spec.container_root())?;
+            creator_channel.send(&SyncMessage::Created)?;
+            *creator = None;
+
+            info!("Created container {}, waiting for it to be started.", run_container_spec.name);
+            starter = Some(wait_for_start(&listener, &channel)?);
+            std::fs::remove_file(run_container_spec.container_root().join(START_SOCKET))?;
+        }
+
+        let phase = StartupPhases::start("exec");
+        channel.send(&SyncMessage::Exec)?;
+
+        // The channel is closed on a successful exec
+        match channel.receive()? {
+            None => {}
+            Some(SyncMessage::Error(report)) => return Err(ContainerRuntimeError::ContainerProcess(report)),
+            Some(message) => return Err(ContainerRuntimeError::ContainerSetup(format!("Unexpected message {:?}", message)))
+        }
+
+        phases.end(phase);
+        if run_container_spec.time_startup {
+            info!("Started container {} in {}.", run_container_spec.name, phases.report());
+        }
+
+        // The state might have changed meanwhile, such as by renaming the container while waiting to be started
+        let mut state = ContainerState::load(&run_container_spec.container_root())?;
+        state.created = false;
+        state.startup_timings = phases.timings();
+        state.save(&run_container_spec.container_root())?;
+
+        Ok(())
+    };
+
+    let setup_result = setup();
+    if let Some(starter) = starter.as_mut() {
+        let message = match &setup_result {
+            Ok(()) => SyncMessage::Started,
+            Err(err) => SyncMessage::Error(relayed_report(err))
+        };
+
+        if let Err(err) = starter.send(&message) {
+            warn!("Failed to report to the starter of the container: {}", err);
+        }
+    }
+    drop(starter);
+
+    if let Err(err) = setup_result {
+        // The container might already have exited
+        linux::pidfd_send_signal(&process, libc::SIGKILL).ok();
+        waitpid(pid, 0)?;
+
+        let state = ContainerState::new(run_container_spec, pid);
+        drop(cleanup);
+        drop(lazy_mounts);
+        unmount_leftovers(sys.as_ref(), &run_container_spec.container_root());
+        if audit_log.has_failures() {
+            remove_container_root.keep(AUDIT_LOG);
+        }
+        hooks::run_hooks_or_warn(HookStage::Poststop, &run_container_spec.hooks.poststop, &state, &run_container_spec.container_root());
+        return Err(err);
+    }
+    drop(channel);
+
+    if let Some(stdin_writer) = stdin_writer {
+        forward_stdin(stdin_writer);
+    }
+
+    // The command is killed if it runs past the timeout, unless it has already exited and dropped the sender
+    let (exited_sender, exited_receiver) = mpsc::channel::<()>();
+    let timeout = run_container_spec.timeout.map(|timeout| {
+        let span = Span::current();
+        let process = process.clone();
+        std::thread::spawn(move || {
+            let _span = span.entered();
+            if exited_receiver.recv_timeout(Duration::from_secs(timeout)) != Err(RecvTimeoutError::Timeout) {
+                return false;
+            }
+
+            warn!("Killing PID {} as it did not exit within {} seconds.", pid, timeout);
+            linux::pidfd_send_signal(&process, libc::SIGKILL).is_ok()
+        })
+    });
+
+    if !run_container_spec.hooks.poststart.is_empty() {
+        let state = ContainerState::load(&run_container_spec.container_root())?;
+        hooks::run_hooks_or_warn(HookStage::Poststart, &run_container_spec.hooks.poststart, &state, &run_container_spec.container_root());
+    }
+
+    let stop_on_signal = match StopOnSignal::install(&run_container_spec.id, pid, &process) {
+        Ok(stop_on_signal) => Some(stop_on_signal),
+        Err(err) => {
+            warn!("Failed to handle the signals of the runtime: {}", err);
+            None
+        }
+    };
+
+    let host_network_watcher = HostNetworkWatcher::start(sys, run_container_spec, pid);
+
+    let forward_output = match (output, log_driver) {
+        (Some((stdout, stderr)), Some(log_driver)) => {
+            let log_driver = Arc::new(Mutex::new(log_driver));
+            vec![
+                logs::forward_output(stdout, LogStream::Stdout, log_driver.clone()),
+                logs::forward_output(stderr, LogStream::Stderr, log_driver)
+            ]
+        }
+        _ => Vec::new()
+    };
+
+    let exit_status = loop {
+        let status = waitpid(pid, libc::WUNTRACED)?;
+        info!("PID {} {}.", pid, status);
+
+        // A stopped container can still be continued, so keep waiting until it terminates
+        if status.has_terminated() {
+            break status;
+        }
+    };
+
+    drop(stop_on_signal);
+    drop(host_network_watcher);
+    drop(exited_sender);
+    let timed_out = timeout.map(|timeout| timeout.join().unwrap_or(false)).unwrap_or(false);
+
+    for forward_output in forward_output {
+        forward_output.join().ok();
+    }
+
+    let state = exited_state(run_container_spec, Some(exit_status))?;
+    drop(cleanup);
+    drop(lazy_mounts);
+    finish_exited(sys.as_ref(), run_container_spec, &audit_log, remove_container_root, &state)?;
+
+    if timed_out {
+        return Err(ContainerRuntimeError::Timeout(format!("Container {} did not exit within {} seconds and was killed", run_container_spec.name, run_container_spec.timeout.unwrap_or_default())));
+    }
+
+    Ok(exit_status)
+}
+
+/// Takes over a running container whose runtime has exited, in a supervisor process that keeps running in the background, as `create` does.
+/// The supervisor follows the output of the container again, records its exit and cleans up after it, where the exit status is not known,
+/// as only the parent of the container can wait for it. The cleanup is run once the supervisor is done. Returns the PID of the supervisor.
+pub fn adopt(sys: &Arc<dyn SysApi>, run_container_spec: &RunContainerSpec, cleanup: Rollback<'static>) -> ContainerRuntimeResult<i32> {
+    let (mut channel, supervisor_channel) = SyncChannel::pair()?;
+    let pid = linux::fork()?;
+    if pid == 0 {
+        drop(channel);
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            unsafe {
+                libc::setsid();
+            }
+            let dev_null = std::fs::OpenOptions::new().read(true).write(true).open("/dev/null")?;
+            for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+                linux::dup2(dev_null.as_raw_fd(), fd)?;
+            }
+
+            let mut adopter = Some(supervisor_channel);
+            let result = adopt_container(sys, run_container_spec, &mut adopter);
+            match (&result, adopter.as_mut()) {
+                (Err(err), Some(adopter)) => {
+                    adopter.send(&SyncMessage::Error(relayed_report(err))).ok();
+                }
+                (Err(err), None) => error!("Container {} failed: {}", run_container_spec.name, err),
+                _ => {}
+            }
+
+            drop(cleanup);
+            result
+        }));
+
+        unsafe {
+            libc::_exit(if matches!(result, Ok(Ok(_))) { 0 } else { 1 });
+        }
+    }
+
+    drop(supervisor_channel);
+    cleanup.commit();
+
+    match channel.receive()? {
+        Some(SyncMessage::Adopted) => Ok(pid),
+        Some(SyncMessage::Error(report)) => Err(ContainerRuntimeError::ContainerProcess(report)),
+        message => Err(ContainerRuntimeError::ContainerSetup(format!("Expected the container to be adopted but got {:?}", message)))
+    }
+}
+
+fn adopt_container(sys: &Arc<dyn SysApi>, run_container_spec: &RunContainerSpec, adopter: &mut Option<SyncChannel>) -> ContainerRuntimeResult<()> {
+    let _span = span(&run_container_spec.id, &run_container_spec.name).entered();
+    let container_root = run_container_spec.container_root();
+    let mut state = ContainerState::load(&container_root)?;
+    let pid = state.pid;
+    // Refers to the container from now on, also if it exits and its PID is reused
+    let process = Arc::new(state.open_process()?);
+
+    let audit_log = Arc::new(AuditLog::new(container_root.join(AUDIT_LOG)));
+    audit_log.open()?;
+    let sys = &AuditedSysApi::wrap(sys, audit_log.clone());
+
+    let log_driver = logs::create_log_driver(run_container_spec)?;
+    let output = match log_driver {
+        Some(_) => match (open_output_fifo(&container_root, STDOUT_FIFO), open_output_fifo(&container_root, STDERR_FIFO)) {
+            (Ok(stdout), Ok(stderr)) => Some((stdout, stderr)),
+            (Err(err), _) | (_, Err(err)) => {
+                warn!("The output of the container cannot be followed: {}", err);
+                None
+            }
+        },
+        None => None
+    };
+
+    state.runtime_pid = Some(std::process::id() as i32);
+    state.adopted = true;
+    state.save(&container_root)?;
+    if let Some(adopter) = adopter.take().as_mut() {
+        adopter.send(&SyncMessage::Adopted)?;
+    }
+    info!("Adopted container with PID {}.", pid);
+
+    // What the runtime of the container would have done once it has exited, where everything it set up is undone
+    let mut cleanup = Rollback::new();
+    add_cgroups_cleanup(&mut cleanup, &audit_log, &run_container_spec.id);
+    if let NetworkSpec::Bridged(_) = &run_container_spec.network {
+        add_container_network_cleanup(&mut cleanup, sys, run_container_spec);
+    }
+    if let (NetworkSpec::Cni(cni_network), Some(result)) = (&run_container_spec.network, state.cni_result) {
+        add_cni_cleanup(&mut cleanup, cni_network, &run_container_spec.id, pid, result);
+    }
+    if let Some(hosts_file) = &run_container_spec.hosts_file {
+        add_hosts_file_cleanup(&mut cleanup, hosts_file, &run_container_spec.containers_base_dir);
+    }
+
+    let stop_on_signal = match StopOnSignal::install(&run_container_spec.id, pid, &process) {
+        Ok(stop_on_signal) => Some(stop_on_signal),
+        Err(err) => {
+            warn!("Failed to handle the signals of the runtime: {}", err);
+            None
+        }
+    };
+
+    let host_network_watcher = HostNetworkWatcher::start(sys, run_container_spec, pid);
+
+    let forward_output = match (output, log_driver) {
+        (Some((stdout, stderr)), Some(log_driver)) => {
+            let log_driver = Arc::new(Mutex::new(log_driver));
+            vec![
+                logs::forward_output(stdout, LogStream::Stdout, log_driver.clone()),
+                logs::forward_output(stderr, LogStream::Stderr, log_driver)
+            ]
+        }
+        _ => Vec::new()
+    };
+
+    linux::poll_readable(&[process.as_raw_fd()])?;
+    info!("PID {} exited.", pid);
+
+    drop(stop_on_signal);
+    drop(host_network_watcher);
+    for forward_output in forward_output {
+        forward_output.join().ok();
+    }
+
+    let state = exited_state(run_container_spec, None)?;
+    drop(cleanup);
+    finish_exited(sys.as_ref(), run_container_spec, &audit_log, RemoveDirGuard::new(container_root), &state)
+}
+
+/// Executes the command in the running container as its user, returning once the command exits. The command enters the namespaces
+/// and cgroups of the container through its pidfd (so never those of a later process), so that it is killed along with the container.
+/// The session is kept in the container root while the container runs, along with the exit status once the command has exited.
+pub fn exec_command(spec: &RunContainerSpec, state: &ContainerState, exec_id: &str, command: &[String], interactive: bool) -> ContainerRuntimeResult<ContainerExitStatus> {
+    let container_root = spec.container_root();
+    let container_process = state.open_process()?;
+    ExecSession::prune(&container_root)?;
+
+    // A process cannot change its own PID namespace, only the one that its children are created in
+    linux::set_namespace(&container_process, libc::CLONE_NEWPID)?;
+    let (mut channel, mut child_channel) = SyncChannel::pair()?;
+    let (pid, process) = clone_process(0)?;
+    if pid == 0 {
+        drop(channel);
+        std::panic::catch_unwind(AssertUnwindSafe(|| {
+            if let Err(err) = execute_in_container(spec, &container_process, &mut child_channel, command, interactive) {
+                child_channel.send(&SyncMessage::Error(err.report())).ok();
+            }
+        })).ok();
+
+        // Only reached when the command could not be executed
+        unsafe {
+            libc::_exit(1);
+        }
+    }
+
+    drop(child_channel);
+    let process = process.unwrap();
+
+    let mut setup = || -> ContainerRuntimeResult<()> {
+        join_cgroups(&spec.id, pid)?;
+        channel.send(&SyncMessage::ParentReady)?;
+
+        // The channel is closed on a successful exec
+        match channel.receive()? {
+            None => Ok(()),
+            Some(SyncMessage::Error(report)) => Err(ContainerRuntimeError::ContainerProcess(report)),
+            Some(message) => Err(ContainerRuntimeError::ContainerSetup(format!("Unexpected message {:?}", message)))
+        }
+    };
+
+    if let Err(err) = setup() {
+        linux::pidfd_send_signal(&process, libc::SIGKILL).ok();
+        waitpid(pid, 0).ok();
+        return Err(err);
+    }
+
+    let tty = [interactive.then_some(libc::STDIN_FILENO), Some(libc::STDOUT_FILENO), Some(libc::STDERR_FILENO)]
+        .into_iter()
+        .flatten()
+        .find_map(linux::terminal_name);
+    let mut session = ExecSession::new(exec_id, command, pid, tty);
+    if let Err(err) = session.save(&container_root) {
+        warn!("Failed to record the exec session: {}", err);
+    }
+    info!("Executing '{}' in the container as PID {} (session {}).", command.join(" "), pid, exec_id);
+
+    // Like a shell waiting for a command, interrupts from the terminal are left to the command, so that its exit is recorded
+    let previous_actions = [libc::SIGINT, libc::SIGQUIT].map(|signal| (signal, linux::set_signal_handler(signal, ignore_signal)));
+    let exit_status = waitpid(pid, 0);
+    for (signal, previous_action) in previous_actions {
+        if let Ok(previous_action) = previous_action {
+            linux::restore_signal_action(signal, &previous_action).ok();
+        }
+    }
+    let exit_status = exit_status?;
+    info!("PID {} of exec session {} {}.", pid, exec_id, exit_status);
+
+    session.exited(exit_status);
+    if let Err(err) = session.save(&container_root) {
+        // Such as when the container has exited meanwhile, which removes its sessions
+        trace!("Exec session {} not recorded as exited: {}", exec_id, err);
+    }
+
+    Ok(exit_status)
+}
+
+extern "C" fn ignore_signal(_signal: libc::c_int) {}
+
+/// Moves the process into the cgroups of the container, before anything runs within it.
+fn join_cgroups(container_id: &str, pid: i32) -> ContainerRuntimeResult<()> {
+    for task_type in CGROUP_CONTROLLERS {
+        // The devices controller is only used with GPUs, and the optional ones might not be available
+        let container_cgroup_dir = container_cgroup_dir(task_type, container_id);
+        if container_cgroup_dir.exists() {
+            std::fs::write(container_cgroup_dir.join("cgroup.procs"), pid.to_string())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Enters the container and executes the command, in a process created in the PID namespace of the container. Only returns on failure.
+fn execute_in_container(spec: &RunContainerSpec, container_process: &File, channel: &mut SyncChannel, command: &[String], interactive: bool) -> ContainerRuntimeResult<()> {
+    channel.wait_for(SyncMessage::ParentReady)?;
+
+    // The root of the mount namespace is the root of the container, which is entered along with it
+    let time_namespace = if spec.time_namespace.is_some() { libc::CLONE_NEWTIME } else { 0 };
+    let namespaces = libc::CLONE_NEWNS | libc::CLONE_NEWUTS | libc::CLONE_NEWIPC | libc::CLONE_NEWNET | libc::CLONE_NEWCGROUP | time_namespace;
+    linux::set_namespace(container_process, namespaces)?;
+
+    let users = User::from_passwd_file(Path::new("/etc/passwd"))?;
+    let groups = User::groups_from_group_file(Path::new("/etc/group"))?;
+    let user = match spec.user(&users, &groups) {
+        Some(user) => Some(user?),
+        None => None
+    };
+
+    let working_dir = user
+        .as_ref()
+        .map(|user| user.home_folder.clone())
+        .unwrap_or_else(|| Path::new("/").to_owned());
+    change_dir(&working_dir)?;
+
+    setup_security(&spec.security)?;
+    setup_resource_limits(&spec.ulimits)?;
+    if let Some(user) = user.as_ref() {
+        setup_user(user, &spec.security.keep_capabilities)?;
+    }
+
+    if !interactive {
+        linux::dup2(File::open("/dev/null")?.as_raw_fd(), libc::STDIN_FILENO)?;
+    }
+
+    exec(command)
+}
+
+/// Creates the root of the container with the PID of its runtime in it, so that it is never taken for what a crashed runtime left behind.
+fn create_container_root(spec: &RunContainerSpec) -> ContainerRuntimeResult<()> {
+    let container_root = spec.container_root();
+    if container_root.exists() {
+        return Err(ContainerRuntimeError::ContainerAlreadyExists(spec.id.clone()));
+    }
+
+    // Created under a name with the PID and renamed into place, so that the root always has the PID file
+    let runtime_pid = std::process::id();
+    let created_root = spec.containers_base_dir.join(format!(".{}.{}.tmp", spec.id, runtime_pid));
+    std::fs::create_dir_all(&created_root)?;
+    let result = std::fs::write(created_root.join(RUNTIME_PID_FILE), runtime_pid.to_string())
+        .and_then(|_| std::fs::rename(&created_root, &container_root));
+    if let Err(err) = result {
+        std::fs::remove_dir_all(&created_root).ok();
+        return Err(err.into());
+    }
+
+    Ok(())
+}
+
+/// If the runtime of the container root is alive and has not recorded the container as exited, such as while setting up the container.
+pub fn has_live_runtime(container_root: &Path) -> bool {
+    let file_name = container_root.file_name().and_then(|file_name| file_name.to_str()).unwrap_or_default();
+    let runtime_pid = if let Some(created_root) = file_name.strip_prefix('.').and_then(|name| name.strip_suffix(".tmp")) {
+        // Being created, as `.<id>.<runtime PID>.tmp`
+        created_root.rsplit_once('.').and_then(|(_, pid)| pid.parse().ok())
+    } else {
+        match ContainerState::load(container_root) {
+            Ok(state) if state.has_exited() => None,
+            Ok(state) => state.runtime_pid,
+            Err(_) => std::fs::read_to_string(container_root.join(RUNTIME_PID_FILE)).ok().and_then(|pid| pid.trim().parse().ok())
+        }
+    };
+
+    runtime_pid.map(linux::is_process_alive).unwrap_or(false)
+}
+
+/// The output of a logged container goes through named pipes in the container root rather than pipes, so that another runtime can
+/// follow it again should the runtime of the container exit. The container holds them open for reading as well as writing, so that
+/// it can keep writing (until they are full) while no runtime reads them. Returns the read and write ends.
+fn create_output_fifo(container_root: &Path, name: &str) -> ContainerRuntimeResult<(File, File)> {
+    let path = container_root.join(name);
+    linux::mkfifo(&path, 0o600)?;
+    let writer = std::fs::OpenOptions::new().read(true).write(true).open(&path)?;
+    // Does not block, as the pipe has a writer
+    let reader = File::open(&path)?;
+    Ok((reader, writer))
+}
+
+/// Opens the read end of an output pipe of a running container, which ends once the container has exited.
+fn open_output_fifo(container_root: &Path, name: &str) -> ContainerRuntimeResult<File> {
+    // Opened without blocking, as the container might have exited meanwhile, but read blocking
+    let reader = std::fs::OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(container_root.join(name))?;
+    linux::set_blocking(reader.as_raw_fd())?;
+    Ok(reader)
+}
+
+/// The cgroups can only be removed once the container has exited.
+fn add_cgroups_cleanup(cleanup: &mut Rollback<'static>, audit_log: &Arc<AuditLog>, container_id: &str) {
+    let container_id = container_id.to_owned();
+    let audit_log = audit_log.clone();
+    cleanup.add("remove cgroups", move || audit_log.record(&format!("remove cgroups of {}", container_id), remove_cgroups(&container_id)));
+}
+
+fn add_container_network_cleanup(cleanup: &mut Rollback<'static>, sys: &Arc<dyn SysApi>, spec: &RunContainerSpec) {
+    let container_id = spec.id.clone();
+    let cleanup_sys = sys.clone();
+    cleanup.add("remove container network", move || network::remove_container_network(cleanup_sys.as_ref(), &container_id));
+
+    let container_root = spec.container_root();
+    let cleanup_sys = sys.clone();
+    cleanup.add("remove attached networks", move || {
+        for attachment in ContainerState::load(&container_root)?.networks {
+            network::detach_container_network(cleanup_sys.as_ref(), &attachment)?;
+        }
+
+        Ok(())
+    });
+}
+
+fn add_cni_cleanup(cleanup: &mut Rollback<'static>, cni_network: &CniNetworkSpec, container_id: &str, pid: i32, result: CniResult) {
+    let cni_network = cni_network.clone();
+    let container_id = container_id.to_owned();
+    cleanup.add("remove container from CNI network", move || cni::delete(&cni_network, &container_id, pid, &result));
+}
+
+fn add_hosts_file_cleanup(cleanup: &mut Rollback<'static>, hosts_file: &HostsFileSpec, containers_base_dir: &Path) {
+    let hosts_file = hosts_file.clone();
+    let containers_base_dir = containers_base_dir.to_owned();
+    cleanup.add("remove the container from the hosts file", move || hosts::update_hosts_file(&hosts_file, &containers_base_dir));
+}
+
+/// The state of the exited container with the resources it has used, which are read before its cgroups are removed.
+fn exited_state(spec: &RunContainerSpec, exit_status: Option<ContainerExitStatus>) -> ContainerRuntimeResult<ContainerState> {
+    let mut state = ContainerState::load(&spec.container_root())?;
+    state.exit_code = Some(exit_status.map(|exit_status| exit_status.exit_code()).unwrap_or(-1));
+    state.exit_status = exit_status;
+    state.usage = match read_resource_usage(&spec.id) {
+        Ok(usage) => Some(usage),
+        Err(err) => {
+            warn!("Failed to read the resource usage: {}", err);
+            None
+        }
+    };
+
+    Ok(state)
+}
+
+/// Only the state, the logs and the filesystem are kept after the container has exited, so that they can be inspected (or copied from)
+/// until the container is removed, and the audit log if a change failed, as the host might then not be as it was.
+fn finish_exited(sys: &dyn SysApi, spec: &RunContainerSpec, audit_log: &AuditLog, mut remove_container_root: RemoveDirGuard, state: &ContainerState) -> ContainerRuntimeResult<()> {
+    unmount_leftovers(sys, &spec.container_root());
+    remove_container_root.keep(LOGS_DIR);
+    remove_container_root.keep(SPEC_FILE);
+    for name in storage::CONTAINER_STORAGE {
+        remove_container_root.keep(name);
+    }
+    if audit_log.has_failures() {
+        remove_container_root.keep(AUDIT_LOG);
+    }
+    drop(remove_container_root);
+    std::fs::create_dir_all(spec.container_root())?;
+    state.save(&spec.container_root())?;
+
+    hooks::run_hooks_or_warn(HookStage::Poststop, &spec.hooks.poststop, state, &spec.container_root());
+    Ok(())
+}
+
+/// How long the container is given to stop when the runtime is asked to stop, as `cort stop` by default.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The signals that ask the runtime to stop, such as when the host shuts down or the terminal of `cort run` is closed.
+const STOP_SIGNALS: [libc::c_int; 3] = [libc::SIGTERM, libc::SIGINT, libc::SIGHUP];
+
+static STOP_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+static STOP_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn request_stop(signal: libc::c_int) {
+    STOP_SIGNAL.store(signal, Ordering::SeqCst);
+    STOP_REQUESTS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Stops the container when the runtime is asked to stop, rather than exiting without recording the exit of the container and
+/// cleaning up after it. The container gets SIGTERM, and is killed if it has not stopped within the timeout, or when asked again.
+/// The previous handling of the signals is restored when dropped.
+struct StopOnSignal {
+    previous_actions: Vec<(libc::c_int, libc::sigaction)>,
+    exited_sender: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl StopOnSignal {
+    fn install(container_id: &str, pid: i32, process: &Arc<File>) -> ContainerRuntimeResult<StopOnSignal> {
+        STOP_REQUESTS.store(0, Ordering::SeqCst);
+
+        // Created before the handlers, so that the actions are restored should any of them fail
+        let (exited_sender, exited_receiver) = mpsc::channel::<()>();
+        let mut stop_on_signal = StopOnSignal {
+            previous_actions: Vec::new(),
+            exited_sender: Some(exited_sender),
+            thread: None
+        };
+
+        for signal in STOP_SIGNALS {
+            let previous_action = linux::set_signal_handler(signal, request_stop)?;
+            stop_on_signal.previous_actions.push((signal, previous_action));
+        }
+
+        let container_id = container_id.to_owned();
+        let process = process.clone();
+        let span = Span::current();
+        stop_on_signal.thread = Some(std::thread::spawn(move || {
+            let _span = span.entered();
+            let mut kill_at = None;
+            let mut handled_requests = 0;
+            while exited_receiver.recv_timeout(Duration::from_millis(100)) == Err(RecvTimeoutError::Timeout) {
+                let requests = STOP_REQUESTS.load(Ordering::SeqCst);
+                match kill_at {
+                    None if requests > 0 => {
+                        info!("Stopping the container as the runtime received {}.", signal_name(STOP_SIGNAL.load(Ordering::SeqCst)));
+                        linux::pidfd_send_signal(&process, libc::SIGTERM).ok();
+                        // Signals are only delivered once the processes are resumed
+                        if is_frozen(&container_id).unwrap_or(false) {
+                            thaw(&container_id).ok();
+                        }
+
+                        kill_at = Some(Instant::now() + STOP_TIMEOUT);
+                        handled_requests = requests;
+                    }
+                    Some(kill_at) if requests > handled_requests || Instant::now() >= kill_at => {
+                        warn!("Killing PID {} as it did not stop.", pid);
+                        linux::pidfd_send_signal(&process, libc::SIGKILL).ok();
+                        return;
+                    }
+                    _ => {}
+                }
+            }
+        }));
+
+        Ok(stop_on_signal)
+    }
+}
+
+impl Drop for StopOnSignal {
+    fn drop(&mut self) {
+        drop(self.exited_sender.take());
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+
+        for (signal, previous_action) in &self.previous_actions {
+            if let Err(err) = linux::restore_signal_action(*signal, previous_action) {
+                warn!("Failed to restore the handling of {}: {}", signal_name(*signal), err);
+            }
+        }
+    }
+}
+
+/// Unmounts what is still mounted below the container root on the host (such as binds that propagated back),
+/// as removing the container root would otherwise fail, or remove the data of the host through a bind.
+fn unmount_leftovers(sys: &dyn SysApi, container_root: &Path) {
+    let mount_points = match linux::mounts_below(container_root) {
+        Ok(mount_points) => mount_points,
+        Err(err) => {
+            warn!("Failed to read the mounts of the container root: {}", err);
+            return;
+        }
+    };
+
+    for mount_point in mount_points {
+        warn!("Unmounting {} which was left mounted by the container.", mount_point.to_str().unwrap());
+        if let Err(err) = sys.unmount(&mount_point) {
+            error!("Failed to unmount {} due to: {}", mount_point.to_str().unwrap(), err);
+        }
+    }
+}
+
+/// The report of an error relayed by the supervisor of a created container, where errors from the container are passed on as they are.
+fn relayed_report(err: &ContainerRuntimeError) -> ErrorReport {
+    match err {
+        ContainerRuntimeError::ContainerProcess(report) => report.clone(),
+        err => err.report()
+    }
+}
+
+/// Waits for the created container to be started, failing if the container exits (closing the channel) before that.
+fn wait_for_start(listener: &UnixListener, channel: &SyncChannel) -> ContainerRuntimeResult<SyncChannel> {
+    if linux::poll_readable(&[listener.as_raw_fd(), channel.as_raw_fd()])? != 0 {
+        return Err(ContainerRuntimeError::ContainerSetup("The container exited before it was started".to_owned()));
+    }
+
+    let (stream, _) = listener.accept()?;
+    Ok(SyncChannel::from_stream(stream))
+}
+
+/// Copies the stdin of the runtime to the container until it ends, when the pipe is closed so that the container gets EOF.
+fn forward_stdin(mut stdin_writer: File) {
+    // Not joined, as the container can exit without reading all of stdin (which might never end)
+    let span = Span::current();
+    std::thread::spawn(move || {
+        let _span = span.entered();
+        if let Err(err) = std::io::copy(&mut std::io::stdin().lock(), &mut stdin_writer) {
+            if err.kind() != std::io::ErrorKind::BrokenPipe {
+                warn!("Failed to forward stdin to the container: {}", err);
+            }
+        }
+    });
+}
+
+fn execute(context: &ExecuteContext, mut channel: SyncChannel) -> ContainerRuntimeResult<()> {
+    let result = setup_and_exec(context, &mut channel);
+    if let Err(err) = &result {
+        if let Err(send_err) = channel.send(&SyncMessage::Error(err.report())) {
+            error!("Container setup failed due to: {} (could not report: {})", err, send_err);
+        }
+    }
+
+    result
+}
+
+fn setup_and_exec(context: &ExecuteContext, channel: &mut SyncChannel) -> ContainerRuntimeResult<()> {
+    let sys = context.sys;
+    let spec = context.spec;
+
+    // Before anything is mounted, as sysfs and mqueue show the namespaces they are mounted in
+    for (namespace, namespace_type) in &context.pod_namespaces {
+        linux::set_namespace(namespace, *namespace_type)?;
+    }
+
+    let mut ip_address = None;
+    if let NetworkSpec::Cni(_) = &spec.network {
+        match channel.receive()? {
+            Some(SyncMessage::CniAdded(address)) => ip_address = address.map(|address| address.address),
+            Some(SyncMessage::Error(report)) => return Err(ContainerRuntimeError::ContainerProcess(report)),
+            message => return Err(ContainerRuntimeError::ContainerSetup(format!("Expected the CNI result but got {:?}", message)))
+        }
+    }
+    channel.wait_for(SyncMessage::ParentReady)?;
+    let mut phases = StartupPhases::default();
+    let phase = StartupPhases::start("netns");
+
+    // The root of a cgroup namespace is the cgroup of the process when created, which is set by the parent
+    if spec.cgroup_namespace == NamespaceMode::Private {
+        unshare(libc::CLONE_NEWCGROUP)?;
+    }
+
+    if let Some(time_namespace) = &spec.time_namespace {
+        setup_time_namespace(time_namespace)?;
+    }
+
+    match &spec.network {
+        NetworkSpec::Bridged(bridged) => {
+            ip_address = bridged.container_ip_address.map(|address| address.address);
+            if let Some(lease) = setup_network(sys, bridged, &spec.id, spec.hostname())? {
+                ip_address = Some(lease.address.address);
+                channel.send(&SyncMessage::Leased(lease))?;
+            }
+        }
+        NetworkSpec::Cni(_) => setup_cni_network(sys, spec.hostname())?,
+        NetworkSpec::Host => {}
+        NetworkSpec::Pod(pod) => ip_address = pod.ip_address.map(|address| address.address)
+    }
+    phases.end(phase);
+
+    let storage_driver = storage::create_storage_driver(&context.storage_driver, &spec.overlay, &spec.image_base_dir);
+    let phase = StartupPhases::start(storage_driver.name());
+    sys.mount(None, Path::new("/"), None, root_propagation(spec) | libc::MS_REC, None)?;
+    let new_root = storage_driver.create_container_root(&context.image_layers, &spec.container_root())?;
+    info!("Container root: {} (storage driver: {})", new_root.to_str().unwrap(), storage_driver.name());
+    phases.end(phase);
+
+    if spec.prewarm {
+        let phase = StartupPhases::start("prewarm");
+        let (files, size) = storage::prewarm(&new_root)?;
+        info!("Prewarmed {} executable files ({} bytes).", files, size);
+        phases.end(phase);
+    }
+
+    let phase = StartupPhases::start("root files");
+    setup_dns(&new_root, spec)?;
+    setup_hosts(&new_root, spec.hostname().as_deref(), ip_address)?;
+
+    if context.localtime.is_some() {
+        // The image usually has /etc/localtime as an absolute symlink, which would be resolved on the host when mounting
+        let localtime_in_new_root = new_root.join("etc").join("localtime");
+        if localtime_in_new_root.is_symlink() {
+            std::fs::remove_file(&localtime_in_new_root)?;
+        }
+    }
+
+    let bind_mounts = container_bind_mounts(spec, context.localtime.as_deref(), context.gpus.as_ref(), context.emulator.as_ref());
+    let gpu_devices = context.gpus.as_ref().map(|gpus| gpus.devices.as_slice()).unwrap_or_default();
+
+    let users = User::from_passwd_file(&new_root.join("etc").join("passwd"))?;
+    let groups = User::groups_from_group_file(&new_root.join("etc").join("group"))?;
+    let user = match spec.user(&users, &groups) {
+        Some(user) => Some(user?),
+        None => None
+    };
+
+    let working_dir = user
+        .as_ref()
+        .map(|user| user.home_folder.clone())
+        .unwrap_or_else(|| Path::new("/").to_owned());
+
+    phases.end(phase);
+
+    setup_container_root(sys, spec, &new_root, &working_dir, &bind_mounts, gpu_devices, &mut phases)?;
+    let phase = StartupPhases::start("process setup");
+    if !context.secrets.is_empty() {
+        setup_secrets(sys, &context.secrets, user.as_ref())?;
+    }
+    setup_sysctls(&spec.sysctls)?;
+    setup_security(&spec.security)?;
+
+    // Raising the hard limits requires root, so they are set before changing user
+    setup_resource_limits(&spec.ulimits)?;
+
+    if let Some(user) = user.as_ref() {
+        setup_user(user, &spec.security.keep_capabilities)?;
+    }
+
+    if let Some(gpus) = &context.gpus {
+        for (name, value) in &gpus.environment {
+            std::env::set_var(name, value);
+        }
+    }
+
+    phases.end(phase);
+
+    channel.send(&SyncMessage::StartupTimes(phases.phases))?;
+    channel.send(&SyncMessage::ChildReady)?;
+    channel.wait_for(SyncMessage::Exec)?;
+
+    match context.stdin {
+        Some(stdin) => linux::dup2(stdin, libc::STDIN_FILENO)?,
+        None => linux::dup2(File::open("/dev/null")?.as_raw_fd(), libc::STDIN_FILENO)?
+    }
+
+    if let Some((stdout, stderr)) = context.output {
+        linux::dup2(stdout, libc::STDOUT_FILENO)?;
+        linux::dup2(stderr, libc::STDERR_FILENO)?;
+    }
+
+    if spec.infra {
+        init::pause(channel.as_raw_fd())?;
+    }
+    if spec.init {
+        init::fork_command(channel.as_raw_fd())?;
+    }
+    if let Some(listen_fds) = &spec.listen_fds {
+        pass_listen_fds(listen_fds)?;
+    }
+    exec(&spec.command)?;
+
+    Ok(())
+}
+
+/// Mounts from the host only keep propagating into the container if a bind mount asks for it, but never the other way.
+fn root_propagation(spec: &RunContainerSpec) -> libc::c_ulong {
+    if spec.bind_mounts.iter().any(|bind_mount| bind_mount.propagation != MountPropagation::Private) {
+        libc::MS_SLAVE
+    } else {
+        libc::MS_PRIVATE
+    }
+}
+
+/// The bind mounts of the spec along with the ones added for the timezone, the GPUs and the emulator.
+fn container_bind_mounts(spec: &RunContainerSpec, localtime: Option<&Path>, gpus: Option<&GpuPassthrough>, emulator: Option<&Emulator>) -> Vec<BindMountSpec> {
+    let mut bind_mounts = spec.bind_mounts.clone();
+    if let Some(localtime) = localtime {
+        bind_mounts.push(BindMountSpec {
+            source: localtime.to_owned(),
+            target: PathBuf::from("/etc/localtime"),
+            is_readonly: true,
+            propagation: MountPropagation::Private
+        });
+    }
+
+    if let Some(gpus) = gpus {
+        bind_mounts.extend(gpus.bind_mounts.iter().cloned());
+    }
+
+    bind_mounts.extend(emulator.and_then(|emulator| emulator.bind_mount()));
+    bind_mounts
+}
+
+/// The emulator of the image when the container is run with emulation, unless the image is for the architecture of the host.
+fn find_emulator(spec: &RunContainerSpec, image_store: &ImageStore) -> ContainerRuntimeResult<Option<Emulator>> {
+    let Some(emulation) = spec.emulation.as_ref().filter(|_| spec.rootfs.is_none()) else {
+        return Ok(None);
+    };
+
+    let platform = image_store.platform(&image_store.load(&spec.image)?)?;
+    if Platform::host().matches(&platform) {
+        return Ok(None);
+    }
+
+    emulation::find_emulator(&platform.architecture, emulation).map(Some)
+}
+
+/// The search path used by execvp when PATH is not set.
+const DEFAULT_PATH: &str = "/bin:/usr/bin";
+
+/// Checks that the command exists in the root filesystem, so that a missing command fails before the container is created.
+fn validate_command(spec: &RunContainerSpec, image_layers: &[PathBuf], gpus: Option<&GpuPassthrough>) -> ContainerRuntimeResult<()> {
+    let command = spec.command.first().ok_or_else(|| ContainerRuntimeError::Input("No command given".to_owned()))?;
+
+    // Bind mounts are only visible inside the container, so commands within them are assumed to exist
+    let is_executable = |path: &Path| -> ContainerRuntimeResult<bool> {
+        let gpu_bind_mounts = gpus.map(|gpus| gpus.bind_mounts.as_slice()).unwrap_or_default();
+        if spec.bind_mounts.iter().chain(gpu_bind_mounts).any(|bind_mount| path.starts_with(&bind_mount.target)) {
+            return Ok(true);
+        }
+
+        Ok(find_in_layers(image_layers, path)?.map(|layer_path| layer_path.is_file()).unwrap_or(false))
+    };
+
+    let found = if command.contains('/') {
+        let command_path = Path::new(command);
+        // Relative paths depend on the working directory, which is only known in the container
+        !command_path.is_absolute() || is_executable(command_path)?
+    } else {
+        // The container inherits the environment, so execvp searches the same PATH unless switching user
+        let search_path = match spec.user {
+            Some(_) => USER_PATH.to_owned(),
+            None => std::env::var("PATH").unwrap_or_else(|_| DEFAULT_PATH.to_owned())
+        };
+        let mut found = false;
+        for dir in search_path.split(':').filter(|dir| dir.starts_with('/')) {
+            if is_executable(&Path::new(dir).join(command))? {
+                found = true;
+                break;
+            }
+        }
+
+        found
+    };
+
+    if found {
+        Ok(())
+    } else {
+        Err(ContainerRuntimeError::CommandNotFound { command: command.clone() })
+    }
+}
+
+fn setup_container_root(sys: &dyn SysApi, spec: &RunContainerSpec, new_root: &Path, working_dir: &Path, bind_mounts: &[BindMountSpec], gpu_devices: &[GpuDevice], phases: &mut StartupPhases) -> ContainerRuntimeResult<()> {
+    trace!("Setup container root - new root: {}, working dir: {}, read only: {}", new_root.to_str().unwrap(), working_dir.to_str().unwrap(), spec.read_only);
+
+    let mut inner = || -> ContainerRuntimeResult<()> {
+        let phase = StartupPhases::start("mounts");
+        setup_mounts(sys, new_root)?;
+        setup_shm(sys, new_root, spec)?;
+        setup_devices(sys, new_root, gpu_devices)?;
+        setup_bind_mounts(sys, new_root, bind_mounts)?;
+        phases.end(phase);
+
+        let phase = StartupPhases::start("pivot_root");
+        let old_root = new_root.join("old_root");
+        std::fs::create_dir_all(&old_root)?;
+
+        sys.pivot_root(new_root, &old_root)?;
+        change_dir(working_dir)?;
+
+        sys.unmount(Path::new("/old_root"))?;
+        std::fs::remove_dir("/old_root")?;
+
+        if spec.read_only {
+            make_root_read_only(sys)?;
+        }
+        phases.end(phase);
+
+        Ok(())
+    };
+
+    inner().map_err(|err| ContainerRuntimeError::SetupContainerRoot(err.to_string()))
+}
+
+fn read_secrets(secrets: &[SecretSpec]) -> ContainerRuntimeResult<Vec<(PathBuf, Vec<u8>)>> {
+    secrets
+        .iter()
+        .map(|secret| {
+            let content = std::fs::read(&secret.source)
+                .map_err(|err| ContainerRuntimeError::SetupSecrets(format!("Failed to read secret {} from {}: {}", secret.id, secret.source.to_str().unwrap(), err)))?;
+            Ok((secret.target(), content))
+        })
+        .collect()
+}
+
+/// Writes the secrets to a tmpfs, so that they are never in the root filesystem, which is then made read-only.
+/// The secrets are only readable by the user of the container.
+fn setup_secrets(sys: &dyn SysApi, secrets: &[(PathBuf, Vec<u8>)], user: Option<&User>) -> ContainerRuntimeResult<()> {
+    trace!("Setup secrets - secrets: {}", secrets.len());
+
+    let inner = || -> ContainerRuntimeResult<()> {
+        let secrets_dir = Path::new(SECRETS_DIR);
+        std::fs::create_dir_all(secrets_dir)?;
+        let flags = libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC;
+        sys.mount(Some("tmpfs"), secrets_dir, Some("tmpfs"), flags, Some("mode=755"))?;
+
+        let (user_id, group_id) = user.map(|user| (user.id as u32, user.group_id.unwrap_or(0) as u32)).unwrap_or((0, 0));
+        for (target, content) in secrets {
+            let mut file = std::fs::OpenOptions::new().write(true).create_new(true).mode(0o400).open(target)?;
+            file.write_all(content)?;
+            std::os::unix::fs::fchown(&file, Some(user_id), Some(group_id))?;
+        }
+
+        sys.mount(None, secrets_dir, None, flags | libc::MS_REMOUNT | libc::MS_RDONLY, None)
+    };
+
+    inner().map_err(|err| ContainerRuntimeError::SetupSecrets(err.to_string()))
+}
+
+/// Remounts the root as read-only, keeping /tmp and /run writable as many programs expect.
+fn make_root_read_only(sys: &dyn SysApi) -> ContainerRuntimeResult<()> {
+    for (path, options) in [("/tmp", "mode=1777"), ("/run", "mode=755")] {
+        let path = Path::new(path);
+        std::fs::create_dir_all(path)?;
+        sys.mount(Some("tmpfs"), path, Some("tmpfs"), libc::MS_NOSUID | libc::MS_NODEV, Some(options))?;
+    }
+
+    // Only changes the flags of the root mount point, so the other mounts stay writable
+    sys.mount(None, Path::new("/"), None, libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY, None)
+}
+
+fn setup_cpu_cgroup(audit_log: &AuditLog, container_id: &str, pid: i32, limits: &ResourceLimits, real_time: bool) -> ContainerRuntimeResult<()> {
+    trace!("Setup cpu group - pid: {}, cpu shares: {:?}, cpus: {:?}, real-time: {}", pid, limits.cpu_shares, limits.cpus, real_time);
+
+    let inner = || -> ContainerRuntimeResult<()> {
+        let container_cpu_cgroup_dir = create_cgroup_task(audit_log, container_id, "cpu", pid)?;
+
+        for (file, value) in cpu_cgroup_limits(limits) {
+            std::fs::write(container_cpu_cgroup_dir.join(file), value)?;
+        }
+
+        // When the kernel schedules real-time tasks per cgroup, they can only run in cgroups given a share of the real-time runtime
+        if real_time && container_cpu_cgroup_dir.join("cpu.rt_runtime_us").exists() {
+            allocate_rt_runtime(&container_cpu_cgroup_dir)?;
+        }
 
-use log::{error, info, trace};
+        Ok(())
+    };
 
-use crate::helpers::RemoveDirGuard;
-use crate::linux::{change_dir, chmod, exec, mount, pivot_root, unmount, waitpid, wrap_libc_error};
-use crate::model::{ContainerRuntimeError, ContainerRuntimeResult, User};
-use crate::network::NetworkNamespace;
-use crate::spec::{BindMountSpec, DNSSpec, NetworkSpec, RunContainerSpec};
+    inner().map_err(|err| ContainerRuntimeError::SetupCpuCgroup(err.to_string()))
+}
 
-pub fn run(run_container_spec: &RunContainerSpec) -> ContainerRuntimeResult<()> {
-    unpack_image(run_container_spec)?;
+/// The scheduling period that the CPU quota of a container is given for.
+const CFS_PERIOD_US: u64 = 100_000;
 
-    let mut child_stack = vec![0u8; 32 * 1024];
+fn cpu_cgroup_limits(limits: &ResourceLimits) -> Vec<(&'static str, String)> {
+    let mut cpu_limits = Vec::new();
+    if let Some(cpu_shares) = limits.cpu_shares {
+        cpu_limits.push(("cpu.shares", cpu_shares.to_string()));
+    }
 
-    let _remove_container_root = RemoveDirGuard::new(run_container_spec.container_root());
-    let network_namespace = if let NetworkSpec::Bridged(bridged) = &run_container_spec.network {
-        Some(NetworkNamespace::create(run_container_spec.network_namespace().unwrap(), bridged)?)
-    } else {
-        None
-    };
+    if let Some(cpus) = limits.cpus {
+        cpu_limits.push(("cpu.cfs_period_us", CFS_PERIOD_US.to_string()));
+        cpu_limits.push(("cpu.cfs_quota_us", ((cpus * CFS_PERIOD_US as f64).round() as u64).to_string()));
+    }
 
-    let pid = unsafe {
-        extern "C" fn clone_callback(args: *mut c_void) -> c_int {
-            let args = args as *const RunContainerSpec;
-            if let Err(err) = execute(unsafe { &*args }) {
-                error!("Container execute failed due to: {}", err.to_string());
-                1
-            } else {
-                0
-            }
+    cpu_limits
+}
+
+/// The real-time runtime (of each period of 1 second) of a container with real-time scheduling.
+const RT_RUNTIME_US: u64 = 50_000;
+
+/// Gives the container its real-time runtime, where the runtime of the parent must cover the runtime of all its children.
+fn allocate_rt_runtime(container_cpu_cgroup_dir: &Path) -> ContainerRuntimeResult<()> {
+    let base_dir = container_cpu_cgroup_dir.parent().unwrap();
+    let mut allocated = 0;
+    for entry in std::fs::read_dir(base_dir)? {
+        let rt_runtime_path = entry?.path().join("cpu.rt_runtime_us");
+        if rt_runtime_path.exists() {
+            allocated += std::fs::read_to_string(rt_runtime_path)?.trim().parse::<u64>().unwrap_or(0);
         }
+    }
 
-        let clone_network_namespace = if network_namespace.is_some() {libc::CLONE_NEWNET} else {0};
+    std::fs::write(base_dir.join("cpu.rt_runtime_us"), (allocated + RT_RUNTIME_US).to_string())?;
+    std::fs::write(container_cpu_cgroup_dir.join("cpu.rt_runtime_us"), RT_RUNTIME_US.to_string())?;
+    Ok(())
+}
 
-        wrap_libc_error(libc::clone(
-            clone_callback,
-            child_stack.as_mut_ptr().offset(child_stack.len() as isize) as *mut c_void,
-            libc::CLONE_NEWPID | libc::CLONE_NEWNS | libc::CLONE_NEWUTS | clone_network_namespace | libc::SIGCHLD,
-            run_container_spec as *const _ as *mut c_void
-        ))
-    }?;
+fn setup_memory_cgroup(audit_log: &AuditLog, spec: &RunContainerSpec, pid: i32) -> ContainerRuntimeResult<()> {
+    trace!(
+        "Setup memory group - pid: {}, memory: {:?}, memory_swap: {:?}, memory_reservation: {:?}, memory_swappiness: {:?}, kernel_memory: {:?}",
+        pid, spec.memory, spec.memory_swap, spec.memory_reservation, spec.memory_swappiness, spec.kernel_memory
+    );
 
-    info!("Running container as PID {}.", pid);
-    let status = waitpid(pid)?;
-    info!("PID {} exited with status {}.", pid, status);
+    let inner = || -> ContainerRuntimeResult<()> {
+        let container_memory_cgroup_dir = create_cgroup_task(audit_log, &spec.id, "memory", pid)?;
 
-    Ok(())
+        // The limits have been validated with the spec
+        for (file, value) in memory_cgroup_limits(&ResourceLimits::from_spec(spec)) {
+            std::fs::write(container_memory_cgroup_dir.join(file), value)?;
+        }
+
+        // Kernel memory limits are deprecated and ignored by newer kernels, so the container runs without one instead of failing
+        if let Some(kernel_memory) = spec.kernel_memory {
+            let kernel_memory_path = container_memory_cgroup_dir.join("memory.kmem.limit_in_bytes");
+            let is_applied = || -> ContainerRuntimeResult<bool> {
+                std::fs::write(&kernel_memory_path, kernel_memory.to_string())?;
+                Ok(std::fs::read_to_string(&kernel_memory_path)?.trim().parse::<i64>().map(|limit| limit <= kernel_memory).unwrap_or(false))
+            };
+
+            if !kernel_memory_path.exists() || !is_applied().unwrap_or(false) {
+                warn!("Kernel memory is not accounted by this kernel, running without a kernel memory limit.");
+            }
+        }
+
+        Ok(())
+    };
+
+    inner().map_err(|err| ContainerRuntimeError::SetupMemoryCgroup(err.to_string()))
+}
+
+/// The limits of the memory cgroup, except the kernel memory which is not accounted by all kernels.
+fn memory_cgroup_limits(limits: &ResourceLimits) -> Vec<(&'static str, String)> {
+    [
+        ("memory.limit_in_bytes", limits.memory),
+        ("memory.memsw.limit_in_bytes", limits.memory_swap),
+        ("memory.soft_limit_in_bytes", limits.memory_reservation),
+        ("memory.swappiness", limits.memory_swappiness.map(i64::from))
+    ]
+        .into_iter()
+        .filter_map(|(file, value)| value.map(|value| (file, value.to_string())))
+        .collect()
 }
 
-fn execute(spec: &RunContainerSpec) -> ContainerRuntimeResult<()> {
-    setup_cpu_cgroup(&spec.id, spec.cpu_shares)?;
-    setup_memory_cgroup(&spec.id, spec.memory, spec.memory_swap)?;
+/// Changes the limits of the cgroups of a running container to the ones set in the update.
+pub fn update_cgroups(audit_log: &AuditLog, container_id: &str, update: &ResourceLimits) -> ContainerRuntimeResult<()> {
+    let inner = || -> ContainerRuntimeResult<()> {
+        // Otherwise systemd restores the limits of the scope when it changes its cgroups
+        if scope_cgroup_dir("memory", container_id).exists() {
+            systemd::set_scope_limits(container_id, update)?;
+        }
 
-    if let Some(network_namespace) = spec.network_namespace() {
-        setup_network(&network_namespace, spec.hostname())?;
-    }
+        let memory_cgroup_dir = container_cgroup_dir("memory", container_id);
+        let mut memory_limits = memory_cgroup_limits(update);
 
-    mount(None, Path::new("/"), None, libc::MS_PRIVATE | libc::MS_REC, None)?;
+        // The memory and swap limit can never be below the memory limit, so it is raised first when the memory limit is raised
+        let memory_limit = std::fs::read_to_string(memory_cgroup_dir.join("memory.limit_in_bytes"))?.trim().parse::<i64>().unwrap_or(i64::MAX);
+        if update.memory.map(|memory| memory > memory_limit).unwrap_or(false) {
+            memory_limits.sort_by_key(|(file, _)| *file != "memory.memsw.limit_in_bytes");
+        }
 
-    let new_root = create_container_root(&spec.image_root(), &spec.container_root())?;
-    info!("Container root: {}", new_root.to_str().unwrap());
+        let writes = cpu_cgroup_limits(update).into_iter()
+            .map(|(file, value)| (container_cgroup_dir("cpu", container_id).join(file), value))
+            .chain(memory_limits.into_iter().map(|(file, value)| (memory_cgroup_dir.join(file), value)));
 
-    setup_dns(&new_root, &spec.dns)?;
+        for (path, value) in writes {
+            let operation = format!("write {} = {}", path.to_str().unwrap(), value);
+            audit_log.record(&operation, std::fs::write(&path, &value).map_err(|err| err.into()))?;
+        }
 
-    let users = User::from_passwd_file(&new_root.join("etc").join("passwd"))?;
-    let user = match spec.user(users.values()) {
-        Some(user) => Some(user?),
-        None => None
+        Ok(())
     };
 
-    let working_dir = user
-        .as_ref()
-        .map(|user| user.home_folder.clone())
-        .unwrap_or(Path::new("/").to_owned());
+    inner().map_err(|err| ContainerRuntimeError::UpdateResources(err.to_string()))
+}
 
-    setup_container_root(&new_root, &working_dir, &spec.bind_mounts)?;
+const CGROUP_CONTROLLERS: [&str; 6] = ["cpu", "memory", "cpuacct", "blkio", "freezer", "devices"];
+/// Controllers used to account the resource usage and to pause containers, which are skipped if not available.
+const OPTIONAL_CGROUP_CONTROLLERS: [&str; 3] = ["cpuacct", "blkio", "freezer"];
 
-    if let Some(user) = user.as_ref() {
-        setup_user(user)?;
-    }
+fn setup_optional_cgroups(audit_log: &AuditLog, container_id: &str, pid: i32) -> ContainerRuntimeResult<()> {
+    trace!("Setup optional cgroups - pid: {}", pid);
 
-    exec(&spec.command)?;
+    for task_type in OPTIONAL_CGROUP_CONTROLLERS {
+        if Path::new("/sys/fs/cgroup").join(task_type).exists() {
+            create_cgroup_task(audit_log, container_id, task_type, pid)?;
+        }
+    }
 
     Ok(())
 }
 
-fn unpack_image(run_container_spec: &RunContainerSpec) -> ContainerRuntimeResult<()> {
-    if !run_container_spec.image_root().exists() {
-        let image_archive = run_container_spec.image_archive();
-        let tar_archive = File::open(&image_archive)?;
-        let mut tar_archive = tar::Archive::new(tar_archive);
+/// The devices created in every container and the pseudo terminals, which together with the GPUs are the only devices allowed.
+const DEFAULT_ALLOWED_DEVICES: [&str; 10] = [
+    "c *:* m", "b *:* m", "c 1:3 rwm", "c 1:5 rwm", "c 1:7 rwm", "c 1:8 rwm", "c 1:9 rwm", "c 5:0 rwm", "c 5:2 rwm", "c 136:* rwm"
+];
+
+/// Restricts the devices of a container with GPUs to the ones it is given, which is skipped without the devices controller.
+fn setup_devices_cgroup(audit_log: &AuditLog, container_id: &str, pid: i32, gpu_devices: &[GpuDevice]) -> ContainerRuntimeResult<()> {
+    trace!("Setup devices cgroup - pid: {}", pid);
+    if !Path::new("/sys/fs/cgroup/devices").exists() {
+        return Ok(());
+    }
 
-        if !run_container_spec.image_root().exists() {
-            std::fs::create_dir_all(run_container_spec.image_root())?;
+    let inner = || -> ContainerRuntimeResult<()> {
+        let container_cgroup_dir = create_cgroup_task(audit_log, container_id, "devices", pid)?;
+        for (file, rule) in devices_cgroup_rules(gpu_devices) {
+            std::fs::write(container_cgroup_dir.join(file), rule)?;
         }
 
-        for entry in tar_archive.entries()? {
-            let mut entry = entry?;
+        Ok(())
+    };
+
+    inner().map_err(|err| ContainerRuntimeError::SetupGpus(err.to_string()))
+}
 
-            entry.set_unpack_xattrs(true);
-            entry.set_preserve_permissions(true);
-            entry.unpack_in(run_container_spec.image_root())?;
+/// Denies all devices and then allows the default ones and the GPUs, as each write adds a single rule.
+fn devices_cgroup_rules(gpu_devices: &[GpuDevice]) -> Vec<(&'static str, String)> {
+    let mut rules = vec![("devices.deny", "a".to_owned())];
+    rules.extend(DEFAULT_ALLOWED_DEVICES.iter().map(|rule| ("devices.allow", rule.to_string())));
+    rules.extend(gpu_devices.iter().map(|device| ("devices.allow", device.cgroup_rule())));
+    rules
+}
 
-            if let (Ok(uid), Ok(gid)) = (entry.header().uid(), entry.header().gid()) {
-                let path = entry.path().unwrap().to_path_buf();
-                let path = run_container_spec.image_root().join(path);
+/// Applied to the container before it executes the command, which (like its children) inherits it.
+fn setup_scheduling(pid: i32, spec: &SchedulingSpec) -> ContainerRuntimeResult<()> {
+    trace!("Setup scheduling - pid: {}, spec: {:?}", pid, spec);
 
-                if path.exists() {
-                    chmod(&path, uid, gid)?;
-                }
+    let inner = || -> ContainerRuntimeResult<()> {
+        if let Some(oom_score_adj) = spec.oom_score_adj {
+            std::fs::write(format!("/proc/{}/oom_score_adj", pid), oom_score_adj.to_string())?;
+        }
+
+        unsafe {
+            if let Some(nice) = spec.nice {
+                wrap_libc_error(libc::setpriority(libc::PRIO_PROCESS, pid as libc::id_t, nice))?;
+            }
+
+            if let Some(rt_priority) = spec.rt_priority {
+                let param = libc::sched_param { sched_priority: rt_priority };
+                wrap_libc_error(libc::sched_setscheduler(pid, libc::SCHED_RR, &param))?;
+            }
+        }
+
+        Ok(())
+    };
+
+    inner().map_err(|err| ContainerRuntimeError::SetupScheduling(err.to_string()))
+}
+
+/// Reads the resource usage from the cgroups, which must be done before they are removed.
+pub fn read_resource_usage(container_id: &str) -> ContainerRuntimeResult<ResourceUsage> {
+    let read_value = |task_type: &str, file: &str| -> ContainerRuntimeResult<u64> {
+        let content = std::fs::read_to_string(container_cgroup_dir(task_type, container_id).join(file))?;
+        content.trim().parse().map_err(|err| ContainerRuntimeError::State(format!("Invalid {}: {}", file, err)))
+    };
+
+    let mut usage = ResourceUsage {
+        cpu_seconds: read_value("cpuacct", "cpuacct.usage")? as f64 / 1e9,
+        memory_peak: read_value("memory", "memory.max_usage_in_bytes")?,
+        ..Default::default()
+    };
+
+    let io_service_bytes = container_cgroup_dir("blkio", container_id).join("blkio.throttle.io_service_bytes_recursive");
+    if io_service_bytes.exists() {
+        let (read_bytes, write_bytes) = parse_io_service_bytes(&std::fs::read_to_string(io_service_bytes)?);
+        usage.io_read_bytes = read_bytes;
+        usage.io_write_bytes = write_bytes;
+    }
+
+    Ok(usage)
+}
+
+/// The current memory usage in bytes, including the page cache.
+pub fn read_memory_usage(container_id: &str) -> ContainerRuntimeResult<u64> {
+    let content = std::fs::read_to_string(container_cgroup_dir("memory", container_id).join("memory.usage_in_bytes"))?;
+    content.trim().parse().map_err(|err| ContainerRuntimeError::State(format!("Invalid memory.usage_in_bytes: {}", err)))
+}
+
+/// Sums the bytes read and written over all devices, from lines such as `8:0 Read 4096`.
+fn parse_io_service_bytes(content: &str) -> (u64, u64) {
+    let mut read_bytes = 0;
+    let mut write_bytes = 0;
+    for line in content.lines() {
+        let parts = line.split_whitespace().collect::<Vec<_>>();
+        if let [_, operation, bytes] = parts[..] {
+            match (operation, bytes.parse::<u64>()) {
+                ("Read", Ok(bytes)) => read_bytes += bytes,
+                ("Write", Ok(bytes)) => write_bytes += bytes,
+                _ => {}
             }
         }
+    }
+
+    (read_bytes, write_bytes)
+}
+
+fn cgroup_base_dir(task_type: &str) -> PathBuf {
+    Path::new(&format!("/sys/fs/cgroup/{}", task_type)).join("container_runtime")
+}
+
+/// The cgroup of the container in the controller, which is the one of its scope when systemd created it.
+fn container_cgroup_dir(task_type: &str, container_id: &str) -> PathBuf {
+    let scope_cgroup_dir = scope_cgroup_dir(task_type, container_id);
+    if scope_cgroup_dir.exists() {
+        scope_cgroup_dir
+    } else {
+        cgroup_base_dir(task_type).join(container_id)
+    }
+}
+
+fn scope_cgroup_dir(task_type: &str, container_id: &str) -> PathBuf {
+    Path::new(&format!("/sys/fs/cgroup/{}", task_type)).join(systemd::SLICE).join(systemd::scope_name(container_id))
+}
+
+fn create_cgroup_task(audit_log: &AuditLog, container_id: &str, task_type: &str, pid: i32) -> ContainerRuntimeResult<PathBuf> {
+    let container_cgroup_dir = container_cgroup_dir(task_type, container_id);
+    if !container_cgroup_dir.exists() {
+        let operation = format!("mkdir -p {}", container_cgroup_dir.to_str().unwrap());
+        audit_log.record(&operation, std::fs::create_dir_all(&container_cgroup_dir).map_err(|err| err.into()))?;
+    }
+
+    File::create(container_cgroup_dir.join("cgroup.procs"))?
+        .write_all(pid.to_string().as_bytes())?;
+
+    Ok(container_cgroup_dir)
+}
 
-        trace!(
-            "Unpacked image '{}' at {} (using {})",
-            run_container_spec.image,
-            run_container_spec.image_root().to_str().unwrap(),
-            image_archive.to_str().unwrap()
-        );
+pub fn remove_cgroups(container_id: &str) -> ContainerRuntimeResult<()> {
+    for task_type in CGROUP_CONTROLLERS {
+        let container_cgroup_dir = cgroup_base_dir(task_type).join(container_id);
+        if container_cgroup_dir.exists() {
+            // cgroup directories only contain virtual files, so they are removed with rmdir
+            std::fs::remove_dir(&container_cgroup_dir)?;
+        }
     }
 
     Ok(())
 }
 
-fn create_container_root(image_root: &Path, container_root: &Path) -> ContainerRuntimeResult<PathBuf> {
-    trace!("Create container root - image root: {}, container root: {}", image_root.to_str().unwrap(), container_root.to_str().unwrap());
+/// Freezes all processes of the container, waiting until they are frozen.
+pub fn freeze(container_id: &str) -> ContainerRuntimeResult<()> {
+    set_freezer_state(container_id, "FROZEN")
+}
+
+/// Resumes the processes of a frozen container.
+pub fn thaw(container_id: &str) -> ContainerRuntimeResult<()> {
+    set_freezer_state(container_id, "THAWED")
+}
+
+pub fn is_frozen(container_id: &str) -> ContainerRuntimeResult<bool> {
+    Ok(freezer_state(container_id)? != "THAWED")
+}
+
+fn freezer_state(container_id: &str) -> ContainerRuntimeResult<String> {
+    let state_path = container_cgroup_dir("freezer", container_id).join("freezer.state");
+    let state = std::fs::read_to_string(&state_path)
+        .map_err(|err| ContainerRuntimeError::Freezer(format!("Failed to read {}: {}", state_path.to_str().unwrap(), err)))?;
+    Ok(state.trim().to_owned())
+}
+
+fn set_freezer_state(container_id: &str, state: &str) -> ContainerRuntimeResult<()> {
+    const TIMEOUT: Duration = Duration::from_secs(10);
 
-    let container_cow_rw = container_root.join("cow_rw");
-    let container_cow_workdir = container_root.join("cow_workdir");
-    let container_rootfs = container_root.join("rootfs");
+    let state_path = container_cgroup_dir("freezer", container_id).join("freezer.state");
+    std::fs::write(&state_path, state)
+        .map_err(|err| ContainerRuntimeError::Freezer(format!("Failed to write {}: {}", state_path.to_str().unwrap(), err)))?;
 
-    for path in [&container_cow_rw, &container_cow_workdir, &container_rootfs] {
-        if !path.exists() {
-            std::fs::create_dir_all(path)?;
+    // Freezing is asynchronous and goes through FREEZING while processes are being stopped
+    let start = Instant::now();
+    while freezer_state(container_id)? != state {
+        if start.elapsed() >= TIMEOUT {
+            return Err(ContainerRuntimeError::Freezer(format!("Container did not become {} within {} seconds", state, TIMEOUT.as_secs())));
         }
+
+        std::fs::write(&state_path, state)?;
+        std::thread::sleep(Duration::from_millis(10));
     }
 
-    mount(
-        Some("overlay"),
-        &container_rootfs,
-        Some("overlay"),
-        libc::MS_NODEV,
-        Some(&format!(
-            "lowerdir={},upperdir={},workdir={}",
-            image_root.to_str().unwrap(),
-            container_cow_rw.to_str().unwrap(),
-            container_cow_workdir.to_str().unwrap()
-        ))
-    )?;
+    Ok(())
+}
 
-    Ok(container_rootfs)
+/// A process running in a container.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ContainerProcess {
+    pub pid: i32,
+    /// The PID within the PID namespace of the container
+    pub container_pid: i32,
+    pub user: String,
+    pub command: String
 }
 
-fn setup_container_root(new_root: &Path, working_dir: &Path, bind_mounts: &Vec<BindMountSpec>) -> ContainerRuntimeResult<()> {
-    trace!("Setup container root - new root: {}, working dir: {}", new_root.to_str().unwrap(), working_dir.to_str().unwrap());
+/// Lists the processes in the cgroup of the container, with users from the passwd file of the container.
+pub fn list_processes(container_id: &str, pid: i32) -> ContainerRuntimeResult<Vec<ContainerProcess>> {
+    let users = User::from_passwd_file(&Path::new("/proc").join(pid.to_string()).join("root/etc/passwd"))?;
+    let procs = std::fs::read_to_string(container_cgroup_dir("memory", container_id).join("cgroup.procs"))?;
+
+    let mut processes = Vec::new();
+    for process_pid in procs.lines().filter_map(|line| line.trim().parse::<i32>().ok()) {
+        let process_dir = Path::new("/proc").join(process_pid.to_string());
+        // Processes can exit while listing
+        let (Ok(status), Ok(cmdline)) = (std::fs::read_to_string(process_dir.join("status")), std::fs::read(process_dir.join("cmdline"))) else {
+            continue;
+        };
+
+        let status = ProcessStatus::parse(&status);
+        let command = cmdline
+            .split(|byte| *byte == 0)
+            .filter(|part| !part.is_empty())
+            .map(|part| String::from_utf8_lossy(part).into_owned())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        processes.push(ContainerProcess {
+            pid: process_pid,
+            container_pid: status.namespace_pid.unwrap_or(process_pid),
+            user: status.uid
+                .map(|uid| users.get(&uid).map(|user| user.username.clone()).unwrap_or_else(|| uid.to_string()))
+                .unwrap_or_else(|| "?".to_owned()),
+            command: if command.is_empty() { format!("[{}]", status.name) } else { command }
+        });
+    }
 
-    let inner = || -> ContainerRuntimeResult<()> {
-        setup_mounts(new_root)?;
-        setup_devices(new_root)?;
-        setup_bind_mounts(new_root, bind_mounts)?;
+    processes.sort_by_key(|process| process.container_pid);
+    Ok(processes)
+}
 
-        let old_root = new_root.join("old_root");
-        std::fs::create_dir_all(&old_root)?;
+#[derive(Debug, Default, PartialEq, Eq)]
+struct ProcessStatus {
+    name: String,
+    uid: Option<i32>,
+    /// The PID in the innermost PID namespace
+    namespace_pid: Option<i32>
+}
 
-        pivot_root(new_root, &old_root)?;
-        change_dir(working_dir)?;
+impl ProcessStatus {
+    fn parse(content: &str) -> ProcessStatus {
+        let mut status = ProcessStatus::default();
+        for line in content.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+
+            match key {
+                "Name" => status.name = value.trim().to_owned(),
+                "Uid" => status.uid = value.split_whitespace().next().and_then(|uid| uid.parse().ok()),
+                "NSpid" => status.namespace_pid = value.split_whitespace().last().and_then(|pid| pid.parse().ok()),
+                _ => {}
+            }
+        }
 
-        unmount(Path::new("/old_root"))?;
-        std::fs::remove_dir("/old_root")?;
+        status
+    }
+}
 
-        Ok(())
+pub fn find_cgroup_container_ids() -> ContainerRuntimeResult<Vec<String>> {
+    let mut container_ids = Vec::new();
+    for task_type in CGROUP_CONTROLLERS {
+        let cgroup_dir = cgroup_base_dir(task_type);
+        if !cgroup_dir.exists() {
+            continue;
+        }
+
+        for entry in std::fs::read_dir(cgroup_dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                let container_id = entry.file_name().to_str().unwrap().to_owned();
+                if !container_ids.contains(&container_id) {
+                    container_ids.push(container_id);
+                }
+            }
+        }
+    }
+
+    Ok(container_ids)
+}
+
+fn setup_network(sys: &dyn SysApi, bridged: &BridgedNetworkSpec, container_id: &str, hostname: Option<String>) -> ContainerRuntimeResult<Option<DhcpLease>> {
+    trace!("Setup network - ip address: {:?}, hostname: {:?}", bridged.container_ip_address, hostname);
+
+    let inner = || -> ContainerRuntimeResult<Option<DhcpLease>> {
+        let lease = network::setup_container_interface(sys, bridged, container_id)?;
+
+        if let Some(hostname) = hostname {
+            sys.set_hostname(&hostname)?;
+        }
+
+        Ok(lease)
     };
 
-    inner().map_err(|err| ContainerRuntimeError::SetupContainerRoot(err.to_string()))
+    inner().map_err(|err| ContainerRuntimeError::SetupNetwork(err.to_string()))
 }
 
-fn setup_cpu_cgroup(container_id: &str, cpu_shares: Option<i64>) -> ContainerRuntimeResult<()> {
-    trace!("Setup cpu group - cpu shares: {:?}", cpu_shares);
+/// The interface of the container is set up by the CNI plugins, so only the loopback interface and the hostname remain.
+fn setup_cni_network(sys: &dyn SysApi, hostname: Option<String>) -> ContainerRuntimeResult<()> {
+    trace!("Setup CNI network - hostname: {:?}", hostname);
 
     let inner = || -> ContainerRuntimeResult<()> {
-        let container_cpu_cgroup_dir = create_cgroup_task(container_id, "cpu")?;
+        network::setup_loopback_interface(sys)?;
 
-        if let Some(cpu_shares) = cpu_shares {
-            std::fs::write(container_cpu_cgroup_dir.join("cpu.shares"), cpu_shares.to_string())?;
+        if let Some(hostname) = hostname {
+            sys.set_hostname(&hostname)?;
         }
 
         Ok(())
     };
 
-    inner().map_err(|err| ContainerRuntimeError::SetupCpuCgroup(err.to_string()))
+    inner().map_err(|err| ContainerRuntimeError::SetupNetwork(err.to_string()))
 }
 
-fn setup_memory_cgroup(container_id: &str, memory: Option<i64>, memory_swap: Option<i64>) -> ContainerRuntimeResult<()> {
-    trace!("Setup memory group - memory: {:?}, memory_swap: {:?}", memory, memory_swap);
+/// The number of name servers used by both the glibc and musl resolvers, which ignore the rest.
+const MAX_NAME_SERVERS: usize = 3;
 
+/// Used when the image has no nsswitch.conf, where glibc would otherwise look up hosts in DNS before /etc/hosts (musl never reads it).
+const DEFAULT_NSSWITCH: &str = "passwd: files
+group: files
+shadow: files
+hosts: files dns
+networks: files
+protocols: files
+services: files
+";
+
+fn setup_dns(new_root: &Path, spec: &RunContainerSpec) -> ContainerRuntimeResult<()> {
     let inner = || -> ContainerRuntimeResult<()> {
-        let container_memory_cgroup_dir = create_cgroup_task(container_id, "memory")?;
+        let base_content = match &spec.dns {
+            DNSSpec::Server(servers) => servers.iter().map(|server| format!("nameserver {}\n", server)).collect::<String>(),
+            DNSSpec::CopyFromHost => std::fs::read_to_string("/etc/resolv.conf")?
+        };
+
+        let resolv_content = resolv_conf(&base_content, &spec.dns_search, &spec.dns_options);
+        trace!("Setup DNS - content: {}", resolv_content.replace('\n', " "));
 
-        if let Some(memory) = memory {
-            std::fs::write(container_memory_cgroup_dir.join("memory.limit_in_bytes"), memory.to_string())?;
+        let etc_dir = new_root.join("etc");
+        std::fs::create_dir_all(&etc_dir)?;
+
+        // Images such as those using systemd-resolved have resolv.conf as a symlink, which could point anywhere on the host
+        let resolv_path = etc_dir.join("resolv.conf");
+        if resolv_path.is_symlink() {
+            std::fs::remove_file(&resolv_path)?;
         }
+        std::fs::write(resolv_path, resolv_content)?;
 
-        if let Some(memory_swap) = memory_swap {
-            std::fs::write(container_memory_cgroup_dir.join("memory.memsw.limit_in_bytes"), memory_swap.to_string())?;
+        let nsswitch_path = etc_dir.join("nsswitch.conf");
+        if nsswitch_path.symlink_metadata().is_err() {
+            std::fs::write(nsswitch_path, DEFAULT_NSSWITCH)?;
         }
 
         Ok(())
     };
 
-    inner().map_err(|err| ContainerRuntimeError::SetupMemoryCgroup(err.to_string()))
+    inner().map_err(|err| ContainerRuntimeError::SetupDNS(err.to_string()))
 }
 
-fn create_cgroup_task(container_id: &str, task_type: &str) -> ContainerRuntimeResult<PathBuf> {
-    let container_cgroup_dir = Path::new(&format!("/sys/fs/cgroup/{}", task_type)).join("container_runtime").join(container_id);
-    if !container_cgroup_dir.exists() {
-        std::fs::create_dir_all(&container_cgroup_dir)?;
+/// Creates the resolv.conf from the base (the one of the host, or the configured name servers) with the search domains and options.
+/// Both the glibc and musl resolvers only use the last search (or domain) line, so a single search line is written, as is a single options line.
+pub(crate) fn resolv_conf(base_content: &str, search: &[String], options: &[String]) -> String {
+    let mut name_servers = Vec::new();
+    let mut base_search = Vec::new();
+    let mut base_options = Vec::new();
+    for line in base_content.lines() {
+        let mut fields = line.split_whitespace();
+        match fields.next() {
+            Some("nameserver") => {
+                if let Some(name_server) = fields.next() {
+                    if !name_servers.contains(&name_server) {
+                        name_servers.push(name_server);
+                    }
+                }
+            }
+            Some("search" | "domain") => base_search = fields.collect(),
+            Some("options") => base_options.extend(fields),
+            _ => {}
+        }
+    }
+
+    if name_servers.len() > MAX_NAME_SERVERS {
+        warn!("Only the first {} of the {} name servers are used by the resolver of the container.", MAX_NAME_SERVERS, name_servers.len());
+        name_servers.truncate(MAX_NAME_SERVERS);
     }
 
-    File::create(container_cgroup_dir.join("tasks"))?
-        .write_all(std::process::id().to_string().as_bytes())?;
+    let search = if search.is_empty() { base_search } else { search.iter().map(|domain| domain.as_str()).collect() };
+    let mut unique_search = Vec::new();
+    for domain in search {
+        if !unique_search.contains(&domain) {
+            unique_search.push(domain);
+        }
+    }
 
-    Ok(container_cgroup_dir)
+    let mut merged_options: Vec<&str> = Vec::new();
+    for option in base_options.into_iter().chain(options.iter().map(|option| option.as_str())) {
+        let name = option.split(':').next().unwrap();
+        merged_options.retain(|existing| existing.split(':').next().unwrap() != name);
+        merged_options.push(option);
+    }
+
+    let mut content = String::new();
+    for name_server in name_servers {
+        content += &format!("nameserver {}\n", name_server);
+    }
+    if !unique_search.is_empty() {
+        content += &format!("search {}\n", unique_search.join(" "));
+    }
+    if !merged_options.is_empty() {
+        content += &format!("options {}\n", merged_options.join(" "));
+    }
+
+    content
 }
 
-fn setup_network(network_namespace: &str, hostname: Option<String>) -> ContainerRuntimeResult<()> {
-    trace!("Setup network - namespace: {}, hostname: {:?}", network_namespace, hostname);
+/// Writes /etc/hostname and /etc/hosts, which are copied from the host when the container uses the host network.
+fn setup_hosts(new_root: &Path, hostname: Option<&str>, ip_address: Option<Ipv4Addr>) -> ContainerRuntimeResult<()> {
+    trace!("Setup hosts - hostname: {:?}, ip address: {:?}", hostname, ip_address);
 
     let inner = || -> ContainerRuntimeResult<()> {
-        let file = File::open(format!("/run/netns/{}", network_namespace))?;
-        unsafe {
-            wrap_libc_error(libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET))?;
-        }
+        let etc_dir = new_root.join("etc");
+        std::fs::create_dir_all(&etc_dir)?;
 
-        if let Some(hostname) = hostname {
-            unsafe {
-                let hostname = CString::new(hostname).unwrap();
-                wrap_libc_error(libc::sethostname(hostname.as_ptr(), hostname.as_bytes().len()))?;
+        match hostname {
+            Some(hostname) => {
+                std::fs::write(etc_dir.join("hostname"), format!("{}\n", hostname))?;
+                std::fs::write(etc_dir.join("hosts"), hosts_file(hostname, ip_address))?;
+            }
+            None => {
+                for file in ["hostname", "hosts"] {
+                    let host_file = Path::new("/etc").join(file);
+                    if host_file.exists() {
+                        std::fs::write(etc_dir.join(file), std::fs::read(host_file)?)?;
+                    }
+                }
             }
         }
 
         Ok(())
     };
 
-    inner().map_err(|err| ContainerRuntimeError::SetupNetwork(err.to_string()))
+    inner().map_err(|err| ContainerRuntimeError::SetupHosts(err.to_string()))
 }
 
-fn setup_dns(new_root: &Path, spec: &DNSSpec) -> ContainerRuntimeResult<()> {
-    let resolv_content = match spec {
-        DNSSpec::Server(servers) => {
-            servers
-                .iter()
-                .map(|server| format!("nameserver {}", server))
-                .collect::<Vec<_>>()
-                .join("\n") + "\n"
-        }
-        DNSSpec::CopyFromHost => std::fs::read_to_string("/etc/resolv.conf")?
-    };
+fn hosts_file(hostname: &str, ip_address: Option<Ipv4Addr>) -> String {
+    let mut content = String::new();
+    content += "127.0.0.1\tlocalhost\n";
+    content += "::1\tlocalhost ip6-localhost ip6-loopback\n";
+    content += "fe00::0\tip6-localnet\n";
+    content += "ff00::0\tip6-mcastprefix\n";
+    content += "ff02::1\tip6-allnodes\n";
+    content += "ff02::2\tip6-allrouters\n";
+    content += &format!("{}\t{}\n", ip_address.unwrap_or(Ipv4Addr::new(127, 0, 1, 1)), hostname);
+    content
+}
 
-    trace!("Setup DNS - content: {}", resolv_content.replace("\n", " "));
+/// Creates a time namespace with the offsets, which the container process enters when it executes the command.
+fn setup_time_namespace(spec: &TimeNamespaceSpec) -> ContainerRuntimeResult<()> {
+    trace!("Setup time namespace - offsets: {:?}", spec);
 
     let inner = || -> ContainerRuntimeResult<()> {
-        std::fs::write(new_root.join("etc").join("resolv.conf"), resolv_content)?;
+        unshare(libc::CLONE_NEWTIME)?;
+        // The offsets can only be set before any process has entered the namespace
+        std::fs::write("/proc/self/timens_offsets", spec.offsets_content())?;
         Ok(())
     };
 
-    inner().map_err(|err| ContainerRuntimeError::SetupDNS(err.to_string()))
+    inner().map_err(|err| ContainerRuntimeError::SetupTimeNamespace(err.to_string()))
+}
+
+/// Checks that the requested security modules are enabled, as the labels are silently ignored otherwise.
+fn validate_security(spec: &SecuritySpec) -> ContainerRuntimeResult<()> {
+    if spec.apparmor_profile.is_some() {
+        let enabled = std::fs::read_to_string("/sys/module/apparmor/parameters/enabled").map(|enabled| enabled.trim() == "Y").unwrap_or(false);
+        if !enabled {
+            return Err(ContainerRuntimeError::SetupSecurity("AppArmor is not enabled on the host".to_owned()));
+        }
+    }
+
+    if spec.selinux_label.is_some() && !Path::new("/sys/fs/selinux/enforce").exists() {
+        return Err(ContainerRuntimeError::SetupSecurity("SELinux is not enabled on the host".to_owned()));
+    }
+
+    Ok(())
+}
+
+/// Sets the labels that the kernel applies to the process at the next exec, which is the command of the container.
+fn setup_security(spec: &SecuritySpec) -> ContainerRuntimeResult<()> {
+    trace!("Setup security - spec: {:?}", spec);
+
+    if let Some(profile) = &spec.apparmor_profile {
+        // Kernels with stacked security modules have a separate interface for AppArmor
+        let apparmor_exec = Path::new("/proc/self/attr/apparmor/exec");
+        let exec_path = if apparmor_exec.exists() { apparmor_exec } else { Path::new("/proc/self/attr/exec") };
+        std::fs::write(exec_path, format!("exec {}", profile))
+            .map_err(|err| ContainerRuntimeError::SetupSecurity(format!("AppArmor profile {}: {}", profile, err)))?;
+    }
+
+    if let Some(label) = &spec.selinux_label {
+        std::fs::write("/proc/self/attr/exec", label)
+            .map_err(|err| ContainerRuntimeError::SetupSecurity(format!("SELinux label {}: {}", label, err)))?;
+    }
+
+    Ok(())
+}
+
+/// Writes the sysctls through the proc of the container, which shows those of its own namespaces.
+fn setup_sysctls(sysctls: &BTreeMap<String, String>) -> ContainerRuntimeResult<()> {
+    for (name, value) in sysctls {
+        trace!("Setup sysctl - {}: {}", name, value);
+        std::fs::write(sysctl_path(name), value)
+            .map_err(|err| ContainerRuntimeError::SetupSysctl(format!("{}: {}", name, err)))?;
+    }
+
+    Ok(())
+}
+
+fn sysctl_path(name: &str) -> PathBuf {
+    Path::new("/proc/sys").join(name.replace('.', "/"))
+}
+
+fn setup_resource_limits(ulimits: &[UlimitSpec]) -> ContainerRuntimeResult<()> {
+    for ulimit in ulimits {
+        trace!("Setup resource limit - {}: {}:{}", ulimit.name, ulimit.soft, ulimit.hard);
+
+        let limit = libc::rlimit { rlim_cur: ulimit.soft, rlim_max: ulimit.hard };
+        unsafe {
+            wrap_libc_error(libc::setrlimit(ulimit.resource, &limit))
+                .map_err(|err| ContainerRuntimeError::SetupResourceLimits(format!("{}: {}", ulimit.name, err)))?;
+        }
+    }
+
+    Ok(())
 }
 
-fn setup_user(user: &User) -> ContainerRuntimeResult<()> {
-    trace!("Setup user - user: {:?}", user);
+/// Users other than root lose all capabilities, except the ones to keep which become ambient so that they survive exec.
+fn setup_user(user: &User, keep_capabilities: &[CapabilitySpec]) -> ContainerRuntimeResult<()> {
+    trace!("Setup user - user: {:?}, keep capabilities: {:?}", user, keep_capabilities);
 
     let inner = || -> ContainerRuntimeResult<()> {
-        std::env::set_var("HOME", user.home_folder.to_str().unwrap());
+        // The runtime runs as root, whose values would otherwise be inherited
+        for (name, value) in user.environment() {
+            std::env::set_var(name, value);
+        }
 
         unsafe {
             if let Some(group_id) = user.group_id {
                 wrap_libc_error(libc::setgid(group_id as libc::gid_t))?;
             }
+        }
+
+        let drop_capabilities = user.id != 0;
+        if drop_capabilities && !keep_capabilities.is_empty() {
+            linux::set_keep_capabilities(true)?;
+        }
 
+        unsafe {
             wrap_libc_error(libc::setuid(user.id as libc::uid_t))?;
         }
+
+        // Files with inheritable capabilities would otherwise let the user regain them
+        if drop_capabilities {
+            let capabilities = keep_capabilities.iter().fold(0, |capabilities, capability| capabilities | (1u64 << capability.number));
+            linux::set_capabilities(capabilities)?;
+            linux::set_ambient_capabilities(&keep_capabilities.iter().map(|capability| capability.number).collect::<Vec<_>>())?;
+            linux::set_keep_capabilities(false)?;
+        }
+
         Ok(())
     };
 
     inner().map_err(|err| ContainerRuntimeError::SetupUser(err.to_string()))
 }
 
-fn setup_mounts(new_root: &Path) -> ContainerRuntimeResult<()> {
+fn setup_mounts(sys: &dyn SysApi, new_root: &Path) -> ContainerRuntimeResult<()> {
     trace!("Setup mounts - new root: {}", new_root.to_str().unwrap());
 
     let inner = || -> ContainerRuntimeResult<()> {
-        mount(Some("proc"), &new_root.join("proc"), Some("proc"), 0, None)?;
-        mount(Some("sysfs"), &new_root.join("sys"), Some("sysfs"), 0, None)?;
-        mount(Some("tmpfs"), &new_root.join("dev"), Some("tmpfs"), libc::MS_NOSUID | libc::MS_STRICTATIME, Some("mode=755"))?;
+        mount_kernel_filesystem(sys, "proc", Path::new("/proc"), &new_root.join("proc"))?;
+        mount_kernel_filesystem(sys, "sysfs", Path::new("/sys"), &new_root.join("sys"))?;
+        sys.mount(Some("tmpfs"), &new_root.join("dev"), Some("tmpfs"), libc::MS_NOSUID | libc::MS_STRICTATIME, Some("mode=755"))?;
 
         let devpts_path = new_root.join("dev").join("pts");
         if !devpts_path.exists() {
             std::fs::create_dir_all(&devpts_path).unwrap();
-            mount(Some("devpts"), &devpts_path, Some("devpts"), 0, None)?;
+            sys.mount(Some("devpts"), &devpts_path, Some("devpts"), 0, None)?;
         }
 
         Ok(())
@@ -310,13 +2394,44 @@ fn setup_mounts(new_root: &Path) -> ContainerRuntimeResult<()> {
     inner().map_err(|err| ContainerRuntimeError::SetupMounts(err.to_string()))
 }
 
-fn setup_devices(new_root: &Path) -> ContainerRuntimeResult<()> {
+/// Mounts /dev/shm, where POSIX shared memory is kept, as a tmpfs of its own, the one of its pod or the one of the host when sharing its IPC namespace.
+fn setup_shm(sys: &dyn SysApi, new_root: &Path, spec: &RunContainerSpec) -> ContainerRuntimeResult<()> {
+    let shm_path = new_root.join("dev").join("shm");
+    std::fs::create_dir_all(&shm_path)?;
+
+    // POSIX shared memory is files in /dev/shm rather than part of the IPC namespace, so the containers of a pod share it as well
+    if let Some(pod) = &spec.pod {
+        return sys.mount(Some(pod.shm_dir.to_str().unwrap()), &shm_path, None, libc::MS_BIND | libc::MS_REC, None);
+    }
+
+    match spec.ipc_namespace {
+        NamespaceMode::Private => {
+            let options = format!("mode=1777,size={}", spec.shm_size);
+            sys.mount(Some("shm"), &shm_path, Some("tmpfs"), libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC, Some(&options))
+        }
+        NamespaceMode::Host => sys.mount(Some("/dev/shm"), &shm_path, None, libc::MS_BIND | libc::MS_REC, None)
+    }
+}
+
+/// Mounts a new proc or sysfs, falling back to bind mounting the existing one when not permitted,
+/// which happens inside other containers that mask parts of them.
+fn mount_kernel_filesystem(sys: &dyn SysApi, fstype: &str, existing: &Path, target: &Path) -> ContainerRuntimeResult<()> {
+    match sys.mount(Some(fstype), target, Some(fstype), 0, None) {
+        Err(ContainerRuntimeError::Mount(err)) if err.raw_os_error() == Some(libc::EPERM) => {
+            warn!("Not permitted to mount {}, bind mounting {} instead.", fstype, existing.to_str().unwrap());
+            sys.mount(Some(existing.to_str().unwrap()), target, None, libc::MS_BIND | libc::MS_REC, None)
+        }
+        result => result
+    }
+}
+
+fn setup_devices(sys: &dyn SysApi, new_root: &Path, gpu_devices: &[GpuDevice]) -> ContainerRuntimeResult<()> {
     let dev_path = new_root.join("dev");
     trace!("Setup devices - dev path: {}", dev_path.to_str().unwrap());
 
     let inner = || -> ContainerRuntimeResult<()> {
         for (fd, dev) in ["stdin", "stdout", "stderr"].iter().enumerate() {
-            std::os::unix::fs::symlink(&format!("/proc/self/fd/{}", fd), dev_path.join(dev))?;
+            std::os::unix::fs::symlink(format!("/proc/self/fd/{}", fd), dev_path.join(dev))?;
         }
 
         let devices = [
@@ -330,14 +2445,13 @@ fn setup_devices(new_root: &Path) -> ContainerRuntimeResult<()> {
         ];
 
         for (device, (device_type, major, minor)) in devices {
-            unsafe {
-                let pathname = CString::new(dev_path.join(device).to_str().unwrap()).unwrap();
-                wrap_libc_error(libc::mknod(
-                    pathname.as_ptr(),
-                    0o666 | device_type,
-                    libc::makedev(major, minor),
-                ))?;
-            }
+            sys.mknod(&dev_path.join(device), 0o666 | device_type, libc::makedev(major, minor))?;
+        }
+
+        for device in gpu_devices {
+            let device_in_new_root = dev_path.join(device.path.strip_prefix("/dev").unwrap());
+            std::fs::create_dir_all(device_in_new_root.parent().unwrap())?;
+            sys.mknod(&device_in_new_root, device.mode, device.rdev)?;
         }
 
         Ok(())
@@ -346,19 +2460,198 @@ fn setup_devices(new_root: &Path) -> ContainerRuntimeResult<()> {
     inner().map_err(|err| ContainerRuntimeError::SetupDevices(err.to_string()))
 }
 
-fn setup_bind_mounts(new_root: &Path, bind_mounts: &Vec<BindMountSpec>) -> ContainerRuntimeResult<()> {
+/// Creates the mount point for a bind mount, which must be a file when the source is a file.
+fn create_mount_target(source: &Path, target: &Path) -> ContainerRuntimeResult<()> {
+    if std::fs::metadata(source)?.is_dir() {
+        std::fs::create_dir_all(target)?;
+    } else {
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        if !target.exists() {
+            File::create(target)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn setup_bind_mounts(sys: &dyn SysApi, new_root: &Path, bind_mounts: &[BindMountSpec]) -> ContainerRuntimeResult<()> {
     for bind_mount in bind_mounts {
         let source = bind_mount.source.to_str().unwrap();
         let target_in_new_root = new_root.join(bind_mount.target.iter().skip(1).collect::<PathBuf>());
         trace!("Setup of bind mount {} -> {}", source, bind_mount.target.to_str().unwrap());
 
-        std::fs::create_dir_all(&target_in_new_root)?;
-        mount(Some(source), &target_in_new_root, None, libc::MS_BIND, None)?;
+        create_mount_target(&bind_mount.source, &target_in_new_root)?;
+        // Mounts already below the source should be visible as well as the ones that appear later
+        let recursive = if bind_mount.propagation == MountPropagation::Private { 0 } else { libc::MS_REC };
+        sys.mount(Some(source), &target_in_new_root, None, libc::MS_BIND | recursive, None)?;
+        // A bind mount of a slave mount is a slave as well, so private mounts must be made private explicitly
+        sys.mount(None, &target_in_new_root, None, bind_mount.propagation.flags() | recursive, None)?;
 
         if bind_mount.is_readonly {
-            mount(Some(source), &target_in_new_root, None, libc::MS_BIND | libc::MS_RDONLY | libc::MS_REMOUNT, None)?;
+            sys.mount(Some(source), &target_in_new_root, None, libc::MS_BIND | libc::MS_RDONLY | libc::MS_REMOUNT, None)?;
         }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_create_mount_target() {
+    let base_dir = std::env::temp_dir().join(format!("cort-mount-target-{}", uuid::Uuid::new_v4()));
+    let _remove_base_dir = RemoveDirGuard::new(base_dir.clone());
+    let source_dir = base_dir.join("source");
+    std::fs::create_dir_all(&source_dir).unwrap();
+    std::fs::write(source_dir.join("localtime"), b"UTC").unwrap();
+
+    let target_dir = base_dir.join("root/data");
+    create_mount_target(&source_dir, &target_dir).unwrap();
+    assert!(target_dir.is_dir());
+
+    let target_file = base_dir.join("root/etc/localtime");
+    create_mount_target(&source_dir.join("localtime"), &target_file).unwrap();
+    assert!(target_file.is_file());
+    assert_eq!(0, std::fs::metadata(&target_file).unwrap().len());
+
+    // Existing files in the image are used as they are
+    std::fs::write(&target_file, b"CET").unwrap();
+    create_mount_target(&source_dir.join("localtime"), &target_file).unwrap();
+    assert_eq!(b"CET".to_vec(), std::fs::read(&target_file).unwrap());
+
+    assert!(create_mount_target(&source_dir.join("missing"), &base_dir.join("root/missing")).is_err());
+}
+
+#[test]
+fn test_setup_bind_mounts() {
+    use crate::sys::{RecordingSysApi, SysCall};
+
+    let base_dir = std::env::temp_dir().join(format!("cort-bind-mounts-{}", uuid::Uuid::new_v4()));
+    let _remove_base_dir = RemoveDirGuard::new(base_dir.clone());
+    let source_dir = base_dir.join("source");
+    std::fs::create_dir_all(&source_dir).unwrap();
+    let new_root = base_dir.join("root");
+
+    let sys = RecordingSysApi::new();
+    let bind_mounts = [
+        BindMountSpec { source: source_dir.clone(), target: PathBuf::from("/data"), is_readonly: true, propagation: MountPropagation::Private }
+    ];
+    setup_bind_mounts(&sys, &new_root, &bind_mounts).unwrap();
+    assert!(new_root.join("data").is_dir());
+
+    let mount = |src: Option<&Path>, flags: libc::c_ulong| SysCall::Mount {
+        src: src.map(|src| src.to_str().unwrap().to_owned()),
+        target: new_root.join("data"),
+        fstype: None,
+        flags,
+        data: None
+    };
+    assert_eq!(
+        vec![
+            mount(Some(&source_dir), libc::MS_BIND),
+            mount(None, libc::MS_PRIVATE),
+            mount(Some(&source_dir), libc::MS_BIND | libc::MS_RDONLY | libc::MS_REMOUNT)
+        ],
+        sys.calls()
+    );
+}
+
+#[test]
+fn test_plan() {
+    let base_dir = std::env::temp_dir().join(format!("cort-plan-test-{}", uuid::Uuid::new_v4()));
+    let _remove_base_dir = RemoveDirGuard::new(base_dir.clone());
+    let rootfs = base_dir.join("rootfs");
+    std::fs::create_dir_all(rootfs.join("bin")).unwrap();
+    std::fs::write(rootfs.join("bin/sh"), b"").unwrap();
+
+    let runtime = crate::ContainerRuntime::new(crate::ContainerRuntimeConfig::from_base_dir(&base_dir.join("data")));
+    let mut spec = runtime.create_run_spec("", vec!["sh".to_owned()], NetworkSpec::Host);
+    spec.rootfs = Some(rootfs);
+    spec.read_only = true;
+
+    let plan = plan(&spec, None).unwrap();
+    let stage = |name: &str| plan.stages.iter().find(|stage| stage.name == name).map(|stage| stage.steps.clone()).unwrap_or_default();
+    let new_root = spec.container_root().join("rootfs");
+
+    assert!(!stage("Namespaces").contains(&"new network namespace".to_owned()));
+    assert!(stage("Host").is_empty());
+    assert!(stage("Mounts").contains(&format!("pivot_root {} {}/old_root", new_root.to_str().unwrap(), new_root.to_str().unwrap())));
+    assert_eq!(Some(&"mount none / (ro,remount,bind)".to_owned()), stage("Mounts").last());
+    let shm_mount = format!("mount -t tmpfs -o mode=1777,size={} shm {}/dev/shm (nosuid,nodev,noexec)", crate::spec::DEFAULT_SHM_SIZE, new_root.to_str().unwrap());
+    assert!(stage("Mounts").contains(&shm_mount), "{:?}", stage("Mounts"));
+    assert_eq!(Some(&"exec sh".to_owned()), stage("Process").last());
+    assert!(!spec.container_root().exists());
+}
+
+#[test]
+fn test_hosts_file() {
+    let content = hosts_file("web", Some(Ipv4Addr::new(10, 10, 1, 2)));
+    assert!(content.starts_with("127.0.0.1\tlocalhost\n"));
+    assert!(content.ends_with("10.10.1.2\tweb\n"));
+
+    assert!(hosts_file("web", None).ends_with("127.0.1.1\tweb\n"));
+}
+
+#[test]
+fn test_resolv_conf() {
+    let host_content = "# Generated by NetworkManager
+domain corp.example.com
+search corp.example.com example.com corp.example.com
+nameserver 10.0.0.1
+nameserver 10.0.0.2
+nameserver 10.0.0.1
+options ndots:1
+options timeout:2 rotate
+";
+
+    assert_eq!(
+        "nameserver 10.0.0.1\nnameserver 10.0.0.2\nsearch corp.example.com example.com\noptions timeout:2 rotate ndots:5\n",
+        resolv_conf(host_content, &[], &["ndots:5".to_owned()])
+    );
+    assert_eq!(
+        "nameserver 8.8.8.8\nsearch svc.local\n",
+        resolv_conf("nameserver 8.8.8.8\n", &["svc.local".to_owned()], &[])
+    );
+    assert_eq!(3, resolv_conf("nameserver 1.1.1.1\nnameserver 1.0.0.1\nnameserver 8.8.8.8\nnameserver 8.8.4.4\n", &[], &[]).lines().count());
+}
+
+#[test]
+fn test_parse_io_service_bytes() {
+    let content = "8:0 Read 4096\n8:0 Write 1024\n8:0 Sync 5120\n8:0 Total 5120\n8:16 Read 100\n8:16 Write 0\nTotal 5220\n";
+    assert_eq!((4196, 1024), parse_io_service_bytes(content));
+}
+
+#[test]
+fn test_parse_process_status() {
+    let content = "Name:\tsleep\nUmask:\t0022\nState:\tS (sleeping)\nPid:\t23412\nUid:\t1000\t1000\t1000\t1000\nNSpid:\t23412\t7\n";
+    assert_eq!(
+        ProcessStatus { name: "sleep".to_owned(), uid: Some(1000), namespace_pid: Some(7) },
+        ProcessStatus::parse(content)
+    );
+}
+
+#[test]
+fn test_startup_phases() {
+    let mut phases = StartupPhases::default();
+    let phase = StartupPhases::start("cgroups");
+    std::thread::sleep(Duration::from_millis(5));
+    phases.end(phase);
+    phases.extend(vec![("mounts".to_owned(), Duration::from_millis(2))]);
+
+    // A failed phase is left out
+    drop(StartupPhases::start("netns"));
+    std::thread::sleep(Duration::from_millis(5));
+    let phase = StartupPhases::start("exec");
+    phases.end(phase);
+
+    assert_eq!(vec!["cgroups", "mounts", "exec"], phases.phases.iter().map(|(phase, _)| phase.as_str()).collect::<Vec<_>>());
+    assert!(phases.phases[0].1 >= Duration::from_millis(5));
+    assert!(phases.phases[2].1 < Duration::from_millis(5));
+
+    // The time between the phases is left out of the total
+    let total = phases.timings().iter().map(|timing| timing.milliseconds).sum::<f64>();
+    assert!(phases.report().starts_with(&format!("{:.1} ms", total)));
+    assert!(phases.report().contains("mounts 2.0 ms"));
+}
+
@@ -0,0 +1,257 @@
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use tracing::{error, trace};
+
+use crate::linux::{change_root, enter_mount_namespace, fork, mount, unshare, waitpid};
+use crate::model::{ContainerRuntimeError, ContainerRuntimeResult};
+use crate::storage::StorageDriver;
+
+/// The filesystem of a container that files are copied to or from, which is entered by a forked process.
+pub enum ContainerFilesystem {
+    /// The mount namespace of the running container with the PID
+    Running(i32),
+    /// The root of a stopped container, which its storage driver mounts again from the layers (top-most first)
+    Stopped { storage_driver: Box<dyn StorageDriver>, layers: Vec<PathBuf>, container_root: PathBuf }
+}
+
+impl ContainerFilesystem {
+    fn enter(&self) -> ContainerRuntimeResult<()> {
+        match self {
+            ContainerFilesystem::Running(pid) => enter_mount_namespace(*pid),
+            ContainerFilesystem::Stopped { storage_driver, layers, container_root } => {
+                // Mounted in a mount namespace of the process, so that it is unmounted when the process exits
+                unshare(libc::CLONE_NEWNS)?;
+                mount(None, Path::new("/"), None, libc::MS_PRIVATE | libc::MS_REC, None)?;
+                change_root(&storage_driver.create_container_root(layers, container_root)?)
+            }
+        }
+    }
+}
+
+impl Display for ContainerFilesystem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContainerFilesystem::Running(pid) => write!(f, "PID {}", pid),
+            ContainerFilesystem::Stopped { container_root, .. } => write!(f, "stopped at {}", container_root.to_str().unwrap())
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CopyPath {
+    Host(PathBuf),
+    Container(String, PathBuf)
+}
+
+impl FromStr for CopyPath {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.split_once(':') {
+            Some((container, path)) if !container.is_empty() && !container.contains('/') => {
+                if !path.starts_with('/') {
+                    return Err("Expected absolute path inside the container.".to_owned());
+                }
+
+                Ok(CopyPath::Container(container.to_owned(), Path::new(path).to_owned()))
+            }
+            _ => Ok(CopyPath::Host(Path::new(text).to_owned()))
+        }
+    }
+}
+
+pub fn copy_to_container(filesystem: &ContainerFilesystem, host_path: &Path, container_path: &Path) -> ContainerRuntimeResult<()> {
+    trace!("Copy to container - container: {}, host path: {}, container path: {}", filesystem, host_path.to_str().unwrap(), container_path.to_str().unwrap());
+
+    let inner = || -> ContainerRuntimeResult<()> {
+        if !host_path.exists() {
+            return Err(ContainerRuntimeError::Copy(format!("Path {} does not exist", host_path.to_str().unwrap())));
+        }
+
+        let (reader, writer) = std::io::pipe()?;
+        let child_pid = fork()?;
+        if child_pid == 0 {
+            drop(writer);
+            exit_child(filesystem.enter().and_then(|_| unpack_archive(reader, container_path)));
+        }
+
+        drop(reader);
+        let result = pack_archive(host_path, writer);
+        wait_for_child(child_pid)?;
+        result
+    };
+
+    inner().map_err(|err| ContainerRuntimeError::Copy(err.to_string()))
+}
+
+pub fn copy_from_container(filesystem: &ContainerFilesystem, container_path: &Path, host_path: &Path) -> ContainerRuntimeResult<()> {
+    trace!("Copy from container - container: {}, container path: {}, host path: {}", filesystem, container_path.to_str().unwrap(), host_path.to_str().unwrap());
+
+    let inner = || -> ContainerRuntimeResult<()> {
+        let (reader, writer) = std::io::pipe()?;
+        let child_pid = fork()?;
+        if child_pid == 0 {
+            drop(reader);
+            exit_child(filesystem.enter().and_then(|_| pack_archive(container_path, writer)));
+        }
+
+        drop(writer);
+        let result = unpack_archive(reader, host_path);
+        wait_for_child(child_pid)?;
+        result
+    };
+
+    inner().map_err(|err| ContainerRuntimeError::Copy(err.to_string()))
+}
+
+pub fn export_container<W: Write>(filesystem: &ContainerFilesystem, mut writer: W) -> ContainerRuntimeResult<()> {
+    trace!("Export container - container: {}", filesystem);
+
+    let mut inner = || -> ContainerRuntimeResult<()> {
+        let (mut reader, pipe_writer) = std::io::pipe()?;
+        let child_pid = fork()?;
+        if child_pid == 0 {
+            drop(reader);
+            exit_child(filesystem.enter().and_then(|_| pack_root(pipe_writer)));
+        }
+
+        drop(pipe_writer);
+        let result = std::io::copy(&mut reader, &mut writer);
+        wait_for_child(child_pid)?;
+        result?;
+        Ok(())
+    };
+
+    inner().map_err(|err| ContainerRuntimeError::Export(err.to_string()))
+}
+
+fn exit_child(result: ContainerRuntimeResult<()>) -> ! {
+    let exit_code = match result {
+        Ok(()) => 0,
+        Err(err) => {
+            error!("Copy failed inside container due to: {}", err);
+            1
+        }
+    };
+
+    unsafe {
+        libc::_exit(exit_code);
+    }
+}
+
+fn wait_for_child(child_pid: i32) -> ContainerRuntimeResult<()> {
+    if waitpid(child_pid, 0)?.success() {
+        Ok(())
+    } else {
+        Err(ContainerRuntimeError::Copy("Copy inside container failed".to_owned()))
+    }
+}
+
+fn pack_archive<W: Write>(source: &Path, writer: W) -> ContainerRuntimeResult<()> {
+    let name = source
+        .file_name()
+        .ok_or_else(|| ContainerRuntimeError::Copy(format!("Invalid source path: {}", source.to_str().unwrap())))?;
+
+    let mut builder = tar::Builder::new(writer);
+    builder.follow_symlinks(false);
+
+    if source.is_dir() {
+        builder.append_dir_all(name, source)?;
+    } else {
+        builder.append_path_with_name(source, name)?;
+    }
+
+    builder.finish()?;
+    Ok(())
+}
+
+fn pack_root<W: Write>(writer: W) -> ContainerRuntimeResult<()> {
+    let mut builder = tar::Builder::new(writer);
+    builder.follow_symlinks(false);
+
+    let root_device = std::fs::symlink_metadata("/")?.dev();
+    for entry in std::fs::read_dir("/")? {
+        append_tree(&mut builder, &entry?.path(), root_device)?;
+    }
+
+    builder.finish()?;
+    Ok(())
+}
+
+fn append_tree<W: Write>(builder: &mut tar::Builder<W>, path: &Path, root_device: u64) -> ContainerRuntimeResult<()> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    let name = path.strip_prefix("/").unwrap();
+
+    // Other filesystems (proc, sysfs, /dev and bind mounts) are not part of the container filesystem
+    let is_container_filesystem = metadata.dev() == root_device;
+
+    if metadata.is_dir() {
+        builder.append_dir(name, path)?;
+
+        if is_container_filesystem {
+            for entry in std::fs::read_dir(path)? {
+                append_tree(builder, &entry?.path(), root_device)?;
+            }
+        }
+    } else if is_container_filesystem && !metadata.file_type().is_socket() {
+        builder.append_path_with_name(path, name)?;
+    }
+
+    Ok(())
+}
+
+fn unpack_archive<R: Read>(reader: R, destination: &Path) -> ContainerRuntimeResult<()> {
+    // Copying into an existing directory keeps the source name, otherwise the destination names the copy
+    let (base_dir, rename) = if destination.is_dir() {
+        (destination.to_owned(), None)
+    } else {
+        let base_dir = destination.parent().unwrap_or(Path::new("/")).to_owned();
+        (base_dir, destination.file_name().map(|name| name.to_owned()))
+    };
+
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_path_buf();
+
+        if !path.components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(ContainerRuntimeError::Copy(format!("Invalid path in archive: {}", path.to_str().unwrap())));
+        }
+
+        let path = match &rename {
+            Some(rename) => {
+                // Joining the empty rest of a single file would add a trailing separator to it
+                let mut components = path.components();
+                components.next();
+                match components.as_path() {
+                    rest if rest.as_os_str().is_empty() => PathBuf::from(rename),
+                    rest => Path::new(rename).join(rest)
+                }
+            }
+            None => path
+        };
+
+        let target = base_dir.join(path);
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        entry.unpack(&target)?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_copy_path_from_str() {
+    assert_eq!(Ok(CopyPath::Host(Path::new("data/file.txt").to_owned())), CopyPath::from_str("data/file.txt"));
+    assert_eq!(Ok(CopyPath::Host(Path::new("./a:b").to_owned())), CopyPath::from_str("./a:b"));
+    assert_eq!(Ok(CopyPath::Container("web".to_owned(), Path::new("/etc/hosts").to_owned())), CopyPath::from_str("web:/etc/hosts"));
+    assert!(CopyPath::from_str("web:etc/hosts").is_err());
+}
@@ -0,0 +1,309 @@
+//! The credentials of registries, as stored by `cort login` in the credentials file of cort or with a Docker credential helper.
+//! The credentials file has the format of the Docker config, whose credentials (and credential helpers) are also used.
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use tracing::{trace, warn};
+
+use crate::model::{ContainerRuntimeError, ContainerRuntimeResult};
+use crate::registry::DOCKER_HUB_REGISTRY;
+
+/// The username that credential helpers return along with identity tokens rather than passwords.
+const IDENTITY_TOKEN_USERNAME: &str = "<token>";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credentials {
+    Password { username: String, password: String },
+    /// A refresh token of the OAuth2 token server of the registry, which is exchanged for access tokens instead of a password
+    IdentityToken(String)
+}
+
+/// The credentials file of cort, which is `$CORT_AUTH_FILE` if set.
+pub fn credentials_path() -> Option<PathBuf> {
+    if let Ok(path) = std::env::var("CORT_AUTH_FILE") {
+        return Some(PathBuf::from(path));
+    }
+
+    std::env::var("HOME").ok().map(|home| Path::new(&home).join(".config").join("cort").join("auth.json"))
+}
+
+fn docker_config_path() -> Option<PathBuf> {
+    if let Ok(docker_config) = std::env::var("DOCKER_CONFIG") {
+        return Some(Path::new(&docker_config).join("config.json"));
+    }
+
+    std::env::var("HOME").ok().map(|home| Path::new(&home).join(".docker").join("config.json"))
+}
+
+/// Finds the credentials of the registry with its credential helper (if configured), or else in the credentials file or the Docker config.
+pub fn find_credentials(registry: &str, helper: Option<&str>) -> ContainerRuntimeResult<Option<Credentials>> {
+    if let Some(helper) = helper {
+        return helper_get(helper, registry);
+    }
+
+    for path in [credentials_path(), docker_config_path()].into_iter().flatten() {
+        // A broken Docker config should not prevent pulling public images
+        let auth_file = match AuthFile::load(&path) {
+            Ok(auth_file) => auth_file,
+            Err(err) => {
+                warn!("Ignoring credentials in {}: {}", path.to_str().unwrap(), err);
+                continue;
+            }
+        };
+
+        if let Some(credentials) = auth_file.find(registry)? {
+            trace!("Using credentials of {} from {}", registry, path.to_str().unwrap());
+            return Ok(Some(credentials));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Stores the credentials of the registry with the credential helper, or else in the credentials file (readable only by the user).
+pub fn store_credentials(registry: &str, credentials: &Credentials, helper: Option<&str>) -> ContainerRuntimeResult<PathBuf> {
+    if let Some(helper) = helper {
+        helper_store(helper, registry, credentials)?;
+        return Ok(helper_path(helper));
+    }
+
+    let path = credentials_path().ok_or_else(|| ContainerRuntimeError::Credentials("No credentials file (HOME is not set)".to_owned()))?;
+    let mut auth_file = AuthFile::load(&path)?;
+    auth_file.auths.insert(server_url(registry), AuthEntry::from_credentials(credentials));
+    auth_file.save(&path)?;
+    Ok(path)
+}
+
+/// Removes the stored credentials of the registry, returning if there were any.
+pub fn erase_credentials(registry: &str, helper: Option<&str>) -> ContainerRuntimeResult<bool> {
+    if let Some(helper) = helper {
+        let found = helper_get(helper, registry)?.is_some();
+        if found {
+            run_helper(helper, "erase", server_url(registry).as_bytes())?;
+        }
+        return Ok(found);
+    }
+
+    let Some(path) = credentials_path() else {
+        return Ok(false);
+    };
+
+    let mut auth_file = AuthFile::load(&path)?;
+    let keys = registry_keys(registry);
+    let found = auth_file.auths.keys().any(|key| keys.contains(key));
+    if found {
+        auth_file.auths.retain(|key, _| !keys.contains(key));
+        auth_file.save(&path)?;
+    }
+
+    Ok(found)
+}
+
+/// The name of the registry used by Docker, which is a URL for Docker Hub.
+fn server_url(registry: &str) -> String {
+    if registry == DOCKER_HUB_REGISTRY {
+        "https://index.docker.io/v1/".to_owned()
+    } else {
+        registry.to_owned()
+    }
+}
+
+/// The names that the credentials of the registry can be stored under.
+fn registry_keys(registry: &str) -> Vec<String> {
+    let mut keys = vec![registry.to_owned(), format!("https://{}", registry), format!("http://{}", registry)];
+    if registry == DOCKER_HUB_REGISTRY {
+        keys.push("https://index.docker.io/v1/".to_owned());
+        keys.push("docker.io".to_owned());
+    }
+
+    keys
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all="camelCase")]
+struct AuthFile {
+    #[serde(default)]
+    auths: BTreeMap<String, AuthEntry>,
+    /// The credential helpers of registries, as `docker-credential-<name>`
+    #[serde(default, skip_serializing_if="BTreeMap::is_empty")]
+    cred_helpers: BTreeMap<String, String>,
+    /// The credential helper of all other registries
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    creds_store: Option<String>
+}
+
+impl AuthFile {
+    fn load(path: &Path) -> ContainerRuntimeResult<AuthFile> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content).map_err(|err| ContainerRuntimeError::Credentials(format!("{}: {}", path.to_str().unwrap(), err))),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(AuthFile::default()),
+            Err(err) => Err(err.into())
+        }
+    }
+
+    fn save(&self, path: &Path) -> ContainerRuntimeResult<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut file = std::fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        Ok(())
+    }
+
+    fn find(&self, registry: &str) -> ContainerRuntimeResult<Option<Credentials>> {
+        let keys = registry_keys(registry);
+
+        if let Some(helper) = keys.iter().find_map(|key| self.cred_helpers.get(key)) {
+            return helper_get(helper, registry);
+        }
+
+        if let Some(entry) = keys.iter().find_map(|key| self.auths.get(key)) {
+            if let Some(credentials) = entry.credentials() {
+                return Ok(Some(credentials));
+            }
+        }
+
+        match &self.creds_store {
+            Some(helper) => helper_get(helper, registry),
+            None => Ok(None)
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AuthEntry {
+    /// The username and password as `<username>:<password>` in base64
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    auth: Option<String>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    identitytoken: Option<String>
+}
+
+impl AuthEntry {
+    fn from_credentials(credentials: &Credentials) -> AuthEntry {
+        match credentials {
+            Credentials::Password { username, password } => {
+                AuthEntry {
+                    auth: Some(base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", username, password))),
+                    identitytoken: None
+                }
+            }
+            Credentials::IdentityToken(token) => {
+                AuthEntry {
+                    auth: None,
+                    identitytoken: Some(token.clone())
+                }
+            }
+        }
+    }
+
+    fn credentials(&self) -> Option<Credentials> {
+        if let Some(token) = self.identitytoken.as_ref().filter(|token| !token.is_empty()) {
+            return Some(Credentials::IdentityToken(token.clone()));
+        }
+
+        let decoded = base64::engine::general_purpose::STANDARD.decode(self.auth.as_ref()?).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+        Some(Credentials::Password { username: username.to_owned(), password: password.to_owned() })
+    }
+}
+
+/// The messages of the protocol of the Docker credential helpers.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all="PascalCase")]
+struct HelperCredentials {
+    #[serde(rename="ServerURL")]
+    server_url: String,
+    username: String,
+    secret: String
+}
+
+fn helper_path(helper: &str) -> PathBuf {
+    PathBuf::from(format!("docker-credential-{}", helper))
+}
+
+fn helper_get(helper: &str, registry: &str) -> ContainerRuntimeResult<Option<Credentials>> {
+    let output = match run_helper(helper, "get", server_url(registry).as_bytes()) {
+        Ok(output) => output,
+        Err(ContainerRuntimeError::Credentials(message)) if message.contains("credentials not found") => return Ok(None),
+        Err(err) => return Err(err)
+    };
+
+    let credentials: HelperCredentials = serde_json::from_slice(&output)
+        .map_err(|err| ContainerRuntimeError::Credentials(format!("Invalid output of {}: {}", helper_path(helper).to_str().unwrap(), err)))?;
+
+    if credentials.username == IDENTITY_TOKEN_USERNAME {
+        Ok(Some(Credentials::IdentityToken(credentials.secret)))
+    } else {
+        Ok(Some(Credentials::Password { username: credentials.username, password: credentials.secret }))
+    }
+}
+
+fn helper_store(helper: &str, registry: &str, credentials: &Credentials) -> ContainerRuntimeResult<()> {
+    let (username, secret) = match credentials {
+        Credentials::Password { username, password } => (username.clone(), password.clone()),
+        Credentials::IdentityToken(token) => (IDENTITY_TOKEN_USERNAME.to_owned(), token.clone())
+    };
+
+    let input = serde_json::to_vec(&HelperCredentials { server_url: server_url(registry), username, secret })?;
+    run_helper(helper, "store", &input)?;
+    Ok(())
+}
+
+/// Runs the action of the helper with the input on stdin, where errors are reported on stdout.
+fn run_helper(helper: &str, action: &str, input: &[u8]) -> ContainerRuntimeResult<Vec<u8>> {
+    let path = helper_path(helper);
+    let inner = || -> std::io::Result<std::process::Output> {
+        let mut child = Command::new(&path)
+            .arg(action)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        child.stdin.take().unwrap().write_all(input)?;
+        child.wait_with_output()
+    };
+
+    let output = inner().map_err(|err| ContainerRuntimeError::Credentials(format!("Failed to run {}: {}", path.to_str().unwrap(), err)))?;
+    if !output.status.success() {
+        let message = if output.stdout.is_empty() { &output.stderr } else { &output.stdout };
+        return Err(
+            ContainerRuntimeError::Credentials(
+                format!("{} {} failed: {}", path.to_str().unwrap(), action, String::from_utf8_lossy(message).trim())
+            )
+        );
+    }
+
+    Ok(output.stdout)
+}
+
+#[test]
+fn test_auth_file() {
+    let mut auth_file: AuthFile = serde_json::from_str(r#"{
+        "auths": {
+            "https://index.docker.io/v1/": { "auth": "dXNlcjpzZWNyZXQ6cGFydA==" },
+            "quay.io": { "auth": "", "identitytoken": "refresh" }
+        },
+        "HttpHeaders": { "User-Agent": "Docker-Client" }
+    }"#).unwrap();
+
+    assert_eq!(
+        Some(Credentials::Password { username: "user".to_owned(), password: "secret:part".to_owned() }),
+        auth_file.find(DOCKER_HUB_REGISTRY).unwrap()
+    );
+    assert_eq!(Some(Credentials::IdentityToken("refresh".to_owned())), auth_file.find("quay.io").unwrap());
+    assert_eq!(None, auth_file.find("ghcr.io").unwrap());
+
+    let credentials = Credentials::Password { username: "pusher".to_owned(), password: "token".to_owned() };
+    auth_file.auths.insert(server_url("ghcr.io"), AuthEntry::from_credentials(&credentials));
+    let auth_file: AuthFile = serde_json::from_str(&serde_json::to_string(&auth_file).unwrap()).unwrap();
+    assert_eq!(Some(credentials), auth_file.find("ghcr.io").unwrap());
+}
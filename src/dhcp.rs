@@ -0,0 +1,266 @@
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+use tracing::info;
+use serde::{Deserialize, Serialize};
+
+use crate::linux;
+use crate::model::{ContainerRuntimeError, ContainerRuntimeResult};
+use crate::network::Ipv4Net;
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const HEADER_SIZE: usize = 236;
+
+const BOOT_REQUEST: u8 = 1;
+const BOOT_REPLY: u8 = 2;
+const BROADCAST_FLAG: u16 = 0x8000;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVERS: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETER_REQUEST_LIST: u8 = 55;
+const OPTION_END: u8 = 255;
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+
+/// Address leased from a DHCP server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DhcpLease {
+    pub address: Ipv4Net,
+    pub router: Option<Ipv4Addr>,
+    pub dns_servers: Vec<Ipv4Addr>,
+    pub server: Ipv4Addr,
+    /// The lease time in seconds
+    pub lease_time: u32
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DhcpMessage {
+    op: u8,
+    xid: u32,
+    flags: u16,
+    your_address: Ipv4Addr,
+    hardware_address: [u8; 6],
+    options: Vec<(u8, Vec<u8>)>
+}
+
+impl DhcpMessage {
+    fn request(message_type: u8, xid: u32, hardware_address: [u8; 6]) -> DhcpMessage {
+        DhcpMessage {
+            op: BOOT_REQUEST,
+            xid,
+            // The client has no address yet, so the server must broadcast its reply
+            flags: BROADCAST_FLAG,
+            your_address: Ipv4Addr::UNSPECIFIED,
+            hardware_address,
+            options: vec![
+                (OPTION_MESSAGE_TYPE, vec![message_type]),
+                (OPTION_PARAMETER_REQUEST_LIST, vec![OPTION_SUBNET_MASK, OPTION_ROUTER, OPTION_DNS_SERVERS, OPTION_LEASE_TIME])
+            ]
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buffer = vec![0u8; HEADER_SIZE];
+        buffer[0] = self.op;
+        buffer[1] = 1; // Ethernet
+        buffer[2] = 6;
+        buffer[4..8].copy_from_slice(&self.xid.to_be_bytes());
+        buffer[10..12].copy_from_slice(&self.flags.to_be_bytes());
+        buffer[16..20].copy_from_slice(&self.your_address.octets());
+        buffer[28..34].copy_from_slice(&self.hardware_address);
+
+        buffer.extend_from_slice(&MAGIC_COOKIE);
+        for (code, value) in &self.options {
+            buffer.push(*code);
+            buffer.push(value.len() as u8);
+            buffer.extend_from_slice(value);
+        }
+        buffer.push(OPTION_END);
+
+        buffer
+    }
+
+    fn decode(buffer: &[u8]) -> Option<DhcpMessage> {
+        if buffer.len() < HEADER_SIZE + MAGIC_COOKIE.len() || buffer[HEADER_SIZE..HEADER_SIZE + 4] != MAGIC_COOKIE {
+            return None;
+        }
+
+        let mut options = Vec::new();
+        let mut position = HEADER_SIZE + MAGIC_COOKIE.len();
+        while position < buffer.len() {
+            let code = buffer[position];
+            match code {
+                OPTION_PAD => {
+                    position += 1;
+                }
+                OPTION_END => {
+                    break;
+                }
+                _ => {
+                    let length = *buffer.get(position + 1)? as usize;
+                    let value = buffer.get(position + 2..position + 2 + length)?;
+                    options.push((code, value.to_vec()));
+                    position += 2 + length;
+                }
+            }
+        }
+
+        Some(
+            DhcpMessage {
+                op: buffer[0],
+                xid: u32::from_be_bytes(buffer[4..8].try_into().unwrap()),
+                flags: u16::from_be_bytes(buffer[10..12].try_into().unwrap()),
+                your_address: Ipv4Addr::new(buffer[16], buffer[17], buffer[18], buffer[19]),
+                hardware_address: buffer[28..34].try_into().unwrap(),
+                options
+            }
+        )
+    }
+
+    fn option(&self, code: u8) -> Option<&[u8]> {
+        self.options
+            .iter()
+            .find(|(option_code, _)| *option_code == code)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    fn addresses(&self, code: u8) -> Vec<Ipv4Addr> {
+        self.option(code)
+            .map(|value| value.chunks_exact(4).map(|chunk| Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3])).collect())
+            .unwrap_or_default()
+    }
+
+    fn message_type(&self) -> Option<u8> {
+        self.option(OPTION_MESSAGE_TYPE).and_then(|value| value.first().copied())
+    }
+
+    fn lease(&self) -> ContainerRuntimeResult<DhcpLease> {
+        let subnet_mask = self.addresses(OPTION_SUBNET_MASK).first().copied()
+            .ok_or_else(|| ContainerRuntimeError::Dhcp("No subnet mask in lease".to_owned()))?;
+        let server = self.addresses(OPTION_SERVER_ID).first().copied()
+            .ok_or_else(|| ContainerRuntimeError::Dhcp("No server identifier in lease".to_owned()))?;
+        let lease_time = self.option(OPTION_LEASE_TIME)
+            .and_then(|value| value.try_into().ok())
+            .map(u32::from_be_bytes)
+            .unwrap_or(0);
+
+        Ok(
+            DhcpLease {
+                address: Ipv4Net::new(self.your_address, u32::from(subnet_mask).count_ones() as u16),
+                router: self.addresses(OPTION_ROUTER).first().copied(),
+                dns_servers: self.addresses(OPTION_DNS_SERVERS),
+                server,
+                lease_time
+            }
+        )
+    }
+}
+
+/// Obtains a lease for the interface, which must be up but does not need an address.
+pub fn request_lease(interface: &str, timeout: Duration) -> ContainerRuntimeResult<DhcpLease> {
+    let hardware_address = hardware_address(interface)?;
+    let xid = uuid::Uuid::new_v4().as_u128() as u32;
+
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, CLIENT_PORT))?;
+    socket.set_broadcast(true)?;
+    linux::bind_to_device(&socket, interface)?;
+
+    let discover = DhcpMessage::request(DISCOVER, xid, hardware_address);
+    let offer = exchange(&socket, &discover, OFFER, timeout)?;
+    let server = offer.option(OPTION_SERVER_ID)
+        .ok_or_else(|| ContainerRuntimeError::Dhcp("No server identifier in offer".to_owned()))?
+        .to_vec();
+
+    let mut request = DhcpMessage::request(REQUEST, xid, hardware_address);
+    request.options.push((OPTION_REQUESTED_ADDRESS, offer.your_address.octets().to_vec()));
+    request.options.push((OPTION_SERVER_ID, server));
+    let ack = exchange(&socket, &request, ACK, timeout)?;
+
+    let lease = ack.lease()?;
+    info!("Leased {} from {} for {} seconds.", lease.address, lease.server, lease.lease_time);
+    Ok(lease)
+}
+
+/// Broadcasts the message until a reply of the expected type is received, retrying once a second.
+fn exchange(socket: &UdpSocket, message: &DhcpMessage, expected_type: u8, timeout: Duration) -> ContainerRuntimeResult<DhcpMessage> {
+    let server_address = SocketAddrV4::new(Ipv4Addr::BROADCAST, SERVER_PORT);
+    let start = Instant::now();
+    let mut buffer = vec![0u8; 1500];
+
+    while start.elapsed() < timeout {
+        socket.send_to(&message.encode(), server_address)?;
+
+        let retry_at = Instant::now() + Duration::from_secs(1);
+        while let Some(remaining) = retry_at.checked_duration_since(Instant::now()).filter(|remaining| !remaining.is_zero()) {
+            socket.set_read_timeout(Some(remaining))?;
+            let size = match socket.recv(&mut buffer) {
+                Ok(size) => size,
+                Err(err) if matches!(err.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => break,
+                Err(err) => return Err(err.into())
+            };
+
+            let reply = match DhcpMessage::decode(&buffer[..size]) {
+                Some(reply) if reply.op == BOOT_REPLY && reply.xid == message.xid => reply,
+                _ => continue
+            };
+
+            match reply.message_type() {
+                Some(message_type) if message_type == expected_type => return Ok(reply),
+                Some(NAK) => return Err(ContainerRuntimeError::Dhcp("Request refused by server".to_owned())),
+                _ => {}
+            }
+        }
+    }
+
+    Err(ContainerRuntimeError::Dhcp(format!("No reply within {:.1} seconds", timeout.as_secs_f64())))
+}
+
+fn hardware_address(interface: &str) -> ContainerRuntimeResult<[u8; 6]> {
+    let content = std::fs::read_to_string(format!("/sys/class/net/{}/address", interface))?;
+    let bytes = content
+        .trim()
+        .split(':')
+        .map(|part| u8::from_str_radix(part, 16))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| ContainerRuntimeError::Dhcp(format!("Invalid hardware address: {}", err)))?;
+
+    bytes.try_into().map_err(|_| ContainerRuntimeError::Dhcp(format!("Invalid hardware address: {}", content.trim())))
+}
+
+#[test]
+fn test_encode_decode_message() {
+    let hardware_address = [0x02, 0x42, 0xac, 0x11, 0x00, 0x02];
+    let mut message = DhcpMessage::request(DISCOVER, 0x3903f326, hardware_address);
+    let decoded = DhcpMessage::decode(&message.encode()).unwrap();
+    assert_eq!(message, decoded);
+    assert_eq!(Some(DISCOVER), decoded.message_type());
+
+    message.op = BOOT_REPLY;
+    message.your_address = Ipv4Addr::new(192, 168, 1, 100);
+    message.options = vec![
+        (OPTION_MESSAGE_TYPE, vec![ACK]),
+        (OPTION_SUBNET_MASK, vec![255, 255, 255, 0]),
+        (OPTION_ROUTER, vec![192, 168, 1, 1]),
+        (OPTION_DNS_SERVERS, vec![192, 168, 1, 1, 8, 8, 8, 8]),
+        (OPTION_SERVER_ID, vec![192, 168, 1, 1]),
+        (OPTION_LEASE_TIME, 3600u32.to_be_bytes().to_vec())
+    ];
+
+    let lease = DhcpMessage::decode(&message.encode()).unwrap().lease().unwrap();
+    assert_eq!(Ipv4Net::new(Ipv4Addr::new(192, 168, 1, 100), 24), lease.address);
+    assert_eq!(Some(Ipv4Addr::new(192, 168, 1, 1)), lease.router);
+    assert_eq!(vec![Ipv4Addr::new(192, 168, 1, 1), Ipv4Addr::new(8, 8, 8, 8)], lease.dns_servers);
+    assert_eq!(3600, lease.lease_time);
+}
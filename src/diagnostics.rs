@@ -0,0 +1,358 @@
+use std::fmt::{Display, Formatter};
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::linux::wrap_libc_error;
+use crate::model::{ContainerRuntimeError, ContainerRuntimeResult};
+use crate::network::{in_network_namespace, ip_command, iptables_command, VethPair};
+use crate::state::ContainerState;
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+const INTERNET_ADDRESS: Ipv4Addr = Ipv4Addr::new(8, 8, 8, 8);
+const RESOLVE_HOSTNAME: &str = "google.com";
+
+/// Report of the network of a running container, used to debug connectivity problems.
+#[derive(Debug)]
+pub struct NetworkReport {
+    pub container: String,
+    pub interfaces: String,
+    pub routes: String,
+    pub resolv_conf: String,
+    pub firewall_rules: Vec<String>,
+    pub checks: Vec<CheckResult>
+}
+
+#[derive(Debug)]
+pub struct CheckResult {
+    pub name: String,
+    pub outcome: Result<String, String>
+}
+
+impl CheckResult {
+    fn new<T: Display>(name: String, outcome: ContainerRuntimeResult<T>) -> CheckResult {
+        CheckResult {
+            name,
+            outcome: outcome
+                .map(|value| value.to_string())
+                .map_err(|err| match err {
+                    ContainerRuntimeError::Diagnostics(message) => message,
+                    err => err.to_string()
+                })
+        }
+    }
+}
+
+impl Display for NetworkReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let indent = |text: &str| text.lines().map(|line| format!("  {}\n", line)).collect::<String>();
+
+        writeln!(f, "Container: {}", self.container)?;
+        write!(f, "\nInterfaces:\n{}", indent(&self.interfaces))?;
+        write!(f, "\nRoutes:\n{}", indent(&self.routes))?;
+        write!(f, "\nDNS (/etc/resolv.conf):\n{}", indent(&self.resolv_conf))?;
+        write!(f, "\nFirewall rules:\n{}", indent(&self.firewall_rules.join("\n")))?;
+
+        writeln!(f, "\nChecks:")?;
+        for check in &self.checks {
+            match &check.outcome {
+                Ok(result) => writeln!(f, "  [OK]   {}: {}", check.name, result)?,
+                Err(err) => writeln!(f, "  [FAIL] {}: {}", check.name, err)?
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Inspects the network of the container from both the host and within its network namespace.
+pub fn inspect_network(state: &ContainerState, bridge_interface: &str) -> ContainerRuntimeResult<NetworkReport> {
+    let resolv_conf = std::fs::read_to_string(format!("/proc/{}/root/etc/resolv.conf", state.pid)).unwrap_or_default();
+    let firewall_rules = find_firewall_rules(state, bridge_interface);
+
+    let mut checks = vec![
+        CheckResult::new("IP forwarding".to_owned(), check_ip_forwarding())
+    ];
+    if state.ip_address.is_some() {
+        let veth = VethPair::for_container(&state.id);
+        checks.push(CheckResult::new(
+            format!("Host interface {} attached to {}", veth.host_interface, bridge_interface),
+            check_bridge_attachment(&veth.host_interface, bridge_interface)
+        ));
+    }
+
+    let name_servers = parse_name_servers(&resolv_conf);
+    let (interfaces, routes, container_checks) = in_network_namespace(state.pid, move || {
+        let interfaces = ip_command(["-brief", "address", "show"])?;
+        let routes = ip_command(["route", "show"])?;
+
+        let mut checks = Vec::new();
+        match default_gateway(&routes) {
+            Some(gateway) => checks.push(CheckResult::new(format!("Ping gateway {}", gateway), ping(gateway, CHECK_TIMEOUT).map(format_duration))),
+            None => checks.push(CheckResult::new::<String>("Default route".to_owned(), Err(ContainerRuntimeError::Diagnostics("No default route".to_owned()))))
+        }
+
+        checks.push(CheckResult::new(format!("Ping {}", INTERNET_ADDRESS), ping(INTERNET_ADDRESS, CHECK_TIMEOUT).map(format_duration)));
+
+        if name_servers.is_empty() {
+            checks.push(CheckResult::new::<String>("DNS".to_owned(), Err(ContainerRuntimeError::Diagnostics("No name servers configured".to_owned()))));
+        }
+        for name_server in name_servers {
+            checks.push(CheckResult::new(
+                format!("Resolve {} using {}", RESOLVE_HOSTNAME, name_server),
+                resolve(name_server, RESOLVE_HOSTNAME, CHECK_TIMEOUT)
+            ));
+        }
+
+        Ok((interfaces, routes, checks))
+    })?;
+    checks.extend(container_checks);
+
+    Ok(
+        NetworkReport {
+            container: format!("{} ({})", state.name, state.id),
+            interfaces,
+            routes,
+            resolv_conf,
+            firewall_rules,
+            checks
+        }
+    )
+}
+
+fn find_firewall_rules(state: &ContainerState, bridge_interface: &str) -> Vec<String> {
+    let ip_address = state.ip_address.map(|ip_address| ip_address.address.to_string());
+    let mut rules = Vec::new();
+    for table in ["filter", "nat"] {
+        match iptables_command(["-t", table, "-S"]) {
+            Ok(output) => {
+                let matching = output
+                    .lines()
+                    .filter(|rule| rule.contains(bridge_interface) || ip_address.as_ref().map(|ip_address| rule.contains(ip_address.as_str())).unwrap_or(false))
+                    .map(|rule| format!("{}: {}", table, rule));
+                rules.extend(matching);
+            }
+            Err(err) => {
+                rules.push(format!("{}: {}", table, err.to_string().trim()));
+            }
+        }
+    }
+
+    rules
+}
+
+fn check_ip_forwarding() -> ContainerRuntimeResult<String> {
+    match std::fs::read_to_string("/proc/sys/net/ipv4/ip_forward")?.trim() {
+        "1" => Ok("enabled".to_owned()),
+        _ => Err(ContainerRuntimeError::Diagnostics("disabled in /proc/sys/net/ipv4/ip_forward".to_owned()))
+    }
+}
+
+fn check_bridge_attachment(host_interface: &str, bridge_interface: &str) -> ContainerRuntimeResult<String> {
+    let output = ip_command(["-o", "link", "show", host_interface])?;
+    if !output.contains(&format!("master {}", bridge_interface)) {
+        return Err(ContainerRuntimeError::Diagnostics("not attached to the bridge".to_owned()));
+    }
+
+    if output.contains("state DOWN") {
+        return Err(ContainerRuntimeError::Diagnostics("interface is down".to_owned()));
+    }
+
+    Ok("attached".to_owned())
+}
+
+fn parse_name_servers(resolv_conf: &str) -> Vec<Ipv4Addr> {
+    resolv_conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|address| Ipv4Addr::from_str(address.trim()).ok())
+        .collect()
+}
+
+fn default_gateway(routes: &str) -> Option<Ipv4Addr> {
+    routes
+        .lines()
+        .filter(|line| line.starts_with("default"))
+        .find_map(|line| {
+            let mut parts = line.split_whitespace();
+            parts.find(|part| *part == "via")?;
+            Ipv4Addr::from_str(parts.next()?).ok()
+        })
+}
+
+fn format_duration(duration: Duration) -> String {
+    format!("reply in {:.1} ms", duration.as_secs_f64() * 1000.0)
+}
+
+/// Sends an ICMP echo request and waits for the reply.
+fn ping(address: Ipv4Addr, timeout: Duration) -> ContainerRuntimeResult<Duration> {
+    let socket = unsafe {
+        OwnedFd::from_raw_fd(wrap_libc_error(libc::socket(libc::AF_INET, libc::SOCK_RAW, libc::IPPROTO_ICMP))?)
+    };
+
+    let identifier = std::process::id() as u16;
+    let mut request = vec![8, 0, 0, 0];
+    request.extend_from_slice(&identifier.to_be_bytes());
+    request.extend_from_slice(&1u16.to_be_bytes());
+    request.extend_from_slice(b"cort network inspect");
+    let checksum = internet_checksum(&request);
+    request[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    let destination = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: 0,
+        sin_addr: libc::in_addr { s_addr: u32::from(address).to_be() },
+        sin_zero: [0; 8]
+    };
+
+    let start = Instant::now();
+    unsafe {
+        wrap_libc_error(libc::sendto(
+            socket.as_raw_fd(),
+            request.as_ptr() as *const libc::c_void,
+            request.len(),
+            0,
+            &destination as *const libc::sockaddr_in as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+        ) as i32)?;
+    }
+
+    let mut buffer = [0u8; 1500];
+    loop {
+        let remaining = timeout.checked_sub(start.elapsed()).unwrap_or_default();
+        let mut poll_fd = libc::pollfd { fd: socket.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        let ready = unsafe { wrap_libc_error(libc::poll(&mut poll_fd, 1, remaining.as_millis() as i32))? };
+        if ready == 0 {
+            return Err(ContainerRuntimeError::Diagnostics("timed out".to_owned()));
+        }
+
+        let size = unsafe {
+            wrap_libc_error(libc::recv(socket.as_raw_fd(), buffer.as_mut_ptr() as *mut libc::c_void, buffer.len(), 0) as i32)? as usize
+        };
+
+        // Raw sockets receive the IP header as well
+        let header_size = ((buffer[0] & 0x0f) as usize) * 4;
+        let source = Ipv4Addr::new(buffer[12], buffer[13], buffer[14], buffer[15]);
+        let reply = &buffer[header_size.min(size)..size];
+        if reply.len() >= 8 && reply[0] == 0 && reply[4..6] == identifier.to_be_bytes() && source == address {
+            return Ok(start.elapsed());
+        }
+    }
+}
+
+fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum = data
+        .chunks(2)
+        .map(|chunk| u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]) as u32)
+        .sum::<u32>();
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+/// Resolves the A record of the hostname using the name server.
+fn resolve(name_server: Ipv4Addr, hostname: &str, timeout: Duration) -> ContainerRuntimeResult<Ipv4Addr> {
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(SocketAddrV4::new(name_server, 53))?;
+    socket.set_read_timeout(Some(timeout))?;
+
+    let id = std::process::id() as u16;
+    socket.send(&dns_query(id, hostname))?;
+
+    let mut buffer = [0u8; 512];
+    let size = socket.recv(&mut buffer).map_err(|err| match err.kind() {
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => ContainerRuntimeError::Diagnostics("timed out".to_owned()),
+        _ => err.into()
+    })?;
+
+    parse_dns_response(id, &buffer[..size])
+}
+
+fn dns_query(id: u16, hostname: &str) -> Vec<u8> {
+    let mut query = Vec::new();
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00]); // Recursion desired
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in hostname.split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&[0, 1, 0, 1]); // A record, internet class
+    query
+}
+
+fn parse_dns_response(id: u16, response: &[u8]) -> ContainerRuntimeResult<Ipv4Addr> {
+    let invalid = || ContainerRuntimeError::Diagnostics("invalid DNS response".to_owned());
+
+    if response.len() < 12 || response[0..2] != id.to_be_bytes() {
+        return Err(invalid());
+    }
+
+    let response_code = response[3] & 0x0f;
+    if response_code != 0 {
+        return Err(ContainerRuntimeError::Diagnostics(format!("server responded with code {}", response_code)));
+    }
+
+    let question_count = u16::from_be_bytes([response[4], response[5]]);
+    let answer_count = u16::from_be_bytes([response[6], response[7]]);
+
+    let skip_name = |mut position: usize| -> Option<usize> {
+        loop {
+            let length = *response.get(position)?;
+            if length == 0 {
+                return Some(position + 1);
+            }
+
+            // Compressed names end with a pointer
+            if length & 0xc0 == 0xc0 {
+                return Some(position + 2);
+            }
+
+            position += 1 + length as usize;
+        }
+    };
+
+    let mut position = 12;
+    for _ in 0..question_count {
+        position = skip_name(position).ok_or_else(invalid)? + 4;
+    }
+
+    for _ in 0..answer_count {
+        position = skip_name(position).ok_or_else(invalid)?;
+        let record = response.get(position..position + 10).ok_or_else(invalid)?;
+        let record_type = u16::from_be_bytes([record[0], record[1]]);
+        let data_length = u16::from_be_bytes([record[8], record[9]]) as usize;
+        let data = response.get(position + 10..position + 10 + data_length).ok_or_else(invalid)?;
+        if record_type == 1 && data_length == 4 {
+            return Ok(Ipv4Addr::new(data[0], data[1], data[2], data[3]));
+        }
+
+        position += 10 + data_length;
+    }
+
+    Err(ContainerRuntimeError::Diagnostics("no address in response".to_owned()))
+}
+
+#[test]
+fn test_parse_dns_response() {
+    let mut response = dns_query(0x1234, "example.com");
+    response[2] = 0x81;
+    response[3] = 0x80;
+    response[7] = 2;
+    // CNAME pointing back to the question name, followed by the A record
+    response.extend_from_slice(&[0xc0, 0x0c, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 0x0c]);
+    response.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 93, 184, 216, 34]);
+    assert_eq!(Ipv4Addr::new(93, 184, 216, 34), parse_dns_response(0x1234, &response).unwrap());
+
+    assert!(parse_dns_response(0x4321, &response).is_err());
+    response[3] = 0x83;
+    assert_eq!("Network diagnostics failed: server responded with code 3", parse_dns_response(0x1234, &response).unwrap_err().to_string());
+
+    assert_eq!(Some(Ipv4Addr::new(10, 10, 1, 1)), default_gateway("10.10.0.0/16 dev eth0 proto kernel\ndefault via 10.10.1.1 dev eth0\n"));
+    assert_eq!(vec![Ipv4Addr::new(8, 8, 8, 8)], parse_name_servers("# comment\nnameserver 8.8.8.8\nnameserver ::1\n"));
+}
@@ -0,0 +1,186 @@
+use std::path::{Path, PathBuf};
+
+use tracing::info;
+
+use crate::linux;
+use crate::model::{ContainerRuntimeError, ContainerRuntimeResult};
+use crate::spec::{BindMountSpec, EmulationSpec, MountPropagation};
+
+const BINFMT_MISC_DIR: &str = "/proc/sys/fs/binfmt_misc";
+
+/// The ELF headers of the executables of each architecture (as named by QEMU), as matched by the handlers qemu-binfmt-conf.sh registers.
+const ELF_MAGICS: [(&str, &str, &str); 7] = [
+    (
+        "aarch64",
+        r"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\xb7\x00",
+        r"\xff\xff\xff\xff\xff\xff\xff\x00\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff\xff"
+    ),
+    (
+        "arm",
+        r"\x7fELF\x01\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x28\x00",
+        r"\xff\xff\xff\xff\xff\xff\xff\x00\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff\xff"
+    ),
+    (
+        "x86_64",
+        r"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x3e\x00",
+        r"\xff\xff\xff\xff\xff\xfe\xfe\x00\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff\xff"
+    ),
+    (
+        "i386",
+        r"\x7fELF\x01\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x03\x00",
+        r"\xff\xff\xff\xff\xff\xfe\xfe\x00\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff\xff"
+    ),
+    (
+        "ppc64le",
+        r"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x15\x00",
+        r"\xff\xff\xff\xff\xff\xff\xff\xfc\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff\x00"
+    ),
+    (
+        "s390x",
+        r"\x7fELF\x02\x02\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x16",
+        r"\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff"
+    ),
+    (
+        "riscv64",
+        r"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\xf3\x00",
+        r"\xff\xff\xff\xff\xff\xff\xff\x00\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff\xff"
+    )
+];
+
+/// The QEMU user mode emulator that the kernel runs the executables of an architecture with, by its binfmt_misc handler.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Emulator {
+    pub handler: PathBuf,
+    pub interpreter: PathBuf,
+    /// The kernel opened the interpreter when the handler was registered (the F flag), so it need not exist in the container
+    pub fix_binary: bool,
+    /// Whether the handler is registered already, rather than registered by `register`
+    pub registered: bool
+}
+
+impl Emulator {
+    /// The interpreter is opened by the kernel within the container when executing, unless it was opened when the handler was registered.
+    pub fn bind_mount(&self) -> Option<BindMountSpec> {
+        if self.fix_binary {
+            return None;
+        }
+
+        Some(
+            BindMountSpec {
+                source: self.interpreter.clone(),
+                target: self.interpreter.clone(),
+                is_readonly: true,
+                propagation: MountPropagation::Private
+            }
+        )
+    }
+
+    /// Registers the handler if it is not registered, where binfmt_misc is mounted first if needed.
+    pub fn register(&mut self) -> ContainerRuntimeResult<()> {
+        if self.registered {
+            return Ok(());
+        }
+
+        let inner = || -> ContainerRuntimeResult<()> {
+            let binfmt_misc_dir = Path::new(BINFMT_MISC_DIR);
+            if !binfmt_misc_dir.join("register").exists() {
+                linux::mount(Some("binfmt_misc"), binfmt_misc_dir, Some("binfmt_misc"), 0, None)?;
+            }
+
+            let name = self.handler.file_name().unwrap().to_str().unwrap();
+            let (_, magic, mask) = ELF_MAGICS
+                .iter()
+                .find(|(architecture, _, _)| Some(*architecture) == name.strip_prefix("qemu-"))
+                .ok_or_else(|| ContainerRuntimeError::Input(format!("No known executable format for {}", name)))?;
+
+            std::fs::write(binfmt_misc_dir.join("register"), format!(":{}:M::{}:{}:{}:", name, magic, mask, self.interpreter.to_str().unwrap()))?;
+            Ok(())
+        };
+
+        inner().map_err(|err| ContainerRuntimeError::Emulation(format!("Failed to register {}: {}", self.handler.to_str().unwrap(), err)))?;
+        info!("Registered the binfmt_misc handler {} for {}.", self.handler.to_str().unwrap(), self.interpreter.to_str().unwrap());
+        self.registered = true;
+        Ok(())
+    }
+}
+
+/// The emulator of the architecture (as named by images), which is the one of its registered handler if any,
+/// and otherwise `qemu-<architecture>-static` in the QEMU directory, whose handler is then to be registered.
+pub fn find_emulator(architecture: &str, spec: &EmulationSpec) -> ContainerRuntimeResult<Emulator> {
+    let handler = handler_path(architecture);
+    match std::fs::read_to_string(&handler) {
+        Ok(content) => {
+            let emulator = parse_handler(&handler, &content);
+            if !content.starts_with("enabled") {
+                return Err(ContainerRuntimeError::Emulation(format!("The binfmt_misc handler {} is disabled", handler.to_str().unwrap())));
+            }
+
+            emulator.ok_or_else(|| ContainerRuntimeError::Emulation(format!("Invalid binfmt_misc handler {}", handler.to_str().unwrap())))
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let name = handler.file_name().unwrap().to_str().unwrap();
+            let interpreter = [format!("{}-static", name), name.to_owned()]
+                .iter()
+                .map(|file_name| spec.qemu_dir.join(file_name))
+                .find(|path| path.is_file())
+                .ok_or_else(|| {
+                    ContainerRuntimeError::Emulation(
+                        format!("No QEMU emulator for {} in {} (such as from qemu-user-static)", architecture, spec.qemu_dir.to_str().unwrap())
+                    )
+                })?;
+
+            Ok(
+                Emulator {
+                    handler,
+                    interpreter,
+                    fix_binary: false,
+                    registered: false
+                }
+            )
+        }
+        Err(err) => Err(err.into())
+    }
+}
+
+/// The binfmt_misc handler of the architecture (as named by images), as registered by qemu-user-static.
+pub fn handler_path(architecture: &str) -> PathBuf {
+    let qemu_architecture = match architecture {
+        "amd64" => "x86_64",
+        "arm64" => "aarch64",
+        "386" => "i386",
+        "mips64le" => "mips64el",
+        architecture => architecture
+    };
+
+    Path::new(BINFMT_MISC_DIR).join(format!("qemu-{}", qemu_architecture))
+}
+
+fn parse_handler(handler: &Path, content: &str) -> Option<Emulator> {
+    let interpreter = content.lines().find_map(|line| line.strip_prefix("interpreter "))?;
+    let flags = content.lines().find_map(|line| line.strip_prefix("flags: ")).unwrap_or_default();
+
+    Some(
+        Emulator {
+            handler: handler.to_owned(),
+            interpreter: PathBuf::from(interpreter),
+            fix_binary: flags.contains('F'),
+            registered: true
+        }
+    )
+}
+
+#[test]
+fn test_parse_handler() {
+    let handler = handler_path("arm64");
+    assert_eq!(Path::new("/proc/sys/fs/binfmt_misc/qemu-aarch64"), handler);
+
+    let content = "enabled\ninterpreter /usr/bin/qemu-aarch64-static\nflags: OCF\noffset 0\nmagic 7f454c460201010000000000000000000200b700\n";
+    let emulator = parse_handler(&handler, content).unwrap();
+    assert_eq!(Path::new("/usr/bin/qemu-aarch64-static"), emulator.interpreter);
+    assert!(emulator.fix_binary);
+    assert!(emulator.bind_mount().is_none());
+
+    // Without the F flag, the interpreter is bind mounted to where the kernel looks for it in the container
+    let emulator = parse_handler(&handler, &content.replace("flags: OCF", "flags: ")).unwrap();
+    assert_eq!(Some(Path::new("/usr/bin/qemu-aarch64-static")), emulator.bind_mount().map(|bind_mount| bind_mount.target).as_deref());
+}
@@ -0,0 +1,385 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::{error, trace};
+
+use crate::model::ContainerRuntimeResult;
+use crate::sys::SysApi;
+
+/// The inode of the root directory of a FUSE filesystem.
+pub const ROOT_INODE: u64 = 1;
+
+/// The version of the kernel protocol spoken, where the minor version is lowered to the one of the kernel if older.
+const PROTOCOL_MAJOR: u32 = 7;
+const PROTOCOL_MINOR: u32 = 31;
+/// The replies to INIT of kernels before 7.23 have no fields after max_write.
+const INIT_OUT_COMPAT_SIZE: usize = 24;
+
+const MAX_READ: u32 = 128 * 1024;
+/// Room for the largest request, which the kernel requires to be at least 8 KiB.
+const REQUEST_BUFFER_SIZE: usize = MAX_READ as usize + 4096;
+/// How many requests are served at once, as reads block on fetching the data.
+const SERVER_THREADS: usize = 4;
+/// How long the kernel caches names and attributes, which never change as the filesystems are read-only.
+const CACHE_TIMEOUT: Duration = Duration::from_secs(3600);
+
+const IN_HEADER_SIZE: usize = 40;
+const OUT_HEADER_SIZE: usize = 16;
+
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_READLINK: u32 = 5;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_STATFS: u32 = 17;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_GETXATTR: u32 = 22;
+const FUSE_LISTXATTR: u32 = 23;
+const FUSE_FLUSH: u32 = 25;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_BATCH_FORGET: u32 = 42;
+
+const FUSE_ASYNC_READ: u32 = 1 << 0;
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+/// The attributes of a file, where the mode includes the file type (such as `S_IFREG`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileAttr {
+    pub inode: u64,
+    pub size: u64,
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u32,
+    pub mtime: i64
+}
+
+pub struct DirEntry {
+    pub inode: u64,
+    pub name: Vec<u8>,
+    pub mode: u32
+}
+
+/// A read-only filesystem served through FUSE, where errors are errno values (such as `libc::ENOENT`).
+pub trait ReadOnlyFilesystem: Send + Sync {
+    fn lookup(&self, parent: u64, name: &[u8]) -> Result<FileAttr, i32>;
+    fn getattr(&self, inode: u64) -> Result<FileAttr, i32>;
+    fn readlink(&self, inode: u64) -> Result<Vec<u8>, i32>;
+    fn read(&self, inode: u64, offset: u64, size: u32) -> Result<Vec<u8>, i32>;
+    /// The entries of the directory, without `.` and `..`
+    fn readdir(&self, inode: u64) -> Result<Vec<DirEntry>, i32>;
+    fn getxattr(&self, inode: u64, name: &[u8]) -> Result<Vec<u8>, i32>;
+    fn listxattr(&self, inode: u64) -> Result<Vec<Vec<u8>>, i32>;
+}
+
+/// A filesystem mounted at a path and served by threads of this process until it is unmounted, which it is when dropped.
+pub struct FuseMount {
+    sys: Arc<dyn SysApi>,
+    mount_point: PathBuf
+}
+
+impl FuseMount {
+    /// The source is shown as the device of the mount, such as in /proc/mounts.
+    pub fn mount(sys: Arc<dyn SysApi>, filesystem: Arc<dyn ReadOnlyFilesystem>, source: &str, mount_point: &Path) -> ContainerRuntimeResult<FuseMount> {
+        let device = Arc::new(OpenOptions::new().read(true).write(true).open("/dev/fuse")?);
+        let options = format!("fd={},rootmode=40000,user_id=0,group_id=0,allow_other,default_permissions", device.as_raw_fd());
+        sys.mount(Some(source), mount_point, Some("fuse"), libc::MS_RDONLY | libc::MS_NODEV, Some(&options))?;
+
+        // The threads end when the filesystem is unmounted, which makes the device fail to read
+        for _ in 0..SERVER_THREADS {
+            let device = device.clone();
+            let filesystem = filesystem.clone();
+            let span = tracing::Span::current();
+            std::thread::spawn(move || span.in_scope(|| serve(&device, filesystem.as_ref())));
+        }
+
+        Ok(
+            FuseMount {
+                sys,
+                mount_point: mount_point.to_owned()
+            }
+        )
+    }
+
+    pub fn mount_point(&self) -> &Path {
+        &self.mount_point
+    }
+}
+
+impl Drop for FuseMount {
+    fn drop(&mut self) {
+        if let Err(err) = self.sys.unmount(&self.mount_point) {
+            error!("Failed to unmount {} due to: {}", self.mount_point.to_str().unwrap(), err);
+        }
+    }
+}
+
+fn serve(mut device: &File, filesystem: &dyn ReadOnlyFilesystem) {
+    let mut buffer = vec![0; REQUEST_BUFFER_SIZE];
+    loop {
+        let size = match device.read(&mut buffer) {
+            Ok(size) => size,
+            // The request was interrupted before it was read
+            Err(err) if matches!(err.raw_os_error(), Some(libc::ENOENT) | Some(libc::EINTR) | Some(libc::EAGAIN)) => continue,
+            Err(err) => {
+                if err.raw_os_error() != Some(libc::ENODEV) {
+                    error!("Failed to read FUSE request: {}", err);
+                }
+                return;
+            }
+        };
+
+        if size < IN_HEADER_SIZE {
+            continue;
+        }
+
+        let request = Request { header: &buffer[..IN_HEADER_SIZE], body: &buffer[IN_HEADER_SIZE..size] };
+        let Some(reply) = handle(filesystem, &request) else {
+            continue;
+        };
+
+        let (error, body) = match reply {
+            Ok(body) => (0, body),
+            Err(errno) => (-errno, Vec::new())
+        };
+
+        let mut message = Vec::with_capacity(OUT_HEADER_SIZE + body.len());
+        put_u32(&mut message, (OUT_HEADER_SIZE + body.len()) as u32);
+        put_u32(&mut message, error as u32);
+        put_u64(&mut message, request.unique());
+        message.extend_from_slice(&body);
+
+        // Fails with ENOENT if the request was interrupted meanwhile, which needs no reply
+        if let Err(err) = device.write_all(&message) {
+            trace!("Failed to reply to FUSE request: {}", err);
+        }
+
+        if request.opcode() == FUSE_DESTROY {
+            return;
+        }
+    }
+}
+
+struct Request<'a> {
+    header: &'a [u8],
+    body: &'a [u8]
+}
+
+impl Request<'_> {
+    fn opcode(&self) -> u32 {
+        get_u32(self.header, 4)
+    }
+
+    fn unique(&self) -> u64 {
+        get_u64(self.header, 8)
+    }
+
+    fn inode(&self) -> u64 {
+        get_u64(self.header, 16)
+    }
+
+    /// The name given after the fixed part of the body, which is terminated by NUL.
+    fn name(&self, offset: usize) -> &[u8] {
+        let name = self.body.get(offset..).unwrap_or_default();
+        name.split(|byte| *byte == 0).next().unwrap_or_default()
+    }
+}
+
+/// The reply to the request, or none for requests that are not replied to.
+fn handle(filesystem: &dyn ReadOnlyFilesystem, request: &Request) -> Option<Result<Vec<u8>, i32>> {
+    let inode = request.inode();
+    let reply = match request.opcode() {
+        FUSE_INIT => Ok(init_reply(request.body)),
+        FUSE_LOOKUP => filesystem.lookup(inode, request.name(0)).map(|attr| entry_reply(&attr)),
+        FUSE_GETATTR => filesystem.getattr(inode).map(|attr| attr_reply(&attr)),
+        FUSE_READLINK => filesystem.readlink(inode),
+        FUSE_OPEN | FUSE_OPENDIR => {
+            let mut body = Vec::new();
+            put_u64(&mut body, 0);
+            put_u32(&mut body, FOPEN_KEEP_CACHE);
+            put_u32(&mut body, 0);
+            Ok(body)
+        }
+        FUSE_READ => filesystem.read(inode, get_u64(request.body, 8), get_u32(request.body, 16).min(MAX_READ)),
+        FUSE_READDIR => filesystem.readdir(inode).map(|entries| readdir_reply(inode, entries, get_u64(request.body, 8), get_u32(request.body, 16))),
+        FUSE_GETXATTR => filesystem.getxattr(inode, request.name(8)).and_then(|value| xattr_reply(value, get_u32(request.body, 0))),
+        FUSE_LISTXATTR => {
+            filesystem.listxattr(inode).and_then(|names| {
+                let list = names.into_iter().flat_map(|mut name| { name.push(0); name }).collect();
+                xattr_reply(list, get_u32(request.body, 0))
+            })
+        }
+        FUSE_STATFS => Ok(statfs_reply()),
+        FUSE_RELEASE | FUSE_RELEASEDIR | FUSE_FLUSH | FUSE_DESTROY => Ok(Vec::new()),
+        FUSE_FORGET | FUSE_BATCH_FORGET | FUSE_INTERRUPT => return None,
+        _ => Err(libc::ENOSYS)
+    };
+
+    Some(reply)
+}
+
+fn init_reply(body: &[u8]) -> Vec<u8> {
+    let kernel_minor = get_u32(body, 4);
+    let max_readahead = get_u32(body, 8);
+    let kernel_flags = get_u32(body, 12);
+
+    let mut reply = Vec::new();
+    put_u32(&mut reply, PROTOCOL_MAJOR);
+    put_u32(&mut reply, PROTOCOL_MINOR.min(kernel_minor));
+    put_u32(&mut reply, max_readahead);
+    put_u32(&mut reply, kernel_flags & FUSE_ASYNC_READ);
+    put_u16(&mut reply, 16); // max_background
+    put_u16(&mut reply, 12); // congestion_threshold
+    put_u32(&mut reply, MAX_READ); // max_write
+    put_u32(&mut reply, 1); // time_gran
+    put_u16(&mut reply, 0); // max_pages
+    put_u16(&mut reply, 0); // map_alignment
+    put_u32(&mut reply, 0); // flags2
+    reply.resize(64, 0);
+
+    if kernel_minor < 23 {
+        reply.truncate(INIT_OUT_COMPAT_SIZE);
+    }
+
+    reply
+}
+
+fn put_attr(body: &mut Vec<u8>, attr: &FileAttr) {
+    put_u64(body, attr.inode);
+    put_u64(body, attr.size);
+    put_u64(body, attr.size.div_ceil(512));
+    for _ in 0..3 {
+        put_u64(body, attr.mtime.max(0) as u64); // atime, mtime and ctime
+    }
+    for _ in 0..3 {
+        put_u32(body, 0);
+    }
+    put_u32(body, attr.mode);
+    put_u32(body, attr.nlink);
+    put_u32(body, attr.uid);
+    put_u32(body, attr.gid);
+    put_u32(body, attr.rdev);
+    put_u32(body, 4096); // blksize
+    put_u32(body, 0); // flags
+}
+
+fn entry_reply(attr: &FileAttr) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_u64(&mut body, attr.inode);
+    put_u64(&mut body, 0); // generation
+    put_u64(&mut body, CACHE_TIMEOUT.as_secs()); // entry_valid
+    put_u64(&mut body, CACHE_TIMEOUT.as_secs()); // attr_valid
+    put_u32(&mut body, 0);
+    put_u32(&mut body, 0);
+    put_attr(&mut body, attr);
+    body
+}
+
+fn attr_reply(attr: &FileAttr) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_u64(&mut body, CACHE_TIMEOUT.as_secs());
+    put_u32(&mut body, 0);
+    put_u32(&mut body, 0);
+    put_attr(&mut body, attr);
+    body
+}
+
+/// The entries from the offset (the index of the entry, where `.` and `..` come first) that fit in the size.
+fn readdir_reply(inode: u64, entries: Vec<DirEntry>, offset: u64, size: u32) -> Vec<u8> {
+    let mut all = vec![
+        DirEntry { inode, name: b".".to_vec(), mode: libc::S_IFDIR },
+        DirEntry { inode, name: b"..".to_vec(), mode: libc::S_IFDIR }
+    ];
+    all.extend(entries);
+
+    let mut body = Vec::new();
+    for (index, entry) in all.iter().enumerate().skip(offset as usize) {
+        let entry_size = (24 + entry.name.len()).next_multiple_of(8);
+        if body.len() + entry_size > size as usize {
+            break;
+        }
+
+        put_u64(&mut body, entry.inode);
+        put_u64(&mut body, index as u64 + 1);
+        put_u32(&mut body, entry.name.len() as u32);
+        put_u32(&mut body, (entry.mode & libc::S_IFMT) >> 12);
+        body.extend_from_slice(&entry.name);
+        body.resize(body.len().next_multiple_of(8), 0);
+    }
+
+    body
+}
+
+/// The size of the value when asked for the size (with a size of 0), and otherwise the value if it fits.
+fn xattr_reply(value: Vec<u8>, size: u32) -> Result<Vec<u8>, i32> {
+    if size == 0 {
+        let mut body = Vec::new();
+        put_u32(&mut body, value.len() as u32);
+        put_u32(&mut body, 0);
+        return Ok(body);
+    }
+
+    if value.len() > size as usize {
+        return Err(libc::ERANGE);
+    }
+
+    Ok(value)
+}
+
+fn statfs_reply() -> Vec<u8> {
+    let mut body = Vec::new();
+    for _ in 0..5 {
+        put_u64(&mut body, 0); // blocks, bfree, bavail, files and ffree
+    }
+    put_u32(&mut body, 4096); // bsize
+    put_u32(&mut body, 255); // namelen
+    put_u32(&mut body, 4096); // frsize
+    body.resize(80, 0);
+    body
+}
+
+fn get_u32(buffer: &[u8], offset: usize) -> u32 {
+    buffer.get(offset..offset + 4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap())).unwrap_or(0)
+}
+
+fn get_u64(buffer: &[u8], offset: usize) -> u64 {
+    buffer.get(offset..offset + 8).map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap())).unwrap_or(0)
+}
+
+fn put_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buffer: &mut Vec<u8>, value: u64) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+#[test]
+fn test_readdir_reply() {
+    let entries = vec![DirEntry { inode: 2, name: b"bin".to_vec(), mode: libc::S_IFDIR | 0o755 }];
+    let body = readdir_reply(ROOT_INODE, entries, 0, 4096);
+    assert_eq!(3 * 32, body.len());
+    assert_eq!(2, get_u64(&body, 64));
+    assert_eq!(3, get_u64(&body, 72));
+    assert_eq!(libc::DT_DIR as u32, get_u32(&body, 84));
+    assert_eq!(b"bin", &body[88..91]);
+
+    // Continues after the entries already read, and stops when the next does not fit
+    assert_eq!(32, readdir_reply(ROOT_INODE, Vec::new(), 1, 4096).len());
+    assert_eq!(32, readdir_reply(ROOT_INODE, Vec::new(), 0, 40).len());
+}
@@ -0,0 +1,255 @@
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use tracing::{info, warn};
+
+use crate::helpers::{command_output, COMMAND_TIMEOUT};
+use crate::model::{ContainerRuntimeError, ContainerRuntimeResult};
+use crate::spec::{BindMountSpec, GpuSpec, MountPropagation};
+
+/// Devices shared by all NVIDIA GPUs, which are needed to use any of them.
+const NVIDIA_CONTROL_DEVICES: [&str; 4] = ["/dev/nvidiactl", "/dev/nvidia-uvm", "/dev/nvidia-uvm-tools", "/dev/nvidia-modeset"];
+
+/// The driver libraries needed by CUDA and NVML, which must match the kernel module of the host rather than come from the image.
+const NVIDIA_LIBRARIES: [&str; 11] = [
+    "libcuda.so", "libcudadebugger.so", "libnvidia-ml.so", "libnvidia-cfg.so", "libnvidia-opencl.so", "libnvidia-gpucomp.so",
+    "libnvidia-ptxjitcompiler.so", "libnvidia-fatbinaryloader.so", "libnvidia-allocator.so", "libnvidia-compiler.so", "libnvidia-nvvm.so"
+];
+
+const NVIDIA_BINARIES: [&str; 5] = ["nvidia-smi", "nvidia-debugdump", "nvidia-persistenced", "nvidia-cuda-mps-control", "nvidia-cuda-mps-server"];
+
+/// The render node of a DRI card has the number of the card offset by this.
+const DRI_RENDER_NODE_OFFSET: u32 = 128;
+
+/// A device node of the host that is created with the same path in the container.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GpuDevice {
+    pub path: PathBuf,
+    pub mode: u32,
+    pub rdev: u64
+}
+
+impl GpuDevice {
+    fn from_path(path: &Path) -> ContainerRuntimeResult<Option<GpuDevice>> {
+        let metadata = match std::fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into())
+        };
+
+        if !metadata.file_type().is_char_device() {
+            return Ok(None);
+        }
+
+        Ok(
+            Some(
+                GpuDevice {
+                    path: path.to_owned(),
+                    mode: metadata.mode(),
+                    rdev: metadata.rdev()
+                }
+            )
+        )
+    }
+
+    /// The rule allowing the device in the devices cgroup.
+    pub fn cgroup_rule(&self) -> String {
+        format!("c {}:{} rwm", libc::major(self.rdev), libc::minor(self.rdev))
+    }
+}
+
+/// What is passed through to the container for the GPUs.
+#[derive(Debug, Clone, Default)]
+pub struct GpuPassthrough {
+    pub devices: Vec<GpuDevice>,
+    /// The driver libraries and utilities, mounted read-only
+    pub bind_mounts: Vec<BindMountSpec>,
+    pub environment: Vec<(String, String)>
+}
+
+/// Finds the device nodes of the GPUs on the host, and for NVIDIA GPUs also the driver libraries and utilities.
+pub fn discover(spec: &GpuSpec) -> ContainerRuntimeResult<GpuPassthrough> {
+    let nvidia_gpus = numbered_devices(Path::new("/dev"), "nvidia")?;
+    let dri_cards = numbered_devices(Path::new("/dev/dri"), "card")?;
+
+    let (nvidia_gpus, dri_cards) = match spec {
+        GpuSpec::All => {
+            if nvidia_gpus.is_empty() && dri_cards.is_empty() {
+                return Err(ContainerRuntimeError::SetupGpus("No GPUs found on the host (no /dev/nvidia* or /dev/dri/card* devices)".to_owned()));
+            }
+
+            (nvidia_gpus, dri_cards)
+        }
+        GpuSpec::Devices(gpus) => {
+            if let Some(gpu) = gpus.iter().find(|gpu| !nvidia_gpus.contains(gpu) && !dri_cards.contains(gpu)) {
+                return Err(ContainerRuntimeError::SetupGpus(format!("GPU {} not found (no /dev/nvidia{} or /dev/dri/card{})", gpu, gpu, gpu)));
+            }
+
+            (
+                nvidia_gpus.into_iter().filter(|gpu| gpus.contains(gpu)).collect::<Vec<_>>(),
+                dri_cards.into_iter().filter(|card| gpus.contains(card)).collect::<Vec<_>>()
+            )
+        }
+    };
+
+    let mut device_paths = Vec::new();
+    for gpu in &nvidia_gpus {
+        device_paths.push(PathBuf::from(format!("/dev/nvidia{}", gpu)));
+    }
+    if !nvidia_gpus.is_empty() {
+        device_paths.extend(NVIDIA_CONTROL_DEVICES.iter().map(PathBuf::from));
+    }
+    for card in &dri_cards {
+        device_paths.push(PathBuf::from(format!("/dev/dri/card{}", card)));
+        device_paths.push(PathBuf::from(format!("/dev/dri/renderD{}", card + DRI_RENDER_NODE_OFFSET)));
+    }
+
+    let mut passthrough = GpuPassthrough::default();
+    for path in device_paths {
+        if let Some(device) = GpuDevice::from_path(&path)? {
+            passthrough.devices.push(device);
+        }
+    }
+
+    if !nvidia_gpus.is_empty() {
+        add_nvidia_driver(spec, &mut passthrough)?;
+    }
+
+    info!("Passing through GPU devices: {:?}", passthrough.devices.iter().map(|device| device.path.to_str().unwrap()).collect::<Vec<_>>());
+    Ok(passthrough)
+}
+
+/// Finds the numbers of the devices named as the prefix followed by a number, such as /dev/nvidia0.
+fn numbered_devices(dir: &Path, prefix: &str) -> ContainerRuntimeResult<Vec<u32>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into())
+    };
+
+    let mut numbers = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let number = entry.file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix(prefix))
+            .and_then(|number| number.parse::<u32>().ok());
+
+        if let Some(number) = number {
+            numbers.push(number);
+        }
+    }
+
+    numbers.sort_unstable();
+    Ok(numbers)
+}
+
+/// Adds the user space driver of the host, which is what the NVIDIA container toolkit does for the compute and utility capabilities.
+fn add_nvidia_driver(spec: &GpuSpec, passthrough: &mut GpuPassthrough) -> ContainerRuntimeResult<()> {
+    let output = command_output(Command::new("ldconfig").arg("-p"), COMMAND_TIMEOUT)
+        .map_err(|err| ContainerRuntimeError::SetupGpus(format!("Failed to list libraries: {}", err)))?;
+    let libraries = find_libraries(&String::from_utf8_lossy(&output.stdout), &NVIDIA_LIBRARIES);
+    if libraries.is_empty() {
+        warn!("No NVIDIA driver libraries found on the host, only the devices are passed through.");
+    }
+
+    let mut library_dirs = Vec::new();
+    for library in libraries {
+        let library_dir = library.parent().unwrap().to_str().unwrap().to_owned();
+        if !library_dirs.contains(&library_dir) {
+            library_dirs.push(library_dir);
+        }
+
+        passthrough.bind_mounts.push(read_only_bind_mount(library.clone(), library));
+    }
+
+    let search_path = std::env::var("PATH").unwrap_or_default();
+    for binary in NVIDIA_BINARIES {
+        let found = search_path
+            .split(':')
+            .map(|dir| Path::new(dir).join(binary))
+            .find(|path| path.is_absolute() && path.is_file());
+
+        if let Some(path) = found {
+            passthrough.bind_mounts.push(read_only_bind_mount(path, Path::new("/usr/bin").join(binary)));
+        }
+    }
+
+    // The libraries are where they are on the host, which the dynamic linker of the image might not search
+    if let Ok(library_path) = std::env::var("LD_LIBRARY_PATH") {
+        library_dirs.extend(library_path.split(':').filter(|dir| !dir.is_empty()).map(|dir| dir.to_owned()));
+    }
+
+    let visible_devices = match spec {
+        GpuSpec::All => "all".to_owned(),
+        GpuSpec::Devices(gpus) => gpus.iter().map(|gpu| gpu.to_string()).collect::<Vec<_>>().join(",")
+    };
+
+    passthrough.environment.push(("NVIDIA_VISIBLE_DEVICES".to_owned(), visible_devices));
+    passthrough.environment.push(("NVIDIA_DRIVER_CAPABILITIES".to_owned(), "compute,utility".to_owned()));
+    if !library_dirs.is_empty() {
+        passthrough.environment.push(("LD_LIBRARY_PATH".to_owned(), library_dirs.join(":")));
+    }
+
+    Ok(())
+}
+
+fn read_only_bind_mount(source: PathBuf, target: PathBuf) -> BindMountSpec {
+    BindMountSpec {
+        source,
+        target,
+        is_readonly: true,
+        propagation: MountPropagation::Private
+    }
+}
+
+/// Finds the libraries (any version) in the output of `ldconfig -p`, skipping those of other architectures.
+fn find_libraries(ldconfig_output: &str, names: &[&str]) -> Vec<PathBuf> {
+    let arch_flag = match std::env::consts::ARCH {
+        "x86_64" => Some("x86-64"),
+        "aarch64" => Some("AArch64"),
+        _ => None
+    };
+
+    let mut libraries = Vec::new();
+    // Lines are of the form `libcuda.so.1 (libc6,x86-64) => /usr/lib/x86_64-linux-gnu/libcuda.so.1`
+    for line in ldconfig_output.lines() {
+        let Some((description, path)) = line.trim().split_once(" => ") else {
+            continue;
+        };
+
+        let Some((name, flags)) = description.split_once(" (") else {
+            continue;
+        };
+
+        let is_wanted = names.iter().any(|wanted| name == *wanted || name.starts_with(&format!("{}.", wanted)));
+        let is_same_arch = arch_flag.map(|arch_flag| flags.trim_end_matches(')').split(',').any(|flag| flag.trim() == arch_flag)).unwrap_or(true);
+        let path = PathBuf::from(path);
+        if is_wanted && is_same_arch && !libraries.contains(&path) {
+            libraries.push(path);
+        }
+    }
+
+    libraries
+}
+
+#[test]
+fn test_find_libraries() {
+    let output = "5 libs found in cache `/etc/ld.so.cache'
+\tlibnvidia-ml.so.1 (libc6,x86-64) => /usr/lib/x86_64-linux-gnu/libnvidia-ml.so.1
+\tlibnvidia-ml.so.1 (libc6) => /usr/lib/i386-linux-gnu/libnvidia-ml.so.1
+\tlibcuda.so.1 (libc6,x86-64, OS ABI: Linux 3.2.0) => /usr/lib/x86_64-linux-gnu/libcuda.so.1
+\tlibcudart.so.12 (libc6,x86-64) => /usr/local/cuda/lib64/libcudart.so.12
+\tlibc.so.6 (libc6,x86-64) => /lib/x86_64-linux-gnu/libc.so.6
+";
+
+    let libraries = find_libraries(output, &["libcuda.so", "libnvidia-ml.so"]);
+    if std::env::consts::ARCH == "x86_64" {
+        assert_eq!(
+            vec![PathBuf::from("/usr/lib/x86_64-linux-gnu/libnvidia-ml.so.1"), PathBuf::from("/usr/lib/x86_64-linux-gnu/libcuda.so.1")],
+            libraries
+        );
+    }
+    assert!(!libraries.contains(&PathBuf::from("/usr/local/cuda/lib64/libcudart.so.12")));
+}
@@ -1,22 +1,235 @@
-use std::path::{PathBuf};
-use log::error;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output};
+use std::time::{Duration, Instant};
+
+use tracing::{error, trace};
+
+use crate::model::ContainerRuntimeResult;
 
 pub struct RemoveDirGuard {
-    dir: PathBuf
+    dir: PathBuf,
+    keep: Vec<String>
 }
 
 impl RemoveDirGuard {
     pub fn new(dir: PathBuf) -> RemoveDirGuard {
         RemoveDirGuard {
-            dir
+            dir,
+            keep: Vec::new()
         }
     }
+
+    /// Keeps the entry of the directory with this name, in which case only the other entries are removed.
+    pub fn keep(&mut self, name: &str) {
+        self.keep.push(name.to_owned());
+    }
+
+    fn remove(&self) -> std::io::Result<()> {
+        unmount_below(&self.dir)?;
+        if self.keep.is_empty() {
+            return std::fs::remove_dir_all(&self.dir);
+        }
+
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if self.keep.iter().any(|name| entry.file_name() == name.as_str()) {
+                continue;
+            }
+
+            if entry.file_type()?.is_dir() {
+                std::fs::remove_dir_all(entry.path())?;
+            } else {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl Drop for RemoveDirGuard {
     fn drop(&mut self) {
-        if let Err(err) = std::fs::remove_dir_all(&self.dir) {
+        if let Err(err) = self.remove() {
             error!("Failed to remove directory {} due to: {}", self.dir.to_str().unwrap(), err);
         }
     }
-}
\ No newline at end of file
+}
+
+/// Like `std::fs::remove_dir_all`, but first unmounts what is mounted below the directory.
+pub fn remove_dir_all_unmounted(dir: &Path) -> std::io::Result<()> {
+    unmount_below(dir)?;
+    std::fs::remove_dir_all(dir)
+}
+
+/// Lazily unmounts what is mounted below the directory, the deepest first, as removing the directory would otherwise
+/// remove what is mounted (such as the data of the host through a bind mount). Fails if anything remains mounted.
+pub fn unmount_below(dir: &Path) -> std::io::Result<()> {
+    let mounts_below = |dir: &Path| crate::linux::mounts_below(dir).map_err(|err| std::io::Error::other(err.to_string()));
+
+    for mount_point in mounts_below(dir)? {
+        trace!("Unmounting {} before removing {}.", mount_point.to_str().unwrap(), dir.to_str().unwrap());
+        if let Err(err) = crate::linux::unmount(&mount_point) {
+            error!("Failed to unmount {} due to: {}", mount_point.to_str().unwrap(), err);
+        }
+    }
+
+    if let Some(mount_point) = mounts_below(dir)?.first() {
+        return Err(std::io::Error::other(format!("{} is still mounted", mount_point.to_str().unwrap())));
+    }
+
+    Ok(())
+}
+
+pub struct RemoveFileGuard {
+    path: PathBuf
+}
+
+impl RemoveFileGuard {
+    pub fn new(path: PathBuf) -> RemoveFileGuard {
+        RemoveFileGuard {
+            path
+        }
+    }
+}
+
+impl Drop for RemoveFileGuard {
+    fn drop(&mut self) {
+        if self.path.exists() {
+            if let Err(err) = std::fs::remove_file(&self.path) {
+                error!("Failed to remove file {} due to: {}", self.path.to_str().unwrap(), err);
+            }
+        }
+    }
+}
+
+/// The time allowed for the commands used when setting up and removing containers (such as `ip`), after which they are considered hung.
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Like `Command::output`, but kills the command if it has not completed within the timeout.
+pub fn command_output(command: &mut Command, timeout: Duration) -> std::io::Result<Output> {
+    let description = std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|part| part.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let child = command
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+    wait_with_output(child, &description, timeout)
+}
+
+/// Like `Child::wait_with_output`, but kills the process if it has not exited within the timeout.
+pub fn wait_with_output(mut child: Child, description: &str, timeout: Duration) -> std::io::Result<Output> {
+    // The output is read while waiting, so that a process filling a pipe does not block
+    fn read_all<R: Read + Send + 'static>(mut reader: R) -> std::thread::JoinHandle<Vec<u8>> {
+        std::thread::spawn(move || {
+            let mut content = Vec::new();
+            reader.read_to_end(&mut content).ok();
+            content
+        })
+    }
+
+    let stdout = child.stdout.take().map(read_all);
+    let stderr = child.stderr.take().map(read_all);
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if start.elapsed() >= timeout {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("`{}` did not complete within {} seconds", description, timeout.as_secs())
+            ));
+        }
+
+        std::thread::sleep(Duration::from_millis(1));
+    };
+
+    let join = |reader: Option<std::thread::JoinHandle<Vec<u8>>>| reader.and_then(|reader| reader.join().ok()).unwrap_or_default();
+    Ok(
+        Output {
+            status,
+            stdout: join(stdout),
+            stderr: join(stderr)
+        }
+    )
+}
+
+type UndoAction<'a> = Box<dyn FnOnce() -> ContainerRuntimeResult<()> + 'a>;
+
+/// Undo actions registered by setup steps, executed in reverse order when dropped unless committed.
+pub struct Rollback<'a> {
+    actions: Vec<(String, UndoAction<'a>)>
+}
+
+impl<'a> Rollback<'a> {
+    pub fn new() -> Rollback<'a> {
+        Rollback {
+            actions: Vec::new()
+        }
+    }
+
+    pub fn add<F: FnOnce() -> ContainerRuntimeResult<()> + 'a>(&mut self, description: &str, action: F) {
+        self.actions.push((description.to_owned(), Box::new(action)));
+    }
+
+    pub fn commit(mut self) {
+        self.actions.clear();
+    }
+}
+
+impl Drop for Rollback<'_> {
+    fn drop(&mut self) {
+        while let Some((description, action)) = self.actions.pop() {
+            trace!("Rollback: {}", description);
+            if let Err(err) = action() {
+                error!("Failed to {} due to: {}", description, err);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_rollback_order() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let executed = Rc::new(RefCell::new(Vec::new()));
+
+    {
+        let mut rollback = Rollback::new();
+        for step in 0..3 {
+            let executed = executed.clone();
+            rollback.add("step", move || { executed.borrow_mut().push(step); Ok(()) });
+        }
+    }
+
+    {
+        let mut rollback = Rollback::new();
+        let executed = executed.clone();
+        rollback.add("step", move || { executed.borrow_mut().push(3); Ok(()) });
+        rollback.commit();
+    }
+
+    assert_eq!(vec![2, 1, 0], *executed.borrow());
+}
+
+#[test]
+fn test_command_output_timeout() {
+    let output = command_output(Command::new("sh").args(["-c", "echo started"]), Duration::from_secs(10)).unwrap();
+    assert!(output.status.success());
+    assert_eq!(b"started\n".to_vec(), output.stdout);
+
+    let err = command_output(Command::new("sleep").arg("10"), Duration::from_millis(50)).unwrap_err();
+    assert_eq!(std::io::ErrorKind::TimedOut, err.kind());
+    assert!(err.to_string().starts_with("`sleep 10` did not complete"));
+}
@@ -0,0 +1,114 @@
+use std::io::Write;
+use std::os::fd::AsFd;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+use serde::Serialize;
+
+use crate::model::{ContainerRuntimeError, ContainerRuntimeResult};
+use crate::spec::HookSpec;
+use crate::state::ContainerState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookStage {
+    Prestart,
+    Poststart,
+    Poststop
+}
+
+impl HookStage {
+    pub fn name(&self) -> &str {
+        match self {
+            HookStage::Prestart => "prestart",
+            HookStage::Poststart => "poststart",
+            HookStage::Poststop => "poststop"
+        }
+    }
+
+    /// The status of the container while the hooks of the stage run.
+    fn status(&self) -> &str {
+        match self {
+            HookStage::Prestart => "created",
+            HookStage::Poststart => "running",
+            HookStage::Poststop => "stopped"
+        }
+    }
+}
+
+/// The input of a hook, which is the state of the container with its status and root directory.
+#[derive(Serialize)]
+struct HookInput<'a> {
+    #[serde(flatten)]
+    state: &'a ContainerState,
+    status: &'a str,
+    bundle: PathBuf
+}
+
+/// Runs the hooks in order, stopping at the first that fails.
+pub fn run_hooks(stage: HookStage, hooks: &[HookSpec], state: &ContainerState, container_root: &Path) -> ContainerRuntimeResult<()> {
+    if hooks.is_empty() {
+        return Ok(());
+    }
+
+    let input = serde_json::to_vec(&HookInput {
+        state,
+        status: stage.status(),
+        bundle: container_root.to_owned()
+    })?;
+
+    for hook in hooks {
+        info!("Running {} hook {}.", stage.name(), hook.path.to_str().unwrap());
+        run_hook(hook, &input).map_err(|err| ContainerRuntimeError::Hook(format!("{} hook {}: {}", stage.name(), hook.path.to_str().unwrap(), err)))?;
+    }
+
+    Ok(())
+}
+
+/// Runs the hooks, only warning if any fails, as the container can no longer be stopped from starting.
+pub fn run_hooks_or_warn(stage: HookStage, hooks: &[HookSpec], state: &ContainerState, container_root: &Path) {
+    if let Err(err) = run_hooks(stage, hooks, state, container_root) {
+        warn!("{}", err);
+    }
+}
+
+fn run_hook(hook: &HookSpec, input: &[u8]) -> Result<(), String> {
+    // The output of hooks goes along with the log of the runtime, rather than mixing with the output of the container
+    let stderr = std::io::stderr().as_fd().try_clone_to_owned().map_err(|err| err.to_string())?;
+    let mut child = Command::new(&hook.path)
+        .args(&hook.args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::from(stderr.try_clone().map_err(|err| err.to_string())?))
+        .stderr(Stdio::from(stderr))
+        .spawn()
+        .map_err(|err| format!("failed to start: {}", err))?;
+
+    // A hook that does not read its input should not fail because of it
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input).ok();
+    }
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(|err| err.to_string())? {
+            break status;
+        }
+
+        if let Some(timeout) = hook.timeout {
+            if start.elapsed() >= Duration::from_secs(timeout) {
+                child.kill().ok();
+                child.wait().ok();
+                return Err(format!("timed out after {} seconds", timeout));
+            }
+        }
+
+        std::thread::sleep(Duration::from_millis(10));
+    };
+
+    if !status.success() {
+        return Err(format!("exited with {}", status));
+    }
+
+    Ok(())
+}
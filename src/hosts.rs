@@ -0,0 +1,146 @@
+use std::path::Path;
+
+use tracing::trace;
+
+use crate::linux::lock_file;
+use crate::model::ContainerRuntimeResult;
+use crate::spec::HostsFileSpec;
+use crate::state::ContainerState;
+
+const BEGIN_MARKER: &str = "# BEGIN cort containers";
+const END_MARKER: &str = "# END cort containers";
+
+/// Writes the names and addresses of the running containers to the hosts file, replacing the ones written before.
+/// The entries are created from the states of all containers each time, so that an update that failed is corrected by the next one.
+pub fn update_hosts_file(spec: &HostsFileSpec, containers_base_dir: &Path) -> ContainerRuntimeResult<()> {
+    // Next to the containers directory, as every entry in it is taken as a container
+    let _lock = lock_file(&containers_base_dir.with_file_name("hosts.lock"))?;
+
+    let running = ContainerState::load_all(containers_base_dir)?
+        .into_iter()
+        .filter(|state| state.is_running())
+        .collect::<Vec<_>>();
+
+    let content = match std::fs::read_to_string(&spec.path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err.into())
+    };
+
+    let updated = replace_entries(&content, &host_entries(&running, spec.domain.as_deref()));
+    if updated != content {
+        trace!("Update hosts file - path: {}, containers: {}", spec.path.to_str().unwrap(), running.len());
+        write_hosts_file(&spec.path, &updated)?;
+    }
+
+    Ok(())
+}
+
+/// The lines of the containers with an address, with the hostname and the name of each (when they differ).
+fn host_entries(states: &[ContainerState], domain: Option<&str>) -> Vec<String> {
+    let mut entries = Vec::new();
+    for state in states {
+        let mut names = state.hostname.iter().collect::<Vec<_>>();
+        if !names.contains(&&state.name) {
+            names.push(&state.name);
+        }
+
+        let names = names
+            .into_iter()
+            .map(|name| match domain {
+                Some(domain) => format!("{}.{}", name, domain),
+                None => name.clone()
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let addresses = state.ip_address.iter().chain(state.networks.iter().map(|attachment| &attachment.ip_address));
+        for address in addresses {
+            entries.push(format!("{}\t{}", address.address, names));
+        }
+    }
+
+    entries
+}
+
+/// Replaces the block of entries written by the runtime, where the rest of the file is kept as is. Without entries, the block is removed.
+fn replace_entries(content: &str, entries: &[String]) -> String {
+    let mut lines = content.lines().collect::<Vec<_>>();
+    let begin = lines.iter().position(|line| *line == BEGIN_MARKER);
+    let end = lines.iter().position(|line| *line == END_MARKER);
+    if let (Some(begin), Some(end)) = (begin, end) {
+        if begin < end {
+            lines.drain(begin..=end);
+        }
+    }
+
+    let mut updated = lines.join("\n");
+    if !updated.is_empty() {
+        updated.push('\n');
+    }
+
+    if !entries.is_empty() {
+        updated += BEGIN_MARKER;
+        updated.push('\n');
+        for entry in entries {
+            updated += entry;
+            updated.push('\n');
+        }
+        updated += END_MARKER;
+        updated.push('\n');
+    }
+
+    updated
+}
+
+/// Replaces the file at once, so that lookups never see it partially written. Files that cannot be replaced, such as a bind mounted
+/// /etc/hosts, are written in place instead.
+fn write_hosts_file(path: &Path, content: &str) -> ContainerRuntimeResult<()> {
+    let temp_path = path.with_file_name(format!(".{}.cort", path.file_name().unwrap().to_str().unwrap()));
+    std::fs::write(&temp_path, content)?;
+    if let Ok(metadata) = std::fs::metadata(path) {
+        std::fs::set_permissions(&temp_path, metadata.permissions())?;
+    }
+
+    if std::fs::rename(&temp_path, path).is_err() {
+        std::fs::remove_file(&temp_path)?;
+        std::fs::write(path, content)?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_replace_entries() {
+    use std::str::FromStr;
+
+    use crate::network::Ipv4Net;
+    use crate::spec::NetworkSpec;
+
+    let runtime = crate::ContainerRuntime::new(crate::ContainerRuntimeConfig::from_base_dir(Path::new("/tmp/cort-test")));
+    let mut spec = runtime.create_run_spec("alpine", vec!["sh".to_owned()], NetworkSpec::Host);
+    spec.name = "web".to_owned();
+
+    let mut web = ContainerState::new(&spec, 1);
+    web.hostname = Some("frontend".to_owned());
+    web.ip_address = Some(Ipv4Net::from_str("10.10.1.2/16").unwrap());
+    spec.name = "db".to_owned();
+    let mut db = ContainerState::new(&spec, 2);
+    db.hostname = Some("db".to_owned());
+    db.ip_address = Some(Ipv4Net::from_str("10.10.1.3/16").unwrap());
+    // Uses the network of the host, so it has no address of its own
+    spec.name = "agent".to_owned();
+    let agent = ContainerState::new(&spec, 3);
+
+    let entries = host_entries(&[web, db, agent], Some("cort"));
+    assert_eq!(vec!["10.10.1.2\tfrontend.cort web.cort", "10.10.1.3\tdb.cort"], entries);
+
+    let hosts = "127.0.0.1\tlocalhost\n::1\tlocalhost\n";
+    let updated = replace_entries(hosts, &entries);
+    assert_eq!(
+        "127.0.0.1\tlocalhost\n::1\tlocalhost\n# BEGIN cort containers\n10.10.1.2\tfrontend.cort web.cort\n10.10.1.3\tdb.cort\n# END cort containers\n",
+        updated
+    );
+    assert_eq!(updated, replace_entries(&updated, &entries));
+    assert_eq!(hosts, replace_entries(&updated, &[]));
+}
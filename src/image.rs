@@ -0,0 +1,1550 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::fmt::{Display, Formatter};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{mpsc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+
+use flate2::Compression;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use tracing::{info, trace, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::config::RegistryConfig;
+use crate::helpers::{RemoveFileGuard, Rollback};
+use crate::lazy::{LazyLayer, RegistryBlobSource, TableOfContents, TOC_DIGEST_ANNOTATION};
+use crate::linux::{get_xattr, lchown, lock_file, lock_file_shared, mknod, set_xattr};
+use crate::model::{ContainerRuntimeError, ContainerRuntimeResult};
+use crate::progress::{NoProgress, ProgressEvent, ProgressReader, ProgressReporter, ProgressStage};
+use crate::registry::{
+    Descriptor,
+    DigestWriter,
+    find_registry_config,
+    ImageReference,
+    Manifest,
+    ManifestList,
+    MEDIA_TYPE_DOCKER_CONFIG,
+    MEDIA_TYPE_DOCKER_LAYER_GZIP,
+    MEDIA_TYPE_DOCKER_MANIFEST,
+    MEDIA_TYPE_DOCKER_MANIFEST_LIST,
+    MEDIA_TYPE_OCI_INDEX,
+    host_architecture,
+    is_sha256_digest,
+    Platform,
+    RegistryClient,
+    sha256_digest
+};
+use crate::signature::{fetch_signatures, ImageSignature, SignatureVerifier};
+use crate::storage::BtrfsStorageDriver;
+
+pub const OVERLAY_OPAQUE_XATTR: &str = "trusted.overlay.opaque";
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// The tag of images referred to without one.
+pub const DEFAULT_TAG: &str = "latest";
+
+/// A local image, referred to as `name[:tag]` or `name@sha256:...` by the digest of its manifest (or index).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalImageReference {
+    pub name: String,
+    /// The tag, or the digest when referred to by digest
+    pub tag: String
+}
+
+impl LocalImageReference {
+    pub fn is_digest(&self) -> bool {
+        is_sha256_digest(&self.tag)
+    }
+
+    /// Parses a reference that must have a tag, such as the image to import or pull to.
+    pub fn tagged(text: &str) -> ContainerRuntimeResult<LocalImageReference> {
+        let reference = LocalImageReference::from_str(text).map_err(ContainerRuntimeError::Input)?;
+        if reference.is_digest() {
+            return Err(ContainerRuntimeError::Input(format!("Expected a tag rather than a digest in '{}'", text)));
+        }
+
+        Ok(reference)
+    }
+}
+
+impl FromStr for LocalImageReference {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (name, tag) = match (text.split_once('@'), text.rsplit_once(':')) {
+            (Some((name, digest)), _) if is_sha256_digest(digest) => (name, digest),
+            (Some(_), _) => return Err(format!("Invalid image reference '{}' (expected <name>@sha256:<hex>).", text)),
+            (None, Some((name, tag))) => (name, tag),
+            (None, None) => (text, DEFAULT_TAG)
+        };
+
+        let is_valid_name = !name.is_empty() && !name.starts_with('.') && !name.contains(['/', ':']);
+        let is_valid_tag = is_sha256_digest(tag) || (
+            !tag.is_empty()
+            && tag.len() <= 128
+            && !tag.starts_with(['.', '-'])
+            && tag.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+        );
+        if !is_valid_name || !is_valid_tag {
+            return Err(format!("Invalid image name '{}'.", text));
+        }
+
+        Ok(LocalImageReference { name: name.to_owned(), tag: tag.to_owned() })
+    }
+}
+
+impl Display for LocalImageReference {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.is_digest() {
+            write!(f, "{}@{}", self.name, self.tag)
+        } else {
+            write!(f, "{}:{}", self.name, self.tag)
+        }
+    }
+}
+
+fn default_tag() -> String {
+    DEFAULT_TAG.to_owned()
+}
+
+/// Local image referring to its manifest in the blob store.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageMetadata {
+    pub name: String,
+    #[serde(default="default_tag")]
+    pub tag: String,
+    pub reference: Option<String>,
+    pub manifest: Descriptor,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub index: Option<Descriptor>,
+    #[serde(default, skip_serializing_if="Vec::is_empty")]
+    pub signatures: Vec<ImageSignature>,
+    /// The platform given by the config of the image
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub platform: Option<Platform>
+}
+
+impl ImageMetadata {
+    pub fn tagged_name(&self) -> String {
+        format!("{}:{}", self.name, self.tag)
+    }
+}
+
+/// A step of the history of an image as given by its config, along with the layer it created.
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryEntry {
+    pub created: Option<String>,
+    pub created_by: Option<String>,
+    pub comment: Option<String>,
+    /// The layer that the step created, which steps only changing the config (such as ENV) do not have
+    pub layer: Option<Descriptor>
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfigHistory {
+    #[serde(default)]
+    history: Vec<ConfigHistoryEntry>
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfigHistoryEntry {
+    created: Option<String>,
+    created_by: Option<String>,
+    comment: Option<String>,
+    #[serde(default)]
+    empty_layer: bool
+}
+
+/// A layer of an image, top-most first.
+pub(crate) enum ImageLayer {
+    Extracted(PathBuf),
+    /// Pulled lazily, where its files are read from the registry as they are used
+    Lazy(Descriptor)
+}
+
+/// Where a lazily pulled layer is read from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LazyLayerMetadata {
+    registry: String,
+    repository: String,
+    size: u64,
+    toc_offset: u64
+}
+
+/// Content-addressed store where blobs and extracted layers are shared between all images.
+pub struct ImageStore {
+    base_dir: PathBuf
+}
+
+impl ImageStore {
+    pub fn new(base_dir: &Path) -> ImageStore {
+        ImageStore {
+            base_dir: base_dir.to_owned()
+        }
+    }
+
+    pub fn blob_path(&self, digest: &str) -> ContainerRuntimeResult<PathBuf> {
+        Ok(self.base_dir.join("blobs").join("sha256").join(digest_hex(digest)?))
+    }
+
+    pub fn layers_dir(&self) -> PathBuf {
+        self.base_dir.join("layers")
+    }
+
+    pub fn layer_path(&self, digest: &str) -> ContainerRuntimeResult<PathBuf> {
+        Ok(self.layers_dir().join("sha256").join(digest_hex(digest)?))
+    }
+
+    /// The subvolumes of the btrfs storage driver, that the layers of images are flattened into.
+    pub fn subvolumes_dir(&self) -> PathBuf {
+        self.base_dir.join("btrfs")
+    }
+
+    /// Held (shared) while an image is added, as what is stored for it is not referenced by any image until its metadata is saved.
+    /// Many images can be added at the same time, but not while the store is pruned, which holds the lock exclusively.
+    fn lock_path(&self) -> PathBuf {
+        self.base_dir.join("store.lock")
+    }
+
+    /// The digest of the files of the layer, recorded when it is extracted.
+    fn layer_tree_digest_path(&self, digest: &str) -> ContainerRuntimeResult<PathBuf> {
+        Ok(self.layers_dir().join("digests").join(digest_hex(digest)?))
+    }
+
+    /// The table of contents of a lazily pulled layer, along with the chunks of its files read so far.
+    fn lazy_layer_dir(&self, digest: &str) -> ContainerRuntimeResult<PathBuf> {
+        Ok(self.layers_dir().join("lazy").join(digest_hex(digest)?))
+    }
+
+    fn manifests_dir(&self) -> PathBuf {
+        self.base_dir.join("manifests")
+    }
+
+    fn metadata_path(&self, name: &str, tag: &str) -> PathBuf {
+        self.manifests_dir().join(name).join(format!("{}.json", tag))
+    }
+
+    fn temp_dir(&self) -> ContainerRuntimeResult<PathBuf> {
+        let temp_dir = self.base_dir.join("tmp");
+        std::fs::create_dir_all(&temp_dir)?;
+        Ok(temp_dir)
+    }
+
+    pub fn has_blob(&self, digest: &str) -> bool {
+        self.blob_path(digest).map(|path| path.exists()).unwrap_or(false)
+    }
+
+    pub fn read_blob(&self, digest: &str) -> ContainerRuntimeResult<Vec<u8>> {
+        Ok(std::fs::read(self.blob_path(digest)?)?)
+    }
+
+    pub fn write_blob(&self, content: &[u8]) -> ContainerRuntimeResult<String> {
+        let digest = sha256_digest(content);
+        if !self.has_blob(&digest) {
+            let temp_path = self.temp_dir()?.join(digest_hex(&digest)?);
+            std::fs::write(&temp_path, content)?;
+            self.add_blob_file(&temp_path, &digest)?;
+        }
+
+        Ok(digest)
+    }
+
+    /// Moves a file whose content has already been verified against the digest into the store.
+    pub fn add_blob_file(&self, path: &Path, digest: &str) -> ContainerRuntimeResult<()> {
+        let blob_path = self.blob_path(digest)?;
+        std::fs::create_dir_all(blob_path.parent().unwrap())?;
+        std::fs::rename(path, &blob_path)?;
+        Ok(())
+    }
+
+    pub fn verify_blob(&self, digest: &str) -> ContainerRuntimeResult<bool> {
+        let mut writer = DigestWriter::new(std::io::sink());
+        std::io::copy(&mut File::open(self.blob_path(digest)?)?, &mut writer)?;
+        let (_, actual_digest, _) = writer.finish();
+        Ok(actual_digest == digest)
+    }
+
+    pub fn exists(&self, image: &str) -> bool {
+        self.load(image).is_ok()
+    }
+
+    /// Loads the image by tag, or by digest, which keeps referring to the same image when its tag is moved to another.
+    pub fn load(&self, image: &str) -> ContainerRuntimeResult<ImageMetadata> {
+        let reference = LocalImageReference::from_str(image).map_err(ContainerRuntimeError::Input)?;
+        self.migrate_untagged_metadata()?;
+
+        if reference.is_digest() {
+            return self.load_by_digest(&reference);
+        }
+
+        let metadata_path = self.metadata_path(&reference.name, &reference.tag);
+        if !metadata_path.exists() {
+            return Err(ContainerRuntimeError::ImageNotFound(image.to_owned()));
+        }
+
+        Ok(serde_json::from_slice(&std::fs::read(metadata_path)?)?)
+    }
+
+    fn load_by_digest(&self, reference: &LocalImageReference) -> ContainerRuntimeResult<ImageMetadata> {
+        let digest = &reference.tag;
+        let tagged = self.list()?
+            .into_iter()
+            .find(|metadata| {
+                metadata.name == reference.name
+                && (&metadata.manifest.digest == digest || metadata.index.as_ref().map(|index| &index.digest) == Some(digest))
+            });
+
+        if let Some(metadata) = tagged {
+            return Ok(metadata);
+        }
+
+        // The manifest of an image that is no longer tagged is kept until pruned
+        let manifest = self.read_blob(digest).ok().and_then(|content| serde_json::from_slice::<Manifest>(&content).ok());
+        match manifest {
+            Some(manifest) if !manifest.layers.is_empty() => {
+                Ok(
+                    ImageMetadata {
+                        name: reference.name.clone(),
+                        tag: digest.clone(),
+                        reference: None,
+                        manifest: Descriptor {
+                            media_type: manifest.media_type.unwrap_or_else(|| MEDIA_TYPE_DOCKER_MANIFEST.to_owned()),
+                            digest: digest.clone(),
+                            size: std::fs::metadata(self.blob_path(digest)?)?.len(),
+                            platform: None,
+                            annotations: None
+                        },
+                        index: None,
+                        signatures: Vec::new(),
+                        platform: None
+                    }
+                )
+            }
+            _ => Err(ContainerRuntimeError::ImageNotFound(reference.to_string()))
+        }
+    }
+
+    pub fn save(&self, metadata: &ImageMetadata) -> ContainerRuntimeResult<()> {
+        let metadata_path = self.metadata_path(&metadata.name, &metadata.tag);
+        std::fs::create_dir_all(metadata_path.parent().unwrap())?;
+
+        let temp_path = metadata_path.with_extension("json.tmp");
+        std::fs::write(&temp_path, serde_json::to_vec_pretty(metadata)?)?;
+        std::fs::rename(&temp_path, &metadata_path)?;
+        Ok(())
+    }
+
+    /// Points the target tag at the image of the source, replacing the image that had the tag.
+    pub fn tag(&self, source: &str, target: &str) -> ContainerRuntimeResult<()> {
+        let target = LocalImageReference::tagged(target)?;
+        let mut metadata = self.load(source)?;
+        metadata.name = target.name;
+        metadata.tag = target.tag;
+        self.save(&metadata)?;
+
+        info!("Tagged image '{}' as '{}'.", source, metadata.tagged_name());
+        Ok(())
+    }
+
+    pub fn list(&self) -> ContainerRuntimeResult<Vec<ImageMetadata>> {
+        self.migrate_untagged_metadata()?;
+
+        let mut images = Vec::new();
+        let manifests_dir = self.manifests_dir();
+        if manifests_dir.exists() {
+            for entry in std::fs::read_dir(&manifests_dir)? {
+                let image_dir = entry?.path();
+                if !image_dir.is_dir() {
+                    continue;
+                }
+
+                for entry in std::fs::read_dir(&image_dir)? {
+                    let path = entry?.path();
+                    if path.extension().map(|extension| extension == "json").unwrap_or(false) {
+                        images.push(serde_json::from_slice::<ImageMetadata>(&std::fs::read(path)?)?);
+                    }
+                }
+            }
+        }
+
+        images.sort_by(|a, b| (&a.name, &a.tag).cmp(&(&b.name, &b.tag)));
+        Ok(images)
+    }
+
+    /// Moves the metadata of images stored before images had tags (as manifests/<name>.json) to the default tag.
+    fn migrate_untagged_metadata(&self) -> ContainerRuntimeResult<()> {
+        let manifests_dir = self.manifests_dir();
+        if !manifests_dir.exists() {
+            return Ok(());
+        }
+
+        for entry in std::fs::read_dir(&manifests_dir)? {
+            let path = entry?.path();
+            if path.is_file() && path.extension().map(|extension| extension == "json").unwrap_or(false) {
+                let metadata = serde_json::from_slice::<ImageMetadata>(&std::fs::read(&path)?)?;
+                if !self.metadata_path(&metadata.name, &metadata.tag).exists() {
+                    self.save(&metadata)?;
+                }
+
+                std::fs::remove_file(&path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn manifest(&self, metadata: &ImageMetadata) -> ContainerRuntimeResult<Manifest> {
+        Ok(serde_json::from_slice(&self.read_blob(&metadata.manifest.digest)?)?)
+    }
+
+    /// The platform of the image, read from its config for images pulled before the platform was stored.
+    pub fn platform(&self, metadata: &ImageMetadata) -> ContainerRuntimeResult<Platform> {
+        match &metadata.platform {
+            Some(platform) => Ok(platform.clone()),
+            None => Ok(serde_json::from_slice(&self.read_blob(&self.manifest(metadata)?.config.digest)?)?)
+        }
+    }
+
+    /// The config of the image, as stored when it was pulled or imported.
+    pub fn config(&self, metadata: &ImageMetadata) -> ContainerRuntimeResult<serde_json::Value> {
+        Ok(serde_json::from_slice(&self.read_blob(&self.manifest(metadata)?.config.digest)?)?)
+    }
+
+    /// The history of the image, most recent step first.
+    pub fn history(&self, metadata: &ImageMetadata) -> ContainerRuntimeResult<Vec<HistoryEntry>> {
+        let manifest = self.manifest(metadata)?;
+        let config: ConfigHistory = serde_json::from_slice(&self.read_blob(&manifest.config.digest)?)?;
+        Ok(history_entries(config.history, &manifest.layers))
+    }
+
+    /// Returns the extracted layers of the image, top-most layer first.
+    pub fn layer_dirs(&self, image: &str) -> ContainerRuntimeResult<Vec<PathBuf>> {
+        self.layers(image)?
+            .into_iter()
+            .map(|layer| {
+                match layer {
+                    ImageLayer::Extracted(layer_dir) => Ok(layer_dir),
+                    ImageLayer::Lazy(layer) => Err(ContainerRuntimeError::Input(format!("Layer {} of image '{}' was pulled lazily and has not been extracted", layer.digest, image)))
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the layers of the image, top-most layer first, where the layers that were pulled lazily are not extracted.
+    pub(crate) fn layers(&self, image: &str) -> ContainerRuntimeResult<Vec<ImageLayer>> {
+        Ok(self.layers_with_digests(image)?.into_iter().map(|(_, layer)| layer).collect())
+    }
+
+    /// Like `layers`, along with the digest of each layer.
+    pub(crate) fn layers_with_digests(&self, image: &str) -> ContainerRuntimeResult<Vec<(String, ImageLayer)>> {
+        self.import_archive(image)?;
+        let manifest = self.manifest(&self.load(image)?)?;
+
+        let mut layers = Vec::new();
+        for layer in &manifest.layers {
+            if self.is_lazy(layer)? {
+                layers.push((layer.digest.clone(), ImageLayer::Lazy(layer.clone())));
+            } else {
+                layers.push((layer.digest.clone(), ImageLayer::Extracted(self.extract_layer(layer, &NoProgress)?)));
+            }
+        }
+
+        layers.reverse();
+        Ok(layers)
+    }
+
+    /// The directory of the layer with the digest, if it has been extracted (rather than pulled lazily or removed).
+    pub fn extracted_layer(&self, digest: &str) -> ContainerRuntimeResult<Option<PathBuf>> {
+        let layer_dir = self.layer_path(digest)?;
+        Ok(if layer_dir.exists() { Some(layer_dir) } else { None })
+    }
+
+    /// Imports the archive placed directly in the image directory (as `<name>.tar`) the first time the image is used.
+    pub fn import_archive(&self, image: &str) -> ContainerRuntimeResult<()> {
+        let image_archive = self.base_dir.join(format!("{}.tar", image));
+        if !self.exists(image) && image_archive.exists() {
+            import_image(self, image, File::open(&image_archive)?, &NoProgress)?;
+        }
+
+        Ok(())
+    }
+
+    /// If the layer was pulled lazily, and has not been downloaded since.
+    fn is_lazy(&self, layer: &Descriptor) -> ContainerRuntimeResult<bool> {
+        Ok(!self.layer_path(&layer.digest)?.exists() && !self.has_blob(&layer.digest) && self.lazy_layer_dir(&layer.digest)?.exists())
+    }
+
+    /// Opens a lazily pulled layer, whose files are read from the registry it was pulled from.
+    pub(crate) fn open_lazy_layer(&self, layer: &Descriptor, registries: &HashMap<String, RegistryConfig>) -> ContainerRuntimeResult<LazyLayer> {
+        let lazy_layer_dir = self.lazy_layer_dir(&layer.digest)?;
+        let metadata: LazyLayerMetadata = serde_json::from_slice(&std::fs::read(lazy_layer_dir.join("layer.json"))?)?;
+        let toc = TableOfContents {
+            offset: metadata.toc_offset,
+            content: std::fs::read(lazy_layer_dir.join("toc.json"))?
+        };
+
+        // The chunks are only as trustworthy as the table of contents they are checked against
+        if Some(&sha256_digest(&toc.content)) != toc_digest(layer) {
+            return Err(ContainerRuntimeError::RootfsIntegrity(format!("The table of contents of layer {} has been modified", layer.digest)));
+        }
+
+        let source = RegistryBlobSource::new(
+            RegistryClient::new(&metadata.registry, &metadata.repository, false, registries)?,
+            &metadata.repository,
+            &layer.digest,
+            metadata.size
+        );
+        LazyLayer::new(Box::new(source), &toc, lazy_layer_dir.join("chunks"))
+    }
+
+    /// Pulls only the table of contents of the layer, from which its files can be read as they are used.
+    /// The layer is later read from the same registry, which might be a mirror.
+    fn pull_lazy_layer(&self, client: &RegistryClient, repository: &str, layer: &Descriptor, toc_digest: &str) -> ContainerRuntimeResult<()> {
+        let lazy_layer_dir = self.lazy_layer_dir(&layer.digest)?;
+        if lazy_layer_dir.exists() {
+            return Ok(());
+        }
+
+        let source = RegistryBlobSource::new(client.clone(), repository, &layer.digest, layer.size);
+        let toc = TableOfContents::read(&source, toc_digest)?;
+
+        let pull_dir = lazy_layer_dir.with_file_name(format!(".{}.tmp", digest_hex(&layer.digest)?));
+        if pull_dir.exists() {
+            std::fs::remove_dir_all(&pull_dir)?;
+        }
+        std::fs::create_dir_all(&pull_dir)?;
+
+        let metadata = LazyLayerMetadata {
+            registry: client.registry().to_owned(),
+            repository: repository.to_owned(),
+            size: layer.size,
+            toc_offset: toc.offset
+        };
+        std::fs::write(pull_dir.join("layer.json"), serde_json::to_vec_pretty(&metadata)?)?;
+        std::fs::write(pull_dir.join("toc.json"), &toc.content)?;
+        std::fs::rename(&pull_dir, &lazy_layer_dir)?;
+
+        trace!("Pulled table of contents of layer {} ({} bytes)", layer.digest, toc.content.len());
+        Ok(())
+    }
+
+    fn extract_layer(&self, layer: &Descriptor, progress: &dyn ProgressReporter) -> ContainerRuntimeResult<PathBuf> {
+        let layer_dir = self.layer_path(&layer.digest)?;
+        if layer_dir.exists() {
+            progress.report(ProgressEvent::Skipped { id: layer.digest.clone(), stage: ProgressStage::Extract });
+            return Ok(layer_dir);
+        }
+
+        let extract_dir = layer_dir.with_file_name(format!(".{}.tmp", digest_hex(&layer.digest)?));
+        if extract_dir.exists() {
+            std::fs::remove_dir_all(&extract_dir)?;
+        }
+        std::fs::create_dir_all(&extract_dir)?;
+
+        let mut rollback = Rollback::new();
+        let extract_dir_clone = extract_dir.clone();
+        rollback.add("remove partially extracted layer", move || Ok(std::fs::remove_dir_all(extract_dir_clone)?));
+
+        let blob = File::open(self.blob_path(&layer.digest)?)?;
+        let size = blob.metadata()?.len();
+        extract_layer_archive(&extract_dir, &layer.media_type, ProgressReader::new(blob, progress, &layer.digest, ProgressStage::Extract, Some(size)))?;
+
+        let tree_digest_path = self.layer_tree_digest_path(&layer.digest)?;
+        std::fs::create_dir_all(tree_digest_path.parent().unwrap())?;
+        std::fs::write(&tree_digest_path, layer_tree_digest(&extract_dir)?)?;
+        std::fs::rename(&extract_dir, &layer_dir)?;
+        rollback.commit();
+        progress.report(ProgressEvent::Finished { id: layer.digest.clone(), stage: ProgressStage::Extract });
+
+        trace!("Extracted layer {} at {}", layer.digest, layer_dir.to_str().unwrap());
+        Ok(layer_dir)
+    }
+
+    /// Removes blobs, extracted layers and image subvolumes that are neither referenced by any image nor used by any of the containers,
+    /// given as the layer digests (top-most first) that each container was created from.
+    pub fn prune(&self, container_layers: &[Vec<String>]) -> ContainerRuntimeResult<Vec<String>> {
+        let _lock = lock_file(&self.lock_path())?;
+
+        let mut referenced = HashSet::new();
+        let mut layer_sets = Vec::new();
+        for metadata in self.list()? {
+            let manifest = self.manifest(&metadata)?;
+            referenced.insert(digest_hex(&metadata.manifest.digest)?.to_owned());
+            if let Some(index) = &metadata.index {
+                referenced.insert(digest_hex(&index.digest)?.to_owned());
+            }
+            referenced.insert(digest_hex(&manifest.config.digest)?.to_owned());
+            for layer in &manifest.layers {
+                referenced.insert(digest_hex(&layer.digest)?.to_owned());
+            }
+            layer_sets.push(manifest.layers.iter().rev().map(|layer| self.layer_path(&layer.digest)).collect::<ContainerRuntimeResult<Vec<_>>>()?);
+        }
+
+        // The image of a container might have been removed, or pulled again with other layers, while the container uses them
+        for layers in container_layers {
+            for digest in layers {
+                referenced.insert(digest_hex(digest)?.to_owned());
+            }
+            layer_sets.push(layers.iter().map(|digest| self.layer_path(digest)).collect::<ContainerRuntimeResult<Vec<_>>>()?);
+        }
+
+        let mut removed = Vec::new();
+        let dirs = [
+            ("blob", self.base_dir.join("blobs").join("sha256")),
+            ("layer", self.layers_dir().join("sha256")),
+            ("layer digest", self.layers_dir().join("digests")),
+            ("lazy layer", self.layers_dir().join("lazy"))
+        ];
+
+        for (kind, dir) in dirs {
+            if !dir.exists() {
+                continue;
+            }
+
+            for entry in std::fs::read_dir(&dir)? {
+                let path = entry?.path();
+                // What is being extracted for a referenced layer (such as when a container is run) is kept as well
+                let name = path.file_name().unwrap().to_str().unwrap();
+                let digest = name.trim_start_matches('.').trim_end_matches(".tmp");
+                if !referenced.contains(digest) {
+                    remove_path(&path)?;
+                    removed.push(format!("{} sha256:{}", kind, digest));
+                }
+            }
+        }
+
+        removed.extend(BtrfsStorageDriver::new(self.subvolumes_dir(), self.layers_dir()).prune_image_subvolumes(&layer_sets)?);
+        Ok(removed)
+    }
+
+    /// Checks that the image has a valid signature for its manifest and that none of its blobs have been modified.
+    pub fn verify_image(&self, image: &str, verifier: &SignatureVerifier) -> ContainerRuntimeResult<()> {
+        let metadata = self.load(image)?;
+        if metadata.signatures.is_empty() {
+            return Err(ContainerRuntimeError::Signature(format!("Image '{}' is not signed", image)));
+        }
+
+        let mut digests = vec![metadata.manifest.digest.clone()];
+        if let Some(index) = &metadata.index {
+            let manifest_list: ManifestList = serde_json::from_slice(&self.read_blob(&index.digest)?)?;
+            if manifest_list.manifests.iter().any(|descriptor| descriptor.digest == metadata.manifest.digest) {
+                digests.push(index.digest.clone());
+            }
+        }
+
+        verifier.verify_any(&metadata.signatures, &digests)?;
+
+        let manifest = self.manifest(&metadata)?;
+        let mut blobs = vec![&metadata.manifest, &manifest.config];
+        blobs.extend(metadata.index.iter());
+        blobs.extend(manifest.layers.iter());
+        for blob in blobs {
+            // The files of lazily pulled layers are checked as they are read, against the table of contents the manifest has the digest of
+            if self.is_lazy(blob)? {
+                continue;
+            }
+
+            if !self.verify_blob(&blob.digest)? {
+                return Err(ContainerRuntimeError::Signature(format!("Blob {} of image '{}' has been modified", blob.digest, image)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the extracted layers of the image have not been changed on disk since they were extracted from its blobs.
+    /// The files of lazily pulled layers are instead checked as they are read.
+    /// Layers extracted before their digest was recorded are extracted again, once their blob has been verified.
+    pub fn verify_layers(&self, image: &str) -> ContainerRuntimeResult<()> {
+        let manifest = self.manifest(&self.load(image)?)?;
+        for layer in &manifest.layers {
+            if self.is_lazy(layer)? {
+                continue;
+            }
+
+            let layer_dir = self.layer_path(&layer.digest)?;
+            let recorded_digest = match std::fs::read_to_string(self.layer_tree_digest_path(&layer.digest)?) {
+                Ok(recorded_digest) => recorded_digest,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                    if !self.verify_blob(&layer.digest)? {
+                        return Err(ContainerRuntimeError::RootfsIntegrity(format!("Blob {} of image '{}' has been modified", layer.digest, image)));
+                    }
+
+                    remove_path(&layer_dir)?;
+                    self.extract_layer(layer, &NoProgress)?;
+                    continue;
+                }
+                Err(err) => return Err(err.into())
+            };
+
+            if layer_tree_digest(&layer_dir)? != recorded_digest.trim() {
+                return Err(
+                    ContainerRuntimeError::RootfsIntegrity(
+                        format!("The files of layer {} of image '{}' have been modified since it was extracted", layer.digest, image)
+                    )
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_manifest(&self, media_type: &str, content: &[u8]) -> ContainerRuntimeResult<Descriptor> {
+        Ok(
+            Descriptor {
+                media_type: media_type.to_owned(),
+                digest: self.write_blob(content)?,
+                size: content.len() as u64,
+                platform: None,
+                annotations: None
+            }
+        )
+    }
+}
+
+struct ResolvedManifest {
+    media_type: String,
+    content: Vec<u8>,
+    index: Option<(String, Vec<u8>)>
+}
+
+pub fn import_image<R: Read>(store: &ImageStore, image: &str, reader: R, progress: &dyn ProgressReporter) -> ContainerRuntimeResult<()> {
+    let mut reader = ProgressReader::new(reader, progress, image, ProgressStage::Import, None);
+    let mut inner = || -> ContainerRuntimeResult<()> {
+        let _lock = lock_file_shared(&store.lock_path())?;
+        let local_reference = LocalImageReference::tagged(image)?;
+
+        let temporary_layer = store.temp_dir()?.join(format!("{}.import", image));
+        let _remove_layer = RemoveFileGuard::new(temporary_layer.clone());
+
+        // The archive is stored as a single compressed layer
+        let layer_file = DigestWriter::new(File::create(&temporary_layer)?);
+        let mut encoder = DigestWriter::new(GzEncoder::new(layer_file, Compression::default()));
+        std::io::copy(&mut decompress_archive(&mut reader)?, &mut encoder)?;
+        let (encoder, diff_id, _) = encoder.finish();
+        let (_, digest, size) = encoder.finish()?.finish();
+        progress.report(ProgressEvent::Finished { id: image.to_owned(), stage: ProgressStage::Import });
+
+        let layer = Descriptor {
+            media_type: MEDIA_TYPE_DOCKER_LAYER_GZIP.to_owned(),
+            digest,
+            size,
+            platform: None,
+            annotations: None
+        };
+
+        if !store.has_blob(&layer.digest) {
+            store.add_blob_file(&temporary_layer, &layer.digest)?;
+        }
+
+        // Extracting validates that the archive is readable before replacing any existing image
+        store.extract_layer(&layer, progress)?;
+
+        let config = serde_json::json!({
+            "architecture": host_architecture(),
+            "os": "linux",
+            "config": {},
+            "rootfs": {
+                "type": "layers",
+                "diff_ids": [diff_id]
+            },
+            "history": [
+                { "created_by": format!("cort import {}", image) }
+            ]
+        });
+        let config = serde_json::to_vec(&config)?;
+
+        let manifest = Manifest {
+            schema_version: 2,
+            media_type: Some(MEDIA_TYPE_DOCKER_MANIFEST.to_owned()),
+            config: Descriptor {
+                media_type: MEDIA_TYPE_DOCKER_CONFIG.to_owned(),
+                digest: store.write_blob(&config)?,
+                size: config.len() as u64,
+                platform: None,
+                annotations: None
+            },
+            layers: vec![layer]
+        };
+
+        store.save(&ImageMetadata {
+            name: local_reference.name,
+            tag: local_reference.tag,
+            reference: None,
+            manifest: store.write_manifest(MEDIA_TYPE_DOCKER_MANIFEST, &serde_json::to_vec(&manifest)?)?,
+            index: None,
+            signatures: Vec::new(),
+            platform: Some(Platform::host())
+        })?;
+
+        info!("Imported image '{}' (layer {}).", image, manifest.layers[0].digest);
+        Ok(())
+    };
+
+    inner().map_err(|err| ContainerRuntimeError::Import(err.to_string()))
+}
+
+/// Pairs the steps of the history that created layers with the layers in order, where layers beyond the history (of images built
+/// without one) are listed without a step.
+fn history_entries(config_history: Vec<ConfigHistoryEntry>, layers: &[Descriptor]) -> Vec<HistoryEntry> {
+    let mut layers = layers.iter();
+    let mut entries = config_history
+        .into_iter()
+        .map(|entry| {
+            HistoryEntry {
+                layer: if entry.empty_layer { None } else { layers.next().cloned() },
+                created: entry.created,
+                created_by: entry.created_by,
+                comment: entry.comment
+            }
+        })
+        .collect::<Vec<_>>();
+
+    entries.extend(layers.map(|layer| HistoryEntry { created: None, created_by: None, comment: None, layer: Some(layer.clone()) }));
+    entries.reverse();
+    entries
+}
+
+/// Decompresses the archive if it is compressed with gzip or zstd, as detected from its first bytes.
+fn decompress_archive<'a, R: Read + 'a>(reader: R) -> ContainerRuntimeResult<Box<dyn Read + 'a>> {
+    let mut reader = BufReader::new(reader);
+    let magic = reader.fill_buf()?;
+    if magic.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(MultiGzDecoder::new(reader)))
+    } else if magic.starts_with(&ZSTD_MAGIC) {
+        Ok(Box::new(zstd::Decoder::with_buffer(reader)?))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+pub struct PullOptions {
+    /// Only pulls the table of contents of eStargz layers
+    pub lazy: bool,
+    pub max_concurrent_downloads: usize,
+    /// Does not pull the image again when the local image already has the same manifest
+    pub skip_unchanged: bool,
+    /// The settings of the registries, such as their mirrors
+    pub registries: HashMap<String, RegistryConfig>,
+    /// The platform to pull from manifest lists, where images that only have another platform are refused
+    pub platform: Option<Platform>
+}
+
+/// Pulls the image, where (if lazy) only the table of contents is pulled of the eStargz layers, whose files are instead read as they are used.
+/// The other layers are downloaded concurrently, up to the given number at once.
+pub fn pull_image(store: &ImageStore, reference: &ImageReference, image: &str, verifier: Option<&SignatureVerifier>, options: &PullOptions, progress: &dyn ProgressReporter) -> ContainerRuntimeResult<()> {
+    let inner = || -> ContainerRuntimeResult<()> {
+        let _lock = lock_file_shared(&store.lock_path())?;
+        let start_time = Instant::now();
+        let local_reference = LocalImageReference::tagged(image)?;
+
+        let wanted_platform = options.platform.clone().unwrap_or_else(Platform::host);
+        let (mut client, resolved) = resolve_from_mirrors(reference, &options.registries, &wanted_platform)?;
+        let manifest: Manifest = serde_json::from_slice(&resolved.content)?;
+
+        if options.skip_unchanged {
+            // Signatures are only pulled again when they are to be verified and the local image has none
+            let local = store.load(image).ok();
+            let is_unchanged = local.as_ref().map(|local| {
+                local.manifest.digest == sha256_digest(&resolved.content) && (verifier.is_none() || !local.signatures.is_empty())
+            });
+
+            if is_unchanged == Some(true) {
+                info!("Image '{}' is up to date with {}.", image, reference);
+                return Ok(());
+            }
+        }
+
+        // Signatures may refer to either the platform manifest or the index it was resolved from
+        let mut signatures = Vec::new();
+        if let Some(verifier) = verifier {
+            let mut digests = vec![sha256_digest(&resolved.content)];
+            if let Some((_, index_content)) = &resolved.index {
+                digests.push(sha256_digest(index_content));
+            }
+
+            let mut found = Vec::new();
+            for digest in &digests {
+                found.extend(fetch_signatures(&mut client, &reference.repository, digest)?);
+            }
+
+            signatures = verifier.verify_any(&found, &digests)?;
+            info!("Verified signature of {}.", reference);
+        }
+
+        // Images of a single platform only give it in their config, which is pulled before the layers to check it
+        let config = client.get_blob(&reference.repository, &manifest.config.digest)?;
+        if store.write_blob(&config)? != manifest.config.digest {
+            return Err(ContainerRuntimeError::Registry(format!("Digest mismatch for config {}", manifest.config.digest)));
+        }
+
+        let platform: Platform = serde_json::from_slice(&config)?;
+        if !wanted_platform.matches(&platform) {
+            if options.platform.is_some() {
+                return Err(ContainerRuntimeError::Platform(format!("{} is for platform {}, not {}", reference, platform, wanted_platform)));
+            }
+
+            warn!("{} is for platform {}, which only runs on this host with emulation.", reference, platform);
+        }
+
+        let mut lazy_layers = HashSet::new();
+        if options.lazy {
+            for layer in &manifest.layers {
+                if store.layer_path(&layer.digest)?.exists() || store.has_blob(&layer.digest) {
+                    continue;
+                }
+
+                if let Some(toc_digest) = toc_digest(layer) {
+                    info!("Pulling table of contents of layer {}.", layer.digest);
+                    store.pull_lazy_layer(&client, &reference.repository, layer, toc_digest)?;
+                    progress.report(ProgressEvent::Skipped { id: layer.digest.clone(), stage: ProgressStage::Download });
+                    progress.report(ProgressEvent::Skipped { id: layer.digest.clone(), stage: ProgressStage::Extract });
+                    lazy_layers.insert(&layer.digest);
+                }
+            }
+        }
+
+        // The same layer can occur more than once in an image, but must only be pulled once
+        let mut blobs = vec![BlobPull { blob: &manifest.config, is_layer: false }];
+        let mut pulled_layers = HashSet::new();
+        for layer in &manifest.layers {
+            if !lazy_layers.contains(&layer.digest) && pulled_layers.insert(&layer.digest) {
+                blobs.push(BlobPull { blob: layer, is_layer: true });
+            }
+        }
+
+        let downloaded = pull_blobs(store, &client, &reference.repository, blobs, options.max_concurrent_downloads, progress)?;
+
+        let index = match &resolved.index {
+            Some((media_type, content)) => Some(store.write_manifest(media_type, content)?),
+            None => None
+        };
+
+        store.save(&ImageMetadata {
+            name: local_reference.name,
+            tag: local_reference.tag,
+            reference: Some(reference.to_string()),
+            manifest: store.write_manifest(&resolved.media_type, &resolved.content)?,
+            index,
+            signatures,
+            platform: Some(platform)
+        })?;
+
+        let elapsed = start_time.elapsed().as_secs_f64();
+        info!(
+            "Pulled image {} as '{}' ({} bytes downloaded in {:.2} seconds, {:.1} MB/s).",
+            reference, image, downloaded, elapsed, downloaded as f64 / elapsed.max(f64::EPSILON) / 1_000_000.0
+        );
+        Ok(())
+    };
+
+    inner().map_err(|err| ContainerRuntimeError::Pull(err.to_string()))
+}
+
+/// Resolves the manifest from the first mirror of the registry of the image that has it, or else from the registry itself.
+/// Returns the client of the registry that it was resolved from, which the blobs are then pulled from as well.
+fn resolve_from_mirrors(reference: &ImageReference, registries: &HashMap<String, RegistryConfig>, platform: &Platform) -> ContainerRuntimeResult<(RegistryClient, ResolvedManifest)> {
+    let mirrors = find_registry_config(registries, &reference.registry).map(|config| config.mirrors.as_slice()).unwrap_or_default();
+    for mirror in mirrors {
+        let result = RegistryClient::new(mirror, &reference.repository, false, registries)
+            .and_then(|mut client| Ok((resolve_manifest(&mut client, reference, platform)?, client)));
+
+        match result {
+            Ok((resolved, client)) => {
+                info!("Pulling {} from mirror {}.", reference, mirror);
+                return Ok((client, resolved));
+            }
+            Err(err) => {
+                warn!("Failed to pull {} from mirror {}: {}", reference, mirror, err);
+            }
+        }
+    }
+
+    let mut client = RegistryClient::new(&reference.registry, &reference.repository, false, registries)?;
+    let resolved = resolve_manifest(&mut client, reference, platform)?;
+    Ok((client, resolved))
+}
+
+/// A blob of an image to pull, where layers are also extracted.
+struct BlobPull<'a> {
+    blob: &'a Descriptor,
+    is_layer: bool
+}
+
+/// Pulls the blobs, at most the given number at once, where each layer is extracted as soon as it has been downloaded.
+/// Blobs that are already stored (and match their digest) are not downloaded again. Returns the number of bytes downloaded.
+fn pull_blobs(store: &ImageStore, client: &RegistryClient, repository: &str, blobs: Vec<BlobPull>, max_concurrent_downloads: usize, progress: &dyn ProgressReporter) -> ContainerRuntimeResult<u64> {
+    let workers = max_concurrent_downloads.clamp(1, blobs.len().max(1));
+    let queue = &Mutex::new(VecDeque::from(blobs));
+    let failed = &AtomicBool::new(false);
+    let downloaded = &AtomicU64::new(0);
+    let (sender, receiver) = mpsc::channel();
+
+    std::thread::scope(|scope| {
+        let handles = (0..workers)
+            .map(|_| {
+                let sender = sender.clone();
+                let mut client = client.clone();
+                scope.spawn(move || -> ContainerRuntimeResult<()> {
+                    // The remaining blobs are left once one has failed, as the pull fails either way
+                    while !failed.load(Ordering::SeqCst) {
+                        let Some(blob_pull) = queue.lock().unwrap().pop_front() else {
+                            break;
+                        };
+
+                        match pull_blob(store, &mut client, repository, &blob_pull, &sender) {
+                            Ok(size) => {
+                                downloaded.fetch_add(size, Ordering::SeqCst);
+                            }
+                            Err(err) => {
+                                failed.store(true, Ordering::SeqCst);
+                                return Err(err);
+                            }
+                        }
+                    }
+
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(sender);
+
+        // The events are reported from this thread, as the reporter is not required to be shared between threads
+        for event in receiver {
+            progress.report(event);
+        }
+
+        for handle in handles {
+            handle.join().map_err(|_| ContainerRuntimeError::Pull("Pull thread panicked".to_owned()))??;
+        }
+
+        Ok(downloaded.load(Ordering::SeqCst))
+    })
+}
+
+fn pull_blob(store: &ImageStore, client: &mut RegistryClient, repository: &str, blob_pull: &BlobPull, progress: &dyn ProgressReporter) -> ContainerRuntimeResult<u64> {
+    let blob = blob_pull.blob;
+    let mut downloaded = 0;
+    if store.has_blob(&blob.digest) && store.verify_blob(&blob.digest)? {
+        trace!("Blob {} already exists", blob.digest);
+        progress.report(ProgressEvent::Skipped { id: blob.digest.clone(), stage: ProgressStage::Download });
+    } else {
+        info!("Pulling blob {} ({} bytes).", blob.digest, blob.size);
+        let download_path = store.temp_dir()?.join(digest_hex(&blob.digest)?);
+        let _remove_download = RemoveFileGuard::new(download_path.clone());
+        client.download_blob(repository, &blob.digest, &download_path, progress)?;
+        store.add_blob_file(&download_path, &blob.digest)?;
+        downloaded = blob.size;
+    }
+
+    if blob_pull.is_layer {
+        store.extract_layer(blob, progress)?;
+    }
+
+    Ok(downloaded)
+}
+
+/// Pushes the image to the registry itself, never to its mirrors.
+pub fn push_image(store: &ImageStore, image: &str, reference: &ImageReference, registries: &HashMap<String, RegistryConfig>, progress: &dyn ProgressReporter) -> ContainerRuntimeResult<()> {
+    let inner = || -> ContainerRuntimeResult<()> {
+        if reference.is_digest() {
+            return Err(ContainerRuntimeError::Input(format!("Cannot push to {}, as the digest is given by the manifest", reference)));
+        }
+
+        let metadata = store.load(image)?;
+        let manifest = store.manifest(&metadata)?;
+
+        let mut client = RegistryClient::new(&reference.registry, &reference.repository, true, registries)?;
+
+        for layer in &manifest.layers {
+            if store.is_lazy(layer)? {
+                return Err(ContainerRuntimeError::Input(format!("Layer {} was pulled lazily, pull the image without --lazy to push it", layer.digest)));
+            }
+        }
+
+        for layer in &manifest.layers {
+            info!("Pushing layer {} ({} bytes).", layer.digest, layer.size);
+            client.upload_blob(&reference.repository, &layer.digest, &store.blob_path(&layer.digest)?, progress)?;
+        }
+
+        client.upload_blob(&reference.repository, &manifest.config.digest, &store.blob_path(&manifest.config.digest)?, progress)?;
+        client.put_manifest(
+            &reference.repository,
+            &reference.tag,
+            &metadata.manifest.media_type,
+            &store.read_blob(&metadata.manifest.digest)?
+        )?;
+
+        info!("Pushed image '{}' to {}.", image, reference);
+        Ok(())
+    };
+
+    inner().map_err(|err| ContainerRuntimeError::Push(err.to_string()))
+}
+
+/// Resolves the manifest of the platform from manifest lists, where the first that matches is used.
+fn resolve_manifest(client: &mut RegistryClient, reference: &ImageReference, platform: &Platform) -> ContainerRuntimeResult<ResolvedManifest> {
+    let (content_type, content) = client.get_manifest(&reference.repository, &reference.tag)?;
+    if reference.is_digest() && sha256_digest(&content) != reference.tag {
+        return Err(ContainerRuntimeError::Registry(format!("Digest mismatch for manifest {}", reference.tag)));
+    }
+
+    if content_type.starts_with(MEDIA_TYPE_DOCKER_MANIFEST_LIST) || content_type.starts_with(MEDIA_TYPE_OCI_INDEX) {
+        let manifest_list: ManifestList = serde_json::from_slice(&content)?;
+        let descriptor = manifest_list.manifests
+            .iter()
+            .find(|descriptor| descriptor.platform.as_ref().is_some_and(|descriptor_platform| platform.matches(descriptor_platform)))
+            .ok_or_else(|| {
+                let available = manifest_list.manifests
+                    .iter()
+                    .filter_map(|descriptor| descriptor.platform.as_ref().map(|platform| platform.to_string()))
+                    .collect::<Vec<_>>();
+                ContainerRuntimeError::Platform(format!("No image found for platform {} (available: {})", platform, available.join(", ")))
+            })?;
+
+        let (_, manifest_content) = client.get_manifest(&reference.repository, &descriptor.digest)?;
+        if sha256_digest(&manifest_content) != descriptor.digest {
+            return Err(ContainerRuntimeError::Registry(format!("Digest mismatch for manifest {}", descriptor.digest)));
+        }
+
+        Ok(
+            ResolvedManifest {
+                media_type: descriptor.media_type.clone(),
+                content: manifest_content,
+                index: Some((content_type, content))
+            }
+        )
+    } else {
+        Ok(
+            ResolvedManifest {
+                media_type: content_type,
+                content,
+                index: None
+            }
+        )
+    }
+}
+
+/// The digest of the table of contents of an eStargz layer, which is what allows pulling it lazily.
+fn toc_digest(layer: &Descriptor) -> Option<&String> {
+    layer.annotations.as_ref().and_then(|annotations| annotations.get(TOC_DIGEST_ANNOTATION))
+}
+
+/// Extracts a layer in the overlay format, where whiteouts are 0/0 character devices and opaque directories are marked with an xattr.
+fn extract_layer_archive<R: Read>(layer_dir: &Path, media_type: &str, layer: R) -> ContainerRuntimeResult<()> {
+    let layer: Box<dyn Read> = if media_type.ends_with("gzip") {
+        // Layers such as eStargz (and those compressed by pigz) consist of more than one gzip member
+        Box::new(MultiGzDecoder::new(layer))
+    } else if media_type.ends_with("zstd") {
+        Box::new(zstd::Decoder::new(layer)?)
+    } else if media_type.ends_with(".tar") {
+        Box::new(layer)
+    } else {
+        return Err(ContainerRuntimeError::Input(format!("Unsupported layer type: {}", media_type)));
+    };
+
+    let mut archive = tar::Archive::new(layer);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = normalize_layer_path(&entry.path()?)?;
+        let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or("").to_owned();
+        let parent = layer_dir.join(path.parent().unwrap_or(Path::new("")));
+
+        if file_name == ".wh..wh..opq" {
+            std::fs::create_dir_all(&parent)?;
+            set_xattr(&parent, OVERLAY_OPAQUE_XATTR, b"y")?;
+        } else if let Some(name) = file_name.strip_prefix(".wh.") {
+            std::fs::create_dir_all(&parent)?;
+            let whiteout = parent.join(name);
+            remove_path(&whiteout)?;
+            mknod(&whiteout, libc::S_IFCHR, 0)?;
+        } else {
+            entry.set_unpack_xattrs(true);
+            entry.set_preserve_permissions(true);
+            entry.unpack_in(layer_dir)?;
+
+            if let (Ok(uid), Ok(gid)) = (entry.header().uid(), entry.header().gid()) {
+                let full_path = layer_dir.join(&path);
+                if full_path.symlink_metadata().is_ok() {
+                    lchown(&full_path, uid, gid)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The digest of the paths, owners, modes and contents of the files in the directory (and of the overlay markers of the layer),
+/// where the modification times are left out as they do not change what a container sees.
+fn layer_tree_digest(layer_dir: &Path) -> ContainerRuntimeResult<String> {
+    let mut writer = DigestWriter::new(std::io::sink());
+    write_tree_entries(layer_dir, Path::new(""), &mut writer)?;
+    let (_, digest, _) = writer.finish();
+    Ok(digest)
+}
+
+/// Entries are written sorted by name, as the order of a directory listing depends on the file system.
+fn write_tree_entries<W: Write>(dir: &Path, relative_dir: &Path, writer: &mut W) -> ContainerRuntimeResult<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let relative_path = relative_dir.join(entry.file_name());
+        let metadata = path.symlink_metadata()?;
+
+        writer.write_all(relative_path.as_os_str().as_bytes())?;
+        write!(writer, "\0{:o} {}:{}\0", metadata.mode(), metadata.uid(), metadata.gid())?;
+
+        let file_type = metadata.file_type();
+        if file_type.is_symlink() {
+            writer.write_all(std::fs::read_link(&path)?.as_os_str().as_bytes())?;
+        } else if file_type.is_char_device() || file_type.is_block_device() {
+            write!(writer, "{}", metadata.rdev())?;
+        } else if file_type.is_file() {
+            write!(writer, "{}\0", metadata.len())?;
+            std::io::copy(&mut File::open(&path)?, writer)?;
+        } else if file_type.is_dir() {
+            if let Some(opaque) = get_xattr(&path, OVERLAY_OPAQUE_XATTR)? {
+                writer.write_all(&opaque)?;
+            }
+            writer.write_all(b"\0")?;
+            write_tree_entries(&path, &relative_path, writer)?;
+        }
+        writer.write_all(b"\0")?;
+    }
+
+    Ok(())
+}
+
+fn normalize_layer_path(path: &Path) -> ContainerRuntimeResult<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir | Component::RootDir => {}
+            _ => return Err(ContainerRuntimeError::Input(format!("Invalid path in layer: {}", path.to_str().unwrap())))
+        }
+    }
+
+    Ok(normalized)
+}
+
+fn digest_hex(digest: &str) -> ContainerRuntimeResult<&str> {
+    match digest.split_once(':') {
+        Some((_, hex)) if is_sha256_digest(digest) => Ok(hex),
+        _ => Err(ContainerRuntimeError::Input(format!("Invalid digest '{}'", digest)))
+    }
+}
+
+pub fn remove_path(path: &Path) -> ContainerRuntimeResult<()> {
+    match path.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(path)?,
+        Ok(_) => std::fs::remove_file(path)?,
+        Err(_) => {}
+    }
+
+    Ok(())
+}
+
+/// Checks that a directory used directly as the root of a container exists, warning if it doesn't look like a Linux root.
+pub fn validate_rootfs(rootfs: &Path) -> ContainerRuntimeResult<PathBuf> {
+    let rootfs = rootfs
+        .canonicalize()
+        .map_err(|err| ContainerRuntimeError::Input(format!("Invalid rootfs {}: {}", rootfs.to_str().unwrap(), err)))?;
+
+    if !rootfs.is_dir() {
+        return Err(ContainerRuntimeError::Input(format!("Rootfs {} is not a directory", rootfs.to_str().unwrap())));
+    }
+
+    if !["etc", "bin", "usr"].iter().any(|dir| rootfs.join(dir).is_dir()) {
+        warn!("Rootfs {} has none of /etc, /bin or /usr, and might not be a Linux root filesystem.", rootfs.to_str().unwrap());
+    }
+
+    Ok(rootfs)
+}
+
+/// Finds the file backing a path in the merged root filesystem of the layers (top-most first), following symlinks within the layers.
+pub fn find_in_layers(layers: &[PathBuf], path: &Path) -> ContainerRuntimeResult<Option<PathBuf>> {
+    const MAX_SYMLINKS: usize = 40;
+
+    let mut remaining = path.components().map(|component| component.as_os_str().to_owned()).collect::<VecDeque<_>>();
+    let mut resolved = PathBuf::from("/");
+    let mut symlinks = 0;
+
+    while let Some(component) = remaining.pop_front() {
+        match Path::new(&component).components().next() {
+            Some(Component::Normal(_)) => {}
+            Some(Component::ParentDir) => {
+                resolved.pop();
+                continue;
+            }
+            _ => continue
+        }
+
+        let candidate = resolved.join(&component);
+        let layer_path = match find_in_layer_stack(layers, &candidate)? {
+            Some(layer_path) => layer_path,
+            None => return Ok(None)
+        };
+
+        if std::fs::symlink_metadata(&layer_path)?.file_type().is_symlink() {
+            symlinks += 1;
+            if symlinks > MAX_SYMLINKS {
+                return Ok(None);
+            }
+
+            let target = std::fs::read_link(&layer_path)?;
+            if target.is_absolute() {
+                resolved = PathBuf::from("/");
+            }
+
+            for target_component in target.components().rev() {
+                remaining.push_front(target_component.as_os_str().to_owned());
+            }
+        } else {
+            resolved = candidate;
+        }
+    }
+
+    find_in_layer_stack(layers, &resolved)
+}
+
+/// Finds the top-most layer that contains the path, without following symlinks.
+fn find_in_layer_stack(layers: &[PathBuf], path: &Path) -> ContainerRuntimeResult<Option<PathBuf>> {
+    let relative_path = path.strip_prefix("/").unwrap_or(path);
+    for layer in layers {
+        let layer_path = layer.join(relative_path);
+        match std::fs::symlink_metadata(&layer_path) {
+            Ok(metadata) if metadata.file_type().is_char_device() && metadata.rdev() == 0 => return Ok(None),
+            Ok(_) => return Ok(Some(layer_path)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound || err.raw_os_error() == Some(libc::ENOTDIR) => {}
+            Err(err) => return Err(err.into())
+        }
+
+        // An opaque directory hides the content of the same directory in lower layers
+        if let Some(parent) = layer_path.parent().filter(|parent| parent.is_dir()) {
+            if get_xattr(parent, OVERLAY_OPAQUE_XATTR)?.map(|value| value == b"y").unwrap_or(false) {
+                return Ok(None);
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+#[test]
+fn test_blob_path() {
+    let store = ImageStore::new(Path::new("/var/lib/cort/images"));
+    let digest = format!("sha256:{}", "ab".repeat(32));
+    assert_eq!(
+        Path::new("/var/lib/cort/images/blobs/sha256").join("ab".repeat(32)),
+        store.blob_path(&digest).unwrap()
+    );
+    assert!(store.blob_path("sha256:../../etc").is_err());
+    assert!(store.blob_path("md5:abcd").is_err());
+}
+
+#[test]
+fn test_find_in_layers() {
+    let base_dir = std::env::temp_dir().join(format!("cort-layers-{}", uuid::Uuid::new_v4()));
+    let _remove_base_dir = crate::helpers::RemoveDirGuard::new(base_dir.clone());
+    let top_layer = base_dir.join("top");
+    let bottom_layer = base_dir.join("bottom");
+
+    std::fs::create_dir_all(bottom_layer.join("usr/bin")).unwrap();
+    std::fs::write(bottom_layer.join("usr/bin/busybox"), b"").unwrap();
+    std::os::unix::fs::symlink("usr/bin", bottom_layer.join("bin")).unwrap();
+    std::fs::create_dir_all(top_layer.join("usr/bin")).unwrap();
+    std::os::unix::fs::symlink("/bin/busybox", top_layer.join("usr/bin/sh")).unwrap();
+
+    let layers = vec![top_layer, bottom_layer.clone()];
+    let busybox = Some(bottom_layer.join("usr/bin/busybox"));
+    assert_eq!(busybox, find_in_layers(&layers, Path::new("/bin/sh")).unwrap());
+    assert_eq!(busybox, find_in_layers(&layers, Path::new("/usr/bin/../bin/busybox")).unwrap());
+    assert_eq!(None, find_in_layers(&layers, Path::new("/bin/bash")).unwrap());
+    assert_eq!(None, find_in_layers(&layers, Path::new("/bin/busybox/sh")).unwrap());
+}
+
+#[test]
+fn test_local_image_reference_from_str() {
+    assert_eq!(Ok(LocalImageReference { name: "alpine".to_owned(), tag: "latest".to_owned() }), LocalImageReference::from_str("alpine"));
+    assert_eq!("alpine:3.18", LocalImageReference::from_str("alpine:3.18").unwrap().to_string());
+
+    let digest = format!("sha256:{}", "ab".repeat(32));
+    let reference = LocalImageReference::from_str(&format!("alpine@{}", digest)).unwrap();
+    assert!(reference.is_digest());
+    assert_eq!(digest, reference.tag);
+    assert!(LocalImageReference::tagged(&format!("alpine@{}", digest)).is_err());
+
+    assert!(LocalImageReference::from_str("alpine@sha256:abc").is_err());
+    assert!(LocalImageReference::from_str("library/alpine").is_err());
+    assert!(LocalImageReference::from_str("alpine:").is_err());
+    assert!(LocalImageReference::from_str("alpine:-rc").is_err());
+    assert!(LocalImageReference::from_str(".alpine").is_err());
+}
+
+#[test]
+fn test_tags_and_digests() {
+    let base_dir = std::env::temp_dir().join(format!("cort-images-{}", uuid::Uuid::new_v4()));
+    let _remove_base_dir = crate::helpers::RemoveDirGuard::new(base_dir.clone());
+    let store = ImageStore::new(&base_dir);
+
+    let archive = |content: &[u8]| {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, "file.txt", content).unwrap();
+        builder.into_inner().unwrap()
+    };
+
+    import_image(&store, "app", archive(b"1").as_slice(), &NoProgress).unwrap();
+    let first = store.load("app:latest").unwrap();
+    store.tag("app", "app:v1").unwrap();
+    import_image(&store, "app", archive(b"2").as_slice(), &NoProgress).unwrap();
+
+    // The tag moved to the new image, while the old one is still found by its tag and digest
+    assert_ne!(first.manifest.digest, store.load("app").unwrap().manifest.digest);
+    assert_eq!(first.manifest.digest, store.load("app:v1").unwrap().manifest.digest);
+    assert_eq!(first.manifest.digest, store.load(&format!("app@{}", first.manifest.digest)).unwrap().manifest.digest);
+    assert!(store.load(&format!("app@sha256:{}", "ab".repeat(32))).is_err());
+
+    // Images stored before tags existed get the default tag
+    std::fs::rename(base_dir.join("manifests/app/v1.json"), base_dir.join("manifests/old.json")).unwrap();
+    let old = std::fs::read_to_string(base_dir.join("manifests/old.json")).unwrap().replace("\"app\"", "\"old\"").replace("\"tag\": \"v1\",", "");
+    std::fs::write(base_dir.join("manifests/old.json"), old).unwrap();
+    assert_eq!(
+        vec!["app:latest", "old:latest"],
+        store.list().unwrap().iter().map(|metadata| metadata.tagged_name()).collect::<Vec<_>>()
+    );
+    assert!(!base_dir.join("manifests/old.json").exists());
+}
+
+#[test]
+fn test_verify_layers() {
+    let base_dir = std::env::temp_dir().join(format!("cort-images-{}", uuid::Uuid::new_v4()));
+    let _remove_base_dir = crate::helpers::RemoveDirGuard::new(base_dir.clone());
+    let store = ImageStore::new(&base_dir);
+
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(5);
+    header.set_mode(0o755);
+    builder.append_data(&mut header, "bin/app", &b"hello"[..]).unwrap();
+    import_image(&store, "app", builder.into_inner().unwrap().as_slice(), &NoProgress).unwrap();
+
+    let layer_dir = store.layer_dirs("app").unwrap().remove(0);
+    store.verify_layers("app").unwrap();
+
+    std::fs::write(layer_dir.join("bin/app"), b"evil!").unwrap();
+    assert!(matches!(store.verify_layers("app"), Err(ContainerRuntimeError::RootfsIntegrity(_))));
+
+    // Without a recorded digest, the layer is extracted again from its blob
+    let manifest = store.manifest(&store.load("app").unwrap()).unwrap();
+    std::fs::remove_file(store.layer_tree_digest_path(&manifest.layers[0].digest).unwrap()).unwrap();
+    store.verify_layers("app").unwrap();
+    assert_eq!(b"hello".to_vec(), std::fs::read(layer_dir.join("bin/app")).unwrap());
+    store.verify_layers("app").unwrap();
+}
+
+#[test]
+fn test_prune() {
+    let base_dir = std::env::temp_dir().join(format!("cort-images-{}", uuid::Uuid::new_v4()));
+    let _remove_base_dir = crate::helpers::RemoveDirGuard::new(base_dir.clone());
+    let store = ImageStore::new(&base_dir);
+
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(5);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, "file.txt", &b"hello"[..]).unwrap();
+    import_image(&store, "app", builder.into_inner().unwrap().as_slice(), &NoProgress).unwrap();
+
+    let layer_digest = store.manifest(&store.load("app").unwrap()).unwrap().layers[0].digest.clone();
+    let extracting_dir = base_dir.join("layers/sha256").join(format!(".{}.tmp", "ab".repeat(32)));
+    std::fs::create_dir_all(&extracting_dir).unwrap();
+    assert_eq!(vec![format!("layer sha256:{}", "ab".repeat(32))], store.prune(&[]).unwrap());
+
+    // The layers of a container are kept after its image is removed, until the container is
+    std::fs::remove_dir_all(base_dir.join("manifests")).unwrap();
+    store.prune(&[vec![layer_digest.clone()]]).unwrap();
+    assert!(store.extracted_layer(&layer_digest).unwrap().is_some());
+    store.prune(&[]).unwrap();
+    assert!(store.extracted_layer(&layer_digest).unwrap().is_none());
+}
+
+#[test]
+fn test_compressed_layers() {
+    let base_dir = std::env::temp_dir().join(format!("cort-images-{}", uuid::Uuid::new_v4()));
+    let _remove_base_dir = crate::helpers::RemoveDirGuard::new(base_dir.clone());
+    let store = ImageStore::new(&base_dir);
+
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(5);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, "etc/motd", &b"hello"[..]).unwrap();
+    let archive = builder.into_inner().unwrap();
+    let zstd_archive = zstd::encode_all(archive.as_slice(), 0).unwrap();
+
+    let layer_dir = base_dir.join("layer");
+    std::fs::create_dir_all(&layer_dir).unwrap();
+    extract_layer_archive(&layer_dir, crate::registry::MEDIA_TYPE_OCI_LAYER_ZSTD, zstd_archive.as_slice()).unwrap();
+    assert_eq!(b"hello".to_vec(), std::fs::read(layer_dir.join("etc/motd")).unwrap());
+    assert!(extract_layer_archive(&base_dir.join("other"), "application/vnd.oci.image.layer.v1.tar+lz4", archive.as_slice()).is_err());
+
+    // Every gzip member is extracted, rather than only the first
+    let mut gzip_archive = Vec::new();
+    for part in archive.chunks(512) {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(part).unwrap();
+        gzip_archive.extend(encoder.finish().unwrap());
+    }
+    let layer_dir = base_dir.join("gzip-layer");
+    std::fs::create_dir_all(&layer_dir).unwrap();
+    extract_layer_archive(&layer_dir, MEDIA_TYPE_DOCKER_LAYER_GZIP, gzip_archive.as_slice()).unwrap();
+    assert_eq!(b"hello".to_vec(), std::fs::read(layer_dir.join("etc/motd")).unwrap());
+
+    // Compressed archives are detected when imported
+    import_image(&store, "app", zstd_archive.as_slice(), &NoProgress).unwrap();
+    let layer_dir = store.layer_dirs("app").unwrap().remove(0);
+    assert_eq!(b"hello".to_vec(), std::fs::read(layer_dir.join("etc/motd")).unwrap());
+}
+
+#[test]
+fn test_history_entries() {
+    let config: ConfigHistory = serde_json::from_str(r#"{
+        "history": [
+            { "created": "2024-01-01T00:00:00Z", "created_by": "/bin/sh -c #(nop) ADD file:abc in /" },
+            { "created_by": "/bin/sh -c #(nop) ENV PATH=/usr/bin", "empty_layer": true },
+            { "created_by": "RUN apt-get install -y build-essential", "comment": "buildkit.dockerfile.v0" }
+        ]
+    }"#).unwrap();
+
+    let layer = |digest: &str, size: u64| {
+        Descriptor { media_type: MEDIA_TYPE_DOCKER_LAYER_GZIP.to_owned(), digest: digest.to_owned(), size, platform: None, annotations: None }
+    };
+    let layers = [layer("sha256:base", 1000), layer("sha256:tools", 5000), layer("sha256:extra", 10)];
+
+    let entries = history_entries(config.history, &layers);
+    assert_eq!(4, entries.len());
+    // Layers beyond the history are listed without a step, most recent first
+    assert_eq!(None, entries[0].created_by);
+    assert_eq!(Some("sha256:extra"), entries[0].layer.as_ref().map(|layer| layer.digest.as_str()));
+    assert_eq!(Some("RUN apt-get install -y build-essential"), entries[1].created_by.as_deref());
+    assert_eq!(Some(5000), entries[1].layer.as_ref().map(|layer| layer.size));
+    assert!(entries[2].layer.is_none());
+    assert_eq!(Some("2024-01-01T00:00:00Z"), entries[3].created.as_deref());
+    assert_eq!(Some("sha256:base"), entries[3].layer.as_ref().map(|layer| layer.digest.as_str()));
+}
@@ -0,0 +1,109 @@
+use std::os::unix::io::RawFd;
+
+use crate::linux::{fork, wrap_libc_error};
+use crate::model::{ContainerExitStatus, ContainerRuntimeResult};
+
+/// Signals that are caused by the init itself, which are not forwarded (SIGKILL and SIGSTOP cannot be blocked).
+const UNFORWARDED_SIGNALS: [libc::c_int; 9] = [
+    libc::SIGFPE, libc::SIGILL, libc::SIGSEGV, libc::SIGBUS, libc::SIGABRT, libc::SIGTRAP, libc::SIGSYS, libc::SIGTTIN, libc::SIGTTOU
+];
+
+/// Forks the process that executes the command, returning in it, while this process stays as PID 1 of the container.
+/// The init reaps orphaned processes and forwards signals to the command, exiting with the exit code of the command.
+pub fn fork_command(channel: RawFd) -> ContainerRuntimeResult<()> {
+    let (signals, previous_mask) = block_signals()?;
+
+    let command_pid = fork()?;
+    if command_pid == 0 {
+        set_signal_mask(&previous_mask)?;
+        return Ok(());
+    }
+
+    // The runtime knows that the command has been executed once all ends of the channel are closed
+    unsafe {
+        libc::close(channel);
+    }
+
+    let exit_status = supervise(command_pid, &signals);
+    unsafe {
+        libc::_exit(exit_status.exit_code());
+    }
+}
+
+/// Keeps the namespaces of a pod alive without executing a command, as the infra container of the pod, until asked to stop.
+/// Processes that are orphaned into the container are reaped.
+pub fn pause(channel: RawFd) -> ContainerRuntimeResult<()> {
+    let (signals, _) = block_signals()?;
+
+    // The runtime knows that the container is running once all ends of the channel are closed
+    unsafe {
+        libc::close(channel);
+    }
+
+    loop {
+        let signal = unsafe { libc::sigwaitinfo(&signals, std::ptr::null_mut()) };
+        match signal {
+            libc::SIGCHLD => {
+                reap_children(0);
+            }
+            libc::SIGTERM | libc::SIGINT | libc::SIGHUP => unsafe {
+                libc::_exit(0);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Blocks the signals that the init handles, so that they are received synchronously, returning these and the previous mask.
+fn block_signals() -> ContainerRuntimeResult<(libc::sigset_t, libc::sigset_t)> {
+    unsafe {
+        let mut signals = std::mem::zeroed::<libc::sigset_t>();
+        wrap_libc_error(libc::sigfillset(&mut signals))?;
+        for signal in UNFORWARDED_SIGNALS {
+            wrap_libc_error(libc::sigdelset(&mut signals, signal))?;
+        }
+
+        let mut previous_mask = std::mem::zeroed::<libc::sigset_t>();
+        wrap_libc_error(libc::sigprocmask(libc::SIG_SETMASK, &signals, &mut previous_mask))?;
+        Ok((signals, previous_mask))
+    }
+}
+
+fn set_signal_mask(mask: &libc::sigset_t) -> ContainerRuntimeResult<()> {
+    unsafe {
+        wrap_libc_error(libc::sigprocmask(libc::SIG_SETMASK, mask, std::ptr::null_mut()))?;
+    }
+
+    Ok(())
+}
+
+fn supervise(command_pid: i32, signals: &libc::sigset_t) -> ContainerExitStatus {
+    loop {
+        let signal = unsafe { libc::sigwaitinfo(signals, std::ptr::null_mut()) };
+        if signal == libc::SIGCHLD {
+            if let Some(exit_status) = reap_children(command_pid) {
+                return exit_status;
+            }
+        } else if signal > 0 {
+            unsafe {
+                libc::kill(command_pid, signal);
+            }
+        }
+    }
+}
+
+/// Reaps all children that have exited, returning the exit status of the command if it was one of them.
+fn reap_children(command_pid: i32) -> Option<ContainerExitStatus> {
+    let mut command_exit_status = None;
+    loop {
+        let mut status = 0;
+        let pid = unsafe { libc::waitpid(-1, &mut status, libc::WNOHANG) };
+        if pid <= 0 {
+            return command_exit_status;
+        }
+
+        if pid == command_pid {
+            command_exit_status = Some(ContainerExitStatus::from_wait_status(status));
+        }
+    }
+}
@@ -0,0 +1,545 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use base64::Engine;
+use flate2::read::GzDecoder;
+use tracing::error;
+use serde::Deserialize;
+
+use crate::fuse::{DirEntry, FileAttr, ReadOnlyFilesystem, ROOT_INODE};
+use crate::image::OVERLAY_OPAQUE_XATTR;
+use crate::model::{ContainerRuntimeError, ContainerRuntimeResult};
+use crate::registry::{RegistryClient, sha256_digest};
+
+/// The annotation of an eStargz layer with the digest of its table of contents, which is what makes the layer verifiable when read in parts.
+pub const TOC_DIGEST_ANNOTATION: &str = "containerd.io/snapshot/stargz/toc.digest";
+
+/// The name of the table of contents in the tar archive at the end of the layer.
+const TOC_NAME: &str = "stargz.index.json";
+/// The footer (an empty gzip member) has the offset of the table of contents in its extra field, as 16 hex digits followed by this.
+const FOOTER_MAGIC: &[u8] = b"STARGZ";
+/// The size of the footer of eStargz, where the footer of the older stargz is 47 bytes.
+const FOOTER_SIZE: u64 = 51;
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// Where the compressed layer is read from, in parts.
+pub trait BlobSource: Send + Sync {
+    fn size(&self) -> u64;
+    fn read_range(&self, offset: u64, length: u64) -> ContainerRuntimeResult<Vec<u8>>;
+}
+
+/// Reads the layer from the registry it was pulled from.
+pub struct RegistryBlobSource {
+    client: Mutex<RegistryClient>,
+    repository: String,
+    digest: String,
+    size: u64
+}
+
+impl RegistryBlobSource {
+    pub fn new(client: RegistryClient, repository: &str, digest: &str, size: u64) -> RegistryBlobSource {
+        RegistryBlobSource {
+            client: Mutex::new(client),
+            repository: repository.to_owned(),
+            digest: digest.to_owned(),
+            size
+        }
+    }
+}
+
+impl BlobSource for RegistryBlobSource {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_range(&self, offset: u64, length: u64) -> ContainerRuntimeResult<Vec<u8>> {
+        self.client.lock().unwrap().get_blob_range(&self.repository, &self.digest, offset, length)
+    }
+}
+
+impl BlobSource for Vec<u8> {
+    fn size(&self) -> u64 {
+        self.len() as u64
+    }
+
+    fn read_range(&self, offset: u64, length: u64) -> ContainerRuntimeResult<Vec<u8>> {
+        self.get(offset as usize..(offset + length) as usize)
+            .map(|range| range.to_vec())
+            .ok_or_else(|| ContainerRuntimeError::Input(format!("Range {}+{} is outside of the blob", offset, length)))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Toc {
+    entries: Vec<TocEntry>
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all="camelCase")]
+struct TocEntry {
+    name: String,
+    #[serde(rename="type")]
+    entry_type: String,
+    #[serde(default)]
+    size: u64,
+    #[serde(default)]
+    modtime: Option<String>,
+    #[serde(default)]
+    link_name: String,
+    #[serde(default)]
+    mode: i64,
+    #[serde(default)]
+    uid: u32,
+    #[serde(default)]
+    gid: u32,
+    #[serde(default)]
+    dev_major: u32,
+    #[serde(default)]
+    dev_minor: u32,
+    /// Base64 encoded values
+    #[serde(default)]
+    xattrs: HashMap<String, String>,
+    #[serde(default)]
+    digest: String,
+    #[serde(default)]
+    offset: u64,
+    #[serde(default)]
+    chunk_offset: u64,
+    #[serde(default)]
+    chunk_size: u64,
+    #[serde(default)]
+    chunk_digest: String
+}
+
+/// The table of contents of an eStargz layer along with where it starts, which is where the last chunk ends.
+pub struct TableOfContents {
+    pub offset: u64,
+    pub content: Vec<u8>
+}
+
+impl TableOfContents {
+    /// Reads the table of contents from the end of the layer, checking it against the digest from the annotation of the layer.
+    pub fn read(source: &dyn BlobSource, digest: &str) -> ContainerRuntimeResult<TableOfContents> {
+        let inner = || -> ContainerRuntimeResult<TableOfContents> {
+            let size = source.size();
+            let footer = source.read_range(size.saturating_sub(FOOTER_SIZE), size.min(FOOTER_SIZE))?;
+            let offset = footer_toc_offset(&footer)
+                .filter(|offset| *offset < size)
+                .ok_or_else(|| ContainerRuntimeError::Input("No eStargz footer".to_owned()))?;
+
+            // The table of contents is the only entry of a tar archive in a gzip member of its own
+            let compressed = source.read_range(offset, size - offset)?;
+            let mut archive = tar::Archive::new(GzDecoder::new(compressed.as_slice()));
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                if entry.path()?.to_str() == Some(TOC_NAME) {
+                    let mut content = Vec::new();
+                    entry.read_to_end(&mut content)?;
+
+                    let actual_digest = sha256_digest(&content);
+                    if actual_digest != digest {
+                        return Err(ContainerRuntimeError::Input(format!("Digest mismatch for the table of contents (expected {} but got {})", digest, actual_digest)));
+                    }
+
+                    return Ok(TableOfContents { offset, content });
+                }
+            }
+
+            Err(ContainerRuntimeError::Input(format!("No {} in the layer", TOC_NAME)))
+        };
+
+        inner().map_err(|err| ContainerRuntimeError::LazyPull(format!("Failed to read the table of contents: {}", err)))
+    }
+}
+
+fn footer_toc_offset(footer: &[u8]) -> Option<u64> {
+    let magic_start = footer.windows(FOOTER_MAGIC.len()).position(|window| window == FOOTER_MAGIC)?;
+    let hex = footer.get(magic_start.checked_sub(16)?..magic_start)?;
+    u64::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()
+}
+
+/// A part of a file, compressed as a gzip member of its own starting at the offset in the blob.
+#[derive(Debug, Clone)]
+struct Chunk {
+    file_offset: u64,
+    size: u64,
+    blob_offset: u64,
+    /// Where the next member starts, which the member (with the tar header of the next file) ends before
+    blob_end: u64,
+    digest: String
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    attr: FileAttr,
+    link: Vec<u8>,
+    children: BTreeMap<Vec<u8>, u64>,
+    xattrs: BTreeMap<Vec<u8>, Vec<u8>>,
+    chunks: Vec<Chunk>
+}
+
+/// An eStargz layer whose files are read from the blob as they are used, where each chunk read is checked against its digest
+/// (from the table of contents, which is checked against the manifest) and then kept in the cache directory.
+/// Whiteouts are shown as overlay shows them, so that the layer can be used as a lower directory of overlay.
+pub struct LazyLayer {
+    nodes: Vec<Node>,
+    source: Box<dyn BlobSource>,
+    cache_dir: PathBuf
+}
+
+impl LazyLayer {
+    pub fn new(source: Box<dyn BlobSource>, toc: &TableOfContents, cache_dir: PathBuf) -> ContainerRuntimeResult<LazyLayer> {
+        let parsed: Toc = serde_json::from_slice(&toc.content)?;
+
+        let mut layer = LazyLayer {
+            nodes: vec![Node { attr: FileAttr { inode: ROOT_INODE, mode: libc::S_IFDIR | 0o755, ..FileAttr::default() }, ..Node::default() }],
+            source,
+            cache_dir
+        };
+
+        let mut last_file = None;
+        for entry in &parsed.entries {
+            let path = entry.name.trim_start_matches("./").trim_matches('/');
+            let (parent_path, name) = path.rsplit_once('/').unwrap_or(("", path));
+
+            if entry.entry_type == "chunk" {
+                let file = last_file.ok_or_else(|| ContainerRuntimeError::LazyPull(format!("Chunk of {} without a file", entry.name)))?;
+                layer.add_chunk(file, entry);
+                continue;
+            }
+
+            if path.is_empty() {
+                layer.nodes[0].attr = entry_attr(entry, ROOT_INODE, libc::S_IFDIR);
+                continue;
+            }
+
+            let parent = layer.directory(parent_path);
+            if name == OPAQUE_WHITEOUT {
+                layer.node_mut(parent).xattrs.insert(OVERLAY_OPAQUE_XATTR.as_bytes().to_vec(), b"y".to_vec());
+                continue;
+            }
+
+            if let Some(name) = name.strip_prefix(WHITEOUT_PREFIX) {
+                let inode = layer.add_node(parent, name, Node::default());
+                layer.node_mut(inode).attr = FileAttr { inode, mode: libc::S_IFCHR, nlink: 1, ..FileAttr::default() };
+                continue;
+            }
+
+            let file_type = match entry.entry_type.as_str() {
+                "dir" => libc::S_IFDIR,
+                "reg" => libc::S_IFREG,
+                "symlink" => libc::S_IFLNK,
+                "char" => libc::S_IFCHR,
+                "block" => libc::S_IFBLK,
+                "fifo" => libc::S_IFIFO,
+                "hardlink" => {
+                    let target = layer.find(entry.link_name.trim_start_matches("./").trim_matches('/'))
+                        .ok_or_else(|| ContainerRuntimeError::LazyPull(format!("Target of hard link {} not found", entry.name)))?;
+                    layer.node_mut(parent).children.insert(name.as_bytes().to_vec(), target);
+                    layer.node_mut(target).attr.nlink += 1;
+                    continue;
+                }
+                entry_type => return Err(ContainerRuntimeError::LazyPull(format!("Unsupported entry type {} of {}", entry_type, entry.name)))
+            };
+
+            // Directories might already have been added as the parent of an earlier entry
+            let inode = match layer.node(parent).children.get(name.as_bytes()) {
+                Some(inode) if file_type == libc::S_IFDIR => *inode,
+                _ => layer.add_node(parent, name, Node::default())
+            };
+
+            let node = layer.node_mut(inode);
+            node.attr = entry_attr(entry, inode, file_type);
+            node.link = entry.link_name.as_bytes().to_vec();
+            for (name, value) in &entry.xattrs {
+                let value = base64::engine::general_purpose::STANDARD
+                    .decode(value)
+                    .map_err(|err| ContainerRuntimeError::LazyPull(format!("Invalid extended attribute {} of {}: {}", name, entry.name, err)))?;
+                node.xattrs.insert(name.as_bytes().to_vec(), value);
+            }
+
+            if file_type == libc::S_IFREG {
+                last_file = Some(inode);
+                if entry.size > 0 {
+                    layer.add_chunk(inode, entry);
+                }
+            }
+        }
+
+        layer.finish(toc.offset)?;
+        Ok(layer)
+    }
+
+    fn node(&self, inode: u64) -> &Node {
+        &self.nodes[inode as usize - 1]
+    }
+
+    fn node_mut(&mut self, inode: u64) -> &mut Node {
+        &mut self.nodes[inode as usize - 1]
+    }
+
+    fn get(&self, inode: u64) -> Result<&Node, i32> {
+        self.nodes.get((inode as usize).wrapping_sub(1)).ok_or(libc::ENOENT)
+    }
+
+    fn add_node(&mut self, parent: u64, name: &str, node: Node) -> u64 {
+        let inode = self.nodes.len() as u64 + 1;
+        self.nodes.push(node);
+        self.node_mut(parent).children.insert(name.as_bytes().to_vec(), inode);
+        inode
+    }
+
+    fn find(&self, path: &str) -> Option<u64> {
+        let mut inode = ROOT_INODE;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            inode = *self.node(inode).children.get(name.as_bytes())?;
+        }
+
+        Some(inode)
+    }
+
+    /// The directory at the path, which is added (along with its parents) if missing.
+    fn directory(&mut self, path: &str) -> u64 {
+        let mut inode = ROOT_INODE;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            inode = match self.node(inode).children.get(name.as_bytes()) {
+                Some(child) => *child,
+                None => {
+                    let child = self.add_node(inode, name, Node::default());
+                    self.node_mut(child).attr = FileAttr { inode: child, mode: libc::S_IFDIR | 0o755, ..FileAttr::default() };
+                    child
+                }
+            };
+        }
+
+        inode
+    }
+
+    fn add_chunk(&mut self, inode: u64, entry: &TocEntry) {
+        let digest = if entry.chunk_digest.is_empty() { entry.digest.clone() } else { entry.chunk_digest.clone() };
+        self.node_mut(inode).chunks.push(
+            Chunk {
+                file_offset: entry.chunk_offset,
+                size: entry.chunk_size,
+                blob_offset: entry.offset,
+                blob_end: 0,
+                digest
+            }
+        );
+    }
+
+    /// Sets where each chunk ends, in the file and in the blob, and the link counts of the directories.
+    fn finish(&mut self, toc_offset: u64) -> ContainerRuntimeResult<()> {
+        let mut member_offsets = self.nodes
+            .iter()
+            .flat_map(|node| node.chunks.iter().map(|chunk| chunk.blob_offset))
+            .collect::<Vec<_>>();
+        member_offsets.push(toc_offset);
+        member_offsets.sort_unstable();
+        member_offsets.dedup();
+
+        for node in &mut self.nodes {
+            let file_size = node.attr.size;
+            for chunk in &mut node.chunks {
+                if chunk.size == 0 {
+                    chunk.size = file_size.saturating_sub(chunk.file_offset);
+                }
+
+                chunk.blob_end = member_offsets.iter().find(|offset| **offset > chunk.blob_offset).copied().unwrap_or(toc_offset);
+                if chunk.digest.is_empty() {
+                    return Err(ContainerRuntimeError::LazyPull(format!("Chunk at {} has no digest", chunk.blob_offset)));
+                }
+            }
+        }
+
+        for index in 0..self.nodes.len() {
+            if self.nodes[index].attr.mode & libc::S_IFMT == libc::S_IFDIR {
+                let subdirectories = self.nodes[index].children
+                    .values()
+                    .filter(|child| self.node(**child).attr.mode & libc::S_IFMT == libc::S_IFDIR)
+                    .count();
+                self.nodes[index].attr.nlink = 2 + subdirectories as u32;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The content of the chunk, from the cache or else from the blob.
+    fn chunk_content(&self, chunk: &Chunk) -> ContainerRuntimeResult<Vec<u8>> {
+        let cache_path = self.cache_dir.join(chunk.digest.trim_start_matches("sha256:"));
+        if let Ok(content) = std::fs::read(&cache_path) {
+            return Ok(content);
+        }
+
+        let compressed = self.source.read_range(chunk.blob_offset, chunk.blob_end - chunk.blob_offset)?;
+        let mut content = vec![0; chunk.size as usize];
+        GzDecoder::new(compressed.as_slice()).read_exact(&mut content)?;
+
+        let actual_digest = sha256_digest(&content);
+        if actual_digest != chunk.digest {
+            return Err(ContainerRuntimeError::LazyPull(format!("Digest mismatch for chunk at {} (expected {} but got {})", chunk.blob_offset, chunk.digest, actual_digest)));
+        }
+
+        // Written to a temporary file first, as another run might read the same chunk meanwhile
+        std::fs::create_dir_all(&self.cache_dir)?;
+        let temp_path = cache_path.with_extension(format!("{}.tmp", uuid::Uuid::new_v4()));
+        std::fs::write(&temp_path, &content)?;
+        std::fs::rename(&temp_path, &cache_path)?;
+        Ok(content)
+    }
+}
+
+fn entry_attr(entry: &TocEntry, inode: u64, file_type: u32) -> FileAttr {
+    let mtime = entry.modtime
+        .as_deref()
+        .and_then(|modtime| chrono::DateTime::parse_from_rfc3339(modtime).ok())
+        .map(|modtime| modtime.timestamp())
+        .unwrap_or(0);
+
+    FileAttr {
+        inode,
+        size: if file_type == libc::S_IFREG { entry.size } else if file_type == libc::S_IFLNK { entry.link_name.len() as u64 } else { 0 },
+        mode: file_type | (entry.mode as u32 & 0o7777),
+        nlink: 1,
+        uid: entry.uid,
+        gid: entry.gid,
+        rdev: libc::makedev(entry.dev_major, entry.dev_minor) as u32,
+        mtime
+    }
+}
+
+impl ReadOnlyFilesystem for LazyLayer {
+    fn lookup(&self, parent: u64, name: &[u8]) -> Result<FileAttr, i32> {
+        let inode = self.get(parent)?.children.get(name).ok_or(libc::ENOENT)?;
+        Ok(self.node(*inode).attr)
+    }
+
+    fn getattr(&self, inode: u64) -> Result<FileAttr, i32> {
+        Ok(self.get(inode)?.attr)
+    }
+
+    fn readlink(&self, inode: u64) -> Result<Vec<u8>, i32> {
+        Ok(self.get(inode)?.link.clone())
+    }
+
+    fn read(&self, inode: u64, offset: u64, size: u32) -> Result<Vec<u8>, i32> {
+        let node = self.get(inode)?;
+        let end = (offset + size as u64).min(node.attr.size);
+
+        let mut content = Vec::new();
+        for chunk in &node.chunks {
+            let chunk_end = chunk.file_offset + chunk.size;
+            if chunk_end <= offset || chunk.file_offset >= end {
+                continue;
+            }
+
+            let chunk_content = self.chunk_content(chunk).map_err(|err| {
+                error!("Failed to read chunk of layer: {}", err);
+                libc::EIO
+            })?;
+
+            let start = offset.max(chunk.file_offset) - chunk.file_offset;
+            let stop = end.min(chunk_end) - chunk.file_offset;
+            content.extend_from_slice(&chunk_content[start as usize..stop as usize]);
+        }
+
+        Ok(content)
+    }
+
+    fn readdir(&self, inode: u64) -> Result<Vec<DirEntry>, i32> {
+        let node = self.get(inode)?;
+        if node.attr.mode & libc::S_IFMT != libc::S_IFDIR {
+            return Err(libc::ENOTDIR);
+        }
+
+        Ok(
+            node.children
+                .iter()
+                .map(|(name, child)| DirEntry { inode: *child, name: name.clone(), mode: self.node(*child).attr.mode })
+                .collect()
+        )
+    }
+
+    fn getxattr(&self, inode: u64, name: &[u8]) -> Result<Vec<u8>, i32> {
+        self.get(inode)?.xattrs.get(name).cloned().ok_or(libc::ENODATA)
+    }
+
+    fn listxattr(&self, inode: u64) -> Result<Vec<Vec<u8>>, i32> {
+        Ok(self.get(inode)?.xattrs.keys().cloned().collect())
+    }
+}
+
+#[test]
+fn test_lazy_layer() {
+    use std::io::Write;
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+
+    let gzip = |content: &[u8]| {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(content).unwrap();
+        encoder.finish().unwrap()
+    };
+
+    // Each chunk is a gzip member of its own, where the tar headers are left out as only the table of contents is used
+    let mut blob = gzip(b"headers");
+    let first_chunk = blob.len();
+    blob.extend(gzip(b"hello "));
+    let second_chunk = blob.len();
+    blob.extend(gzip(b"world"));
+    let toc_offset = blob.len();
+
+    let toc = serde_json::json!({
+        "version": 1,
+        "entries": [
+            { "name": "usr/bin/", "type": "dir", "mode": 0o755 },
+            { "name": "usr/bin/greet", "type": "reg", "mode": 0o755, "size": 11, "offset": first_chunk, "chunkSize": 6, "chunkDigest": sha256_digest(b"hello ") },
+            { "name": "usr/bin/greet", "type": "chunk", "offset": second_chunk, "chunkOffset": 6, "chunkDigest": sha256_digest(b"world") },
+            { "name": "bin", "type": "symlink", "linkName": "usr/bin" },
+            { "name": "etc/.wh..wh..opq", "type": "reg" },
+            { "name": "etc/.wh.passwd", "type": "reg" }
+        ]
+    });
+    let toc_content = serde_json::to_vec(&toc).unwrap();
+    let mut archive = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(toc_content.len() as u64);
+    archive.append_data(&mut header, TOC_NAME, toc_content.as_slice()).unwrap();
+    blob.extend(gzip(&archive.into_inner().unwrap()));
+    blob.extend(flate2::GzBuilder::new().extra(format!("SG\x16\x00{:016x}STARGZ", toc_offset)).write(Vec::new(), Compression::none()).finish().unwrap());
+
+    let cache_dir = std::env::temp_dir().join(format!("cort-lazy-{}", uuid::Uuid::new_v4()));
+    let _remove_cache_dir = crate::helpers::RemoveDirGuard::new(cache_dir.clone());
+    assert!(TableOfContents::read(&blob, &sha256_digest(b"other")).is_err());
+    let toc = TableOfContents::read(&blob, &sha256_digest(&toc_content)).unwrap();
+    assert_eq!(toc_offset as u64, toc.offset);
+    let layer = LazyLayer::new(Box::new(blob.clone()), &toc, cache_dir.clone()).unwrap();
+
+    let usr = layer.lookup(ROOT_INODE, b"usr").unwrap();
+    let bin = layer.lookup(usr.inode, b"bin").unwrap();
+    let greet = layer.lookup(bin.inode, b"greet").unwrap();
+    assert_eq!(libc::S_IFREG | 0o755, greet.mode);
+    assert_eq!(b"hello world".to_vec(), layer.read(greet.inode, 0, 4096).unwrap());
+    assert_eq!(b"lo wo".to_vec(), layer.read(greet.inode, 3, 5).unwrap());
+    assert_eq!(b"usr/bin".to_vec(), layer.readlink(layer.lookup(ROOT_INODE, b"bin").unwrap().inode).unwrap());
+
+    // Whiteouts are shown as overlay expects them
+    let etc = layer.lookup(ROOT_INODE, b"etc").unwrap();
+    assert_eq!(Ok(b"y".to_vec()), layer.getxattr(etc.inode, OVERLAY_OPAQUE_XATTR.as_bytes()));
+    assert_eq!(libc::S_IFCHR, layer.lookup(etc.inode, b"passwd").unwrap().mode);
+    assert_eq!(vec![b"passwd".to_vec()], layer.readdir(etc.inode).unwrap().into_iter().map(|entry| entry.name).collect::<Vec<_>>());
+
+    // Chunks are read from the cache once fetched, and checked against their digest when fetched
+    let mut tampered = blob.clone();
+    tampered.splice(first_chunk..second_chunk, gzip(b"HELLO "));
+    let toc = TableOfContents::read(&blob, &sha256_digest(&toc_content)).unwrap();
+    let tampered_layer = LazyLayer::new(Box::new(tampered), &toc, cache_dir.join("other")).unwrap();
+    assert_eq!(Err(libc::EIO), tampered_layer.read(greet.inode, 0, 4096));
+    assert!(cache_dir.join(sha256_digest(b"hello ").trim_start_matches("sha256:")).exists());
+}
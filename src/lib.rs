@@ -0,0 +1,40 @@
+pub mod config;
+pub mod model;
+pub mod spec;
+pub mod state;
+pub mod runtime;
+pub mod network;
+pub mod storage;
+pub mod copy;
+pub mod image;
+pub mod registry;
+pub mod credentials;
+pub mod signature;
+pub mod dhcp;
+pub mod cni;
+pub mod diagnostics;
+pub mod names;
+pub mod logs;
+pub mod metrics;
+pub mod hooks;
+pub mod progress;
+pub mod sys;
+pub mod audit;
+pub mod systemd;
+pub mod pod;
+
+mod container;
+mod gpu;
+mod hosts;
+mod emulation;
+mod init;
+mod linux;
+mod netwatch;
+mod helpers;
+mod fuse;
+mod lazy;
+mod sync;
+
+pub use crate::config::ContainerRuntimeConfig;
+pub use crate::runtime::ContainerRuntime;
+pub use crate::model::{ContainerRuntimeError, ContainerRuntimeResult};
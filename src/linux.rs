@@ -1,8 +1,14 @@
-use std::ffi::{c_int, c_ulong, CStr, CString};
-use std::path::Path;
+use std::ffi::{c_int, c_ulong, c_void, CStr, CString, OsString};
+use std::fs::File;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
 use libc::{gid_t, uid_t};
 
-use crate::model::{ContainerRuntimeError, ContainerRuntimeResult};
+use crate::model::{ContainerExitStatus, ContainerRuntimeError, ContainerRuntimeResult};
+
+pub const BTRFS_SUPER_MAGIC: i64 = 0x9123683e;
+pub const OVERLAYFS_SUPER_MAGIC: i64 = 0x794c7630;
 
 pub fn mount(src: Option<&str>, target: &Path, fstype: Option<&str>, flags: c_ulong, data: Option<&str>) -> ContainerRuntimeResult<()> {
     let src = src.map(|x| CString::new(x).unwrap());
@@ -22,7 +28,7 @@ pub fn mount(src: Option<&str>, target: &Path, fstype: Option<&str>, flags: c_ul
         if result == 0 {
             Ok(())
         } else {
-            Err(ContainerRuntimeError::Mount(extract_libc_error_message()))
+            Err(ContainerRuntimeError::Mount(std::io::Error::last_os_error()))
         }
     }
 }
@@ -42,6 +48,16 @@ pub fn pivot_root(new_root: &Path, old_root: &Path) -> ContainerRuntimeResult<()
     Ok(())
 }
 
+/// Changes the root directory of the process to the directory, along with its working directory.
+pub fn change_root(new_root: &Path) -> ContainerRuntimeResult<()> {
+    unsafe {
+        let new_root = CString::new(new_root.to_str().unwrap()).unwrap();
+        wrap_libc_error(libc::chroot(new_root.as_ptr()))?;
+    }
+
+    change_dir(Path::new("/"))
+}
+
 pub fn change_dir(working_dir: &Path) -> ContainerRuntimeResult<()> {
     unsafe {
         let working_dir = CString::new(working_dir.to_str().unwrap()).unwrap();
@@ -60,17 +76,138 @@ pub fn unmount(target: &Path) -> ContainerRuntimeResult<()> {
     Ok(())
 }
 
-pub fn chmod(path: &Path, uid: u64, gid: u64) -> ContainerRuntimeResult<()> {
+/// The mount points below the directory (or the directory itself) in the mount namespace of this process, the deepest first.
+pub fn mounts_below(dir: &Path) -> ContainerRuntimeResult<Vec<PathBuf>> {
+    let dir = std::fs::canonicalize(dir).unwrap_or_else(|_| dir.to_owned());
+    Ok(mount_points_below(&std::fs::read_to_string("/proc/self/mountinfo")?, &dir))
+}
+
+fn mount_points_below(mountinfo: &str, dir: &Path) -> Vec<PathBuf> {
+    let mut mount_points = parse_mount_points(mountinfo)
+        .into_iter()
+        .filter(|mount_point| mount_point.starts_with(dir))
+        .collect::<Vec<_>>();
+
+    // Stable, so that mounts on the same mount point are unmounted in the reverse order of being mounted
+    mount_points.reverse();
+    mount_points.sort_by_key(|mount_point| std::cmp::Reverse(mount_point.components().count()));
+    mount_points
+}
+
+/// The mount points of a mountinfo file, in order, where spaces and other special characters are escaped in octal (such as \040).
+fn parse_mount_points(mountinfo: &str) -> Vec<PathBuf> {
+    let unescape = |field: &str| -> PathBuf {
+        let bytes = field.as_bytes();
+        let mut unescaped = Vec::new();
+        let mut index = 0;
+        while index < bytes.len() {
+            let escaped = bytes.get(index + 1..index + 4)
+                .filter(|_| bytes[index] == b'\\')
+                .and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok());
+
+            match escaped {
+                Some(byte) => {
+                    unescaped.push(byte);
+                    index += 4;
+                }
+                None => {
+                    unescaped.push(bytes[index]);
+                    index += 1;
+                }
+            }
+        }
+
+        PathBuf::from(OsString::from_vec(unescaped))
+    };
+
+    mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(unescape)
+        .collect()
+}
+
+pub fn lchown(path: &Path, uid: u64, gid: u64) -> ContainerRuntimeResult<()> {
+    unsafe {
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+        wrap_libc_error(libc::lchown(path.as_ptr(), uid as uid_t, gid as gid_t))?;
+    }
+
+    Ok(())
+}
+
+pub fn mknod(path: &Path, mode: u32, device: u64) -> ContainerRuntimeResult<()> {
     unsafe {
-        let path = path.to_str().unwrap();
-        let path = CString::new(path).unwrap();
-        wrap_libc_error(libc::chown(path.as_ptr(), uid as uid_t, gid as gid_t))?;
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+        wrap_libc_error(libc::mknod(path.as_ptr(), mode, device))?;
     }
 
     Ok(())
 }
 
-pub fn exec(command: &Vec<String>) -> ContainerRuntimeResult<()> {
+pub fn set_xattr(path: &Path, name: &str, value: &[u8]) -> ContainerRuntimeResult<()> {
+    unsafe {
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+        let name = CString::new(name).unwrap();
+        wrap_libc_error(libc::lsetxattr(path.as_ptr(), name.as_ptr(), value.as_ptr() as *const c_void, value.len(), 0))?;
+    }
+
+    Ok(())
+}
+
+pub fn get_xattr(path: &Path, name: &str) -> ContainerRuntimeResult<Option<Vec<u8>>> {
+    unsafe {
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+        let name = CString::new(name).unwrap();
+        let mut value = vec![0u8; 256];
+        let size = libc::lgetxattr(path.as_ptr(), name.as_ptr(), value.as_mut_ptr() as *mut c_void, value.len());
+        if size < 0 {
+            return match *libc::__errno_location() {
+                libc::ENODATA | libc::ENOTSUP => Ok(None),
+                _ => Err(ContainerRuntimeError::Libc(std::io::Error::last_os_error()))
+            };
+        }
+
+        value.truncate(size as usize);
+        Ok(Some(value))
+    }
+}
+
+/// Reads the start of the file into the page cache, without waiting for the data.
+pub fn readahead(file: &File, size: u64) -> ContainerRuntimeResult<()> {
+    unsafe {
+        wrap_libc_error(libc::readahead(file.as_raw_fd(), 0, size as usize) as i32)?;
+    }
+
+    Ok(())
+}
+
+pub fn bind_to_device<T: AsRawFd>(socket: &T, interface: &str) -> ContainerRuntimeResult<()> {
+    unsafe {
+        let interface = CString::new(interface).unwrap();
+        wrap_libc_error(libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            interface.as_ptr() as *const c_void,
+            interface.as_bytes_with_nul().len() as libc::socklen_t
+        ))?;
+    }
+
+    Ok(())
+}
+
+pub fn filesystem_type(path: &Path) -> ContainerRuntimeResult<i64> {
+    unsafe {
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+        let mut stat: libc::statfs = std::mem::zeroed();
+        wrap_libc_error(libc::statfs(path.as_ptr(), &mut stat))?;
+        #[allow(clippy::unnecessary_cast)]
+        Ok(stat.f_type as i64)
+    }
+}
+
+pub fn exec(command: &[String]) -> ContainerRuntimeResult<()> {
     let command = command.iter().map(|part| CString::new(part.as_str()).unwrap()).collect::<Vec<_>>();
     let mut command_ptrs = command.iter().map(|part| part.as_ptr()).collect::<Vec<_>>();
     command_ptrs.push(std::ptr::null());
@@ -79,16 +216,358 @@ pub fn exec(command: &Vec<String>) -> ContainerRuntimeResult<()> {
         if libc::execvp(command_ptrs[0], &command_ptrs[0]) == 0 {
             Ok(())
         } else {
-            Err(ContainerRuntimeError::Execute(extract_libc_error_message()))
+            Err(ContainerRuntimeError::Execute(std::io::Error::last_os_error()))
         }
     }
 }
 
-pub fn waitpid(pid: i32) -> ContainerRuntimeResult<i32> {
+/// Makes the permitted capabilities survive changing from root to another user, until the next exec.
+pub fn set_keep_capabilities(keep: bool) -> ContainerRuntimeResult<()> {
+    unsafe {
+        wrap_libc_error(libc::prctl(libc::PR_SET_KEEPCAPS, keep as libc::c_ulong, 0, 0, 0))?;
+    }
+
+    Ok(())
+}
+
+/// Sets the effective, permitted and inheritable capabilities of the process to the ones given as a bit set by number.
+pub fn set_capabilities(capabilities: u64) -> ContainerRuntimeResult<()> {
+    #[repr(C)]
+    struct CapUserHeader {
+        version: u32,
+        pid: c_int
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct CapUserData {
+        effective: u32,
+        permitted: u32,
+        inheritable: u32
+    }
+
+    const LINUX_CAPABILITY_VERSION_3: u32 = 0x20080522;
+
+    let mut header = CapUserHeader { version: LINUX_CAPABILITY_VERSION_3, pid: 0 };
+    let data = [capabilities as u32, (capabilities >> 32) as u32].map(|bits| CapUserData { effective: bits, permitted: bits, inheritable: bits });
+    unsafe {
+        wrap_libc_error(libc::syscall(libc::SYS_capset, &mut header as *mut CapUserHeader, data.as_ptr()) as c_int)?;
+    }
+
+    Ok(())
+}
+
+/// Ambient capabilities are kept across exec by processes that are not root, without the files having capabilities.
+pub fn set_ambient_capabilities(capabilities: &[u32]) -> ContainerRuntimeResult<()> {
+    unsafe {
+        wrap_libc_error(libc::prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_CLEAR_ALL as libc::c_ulong, 0, 0, 0))?;
+        for capability in capabilities {
+            wrap_libc_error(libc::prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_RAISE as libc::c_ulong, *capability as libc::c_ulong, 0, 0))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Takes an exclusive lock on the file (created if needed), which is held until the returned file is closed.
+pub fn lock_file(path: &Path) -> ContainerRuntimeResult<File> {
+    flock_file(path, libc::LOCK_EX)
+}
+
+/// Takes a shared lock on the file (created if needed), which is held along with other shared locks but never with an exclusive one.
+pub fn lock_file_shared(path: &Path) -> ContainerRuntimeResult<File> {
+    flock_file(path, libc::LOCK_SH)
+}
+
+fn flock_file(path: &Path, operation: c_int) -> ContainerRuntimeResult<File> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let file = File::create(path)?;
+    unsafe {
+        wrap_libc_error(libc::flock(file.as_raw_fd(), operation))?;
+    }
+
+    Ok(file)
+}
+
+/// Creates a pipe, returning the read and write ends, which are closed on exec.
+pub fn pipe() -> ContainerRuntimeResult<(File, File)> {
+    let mut fds = [0 as c_int; 2];
+    unsafe {
+        wrap_libc_error(libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC))?;
+        Ok((File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])))
+    }
+}
+
+/// Creates a named pipe at the path.
+pub fn mkfifo(path: &Path, mode: u32) -> ContainerRuntimeResult<()> {
+    let path = CString::new(path.to_str().unwrap()).unwrap();
+    unsafe {
+        wrap_libc_error(libc::mkfifo(path.as_ptr(), mode as libc::mode_t))?;
+    }
+
+    Ok(())
+}
+
+/// Duplicates the file descriptor onto the target, which stays open on exec.
+pub fn dup2(fd: RawFd, target: RawFd) -> ContainerRuntimeResult<()> {
+    unsafe {
+        wrap_libc_error(libc::dup2(fd, target))?;
+    }
+
+    Ok(())
+}
+
+/// The path of the terminal (such as /dev/pts/2) of the file descriptor, if it is one.
+pub fn terminal_name(fd: RawFd) -> Option<String> {
+    let mut name = [0 as libc::c_char; 256];
+    unsafe {
+        if libc::ttyname_r(fd, name.as_mut_ptr(), name.len()) != 0 {
+            return None;
+        }
+
+        CStr::from_ptr(name.as_ptr()).to_str().ok().map(|name| name.to_owned())
+    }
+}
+
+/// Makes reads and writes of the file descriptor block, such as after opening it without blocking.
+pub fn set_blocking(fd: RawFd) -> ContainerRuntimeResult<()> {
+    unsafe {
+        let flags = wrap_libc_error(libc::fcntl(fd, libc::F_GETFL))?;
+        wrap_libc_error(libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK))?;
+    }
+
+    Ok(())
+}
+
+/// Makes the file descriptor stay open when executing a command.
+pub fn set_inheritable(fd: RawFd) -> ContainerRuntimeResult<()> {
+    unsafe {
+        let flags = wrap_libc_error(libc::fcntl(fd, libc::F_GETFD))?;
+        wrap_libc_error(libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC))?;
+    }
+
+    Ok(())
+}
+
+/// Waits until any of the file descriptors is readable (or closed), returning the index of the first that is.
+pub fn poll_readable(fds: &[RawFd]) -> ContainerRuntimeResult<usize> {
+    let mut poll_fds = fds
+        .iter()
+        .map(|fd| libc::pollfd { fd: *fd, events: libc::POLLIN, revents: 0 })
+        .collect::<Vec<_>>();
+
+    loop {
+        let result = unsafe { libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as libc::nfds_t, -1) };
+        if result == -1 && std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+            continue;
+        }
+
+        wrap_libc_error(result)?;
+        return Ok(poll_fds.iter().position(|poll_fd| poll_fd.revents != 0).unwrap_or_default());
+    }
+}
+
+/// Opens the file at the path resolved as if the root was the root directory, so that symlinks (such as those in the root filesystem
+/// of a container) cannot lead outside of it.
+pub fn open_in_root(root: &Path, path: &Path, flags: c_int) -> ContainerRuntimeResult<File> {
+    let root = File::open(root)?;
+    let path = CString::new(path.to_str().unwrap()).unwrap();
+
+    unsafe {
+        let mut how: libc::open_how = std::mem::zeroed();
+        how.flags = (flags | libc::O_CLOEXEC) as u64;
+        how.resolve = libc::RESOLVE_IN_ROOT | libc::RESOLVE_NO_MAGICLINKS;
+
+        let fd = libc::syscall(libc::SYS_openat2, root.as_raw_fd(), path.as_ptr(), &how as *const libc::open_how, std::mem::size_of::<libc::open_how>());
+        Ok(File::from_raw_fd(wrap_libc_error(fd as c_int)?))
+    }
+}
+
+/// Opens a nonblocking socket that receives a message each time an IPv4 route or address of the host changes.
+pub fn route_monitor_socket() -> ContainerRuntimeResult<File> {
+    unsafe {
+        let fd = wrap_libc_error(libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK, libc::NETLINK_ROUTE))?;
+        let socket = File::from_raw_fd(fd);
+
+        let mut address: libc::sockaddr_nl = std::mem::zeroed();
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        address.nl_groups = (libc::RTMGRP_IPV4_ROUTE | libc::RTMGRP_IPV4_IFADDR) as u32;
+        wrap_libc_error(libc::bind(
+            fd,
+            &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t
+        ))?;
+
+        Ok(socket)
+    }
+}
+
+pub fn unshare(flags: c_int) -> ContainerRuntimeResult<()> {
+    unsafe {
+        wrap_libc_error(libc::unshare(flags))?;
+    }
+
+    Ok(())
+}
+
+/// The arguments of clone3, as struct clone_args in linux/sched.h.
+#[repr(C)]
+#[derive(Default)]
+struct CloneArgs {
+    flags: u64,
+    pidfd: u64,
+    child_tid: u64,
+    parent_tid: u64,
+    exit_signal: u64,
+    stack: u64,
+    stack_size: u64,
+    tls: u64
+}
+
+/// Creates a child process in new namespaces, which like fork returns 0 in the child, where it continues on a copy of the stack of the caller.
+/// The parent also gets a pidfd of the child (see `pidfd_open`), which is None in the child.
+/// Falls back to clone when clone3 is not available, such as when it is blocked by seccomp when running inside another container.
+pub fn clone_process(namespaces: c_int) -> ContainerRuntimeResult<(i32, Option<File>)> {
+    let mut pidfd: c_int = -1;
+    let args = CloneArgs {
+        flags: namespaces as u64 | libc::CLONE_PIDFD as u64,
+        pidfd: &mut pidfd as *mut c_int as u64,
+        exit_signal: libc::SIGCHLD as u64,
+        ..Default::default()
+    };
+
+    unsafe {
+        let mut pid = libc::syscall(libc::SYS_clone3, &args as *const CloneArgs, std::mem::size_of::<CloneArgs>());
+        if pid == -1 && std::io::Error::last_os_error().raw_os_error() == Some(libc::ENOSYS) {
+            // Without a stack, the child of clone also continues on a copy of the stack
+            pid = libc::syscall(libc::SYS_clone, (namespaces | libc::SIGCHLD) as c_ulong, 0 as c_ulong, 0 as c_ulong, 0 as c_ulong, 0 as c_ulong);
+        }
+
+        let pid = wrap_libc_error(pid as c_int)?;
+        if pid == 0 {
+            return Ok((0, None));
+        }
+
+        // The child cannot be reaped meanwhile, so its PID still refers to it
+        let pidfd = if pidfd >= 0 { File::from_raw_fd(pidfd) } else { pidfd_open(pid)? };
+        Ok((pid, Some(pidfd)))
+    }
+}
+
+pub fn fork() -> ContainerRuntimeResult<i32> {
+    unsafe {
+        wrap_libc_error(libc::fork())
+    }
+}
+
+pub fn enter_mount_namespace(pid: i32) -> ContainerRuntimeResult<()> {
+    let namespace = std::fs::File::open(format!("/proc/{}/ns/mnt", pid))?;
+    unsafe {
+        wrap_libc_error(libc::setns(namespace.as_raw_fd(), libc::CLONE_NEWNS))?;
+    }
+
+    change_dir(Path::new("/"))
+}
+
+/// Moves the calling thread (and the processes it spawns) into the network namespace of the process.
+pub fn enter_network_namespace(pid: i32) -> ContainerRuntimeResult<()> {
+    let namespace = std::fs::File::open(format!("/proc/{}/ns/net", pid))?;
+    unsafe {
+        wrap_libc_error(libc::setns(namespace.as_raw_fd(), libc::CLONE_NEWNET))?;
+    }
+
+    Ok(())
+}
+
+/// Opens a namespace of the process (such as net or ipc), which can be entered later with `set_namespace`.
+pub fn open_namespace(pid: i32, namespace: &str) -> ContainerRuntimeResult<File> {
+    Ok(File::open(format!("/proc/{}/ns/{}", pid, namespace))?)
+}
+
+/// Moves the calling thread into the namespace, where the type is the clone flag of the namespace.
+pub fn set_namespace(namespace: &File, namespace_type: c_int) -> ContainerRuntimeResult<()> {
+    unsafe {
+        wrap_libc_error(libc::setns(namespace.as_raw_fd(), namespace_type))?;
+    }
+
+    Ok(())
+}
+
+/// Waits for the process to change state, which is only when it terminates unless the options include WUNTRACED.
+pub fn waitpid(pid: i32, options: c_int) -> ContainerRuntimeResult<ContainerExitStatus> {
      unsafe {
         let mut status = 0;
-        wrap_libc_error(libc::waitpid(pid, &mut status as *mut c_int, 0))?;
-        Ok(status)
+        wrap_libc_error(libc::waitpid(pid, &mut status as *mut c_int, options))?;
+        Ok(ContainerExitStatus::from_wait_status(status))
+    }
+}
+
+/// Handles the signal with the function, where interrupted calls are restarted. Returns the previous action, so that it can be restored.
+pub fn set_signal_handler(signal: c_int, handler: extern "C" fn(c_int)) -> ContainerRuntimeResult<libc::sigaction> {
+    unsafe {
+        let mut action = std::mem::zeroed::<libc::sigaction>();
+        action.sa_sigaction = handler as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+
+        let mut previous_action = std::mem::zeroed::<libc::sigaction>();
+        wrap_libc_error(libc::sigaction(signal, &action, &mut previous_action))?;
+        Ok(previous_action)
+    }
+}
+
+pub fn restore_signal_action(signal: c_int, action: &libc::sigaction) -> ContainerRuntimeResult<()> {
+    unsafe {
+        wrap_libc_error(libc::sigaction(signal, action, std::ptr::null_mut()))?;
+    }
+
+    Ok(())
+}
+
+/// Opens a file descriptor referring to the process, which becomes readable when the process exits (also when it is not a child),
+/// and keeps referring to it even if the PID is reused.
+pub fn pidfd_open(pid: i32) -> ContainerRuntimeResult<File> {
+    unsafe {
+        let fd = libc::syscall(libc::SYS_pidfd_open, pid, 0);
+        Ok(File::from_raw_fd(wrap_libc_error(fd as c_int)?))
+    }
+}
+
+/// Sends the signal to the process of the pidfd, which fails (with ESRCH) once it has exited rather than reaching another process.
+pub fn pidfd_send_signal(pidfd: &File, signal: c_int) -> ContainerRuntimeResult<()> {
+    unsafe {
+        let result = libc::syscall(libc::SYS_pidfd_send_signal, pidfd.as_raw_fd(), signal, std::ptr::null::<libc::siginfo_t>(), 0);
+        wrap_libc_error(result as c_int)?;
+    }
+
+    Ok(())
+}
+
+/// If the process of the pidfd has exited, without waiting for it.
+pub fn has_exited(pidfd: &File) -> bool {
+    let mut poll_fd = libc::pollfd { fd: pidfd.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+    unsafe {
+        libc::poll(&mut poll_fd, 1, 0) == 1
+    }
+}
+
+/// The time that the process started (in clock ticks since boot), which tells it apart from a later process with the same PID.
+pub fn process_start_time(pid: i32) -> ContainerRuntimeResult<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    parse_start_time(&stat).ok_or_else(|| ContainerRuntimeError::Libc(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid stat of process")))
+}
+
+/// The 22nd field of /proc/<pid>/stat, counted after the command, which is in parentheses and can contain anything.
+fn parse_start_time(stat: &str) -> Option<u64> {
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+pub fn is_process_alive(pid: i32) -> bool {
+    unsafe {
+        libc::kill(pid, 0) == 0
     }
 }
 
@@ -96,13 +575,129 @@ pub fn wrap_libc_error(result: i32) -> ContainerRuntimeResult<i32> {
     if result >= 0 {
         Ok(result)
     } else {
-        Err(ContainerRuntimeError::Libc(extract_libc_error_message()))
+        Err(ContainerRuntimeError::Libc(std::io::Error::last_os_error()))
     }
 }
 
-pub fn extract_libc_error_message() -> String {
-    unsafe {
-        let error_message = CStr::from_ptr(libc::strerror(*libc::__errno_location()));
-        error_message.to_str().unwrap().to_owned()
+#[test]
+fn test_clone_process_owns_arguments() {
+    let command = ["/bin/sh".to_owned(), "-c".to_owned(), "exit 0".to_owned()];
+    let (mut reader, mut writer) = pipe().unwrap();
+
+    let (pid, pidfd) = clone_process(0).unwrap();
+    if pid == 0 {
+        drop(reader);
+        let result = std::io::Write::write_all(&mut writer, command.join(" ").as_bytes());
+        unsafe {
+            libc::_exit(if result.is_ok() { 0 } else { 1 });
+        }
+    }
+
+    drop(writer);
+    let mut received = String::new();
+    std::io::Read::read_to_string(&mut reader, &mut received).unwrap();
+    assert!(waitpid(pid, 0).unwrap().success());
+    assert_eq!("/bin/sh -c exit 0", received);
+
+    // Refers to the reaped child rather than whatever gets its PID next
+    let pidfd = pidfd.unwrap();
+    assert!(has_exited(&pidfd));
+    assert!(pidfd_send_signal(&pidfd, 0).is_err());
+}
+
+#[test]
+fn test_clone_process_deep_recursion() {
+    fn recurse(depth: usize) -> u64 {
+        let frame = std::hint::black_box([depth as u8; 1024]);
+        if depth == 0 {
+            frame[0] as u64
+        } else {
+            recurse(depth - 1) + frame[1023] as u64
+        }
+    }
+
+    // The child continues on a copy of the stack of the caller, so it can recurse as deep as the caller (at least 4 MiB here, where the old clone stack was 32 KiB)
+    let caller = std::thread::Builder::new().stack_size(16 * 1024 * 1024).spawn(|| {
+        let (pid, _) = clone_process(0).unwrap();
+        if pid == 0 {
+            let depth = recurse(4 * 1024);
+            unsafe {
+                libc::_exit(if depth > 0 { 0 } else { 1 });
+            }
+        }
+
+        waitpid(pid, 0).unwrap()
+    }).unwrap();
+    assert!(caller.join().unwrap().success());
+}
+
+#[test]
+fn test_parse_start_time() {
+    let stat = "4242 (a) b (c)) S 1 4242 4242 0 -1 4194560 1203 0 0 0 2 1 0 0 20 0 1 0 884567 8691712 842 18446744073709551615";
+    assert_eq!(Some(884567), parse_start_time(stat));
+    assert!(process_start_time(std::process::id() as i32).is_ok());
+}
+
+#[test]
+fn test_exec_preserves_arguments() {
+    // Each argument reaches the command as is, without being split or interpreted by a shell
+    let command = ["/bin/sh", "-c", r#"test "$#" = 2 && test "$1" = "a  'b'" && test "$2" = '$HOME;*'"#, "sh", "a  'b'", "$HOME;*"]
+        .map(|part| part.to_owned());
+
+    let pid = fork().unwrap();
+    if pid == 0 {
+        exec(&command).ok();
+        unsafe {
+            libc::_exit(127);
+        }
     }
-}
\ No newline at end of file
+
+    assert!(waitpid(pid, 0).unwrap().success());
+}
+
+#[test]
+fn test_parse_mount_points() {
+    let mountinfo = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+98 22 0:52 / /var/lib/cort/containers/abc/rootfs rw,relatime - overlay overlay rw,lowerdir=/a,upperdir=/b,workdir=/c
+99 98 8:1 /srv/my\\040data /var/lib/cort/containers/abc/rootfs/data rw,relatime - ext4 /dev/sda1 rw
+";
+
+    assert_eq!(
+        vec![
+            PathBuf::from("/"),
+            PathBuf::from("/var/lib/cort/containers/abc/rootfs"),
+            PathBuf::from("/var/lib/cort/containers/abc/rootfs/data")
+        ],
+        parse_mount_points(mountinfo)
+    );
+
+    let mountinfo = "99 98 8:1 / /srv/my\\040data rw - ext4 /dev/sda1 rw\n";
+    assert_eq!(vec![PathBuf::from("/srv/my data")], parse_mount_points(mountinfo));
+}
+
+#[test]
+fn test_mount_points_below() {
+    let mountinfo = "\
+22 1 8:1 / / rw - ext4 /dev/sda1 rw
+98 22 0:52 / /data/abc/rootfs rw - overlay overlay rw
+99 98 8:1 /srv /data/abc/rootfs/srv rw - ext4 /dev/sda1 rw
+100 98 0:53 / /data/abc/rootfs/dev rw - tmpfs tmpfs rw
+101 100 0:54 / /data/abc/rootfs/dev/shm rw - tmpfs shm rw
+102 99 8:1 /other /data/abc/rootfs/srv rw - ext4 /dev/sda1 rw
+103 22 8:1 / /data/abcd rw - ext4 /dev/sda1 rw
+";
+
+    // The deepest first, and stacked mounts the last mounted first
+    assert_eq!(
+        vec![
+            PathBuf::from("/data/abc/rootfs/dev/shm"),
+            PathBuf::from("/data/abc/rootfs/srv"),
+            PathBuf::from("/data/abc/rootfs/dev"),
+            PathBuf::from("/data/abc/rootfs/srv"),
+            PathBuf::from("/data/abc/rootfs")
+        ],
+        mount_points_below(mountinfo, Path::new("/data/abc"))
+    );
+}
+
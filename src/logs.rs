@@ -0,0 +1,491 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use tracing::error;
+use serde::{Deserialize, Serialize};
+
+use crate::model::{ContainerRuntimeError, ContainerRuntimeResult};
+use crate::spec::{LogDriverSpec, RunContainerSpec};
+
+/// The directory in the container root with the json-file log, which is kept after the container has exited.
+pub const LOGS_DIR: &str = "logs";
+
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// The number of json-file logs kept when rotating, unless given by max-file.
+const DEFAULT_MAX_FILE: u32 = 2;
+/// How often a followed log is checked for new entries.
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Syslog facility for system daemons.
+const SYSLOG_FACILITY_DAEMON: u8 = 3;
+pub const SYSLOG_SEVERITY_ERROR: u8 = 3;
+pub const SYSLOG_SEVERITY_WARNING: u8 = 4;
+pub const SYSLOG_SEVERITY_INFO: u8 = 6;
+pub const SYSLOG_SEVERITY_DEBUG: u8 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum LogStream {
+    Stdout,
+    Stderr
+}
+
+impl LogStream {
+    fn syslog_severity(&self) -> u8 {
+        match self {
+            LogStream::Stdout => SYSLOG_SEVERITY_INFO,
+            LogStream::Stderr => SYSLOG_SEVERITY_ERROR
+        }
+    }
+}
+
+/// A line written by the container.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub stream: LogStream,
+    /// When the line was written, in RFC 3339 format
+    pub time: String,
+    pub log: String
+}
+
+impl LogEntry {
+    pub fn new(stream: LogStream, log: String) -> LogEntry {
+        LogEntry {
+            stream,
+            time: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
+            log
+        }
+    }
+}
+
+pub trait LogDriver: Send {
+    fn name(&self) -> &str;
+    fn write(&mut self, entry: &LogEntry) -> ContainerRuntimeResult<()>;
+}
+
+/// Creates the log driver of the container, or none if the output is not captured.
+pub fn create_log_driver(spec: &RunContainerSpec) -> ContainerRuntimeResult<Option<Box<dyn LogDriver>>> {
+    let tag = spec.log.tag.clone().unwrap_or_else(|| spec.name.clone());
+    match spec.log.driver {
+        LogDriverSpec::None => Ok(None),
+        LogDriverSpec::JsonFile => {
+            let max_file = spec.log.max_file.unwrap_or(DEFAULT_MAX_FILE);
+            Ok(Some(Box::new(JsonFileLogDriver::new(&json_file_path(&spec.container_root()), spec.log.max_size, max_file)?)))
+        }
+        LogDriverSpec::Journald => Ok(Some(Box::new(JournaldLogDriver::new(&spec.id, &spec.name, &tag)?))),
+        LogDriverSpec::Syslog => {
+            let address = spec.log.syslog_address.clone().unwrap_or_else(|| PathBuf::from(SYSLOG_SOCKET));
+            Ok(Some(Box::new(SyslogLogDriver::new(&address, &tag)?)))
+        }
+    }
+}
+
+pub fn json_file_path(container_root: &Path) -> PathBuf {
+    container_root.join(LOGS_DIR).join("container.log")
+}
+
+/// Reads the json-file log of a container, oldest entry first.
+pub fn read_json_file(container_root: &Path) -> ContainerRuntimeResult<Vec<LogEntry>> {
+    let mut entries = Vec::new();
+    follow_json_file(container_root, || false, |entry| entries.push(entry))?;
+    Ok(entries)
+}
+
+/// Calls the callback with each entry of the json-file log, oldest first, and then with new entries until `is_running` returns false.
+/// Rotations while reading are followed, so that no entry is missed or repeated.
+pub fn follow_json_file<F: FnMut(LogEntry)>(container_root: &Path, is_running: impl Fn() -> bool, mut on_entry: F) -> ContainerRuntimeResult<()> {
+    let path = json_file_path(container_root);
+
+    // The current file is opened first, so that a rotation while reading the rotated files is noticed
+    let mut current = LogFileReader::open(&path)?;
+    for rotated_path in rotated_paths(&path) {
+        match LogFileReader::open(&rotated_path)? {
+            Some(mut rotated) if !current.as_ref().map(|current| current.is_same_file(&rotated)).unwrap_or(false) => {
+                rotated.read_entries(&mut on_entry)?;
+            }
+            _ => {}
+        }
+    }
+
+    loop {
+        if let Some(current) = current.as_mut() {
+            current.read_entries(&mut on_entry)?;
+        }
+
+        // The path is always replaced by a new file when rotating, after which nothing more is written to the open file
+        let latest = LogFileReader::open(&path)?;
+        let is_rotated = match (&current, &latest) {
+            (Some(current), Some(latest)) => !current.is_same_file(latest),
+            (None, Some(_)) => true,
+            _ => false
+        };
+
+        if is_rotated {
+            if let Some(current) = current.as_mut() {
+                current.read_entries(&mut on_entry)?;
+            }
+            current = latest;
+            continue;
+        }
+
+        // Everything has been written once the container has exited
+        let is_running = is_running();
+        if !is_running {
+            if let Some(current) = current.as_mut() {
+                current.read_entries(&mut on_entry)?;
+            }
+            return Ok(());
+        }
+
+        std::thread::sleep(FOLLOW_POLL_INTERVAL);
+    }
+}
+
+/// The rotated files of the log, oldest first.
+fn rotated_paths(path: &Path) -> Vec<PathBuf> {
+    let mut rotated_paths = (1..)
+        .map(|index| rotated_path(path, index))
+        .take_while(|rotated_path| rotated_path.exists())
+        .collect::<Vec<_>>();
+    rotated_paths.reverse();
+    rotated_paths
+}
+
+fn rotated_path(path: &Path, index: u32) -> PathBuf {
+    let mut rotated_path = path.as_os_str().to_owned();
+    rotated_path.push(format!(".{}", index));
+    PathBuf::from(rotated_path)
+}
+
+/// Reads entries from an open log file, keeping incomplete lines until the rest has been written.
+struct LogFileReader {
+    reader: BufReader<File>,
+    inode: u64,
+    line: String
+}
+
+impl LogFileReader {
+    fn open(path: &Path) -> ContainerRuntimeResult<Option<LogFileReader>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into())
+        };
+
+        Ok(
+            Some(
+                LogFileReader {
+                    inode: file.metadata()?.ino(),
+                    reader: BufReader::new(file),
+                    line: String::new()
+                }
+            )
+        )
+    }
+
+    fn is_same_file(&self, other: &LogFileReader) -> bool {
+        self.inode == other.inode
+    }
+
+    fn read_entries<F: FnMut(LogEntry)>(&mut self, on_entry: &mut F) -> ContainerRuntimeResult<()> {
+        while self.reader.read_line(&mut self.line)? > 0 {
+            if !self.line.ends_with('\n') {
+                break;
+            }
+
+            if !self.line.trim().is_empty() {
+                on_entry(serde_json::from_str(&self.line)?);
+            }
+            self.line.clear();
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes each line as JSON to a file in the container root.
+pub struct JsonFileLogDriver {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: Option<u64>,
+    max_file: u32
+}
+
+impl JsonFileLogDriver {
+    /// The number of files includes the current one, so it must be at least 1.
+    pub fn new(path: &Path, max_size: Option<u64>, max_file: u32) -> ContainerRuntimeResult<JsonFileLogDriver> {
+        if max_file == 0 {
+            return Err(ContainerRuntimeError::Input("Invalid max-file 0 (expected a positive number)".to_owned()));
+        }
+
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        let file = File::options().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(
+            JsonFileLogDriver {
+                path: path.to_owned(),
+                file,
+                size,
+                max_size,
+                max_file
+            }
+        )
+    }
+
+    /// Shifts the rotated files, dropping the oldest, and replaces the current file with a new one.
+    /// The path of the current file always exists, so that readers never miss it.
+    fn rotate(&mut self) -> ContainerRuntimeResult<()> {
+        let rotated_files = self.max_file - 1;
+        if rotated_files > 0 {
+            for index in (1..rotated_files).rev() {
+                let older_path = rotated_path(&self.path, index);
+                if older_path.exists() {
+                    std::fs::rename(&older_path, rotated_path(&self.path, index + 1))?;
+                }
+            }
+
+            let first_rotated_path = rotated_path(&self.path, 1);
+            if first_rotated_path.exists() {
+                std::fs::remove_file(&first_rotated_path)?;
+            }
+            std::fs::hard_link(&self.path, &first_rotated_path)?;
+        }
+
+        let mut new_path = self.path.as_os_str().to_owned();
+        new_path.push(".new");
+        let file = File::options().create(true).truncate(true).write(true).open(&new_path)?;
+        std::fs::rename(&new_path, &self.path)?;
+
+        self.file = file;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl LogDriver for JsonFileLogDriver {
+    fn name(&self) -> &str {
+        "json-file"
+    }
+
+    fn write(&mut self, entry: &LogEntry) -> ContainerRuntimeResult<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        if let Some(max_size) = self.max_size {
+            if self.size > 0 && self.size + line.len() as u64 > max_size {
+                self.rotate()?;
+            }
+        }
+
+        self.file.write_all(&line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Sends each line to the journal using its native protocol, with the container as fields.
+pub struct JournaldLogDriver {
+    socket: UnixDatagram,
+    container_id: String,
+    container_name: String,
+    tag: String
+}
+
+impl JournaldLogDriver {
+    pub fn new(container_id: &str, container_name: &str, tag: &str) -> ContainerRuntimeResult<JournaldLogDriver> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNALD_SOCKET)?;
+
+        Ok(
+            JournaldLogDriver {
+                socket,
+                container_id: container_id.to_owned(),
+                container_name: container_name.to_owned(),
+                tag: tag.to_owned()
+            }
+        )
+    }
+}
+
+impl LogDriver for JournaldLogDriver {
+    fn name(&self) -> &str {
+        "journald"
+    }
+
+    fn write(&mut self, entry: &LogEntry) -> ContainerRuntimeResult<()> {
+        let priority = entry.stream.syslog_severity().to_string();
+        self.socket.send(&journald_message(&[
+            ("PRIORITY", &priority),
+            ("SYSLOG_IDENTIFIER", &self.tag),
+            ("CONTAINER_ID", &self.container_id),
+            ("CONTAINER_NAME", &self.container_name),
+            ("MESSAGE", &entry.log)
+        ]))?;
+        Ok(())
+    }
+}
+
+/// Sends each line to a syslog daemon listening on a unix socket.
+pub struct SyslogLogDriver {
+    socket: UnixDatagram,
+    tag: String
+}
+
+impl SyslogLogDriver {
+    pub fn new(address: &Path, tag: &str) -> ContainerRuntimeResult<SyslogLogDriver> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(address)?;
+
+        Ok(
+            SyslogLogDriver {
+                socket,
+                tag: tag.to_owned()
+            }
+        )
+    }
+}
+
+impl LogDriver for SyslogLogDriver {
+    fn name(&self) -> &str {
+        "syslog"
+    }
+
+    fn write(&mut self, entry: &LogEntry) -> ContainerRuntimeResult<()> {
+        self.socket.send(syslog_message(entry.stream.syslog_severity(), &self.tag, None, &entry.log).as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Encodes the fields as a message of the native journal protocol.
+pub fn journald_message(fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut message = Vec::new();
+    for (name, value) in fields {
+        message.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            // Values with newlines use the binary format
+            message.push(b'\n');
+            message.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            message.push(b'=');
+        }
+        message.extend_from_slice(value.as_bytes());
+        message.push(b'\n');
+    }
+
+    message
+}
+
+/// Formats a message for a local syslog daemon, which adds the timestamp and hostname.
+pub fn syslog_message(severity: u8, tag: &str, pid: Option<u32>, message: &str) -> String {
+    let priority = SYSLOG_FACILITY_DAEMON * 8 + severity;
+    match pid {
+        Some(pid) => format!("<{}>{}[{}]: {}", priority, tag, pid, message),
+        None => format!("<{}>{}: {}", priority, tag, message)
+    }
+}
+
+/// Copies the output of the container to the same stream of the runtime, and sends each line to the log driver.
+pub fn forward_output(mut output: File, stream: LogStream, log_driver: Arc<Mutex<Box<dyn LogDriver>>>) -> JoinHandle<()> {
+    let span = tracing::Span::current();
+    std::thread::spawn(move || {
+        let _span = span.entered();
+        let mut buffer = vec![0u8; 8192];
+        let mut line = Vec::new();
+        let write_line = |line: &[u8]| {
+            let entry = LogEntry::new(stream, String::from_utf8_lossy(line).into_owned());
+            let mut log_driver = log_driver.lock().unwrap();
+            if let Err(err) = log_driver.write(&entry) {
+                error!("Failed to write to the {} log: {}", log_driver.name(), err);
+            }
+        };
+
+        loop {
+            let size = match output.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(size) => size,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    error!("Failed to read the {:?} of the container: {}", stream, err);
+                    break;
+                }
+            };
+
+            // Nothing should be held back from an attached user, such as a prompt without a newline
+            let forwarded = match stream {
+                LogStream::Stdout => std::io::stdout().lock().write_all(&buffer[..size]).and_then(|_| std::io::stdout().flush()),
+                LogStream::Stderr => std::io::stderr().lock().write_all(&buffer[..size])
+            };
+            forwarded.ok();
+
+            line.extend_from_slice(&buffer[..size]);
+            while let Some(end) = line.iter().position(|byte| *byte == b'\n') {
+                write_line(&line[..end]);
+                line.drain(..=end);
+            }
+        }
+
+        if !line.is_empty() {
+            write_line(&line);
+        }
+    })
+}
+
+#[test]
+fn test_json_file_rotation() {
+    let container_root = std::env::temp_dir().join(format!("cort-logs-{}", uuid::Uuid::new_v4()));
+
+    // Each file fits one entry
+    let mut log_driver = JsonFileLogDriver::new(&json_file_path(&container_root), Some(100), 3).unwrap();
+    for index in 0..5 {
+        log_driver.write(&LogEntry::new(LogStream::Stdout, format!("line {}", index))).unwrap();
+    }
+
+    let entries = read_json_file(&container_root).unwrap();
+    let rotated_paths = rotated_paths(&json_file_path(&container_root));
+    std::fs::remove_dir_all(&container_root).unwrap();
+
+    assert_eq!(2, rotated_paths.len());
+    assert_eq!(
+        vec!["line 2", "line 3", "line 4"],
+        entries.iter().map(|entry| entry.log.as_str()).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_json_file_rotation_without_rotated_files() {
+    let container_root = std::env::temp_dir().join(format!("cort-logs-{}", uuid::Uuid::new_v4()));
+    assert!(matches!(JsonFileLogDriver::new(&json_file_path(&container_root), Some(100), 0), Err(ContainerRuntimeError::Input(_))));
+
+    // Only the current file is kept, which is replaced when it is full
+    let mut log_driver = JsonFileLogDriver::new(&json_file_path(&container_root), Some(100), 1).unwrap();
+    for index in 0..3 {
+        log_driver.write(&LogEntry::new(LogStream::Stdout, format!("line {}", index))).unwrap();
+    }
+
+    let entries = read_json_file(&container_root).unwrap();
+    let rotated_paths = rotated_paths(&json_file_path(&container_root));
+    std::fs::remove_dir_all(&container_root).unwrap();
+
+    assert!(rotated_paths.is_empty());
+    assert_eq!(vec!["line 2"], entries.iter().map(|entry| entry.log.as_str()).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_journald_message() {
+    assert_eq!(b"PRIORITY=6\nMESSAGE=hello\n".to_vec(), journald_message(&[("PRIORITY", "6"), ("MESSAGE", "hello")]));
+
+    let mut expected = b"MESSAGE\n".to_vec();
+    expected.extend_from_slice(&3u64.to_le_bytes());
+    expected.extend_from_slice(b"a\nb\n");
+    assert_eq!(expected, journald_message(&[("MESSAGE", "a\nb")]));
+}
@@ -1,105 +1,1273 @@
-use std::path::{ PathBuf};
+use std::fs::File;
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use log::{error, LevelFilter};
-use uuid::Uuid;
+use tracing::{error, Event, Level, span, Subscriber};
+use tracing::field::{Field, Visit};
+use tracing_subscriber::{EnvFilter, layer, Layer, Registry};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::fmt::time::ChronoLocal;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+use serde::Serialize;
+use structopt::clap::{AppSettings, Shell};
 use structopt::StructOpt;
 
-mod model;
-mod spec;
-mod container;
-mod network;
-mod linux;
-mod helpers;
-
-use crate::spec::{BindMountSpec, BridgedNetworkSpec, BridgeSpec, NetworkSpec, RunContainerSpec, UserSpec};
-use crate::model::{ContainerRuntimeResult};
+use container_runtime::{ContainerRuntime, ContainerRuntimeConfig, ContainerRuntimeError, ContainerRuntimeResult};
+use container_runtime::copy::CopyPath;
+use container_runtime::registry::{normalize_registry, ImageReference, Platform};
+use container_runtime::config::{DEFAULT_CONFIG_PATH, LogFormat, LogSink};
+use container_runtime::model::{ContainerExitStatus, signal_name};
+use container_runtime::state::{ContainerFilter, ContainerState, ExecSession, ResourceLimits, StartupTiming};
+use container_runtime::sys::DryRunSysApi;
+use container_runtime::logs::{self, LogEntry, LogStream};
+use container_runtime::metrics;
+use container_runtime::pod::PodNetwork;
+use container_runtime::progress::{NoProgress, ProgressEvent, ProgressReporter, ProgressStage};
+use container_runtime::systemd::ServiceOptions;
+use container_runtime::spec::{parse_labels, parse_size, parse_sysctls, BandwidthSpec, BindMountSpec, CapabilitySpec, CgroupDriverSpec, DNSSpec, EmulationSpec, GpuSpec, ListenFdsSpec, LogDriverSpec, LogSpec, NamespaceMode, NetworkSpec, PullPolicy, RestartPolicy, SchedulingSpec, RunContainerSpec, SecretSpec, SecuritySpec, StorageDriverSpec, TimeNamespaceSpec, TimezoneSpec, UlimitSpec, UserSpec};
 
 fn main() {
     let console_config: ConsoleConfig = ConsoleConfig::from_args();
+    let json_errors = console_config.json;
     if let Err(err) = run(console_config) {
-        error!("Failure: {}", err.to_string());
-        std::process::exit(1);
+        if json_errors {
+            eprintln!("{}", serde_json::to_string(&err.report()).unwrap());
+        } else if !tracing::dispatcher::has_been_set() {
+            // Logging has not been set up, such as when loading the config fails
+            eprintln!("Failure: {}", err);
+        } else {
+            error!("Failure: {}", err);
+        }
+
+        std::process::exit(err.exit_code());
     }
 }
 
 fn run(console_config: ConsoleConfig) -> ContainerRuntimeResult<()> {
-    setup_logging(&console_config).unwrap();
+    if let Command::Completions { shell } = console_config.command {
+        ConsoleConfig::clap().gen_completions_to("cort", shell, &mut std::io::stdout());
+        return Ok(());
+    }
+
+    let mut config = ContainerRuntimeConfig::load_or_default(&console_config.config)?;
+    if let Some(data_dir) = console_config.data_dir.clone() {
+        config.data_dir = data_dir;
+    }
+
+    // The log level given to the command replaces RUST_LOG, which replaces the one of the config
+    let log_filter = console_config.log_level.clone()
+        .or_else(|| std::env::var("RUST_LOG").ok())
+        .or(config.log_level.clone())
+        .unwrap_or_else(|| "info".to_owned());
+    setup_logging(
+        &log_filter,
+        console_config.log_format.unwrap_or(config.log_format),
+        console_config.log_file.as_deref().or(config.log_file.as_deref()),
+        console_config.log_sink.or(config.log_sink)
+    ).map_err(|err| ContainerRuntimeError::Input(format!("Failed to set up logging: {}", err)))?;
+
+    let runtime = ContainerRuntime::new(config);
+
+    match console_config.command {
+        Command::Run(run_config) if run_config.dry_run => print_run_plan(&runtime, run_config),
+        Command::Run(run_config) => {
+            let print_timings = run_config.print_timings;
+            let run_container_spec = create_run_spec(&runtime, run_config)?;
+
+            // Like a shell, the exit code is the one of the container (or 128 + signal when it was killed)
+            let exit_status = runtime.run(&run_container_spec)?;
+            if print_timings {
+                print_startup_timings(&runtime.inspect(&run_container_spec.id)?.startup_timings);
+            }
+            if !exit_status.success() {
+                std::process::exit(exit_status.exit_code());
+            }
+
+            Ok(())
+        }
+        Command::Create(run_config) if run_config.dry_run => print_run_plan(&runtime, run_config),
+        Command::Create(run_config) => {
+            let run_container_spec = create_run_spec(&runtime, run_config)?;
+            runtime.create(&run_container_spec)?;
+            println!("{}", run_container_spec.id);
+            Ok(())
+        }
+        Command::Start { container } => {
+            runtime.start(&container)
+        }
+        Command::Stop { container, timeout } => {
+            runtime.stop(&container, Duration::from_secs_f64(timeout))
+        }
+        Command::Rm { containers, filter } => {
+            if containers.is_empty() && filter.is_empty() {
+                return Err(ContainerRuntimeError::Input("Expected containers or filters".to_owned()));
+            }
+
+            let mut removed = Vec::new();
+            for container in containers {
+                removed.extend(runtime.remove(&container)?);
+            }
+            if !filter.is_empty() {
+                removed.extend(runtime.remove_filtered(&filter)?);
+            }
+
+            for state in removed {
+                println!("{}", state.id);
+            }
+
+            Ok(())
+        }
+        Command::Cp { source, destination } => {
+            match (source, destination) {
+                (CopyPath::Host(host_path), CopyPath::Container(container, container_path)) => {
+                    runtime.copy_to_container(&container, &host_path, &container_path)
+                }
+                (CopyPath::Container(container, container_path), CopyPath::Host(host_path)) => {
+                    runtime.copy_from_container(&container, &container_path, &host_path)
+                }
+                _ => Err(ContainerRuntimeError::Input("Expected exactly one of the paths to be inside a container".to_owned()))
+            }
+        }
+        Command::Export { container, output } => {
+            let output = File::create(output)?;
+            runtime.export(&container, output)
+        }
+        Command::Import { archive, image } => {
+            if archive == Path::new("-") {
+                runtime.import(&image, std::io::stdin().lock(), progress_bar().as_ref())
+            } else {
+                runtime.import(&image, File::open(archive)?, progress_bar().as_ref())
+            }
+        }
+        Command::Pull { reference, name, verify, lazy, platform } => {
+            let name = name.unwrap_or_else(|| reference.local_name());
+            runtime.pull(&reference, &name, verify, lazy, platform, progress_bar().as_ref())
+        }
+        Command::Push { image, reference } => {
+            runtime.push(&image, &reference, progress_bar().as_ref())
+        }
+        Command::Login { registry, username, password_stdin } => {
+            let registry = normalize_registry(&registry);
+            let username = match username {
+                Some(username) => username,
+                None => read_line("Username: ")?
+            };
+            let password = if password_stdin { read_line("")? } else { read_password("Password: ")? };
+
+            let path = runtime.login(registry, &username, &password)?;
+            println!("Login succeeded (credentials stored in {})", path.to_str().unwrap());
+            Ok(())
+        }
+        Command::Logout { registry } => {
+            let registry = normalize_registry(&registry);
+            if !runtime.logout(registry)? {
+                return Err(ContainerRuntimeError::Input(format!("Not logged in to {}", registry)));
+            }
+            Ok(())
+        }
+        Command::Tag { image, target } => {
+            runtime.tag(&image, &target)
+        }
+        Command::Network(NetworkCommand::Inspect { container }) => {
+            print!("{}", runtime.inspect_network(&container)?);
+            Ok(())
+        }
+        Command::Network(NetworkCommand::Connect { network, container }) => {
+            let attachment = runtime.connect_network(&network, &container)?;
+            println!("{} {}", attachment.interface, attachment.ip_address);
+            Ok(())
+        }
+        Command::Network(NetworkCommand::Disconnect { network, container }) => {
+            runtime.disconnect_network(&network, &container)
+        }
+        Command::Pod(PodCommand::Create { name, network, hostname, no_infra }) => {
+            runtime.create_pod(&name, hostname, network, !no_infra)?;
+            Ok(())
+        }
+        Command::Pod(PodCommand::Add { pod, mut run_config }) => {
+            run_config.pod = Some(pod);
+            if run_config.dry_run {
+                return print_run_plan(&runtime, run_config);
+            }
+
+            let run_container_spec = create_run_spec(&runtime, run_config)?;
+            runtime.create(&run_container_spec)?;
+            println!("{}", run_container_spec.id);
+            Ok(())
+        }
+        Command::Pod(PodCommand::Start { pod }) => {
+            runtime.start_pod(&pod)?;
+            Ok(())
+        }
+        Command::Pod(PodCommand::Stop { pod, timeout }) => {
+            runtime.stop_pod(&pod, Duration::from_secs_f64(timeout))?;
+            Ok(())
+        }
+        Command::Pod(PodCommand::Rm { pod }) => {
+            for state in runtime.remove_pod(&pod)? {
+                println!("{}", state.id);
+            }
+
+            Ok(())
+        }
+        Command::Pod(PodCommand::Ls) => {
+            let containers = runtime.list_all()?;
+            println!("{:<20}  {:<20}  {:<20}  {:<10}  INFRA", "NAME", "HOSTNAME", "NETWORK", "CONTAINERS");
+            for pod in runtime.list_pods()? {
+                let states = containers.iter().filter(|state| pod.container_ids().any(|container_id| container_id == &state.id)).collect::<Vec<_>>();
+                let running = states.iter().filter(|state| !state.has_exited() && !state.created).count();
+                let infra = pod.infra.as_deref().unwrap_or("-");
+                println!("{:<20}  {:<20}  {:<20}  {:<10}  {}", pod.name, pod.hostname, pod.network.to_string(), format!("{}/{}", running, states.len()), infra);
+            }
+
+            Ok(())
+        }
+        Command::Rename { container, name } => {
+            runtime.rename(&container, &name)
+        }
+        Command::Pause { container } => {
+            runtime.pause(&container)
+        }
+        Command::Unpause { container } => {
+            runtime.unpause(&container)
+        }
+        Command::Update { container, cpu_shares, cpus, memory, memory_swap, memory_reservation, memory_swappiness } => {
+            let update = ResourceLimits {
+                cpu_shares,
+                cpus,
+                memory: memory.map(|memory| memory as i64),
+                memory_swap: memory_swap.map(|memory_swap| memory_swap as i64),
+                memory_reservation: memory_reservation.map(|memory_reservation| memory_reservation as i64),
+                memory_swappiness
+            };
+
+            runtime.update(&container, &update)?;
+            Ok(())
+        }
+        Command::Top { container, format } => {
+            let processes = runtime.top(&container)?;
+            if format == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&processes)?);
+                return Ok(());
+            }
+
+            println!("{:<8}  {:<13}  {:<12}  COMMAND", "PID", "CONTAINER PID", "USER");
+            for process in processes {
+                println!("{:<8}  {:<13}  {:<12}  {}", process.pid, process.container_pid, process.user, process.command);
+            }
+
+            Ok(())
+        }
+        Command::Exec { interactive, container, command } => {
+            // Like run, the exit code is the one of the command
+            let exit_status = runtime.exec(&container, &command, interactive)?;
+            if !exit_status.success() {
+                std::process::exit(exit_status.exit_code());
+            }
+
+            Ok(())
+        }
+        Command::Inspect { container } => {
+            let state = runtime.inspect(&container)?;
+            let execs = if state.has_exited() {
+                Vec::new()
+            } else {
+                runtime.exec_sessions(&state.id)?.into_iter().filter(|session| session.is_running()).collect()
+            };
+
+            println!("{}", serde_json::to_string_pretty(&ContainerInspectEntry { state, execs })?);
+            Ok(())
+        }
+        Command::Logs { container, follow, timestamps } => {
+            let print_entry = |entry: LogEntry| {
+                let line = if timestamps { format!("{} {}", entry.time, entry.log) } else { entry.log };
+                match entry.stream {
+                    LogStream::Stdout => println!("{}", line),
+                    LogStream::Stderr => eprintln!("{}", line)
+                }
+            };
+
+            if follow {
+                runtime.follow_logs(&container, print_entry)
+            } else {
+                runtime.logs(&container)?.into_iter().for_each(print_entry);
+                Ok(())
+            }
+        }
+        Command::Generate(GenerateCommand::Systemd { container, restart, stop_timeout, listen, files }) => {
+            let cort = vec![
+                std::env::current_exe()?.to_str().unwrap().to_owned(),
+                "--config".to_owned(),
+                std::path::absolute(&console_config.config)?.to_str().unwrap().to_owned(),
+                "--data-dir".to_owned(),
+                runtime.config().data_dir.to_str().unwrap().to_owned()
+            ];
+            let units = runtime.generate_systemd_units(&container, &ServiceOptions { cort, restart, stop_timeout, listen })?;
+
+            for (index, unit) in units.iter().enumerate() {
+                match &files {
+                    Some(files) => {
+                        let path = files.join(&unit.name);
+                        std::fs::write(&path, &unit.content)?;
+                        println!("{}", path.to_str().unwrap());
+                    }
+                    None => {
+                        if index > 0 {
+                            println!();
+                        }
+                        print!("# {}\n{}", unit.name, unit.content);
+                    }
+                }
+            }
+
+            Ok(())
+        }
+        Command::System(SystemCommand::Prune) => {
+            for removed in runtime.prune()? {
+                println!("Removed {}", removed);
+            }
+
+            Ok(())
+        }
+        Command::System(SystemCommand::Restore) => {
+            let (adopted, failed) = runtime.restore()?;
+            for name in adopted {
+                println!("{}", name);
+            }
+
+            if failed > 0 {
+                return Err(ContainerRuntimeError::Restore(format!("{} of the containers could not be adopted", failed)));
+            }
+
+            Ok(())
+        }
+        Command::System(SystemCommand::Shutdown { timeout }) => {
+            for name in runtime.shutdown(Duration::from_secs_f64(timeout))? {
+                println!("{}", name);
+            }
 
-    let base_dir = std::env::current_dir().unwrap();
-    let image_base_dir = base_dir.join("images");
-    let containers_base_dir = base_dir.join("containers");
+            Ok(())
+        }
+        Command::Ps { all, filter, format, execs: true } => {
+            let mut sessions = Vec::new();
+            for state in runtime.list()?.into_iter().filter(|state| ContainerFilter::matches_all(&filter, state)) {
+                for session in runtime.exec_sessions(&state.id)? {
+                    let status = match &session.exit_status {
+                        Some(ContainerExitStatus::Exited { code }) => format!("exited ({})", code),
+                        Some(ContainerExitStatus::Signaled { signal, .. }) => format!("killed ({})", signal_name(*signal)),
+                        _ if session.is_lost() => "exited (unknown)".to_owned(),
+                        _ => "running".to_owned()
+                    };
+
+                    if all || session.is_running() {
+                        sessions.push(ExecListEntry { container: state.name.clone(), session, status });
+                    }
+                }
+            }
+
+            if format == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&sessions)?);
+                return Ok(());
+            }
+
+            println!("{:<20}  {:<12}  {:<8}  {:<12}  {:<16}  COMMAND", "CONTAINER", "EXEC ID", "PID", "TTY", "STATUS");
+            for ExecListEntry { container, session, status } in sessions {
+                println!("{:<20}  {:<12}  {:<8}  {:<12}  {:<16}  {}", container, session.id, session.pid, session.tty.as_deref().unwrap_or("-"), status, session.command.join(" "));
+            }
+
+            Ok(())
+        }
+        Command::Ps { all, filter, format, .. } => {
+            let containers = if all { runtime.list_all()? } else { runtime.list()? };
+            let containers = containers
+                .into_iter()
+                .filter(|state| ContainerFilter::matches_all(&filter, state))
+                .map(|state| {
+                    let status = match state.exit_status {
+                        Some(ContainerExitStatus::Exited { code }) => format!("exited ({})", code),
+                        Some(ContainerExitStatus::Signaled { signal, .. }) => format!("killed ({})", signal_name(signal)),
+                        // Containers that exited before the status was kept
+                        None if state.has_exited() && state.adopted => "exited (unknown)".to_owned(),
+                        None if state.has_exited() => format!("exited ({})", state.exit_code.unwrap_or_default()),
+                        _ if state.created => "created".to_owned(),
+                        _ if runtime.is_paused(&state.id) => "paused".to_owned(),
+                        _ => "running".to_owned()
+                    };
+                    ContainerListEntry { state, status }
+                })
+                .collect::<Vec<_>>();
+
+            if format == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&containers)?);
+                return Ok(());
+            }
+
+            println!("{:<36}  {:<20}  {:<20}  {:<8}  {:<16}  {:<18}  COMMAND", "ID", "NAME", "IMAGE", "PID", "STATUS", "IP ADDRESS");
+            for ContainerListEntry { state, status } in containers {
+                let ip_address = state.ip_address.map(|ip_address| ip_address.to_string()).unwrap_or_else(|| "-".to_owned());
+                println!("{:<36}  {:<20}  {:<20}  {:<8}  {:<16}  {:<18}  {}", state.id, state.name, state.image, state.pid, status, ip_address, state.command.join(" "));
+            }
 
-    let network = match console_config.network {
-        Network::Host => {
-            NetworkSpec::Host
+            Ok(())
         }
-        Network::Bridge => {
-            let bridge = BridgeSpec::default()?;
-            network::create_bridge(&bridge)?;
+        Command::Images { format } => {
+            let images = runtime.images()?;
+            if format == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&images)?);
+                return Ok(());
+            }
 
-            let bridged = BridgedNetworkSpec::from_bridge(&bridge)?
-                .with_hostname(console_config.hostname);
+            println!("{:<20}  {:<20}  {:<40}  {:<19}  {:<14}  SIGNED", "NAME", "TAG", "REFERENCE", "DIGEST", "PLATFORM");
+            for image in images {
+                let digest = &image.manifest.digest[..image.manifest.digest.len().min(19)];
+                let platform = image.platform.as_ref().map(|platform| platform.to_string()).unwrap_or_else(|| "-".to_owned());
+                let signed = if image.signatures.is_empty() { "no" } else { "yes" };
+                println!("{:<20}  {:<20}  {:<40}  {:<19}  {:<14}  {}", image.name, image.tag, image.reference.as_deref().unwrap_or("-"), digest, platform, signed);
+            }
 
-            NetworkSpec::Bridged(bridged)
+            Ok(())
+        }
+        Command::History { image, no_trunc, format } => {
+            let history = runtime.image_history(&image)?;
+            if format == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&history)?);
+                return Ok(());
+            }
+
+            println!("{:<19}  {:<19}  {:<50}  {:>9}  COMMENT", "LAYER", "CREATED", "CREATED BY", "SIZE");
+            for entry in history {
+                let digest = entry.layer.as_ref().map(|layer| &layer.digest[..layer.digest.len().min(19)]).unwrap_or("-");
+                // Without the fractions of seconds and the timezone, which is UTC in practice
+                let created = entry.created.as_deref().map(|created| created.get(..19).unwrap_or(created)).unwrap_or("-");
+                let mut created_by = entry.created_by.unwrap_or_else(|| "-".to_owned()).replace('\n', " ");
+                if !no_trunc && created_by.chars().count() > 50 {
+                    created_by = format!("{}...", created_by.chars().take(47).collect::<String>());
+                }
+                let size = format_bytes(entry.layer.as_ref().map(|layer| layer.size).unwrap_or(0));
+                println!("{:<19}  {:<19}  {:<50}  {:>9}  {}", digest, created, created_by, size, entry.comment.as_deref().unwrap_or(""));
+            }
+
+            Ok(())
+        }
+        Command::Image(ImageCommand::Inspect { image }) => {
+            println!("{}", serde_json::to_string_pretty(&runtime.inspect_image(&image)?)?);
+            Ok(())
+        }
+        Command::Metrics { listen } => {
+            metrics::serve(&runtime, listen)
+        }
+        Command::Completions { .. } => {
+            unreachable!("Completions are generated before the runtime is created")
+        }
+    }
+}
+
+/// Printed to stderr, as stdout has the output of the container.
+fn print_startup_timings(timings: &[StartupTiming]) {
+    eprintln!("{:<16}  {:>10}", "PHASE", "TIME (ms)");
+    for timing in timings {
+        eprintln!("{:<16}  {:>10.1}", timing.phase, timing.milliseconds);
+    }
+    eprintln!("{:<16}  {:>10.1}", "total", timings.iter().map(|timing| timing.milliseconds).sum::<f64>());
+}
+
+/// The spec of the container to run (or create) with the options.
+fn create_run_spec(runtime: &ContainerRuntime, run_config: RunConfig) -> ContainerRuntimeResult<RunContainerSpec> {
+    let (pod, network) = match &run_config.pod {
+        Some(_) if run_config.hostname.is_some() => {
+            return Err(ContainerRuntimeError::Input("The hostname of a container in a pod is the one of the pod".to_owned()));
+        }
+        Some(pod) => {
+            let (pod, network) = runtime.join_pod(pod)?;
+            (Some(pod), network)
+        }
+        None => {
+            let network = match run_config.network {
+                Network::Host => NetworkSpec::Host,
+                Network::Bridge => runtime.bridged_network(run_config.hostname)?,
+                Network::Cni(name) => runtime.cni_network(&name, run_config.hostname)?
+            };
+            (None, network)
         }
     };
+    let network = network.with_bandwidth_limits(run_config.network_bw_limit_ingress, run_config.network_bw_limit_egress)?;
 
-    let id = Uuid::new_v4().to_string();
-    let dns = network.default_dns();
-
-    let run_container_spec = RunContainerSpec {
-        image_base_dir,
-        containers_base_dir,
-        id: id.clone(),
-        name: console_config.name.unwrap_or_else(|| id),
-        image: console_config.image,
-        command: console_config.command,
-        network,
-        dns,
-        user: console_config.user.map(|user| UserSpec::Name(user)),
-        cpu_shares: Some(256),
-        memory: Some(1024 * 1024 * 1024),
-        memory_swap: None,
-        bind_mounts: BindMountSpec::from_paths(console_config.mounts)?
+    let mut command = run_config.image_and_command;
+    let mut run_container_spec = match run_config.rootfs {
+        Some(rootfs) => {
+            // Without an image, all positional arguments are the command
+            let mut run_container_spec = runtime.create_run_spec(rootfs.to_str().unwrap(), command, network);
+            run_container_spec.rootfs = Some(rootfs);
+            run_container_spec
+        }
+        None if command.is_empty() => runtime.create_run_spec("", command, network),
+        None => {
+            let image = command.remove(0);
+            // The separator is kept as part of the command when given after the image
+            if command.first().map(|part| part == "--").unwrap_or(false) {
+                command.remove(0);
+            }
+            runtime.create_run_spec(&image, command, network)
+        }
     };
+    if run_config.shell && !run_container_spec.command.is_empty() {
+        // The arguments are joined like "$*" in a shell, so that operators such as && can be given unquoted
+        run_container_spec.command = vec!["/bin/sh".to_owned(), "-c".to_owned(), run_container_spec.command.join(" ")];
+    }
+    if let Some(name) = run_config.name {
+        run_container_spec.name = name;
+    }
+    run_container_spec.pod = pod;
+    run_container_spec.labels = parse_labels(&run_config.label)?;
+    if !run_config.dns.is_empty() {
+        run_container_spec.dns = DNSSpec::Server(run_config.dns);
+    }
+    run_container_spec.dns_search = run_config.dns_search;
+    run_container_spec.dns_options = run_config.dns_option;
+    run_container_spec.user = run_config.user;
+    run_container_spec.bind_mounts = BindMountSpec::from_paths(run_config.mounts)?;
+    run_container_spec.timezone = run_config.timezone;
+    run_container_spec.read_only = run_config.read_only;
+    if let Some(shm_size) = run_config.shm_size {
+        run_container_spec.shm_size = shm_size;
+    }
+    run_container_spec.interactive = run_config.interactive;
+    run_container_spec.init = run_config.init;
+    run_container_spec.prewarm = run_config.prewarm;
+    run_container_spec.time_startup = run_config.time_startup;
+    run_container_spec.timeout = run_config.timeout;
+    run_container_spec.ulimits = run_config.ulimit;
+    // Relative to where the container is created, as it can be started from elsewhere
+    run_container_spec.secrets = run_config.secret
+        .into_iter()
+        .map(|secret| Ok(SecretSpec { source: std::path::absolute(&secret.source)?, ..secret }))
+        .collect::<ContainerRuntimeResult<_>>()?;
+    run_container_spec.sysctls = parse_sysctls(&run_config.sysctl)?;
+    if let Some(cpus) = run_config.cpus {
+        run_container_spec.cpus = Some(cpus);
+    }
+    if let Some(memory_reservation) = run_config.memory_reservation {
+        run_container_spec.memory_reservation = Some(memory_reservation as i64);
+    }
+    if let Some(memory_swappiness) = run_config.memory_swappiness {
+        run_container_spec.memory_swappiness = Some(memory_swappiness);
+    }
+    if let Some(kernel_memory) = run_config.kernel_memory {
+        run_container_spec.kernel_memory = Some(kernel_memory as i64);
+    }
+    run_container_spec.scheduling = SchedulingSpec::new(run_config.oom_score_adj, run_config.nice, run_config.rt_priority)?;
+    run_container_spec.gpus = run_config.gpus;
+    run_container_spec.ipc_namespace = run_config.ipc;
+    run_container_spec.cgroup_namespace = run_config.cgroupns;
+    run_container_spec.security = SecuritySpec::from_options(&run_config.security_opt)?;
+    run_container_spec.security.keep_capabilities = run_config.keep_caps;
+    run_container_spec.time_namespace = TimeNamespaceSpec::from_offsets(run_config.time_offset_monotonic, run_config.time_offset_boottime);
+    if let Some(storage_driver) = run_config.storage_driver {
+        run_container_spec.storage_driver = storage_driver;
+    }
+    if let Some(cgroup_driver) = run_config.cgroup_driver {
+        run_container_spec.cgroup_driver = cgroup_driver;
+    }
+    let log_driver = run_config.log_driver.unwrap_or(run_container_spec.log.driver);
+    run_container_spec.log = LogSpec::from_options(log_driver, &run_config.log_opt)?;
+    run_container_spec.hooks.add_options(&run_config.hook)?;
+    run_container_spec.verify_signature = run_config.verify;
+    run_container_spec.verify_rootfs = run_config.verify_rootfs;
+    run_container_spec.platform = run_config.platform;
+    if run_config.emulate {
+        run_container_spec.emulation = Some(EmulationSpec { qemu_dir: runtime.config().images.qemu_dir.clone() });
+    }
+    if let Some(pull) = run_config.pull {
+        run_container_spec.pull_policy = pull;
+    }
+    if let Some(spec) = run_config.spec {
+        run_container_spec = run_container_spec.with_file(&spec)?;
+    }
+    run_container_spec.listen_fds = ListenFdsSpec::from_env();
+
+    Ok(run_container_spec)
+}
+
+/// Resolves the spec with a runtime that records the changes to the host instead of making them, as resolving creates the bridge if missing.
+fn print_run_plan(runtime: &ContainerRuntime, run_config: RunConfig) -> ContainerRuntimeResult<()> {
+    let runtime = ContainerRuntime::with_sys(runtime.config().clone(), Arc::new(DryRunSysApi::new()));
+    let run_container_spec = create_run_spec(&runtime, run_config)?;
+    print!("{}", runtime.plan_run(&run_container_spec)?);
+    Ok(())
+}
 
-    container::run(&run_container_spec)
+/// Shows the progress of pulls, pushes and imports on stderr, as a line per blob with a bar, when stderr is a terminal.
+fn progress_bar() -> Box<dyn ProgressReporter> {
+    const WIDTH: u64 = 30;
+
+    if !std::io::stderr().is_terminal() {
+        return Box::new(NoProgress);
+    }
+
+    Box::new(|event: ProgressEvent| {
+        let short_id = |id: &str| id.strip_prefix("sha256:").map(|hex| hex[..hex.len().min(12)].to_owned()).unwrap_or_else(|| id.to_owned());
+        match event {
+            ProgressEvent::Started { id, stage, .. } => {
+                eprint!("\r\x1b[K{}: {}", short_id(&id), stage.name());
+            }
+            ProgressEvent::Advanced { id, stage, current, total: Some(total) } if total > 0 => {
+                let filled = (current.min(total) * WIDTH / total) as usize;
+                eprint!(
+                    "\r\x1b[K{}: {} [{}{}] {}/{}",
+                    short_id(&id), stage.name(), "=".repeat(filled), " ".repeat(WIDTH as usize - filled), format_bytes(current), format_bytes(total)
+                );
+            }
+            ProgressEvent::Advanced { id, stage, current, .. } => {
+                eprint!("\r\x1b[K{}: {} {}", short_id(&id), stage.name(), format_bytes(current));
+            }
+            ProgressEvent::Finished { id, stage } => {
+                eprintln!("\r\x1b[K{}: {} done", short_id(&id), stage.name());
+            }
+            ProgressEvent::Skipped { id, stage: ProgressStage::Download | ProgressStage::Upload } => {
+                eprintln!("\r\x1b[K{}: Already exists", short_id(&id));
+            }
+            ProgressEvent::Skipped { .. } => {}
+        }
+    })
+}
+
+/// Reads a line from stdin, after showing the prompt on a terminal.
+fn read_line(prompt: &str) -> ContainerRuntimeResult<String> {
+    if std::io::stdin().is_terminal() {
+        eprint!("{}", prompt);
+    }
+
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    let line = line.trim_end_matches(['\r', '\n']).to_owned();
+    if line.is_empty() {
+        return Err(ContainerRuntimeError::Input(format!("No {} given", prompt.trim_end_matches(": ").to_lowercase())));
+    }
+
+    Ok(line)
+}
+
+/// Reads a line from the terminal without showing it as typed.
+fn read_password(prompt: &str) -> ContainerRuntimeResult<String> {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        return Err(ContainerRuntimeError::Input("Cannot prompt for the password, use --password-stdin".to_owned()));
+    }
+
+    let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let original = termios;
+    termios.c_lflag &= !libc::ECHO;
+    unsafe {
+        libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios);
+    }
+
+    let password = read_line(prompt);
+    unsafe {
+        libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &original);
+    }
+    eprintln!();
+    password
+}
+
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..=999 => format!("{} B", bytes),
+        1_000..=999_999 => format!("{:.1} kB", bytes as f64 / 1e3),
+        1_000_000..=999_999_999 => format!("{:.1} MB", bytes as f64 / 1e6),
+        _ => format!("{:.1} GB", bytes as f64 / 1e9)
+    }
 }
 
 #[derive(Debug, StructOpt)]
 #[structopt(name="cort", about="Container runtime")]
 struct ConsoleConfig {
-    /// The log level
+    /// The log level, or filter directives such as info,container_runtime::network=trace (replacing RUST_LOG)
+    #[structopt(long)]
+    log_level: Option<String>,
+    /// The format of the log (text or json)
+    #[structopt(long)]
+    log_format: Option<LogFormat>,
+    /// Writes the log to this file instead of stderr (errors are still printed to stderr)
     #[structopt(long)]
-    log_level: Option<LevelFilter>,
+    log_file: Option<PathBuf>,
+    /// Also sends the log to syslog or journald
+    #[structopt(long)]
+    log_sink: Option<LogSink>,
+    /// The config file to use
+    #[structopt(long, default_value=DEFAULT_CONFIG_PATH)]
+    config: PathBuf,
+    /// The directory where images and containers are stored
+    #[structopt(long, alias="root")]
+    data_dir: Option<PathBuf>,
+    /// Prints errors as JSON to stderr
+    #[structopt(long)]
+    json: bool,
+    #[structopt(subcommand)]
+    command: Command
+}
+
+// Only parsed once, so the size of the run options does not matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Runs a new container
+    Run(RunConfig),
+    /// Creates a new container without starting it, taking the same options as run, and prints its id
+    Create(RunConfig),
+    /// Starts a created container, returning once it has executed its command
+    Start {
+        /// The id or name of the container
+        #[structopt()]
+        container: String
+    },
+    /// Stops a running container
+    Stop {
+        /// The id or name of the container
+        #[structopt()]
+        container: String,
+        /// The number of seconds to wait before killing the container
+        #[structopt(long, default_value="10")]
+        timeout: f64
+    },
+    /// Removes exited containers, by id or name or all that match the filters
+    Rm {
+        /// The ids or names of the containers
+        #[structopt()]
+        containers: Vec<String>,
+        /// Removes the exited containers matching the filter (label=<key>[=<value>], name=<name> or id=<id>), where all filters must match
+        #[structopt(short, long, number_of_values=1)]
+        filter: Vec<ContainerFilter>
+    },
+    /// Renames a running container
+    Rename {
+        /// The id or current name of the container
+        #[structopt()]
+        container: String,
+        /// The new name of the container
+        #[structopt()]
+        name: String
+    },
+    /// Pauses all processes of a running container
+    Pause {
+        /// The id or name of the container
+        #[structopt()]
+        container: String
+    },
+    /// Resumes the processes of a paused container
+    Unpause {
+        /// The id or name of the container
+        #[structopt()]
+        container: String
+    },
+    /// Changes the resource limits of a running container, keeping the limits that are not given
+    Update {
+        /// The id or name of the container
+        #[structopt()]
+        container: String,
+        /// The relative share of CPU time of the container
+        #[structopt(long)]
+        cpu_shares: Option<i64>,
+        /// The number of CPUs worth of time the container can use, such as 1.5
+        #[structopt(long)]
+        cpus: Option<f64>,
+        /// The limit of the memory (such as 2g)
+        #[structopt(long, parse(try_from_str=parse_size))]
+        memory: Option<u64>,
+        /// The limit of the memory and swap combined (such as 4g)
+        #[structopt(long, parse(try_from_str=parse_size))]
+        memory_swap: Option<u64>,
+        /// The soft limit of the memory (such as 512m), which the container is reduced to when the host is low on memory
+        #[structopt(long, parse(try_from_str=parse_size))]
+        memory_reservation: Option<u64>,
+        /// How willing the kernel is to swap out memory of the container, from 0 (avoid swapping) to 100
+        #[structopt(long)]
+        memory_swappiness: Option<u8>
+    },
+    /// Lists running containers, or all containers with --all
+    Ps {
+        /// Includes exited containers
+        #[structopt(short, long)]
+        all: bool,
+        /// Only lists containers matching the filter (label=<key>[=<value>], name=<name> or id=<id>), where all filters must match
+        #[structopt(short, long, number_of_values=1)]
+        filter: Vec<ContainerFilter>,
+        /// The output format (table or json)
+        #[structopt(long, default_value="table")]
+        format: OutputFormat,
+        /// Lists the commands executed in the running containers (by exec) instead, including the exited ones with --all
+        #[structopt(long)]
+        execs: bool
+    },
+    /// Lists local images
+    Images {
+        /// The output format (table or json)
+        #[structopt(long, default_value="table")]
+        format: OutputFormat
+    },
+    /// Shows the steps an image was built with, most recent first, along with the layer each created and its size
+    History {
+        /// The local image (name[:tag] or name@sha256:<digest>)
+        #[structopt()]
+        image: String,
+        /// Shows the full commands of the steps instead of truncating them
+        #[structopt(long)]
+        no_trunc: bool,
+        /// The output format (table or json)
+        #[structopt(long, default_value="table")]
+        format: OutputFormat
+    },
+    /// Manages local images
+    Image(ImageCommand),
+    /// Lists the processes running in a container
+    Top {
+        /// The id or name of the container
+        #[structopt()]
+        container: String,
+        /// The output format (table or json)
+        #[structopt(long, default_value="table")]
+        format: OutputFormat
+    },
+    /// Executes a command in a running container as its user, exiting with the exit code of the command
+    #[structopt(setting=AppSettings::TrailingVarArg)]
+    Exec {
+        /// Passes stdin on to the command (which otherwise reads from /dev/null)
+        #[structopt(short, long)]
+        interactive: bool,
+        /// The id or name of the container
+        #[structopt()]
+        container: String,
+        /// The command to execute, which can also be separated from the options by --
+        #[structopt(required=true)]
+        command: Vec<String>
+    },
+    /// Shows the state of a running or exited container as JSON, including the resources used by exited containers
+    Inspect {
+        /// The id or name of the container
+        #[structopt()]
+        container: String
+    },
+    /// Prints the output of a container that uses the json-file log driver
+    Logs {
+        /// The id or name of the container
+        #[structopt()]
+        container: String,
+        /// Keeps printing new output until the container exits
+        #[structopt(long, short)]
+        follow: bool,
+        /// Prefixes each line with the time it was written
+        #[structopt(long, short)]
+        timestamps: bool
+    },
+    /// Copies files between the host and a running container
+    Cp {
+        /// The source path (either host path or container:path)
+        #[structopt()]
+        source: CopyPath,
+        /// The destination path (either host path or container:path)
+        #[structopt()]
+        destination: CopyPath
+    },
+    /// Exports the filesystem of a running container as a tar archive
+    Export {
+        /// The id or name of the container
+        #[structopt()]
+        container: String,
+        /// The file to write the archive to
+        #[structopt(short, long)]
+        output: PathBuf
+    },
+    /// Imports a tar archive of a filesystem as an image
+    Import {
+        /// The archive to import ('-' for stdin)
+        #[structopt()]
+        archive: PathBuf,
+        /// The name of the image (name[:tag])
+        #[structopt()]
+        image: String
+    },
+    /// Pulls an image from a registry
+    Pull {
+        /// The image to pull ([registry/]repository[:tag] or [registry/]repository@sha256:<digest>)
+        #[structopt()]
+        reference: ImageReference,
+        /// The local name of the image, as name[:tag] (defaults to the last part of the repository and the tag)
+        #[structopt(long)]
+        name: Option<String>,
+        /// Verifies the image signature against the configured public keys
+        #[structopt(long)]
+        verify: bool,
+        /// Only pulls the table of contents of eStargz layers, whose files are fetched from the registry when the container first reads them
+        #[structopt(long)]
+        lazy: bool,
+        /// The platform to pull from multi-platform images, such as linux/arm64 or linux/arm/v7 (the one of the host by default)
+        #[structopt(long)]
+        platform: Option<Platform>
+    },
+    /// Adds a tag to a local image, which is moved from the image that had it
+    Tag {
+        /// The local image (name[:tag] or name@sha256:<digest>)
+        #[structopt()]
+        image: String,
+        /// The new name and tag of the image (name[:tag])
+        #[structopt()]
+        target: String
+    },
+    /// Pushes a local image to a registry
+    Push {
+        /// The local image to push
+        #[structopt()]
+        image: String,
+        /// The destination ([registry/]repository[:tag])
+        #[structopt()]
+        reference: ImageReference
+    },
+    /// Logs in to a registry, storing the credentials used to pull from and push to it
+    Login {
+        /// The registry, such as ghcr.io or registry.internal:5000
+        #[structopt(default_value="docker.io")]
+        registry: String,
+        #[structopt(long, short)]
+        username: Option<String>,
+        /// Reads the password (or access token) from stdin instead of prompting for it
+        #[structopt(long)]
+        password_stdin: bool
+    },
+    /// Removes the stored credentials of a registry
+    Logout {
+        #[structopt(default_value="docker.io")]
+        registry: String
+    },
+    /// Manages the networks of containers
+    Network(NetworkCommand),
+    /// Manages pods, whose containers share the network, hostname, IPC and /dev/shm of the pod
+    Pod(PodCommand),
+    /// Manages the runtime itself
+    System(SystemCommand),
+    /// Generates files that run containers with other tools
+    Generate(GenerateCommand),
+    /// Serves metrics of the running containers for Prometheus at /metrics
+    Metrics {
+        /// The address to listen on
+        #[structopt(long, default_value="127.0.0.1:9323")]
+        listen: SocketAddr
+    },
+    /// Generates shell completions (bash, zsh, fish, powershell or elvish)
+    Completions {
+        #[structopt()]
+        shell: Shell
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum NetworkCommand {
+    /// Shows the network configuration of a running container and checks its connectivity
+    Inspect {
+        /// The id or name of the container
+        #[structopt()]
+        container: String
+    },
+    /// Connects a running container to an additional network
+    Connect {
+        /// The name of the network ('default' or one of the configured networks)
+        #[structopt()]
+        network: String,
+        /// The id or name of the container
+        #[structopt()]
+        container: String
+    },
+    /// Disconnects a running container from a network it was connected to
+    Disconnect {
+        /// The name of the network
+        #[structopt()]
+        network: String,
+        /// The id or name of the container
+        #[structopt()]
+        container: String
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum ImageCommand {
+    /// Shows the config of a local image as JSON, such as its environment, entrypoint and history
+    Inspect {
+        /// The local image (name[:tag] or name@sha256:<digest>)
+        #[structopt()]
+        image: String
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, StructOpt)]
+enum PodCommand {
+    /// Creates a pod along with its infra container, which keeps the namespaces of the pod
+    Create {
+        /// The name of the pod
+        #[structopt()]
+        name: String,
+        /// The network of the pod (bridge or cni:<name>)
+        #[structopt(long="net", alias="network", default_value="bridge")]
+        network: PodNetwork,
+        /// The hostname of the pod, which is its name by default
+        #[structopt(long)]
+        hostname: Option<String>,
+        /// Creates the pod without an infra container, where the first container run in the pod creates its namespaces
+        #[structopt(long)]
+        no_infra: bool
+    },
+    /// Creates a container in the pod without starting it, taking the same options as create, and prints its id
+    #[structopt(setting=AppSettings::TrailingVarArg)]
+    Add {
+        /// The name of the pod
+        #[structopt(name="POD")]
+        pod: String,
+        #[structopt(flatten)]
+        run_config: RunConfig
+    },
+    /// Starts the created containers of the pod, its infra container first
+    Start {
+        /// The name of the pod
+        #[structopt()]
+        pod: String
+    },
+    /// Stops the containers of the pod, its infra container last
+    Stop {
+        /// The name of the pod
+        #[structopt()]
+        pod: String,
+        /// The number of seconds to wait before killing each container
+        #[structopt(long, default_value="10")]
+        timeout: f64
+    },
+    /// Removes a stopped pod along with its containers
+    Rm {
+        /// The name of the pod
+        #[structopt()]
+        pod: String
+    },
+    /// Lists the pods
+    Ls
+}
+
+#[derive(Debug, StructOpt)]
+enum GenerateCommand {
+    /// Generates a systemd service that runs a new container from the spec of the container on each start,
+    /// along with a socket unit that activates it with --listen
+    Systemd {
+        /// The id or name of the container
+        #[structopt()]
+        container: String,
+        /// When the service is restarted after the container has exited (no, on-failure[:<max retries>], always or unless-stopped)
+        #[structopt(long, default_value="no")]
+        restart: RestartPolicy,
+        /// The number of seconds to wait before killing the container when the service is stopped
+        #[structopt(long, default_value="10")]
+        stop_timeout: u64,
+        /// An address that the socket unit listens on (such as 8080 or 0.0.0.0:80), where the container gets the sockets from systemd
+        #[structopt(long, number_of_values=1)]
+        listen: Vec<String>,
+        /// Writes the units to files in this directory instead of printing them
+        #[structopt(long)]
+        files: Option<PathBuf>
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum SystemCommand {
+    /// Removes exited containers, leftovers (container roots, cgroups, veth interfaces, network namespaces) of containers that are
+    /// no longer running, iptables rules of removed bridges and unused image layers
+    Prune,
+    /// Adopts the running containers whose runtime has exited (such as after upgrading it), which then have their exits recorded
+    /// and are cleaned up after
+    Restore,
+    /// Stops all running containers, the infra containers of pods last, such as before the host shuts down
+    Shutdown {
+        /// The number of seconds to wait before killing the containers that have not stopped
+        #[structopt(long, default_value="10")]
+        timeout: f64
+    }
+}
+
+// Everything from the command on belongs to the command, so that its flags are not taken as options of run
+#[derive(Debug, StructOpt)]
+#[structopt(setting=AppSettings::TrailingVarArg)]
+struct RunConfig {
     /// The name of the container
     #[structopt(long)]
     name: Option<String>,
-    /// The user to use
+    /// Sets a label (key=value) of the container, which can be used to filter containers
+    #[structopt(short, long, number_of_values=1)]
+    label: Vec<String>,
+    /// The user to use, as <name>, <uid> or <user>:<group> (where either is a name or an id)
     #[structopt(short, long)]
-    user: Option<String>,
+    user: Option<UserSpec>,
     /// The network type to use
-    #[structopt(long="net", default_value="bridge")]
+    #[structopt(long="net", alias="network", default_value="bridge")]
     network: Network,
     /// The hostname to use
     #[structopt(long)]
     hostname: Option<String>,
-    /// The paths to bind mount into the container
+    /// Adds the container to the pod, where it shares the network (instead of using --net), hostname, IPC and /dev/shm of the pod
+    #[structopt(long)]
+    pod: Option<String>,
+    /// A name server of the container, replacing the default ones
+    #[structopt(long, number_of_values=1)]
+    dns: Vec<String>,
+    /// A search domain of the container, replacing those of the host
+    #[structopt(long, number_of_values=1)]
+    dns_search: Vec<String>,
+    /// An option of the resolver of the container, such as ndots:2
+    #[structopt(long, number_of_values=1)]
+    dns_option: Vec<String>,
+    /// Limits the bandwidth received by the container (such as 10mbit)
+    #[structopt(long)]
+    network_bw_limit_ingress: Option<BandwidthSpec>,
+    /// Limits the bandwidth sent by the container (such as 10mbit)
+    #[structopt(long)]
+    network_bw_limit_egress: Option<BandwidthSpec>,
+    /// The paths to bind mount into the container, as source and target pairs (the target can end with :shared, :slave or :private)
     #[structopt(long)]
     mounts: Vec<PathBuf>,
-    /// The image to run
-    #[structopt()]
-    image: String,
-    /// The command to run
-    #[structopt()]
-    command: Vec<String>
+    /// The storage driver to use (overlay, vfs or btrfs)
+    #[structopt(long)]
+    storage_driver: Option<StorageDriverSpec>,
+    /// Whether the runtime writes the cgroups or has systemd create them as a scope in cort.slice (cgroupfs or systemd)
+    #[structopt(long)]
+    cgroup_driver: Option<CgroupDriverSpec>,
+    /// The timezone to use, such as Europe/Stockholm, or 'host' to use the timezone of the host
+    #[structopt(long)]
+    timezone: Option<TimezoneSpec>,
+    /// Mounts the root filesystem of the container as read-only
+    #[structopt(long)]
+    read_only: bool,
+    /// The size of /dev/shm (such as 1g), 64m by default
+    #[structopt(long, parse(try_from_str=parse_size))]
+    shm_size: Option<u64>,
+    /// Passes stdin on to the command (which otherwise reads from /dev/null), such as data piped to cort
+    #[structopt(short, long)]
+    interactive: bool,
+    /// Runs the command under a minimal init that reaps orphaned processes and forwards signals
+    #[structopt(long)]
+    init: bool,
+    /// Reads the executable files of the container into the page cache before running the command, so that large binaries start faster
+    #[structopt(long)]
+    prewarm: bool,
+    /// Logs how long each phase of starting the container took
+    #[structopt(long)]
+    time_startup: bool,
+    /// Prints how long each phase of starting the container took once it has exited (run only)
+    #[structopt(long)]
+    print_timings: bool,
+    /// Kills the command if it has not exited after this many seconds
+    #[structopt(long)]
+    timeout: Option<u64>,
+    /// Sets a resource limit of the command, such as nofile=1024:4096 (soft:hard) or core=unlimited
+    #[structopt(long, number_of_values=1)]
+    ulimit: Vec<UlimitSpec>,
+    /// Gives a file of the host to the container as /run/secrets/<id>, on a tmpfs and readable only by its user, such as id=db,src=./db.txt
+    #[structopt(long, number_of_values=1)]
+    secret: Vec<SecretSpec>,
+    /// Sets a kernel parameter of the namespaces of the container, such as net.ipv4.ip_unprivileged_port_start=0
+    #[structopt(long, number_of_values=1)]
+    sysctl: Vec<String>,
+    /// The number of CPUs worth of time the container can use, such as 1.5
+    #[structopt(long)]
+    cpus: Option<f64>,
+    /// The soft limit of the memory (such as 512m), which the container is reduced to when the host is low on memory
+    #[structopt(long, parse(try_from_str=parse_size))]
+    memory_reservation: Option<u64>,
+    /// How willing the kernel is to swap out memory of the container, from 0 (avoid swapping) to 100
+    #[structopt(long)]
+    memory_swappiness: Option<u8>,
+    /// The limit of the kernel memory (such as 64m), on kernels that account it
+    #[structopt(long, parse(try_from_str=parse_size))]
+    kernel_memory: Option<u64>,
+    /// Adjusts how likely the container is to be killed when out of memory, from -1000 (never) to 1000 (first)
+    #[structopt(long, allow_hyphen_values=true)]
+    oom_score_adj: Option<i32>,
+    /// The scheduling priority of the container, from -20 (highest) to 19 (lowest)
+    #[structopt(long, allow_hyphen_values=true)]
+    nice: Option<i32>,
+    /// Runs the container with the real-time round-robin scheduler at this priority (1 to 99)
+    #[structopt(long)]
+    rt_priority: Option<i32>,
+    /// Passes through GPUs of the host (all or a list such as 0,1), along with the NVIDIA driver libraries
+    #[structopt(long)]
+    gpus: Option<GpuSpec>,
+    /// The IPC namespace to use (private or host)
+    #[structopt(long, default_value="private")]
+    ipc: NamespaceMode,
+    /// The cgroup namespace to use (private or host)
+    #[structopt(long, default_value="private")]
+    cgroupns: NamespaceMode,
+    /// Runs the container in a time namespace where the monotonic clock is offset by this many seconds
+    #[structopt(long, allow_hyphen_values=true)]
+    time_offset_monotonic: Option<i64>,
+    /// Runs the container in a time namespace where the boot time clock (and uptime) is offset by this many seconds
+    #[structopt(long, allow_hyphen_values=true)]
+    time_offset_boottime: Option<i64>,
+    /// Security options, such as apparmor=<profile> or label=<SELinux label>
+    #[structopt(long)]
+    security_opt: Vec<String>,
+    /// A capability (such as NET_BIND_SERVICE) kept when running as a non-root user, which otherwise drops all
+    #[structopt(long, number_of_values=1)]
+    keep_caps: Vec<CapabilitySpec>,
+    /// Where the output of the container is sent (none, json-file, journald or syslog)
+    #[structopt(long)]
+    log_driver: Option<LogDriverSpec>,
+    /// Options of the log driver, such as max-size=10m (json-file) or tag=<tag> (journald and syslog)
+    #[structopt(long)]
+    log_opt: Vec<String>,
+    /// Runs an executable at a point in the lifecycle of the container, as prestart=<path>, poststart=<path> or poststop=<path>
+    #[structopt(long)]
+    hook: Vec<String>,
+    /// Verifies the image signature against the configured public keys
+    #[structopt(long)]
+    verify: bool,
+    /// Refuses to run the image if the files of its layers have been changed on disk since they were extracted
+    #[structopt(long)]
+    verify_rootfs: bool,
+    /// When to pull the image from its registry before running it (always, missing or never)
+    #[structopt(long)]
+    pull: Option<PullPolicy>,
+    /// The platform of the image to pull and run, such as linux/arm64 (the one of the host by default)
+    #[structopt(long)]
+    platform: Option<Platform>,
+    /// Runs an image of another architecture with the QEMU user mode emulator (qemu-<architecture>-static), registering its binfmt_misc handler if needed
+    #[structopt(long)]
+    emulate: bool,
+    /// Runs a directory as the root filesystem instead of an image
+    #[structopt(long)]
+    rootfs: Option<PathBuf>,
+    /// Runs the command with /bin/sh -c of the image, such as 'cd /app && make'
+    #[structopt(long)]
+    shell: bool,
+    /// Reads the spec of the container from a YAML (or JSON) file, where the fields in the file replace those from the other options
+    #[structopt(long)]
+    spec: Option<PathBuf>,
+    /// Prints the mounts, namespaces, cgroup writes and network changes that running the container would make, without making them
+    #[structopt(long)]
+    dry_run: bool,
+    /// The image to run followed by the command, where the image is omitted when using --rootfs or given by --spec.
+    /// Everything after the image belongs to the command, which can also be separated from the options by --
+    #[structopt(required_unless="spec")]
+    image_and_command: Vec<String>
 }
 
 #[derive(Debug)]
 enum Network {
     Host,
-    Bridge
+    Bridge,
+    Cni(String)
 }
 
 impl FromStr for Network {
@@ -109,24 +1277,201 @@ impl FromStr for Network {
         match text {
             "host" => Ok(Network::Host),
             "bridge" => Ok(Network::Bridge),
-            _ => Err("Invalid network mode.".to_owned())
+            _ => match text.strip_prefix("cni:") {
+                Some(name) if !name.is_empty() => Ok(Network::Cni(name.to_owned())),
+                _ => Err("Invalid network mode (expected host, bridge or cni:<name>).".to_owned())
+            }
         }
     }
 }
 
-fn setup_logging(console_config: &ConsoleConfig) -> Result<(), log::SetLoggerError> {
-    fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "{}[{}][{}] {}",
-                chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S.%f]"),
-                record.target(),
-                record.level(),
-                message
-            ))
-        })
-        .level(console_config.log_level.unwrap_or(LevelFilter::Debug))
-        .chain(std::io::stdout())
-        .apply()?;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Table,
+    Json
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err("Invalid output format.".to_owned())
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ContainerListEntry {
+    #[serde(flatten)]
+    state: ContainerState,
+    status: String
+}
+
+#[derive(Serialize)]
+struct ExecListEntry {
+    container: String,
+    #[serde(flatten)]
+    session: ExecSession,
+    status: String
+}
+
+/// The state of a container along with the commands running in it by exec.
+#[derive(Serialize)]
+struct ContainerInspectEntry {
+    #[serde(flatten)]
+    state: ContainerState,
+    #[serde(skip_serializing_if="Vec::is_empty")]
+    execs: Vec<ExecSession>
+}
+
+fn setup_logging(log_filter: &str, log_format: LogFormat, log_file: Option<&Path>, log_sink: Option<LogSink>) -> Result<(), Box<dyn std::error::Error>> {
+    let filter = EnvFilter::try_new(log_filter)?;
+
+    // The log goes to stderr so that it never interleaves with the output of the container
+    let stderr_level = if log_file.is_some() || log_sink.is_some() { LevelFilter::ERROR } else { LevelFilter::TRACE };
+    let mut layers = vec![format_layer(log_format, std::io::stderr).with_filter(stderr_level).boxed()];
+
+    if let Some(log_file) = log_file {
+        let log_file = std::fs::OpenOptions::new().create(true).append(true).open(log_file)?;
+        layers.push(format_layer(log_format, Mutex::new(log_file)));
+    }
+
+    match log_sink {
+        Some(LogSink::Syslog) => {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect("/dev/log")?;
+            layers.push(SinkLayer { socket, sink: LogSink::Syslog }.boxed());
+        }
+        Some(LogSink::Journald) => {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect("/run/systemd/journal/socket")?;
+            layers.push(SinkLayer { socket, sink: LogSink::Journald }.boxed());
+        }
+        None => {}
+    }
+
+    tracing_subscriber::registry().with(layers).with(filter).try_init()?;
     Ok(())
-}
\ No newline at end of file
+}
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Each line has the fields of the containers the event belongs to, such as `container{container_id=... container_name=web}`.
+fn format_layer<W>(log_format: LogFormat, writer: W) -> BoxedLayer where W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static {
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer).with_ansi(false);
+    match log_format {
+        LogFormat::Text => layer.with_timer(ChronoLocal::new("[%Y-%m-%d][%H:%M:%S%.f]".to_owned())).boxed(),
+        LogFormat::Json => layer.with_timer(ChronoLocal::rfc_3339()).json().with_span_list(false).boxed()
+    }
+}
+
+/// Sends the log to syslog or journald, where the container an event belongs to is given by the fields of its span.
+struct SinkLayer {
+    socket: UnixDatagram,
+    sink: LogSink
+}
+
+/// The fields of a span, kept in its extensions.
+struct SpanFields(Vec<(String, String)>);
+
+impl<S> Layer<S> for SinkLayer where S: Subscriber + for<'span> LookupSpan<'span> {
+    fn on_new_span(&self, attributes: &span::Attributes<'_>, id: &span::Id, context: layer::Context<'_, S>) {
+        let mut fields = FieldVisitor::default();
+        attributes.record(&mut fields);
+        if let Some(span) = context.span(id) {
+            span.extensions_mut().insert(SpanFields(fields.fields));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, context: layer::Context<'_, S>) {
+        let mut fields = FieldVisitor::default();
+        event.record(&mut fields);
+        for span in context.event_scope(event).into_iter().flat_map(|scope| scope.from_root()) {
+            if let Some(span_fields) = span.extensions().get::<SpanFields>() {
+                fields.fields.extend(span_fields.0.iter().cloned());
+            }
+        }
+
+        let metadata = event.metadata();
+        let _ = match self.sink {
+            LogSink::Syslog => self.socket.send(syslog_message(metadata.level(), &fields).as_bytes()),
+            LogSink::Journald => self.socket.send(&journald_message(metadata.level(), metadata.target(), &fields))
+        };
+    }
+}
+
+#[derive(Default)]
+struct FieldVisitor {
+    message: String,
+    fields: Vec<(String, String)>
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{}", value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{:?}", value);
+        } else {
+            self.fields.push((field.name().to_owned(), format!("{:?}", value)));
+        }
+    }
+}
+
+fn syslog_severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => logs::SYSLOG_SEVERITY_ERROR,
+        Level::WARN => logs::SYSLOG_SEVERITY_WARNING,
+        Level::INFO => logs::SYSLOG_SEVERITY_INFO,
+        Level::DEBUG | Level::TRACE => logs::SYSLOG_SEVERITY_DEBUG
+    }
+}
+
+/// The fields follow the message, such as `Stopped container web. container_id=... container_name=web`.
+fn syslog_message(level: &Level, fields: &FieldVisitor) -> String {
+    let mut message = fields.message.clone();
+    for (name, value) in &fields.fields {
+        message.push_str(&format!(" {}={}", name, value));
+    }
+
+    logs::syslog_message(syslog_severity(level), "cort", Some(std::process::id()), &message)
+}
+
+/// The fields are added in upper case, such as CONTAINER_ID and CONTAINER_NAME.
+fn journald_message(level: &Level, target: &str, fields: &FieldVisitor) -> Vec<u8> {
+    let priority = syslog_severity(level).to_string();
+    let pid = std::process::id().to_string();
+    let field_names = fields.fields.iter().map(|(name, _)| name.to_uppercase()).collect::<Vec<_>>();
+
+    let mut journald_fields = vec![
+        ("PRIORITY", priority.as_str()),
+        ("SYSLOG_IDENTIFIER", "cort"),
+        ("SYSLOG_PID", pid.as_str()),
+        ("CODE_MODULE", target),
+        ("MESSAGE", fields.message.as_str())
+    ];
+    journald_fields.extend(field_names.iter().zip(&fields.fields).map(|(name, (_, value))| (name.as_str(), value.as_str())));
+    logs::journald_message(&journald_fields)
+}
+
+#[test]
+fn test_sink_layer() {
+    let (socket, receiver) = UnixDatagram::pair().unwrap();
+    let subscriber = tracing_subscriber::registry().with(SinkLayer { socket, sink: LogSink::Journald });
+    tracing::subscriber::with_default(subscriber, || {
+        let _span = tracing::info_span!("container", container_id = "0f4e2b9c", container_name = "web").entered();
+        tracing::info!("Stopped container {}.", "web");
+    });
+
+    let mut buffer = vec![0; 4096];
+    let size = receiver.recv(&mut buffer).unwrap();
+    let message = String::from_utf8_lossy(&buffer[..size]);
+    assert!(message.contains("MESSAGE=Stopped container web.\n"));
+    assert!(message.contains("PRIORITY=6\n"));
+    assert!(message.contains("CONTAINER_ID=0f4e2b9c\nCONTAINER_NAME=web\n"));
+}
@@ -0,0 +1,210 @@
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::container;
+use crate::model::{ContainerRuntimeError, ContainerRuntimeResult};
+use crate::network;
+use crate::network::VethPair;
+use crate::runtime::ContainerRuntime;
+use crate::state::{ContainerState, ResourceUsage};
+
+/// How long a client may take to send its request or read the response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Metrics of a running container, gathered from its cgroups and the host end of its veth pairs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContainerMetrics {
+    pub id: String,
+    pub name: String,
+    pub image: String,
+    pub usage: ResourceUsage,
+    pub memory_usage: u64,
+    pub networks: Vec<NetworkMetrics>
+}
+
+/// Traffic of the container on one network, as seen from the container.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkMetrics {
+    pub network: String,
+    pub receive_bytes: u64,
+    pub transmit_bytes: u64
+}
+
+impl ContainerMetrics {
+    pub fn collect(state: &ContainerState) -> ContainerRuntimeResult<ContainerMetrics> {
+        let mut interfaces = vec![("default".to_owned(), VethPair::for_container(&state.id).host_interface)];
+        interfaces.extend(state.networks.iter().map(|attachment| (attachment.network.clone(), attachment.host_interface.clone())));
+
+        let mut networks = Vec::new();
+        for (network, host_interface) in interfaces {
+            // Containers using the host network have no veth pair
+            if let Ok((host_receive_bytes, host_transmit_bytes)) = network::interface_statistics(&host_interface) {
+                networks.push(NetworkMetrics {
+                    network,
+                    receive_bytes: host_transmit_bytes,
+                    transmit_bytes: host_receive_bytes
+                });
+            }
+        }
+
+        Ok(
+            ContainerMetrics {
+                id: state.id.clone(),
+                name: state.name.clone(),
+                image: state.image.clone(),
+                usage: container::read_resource_usage(&state.id)?,
+                memory_usage: container::read_memory_usage(&state.id)?,
+                networks
+            }
+        )
+    }
+}
+
+/// Renders the metrics in the Prometheus text format.
+pub fn render(containers: &[ContainerMetrics]) -> String {
+    let mut output = String::new();
+    let mut family = |name: &str, metric_type: &str, help: &str, samples: Vec<(String, String)>| {
+        writeln!(output, "# HELP {} {}", name, help).unwrap();
+        writeln!(output, "# TYPE {} {}", name, metric_type).unwrap();
+        for (labels, value) in samples {
+            if labels.is_empty() {
+                writeln!(output, "{} {}", name, value).unwrap();
+            } else {
+                writeln!(output, "{}{{{}}} {}", name, labels, value).unwrap();
+            }
+        }
+    };
+
+    let labels = |container: &ContainerMetrics| {
+        format!(
+            "id=\"{}\",name=\"{}\",image=\"{}\"",
+            escape_label(&container.id),
+            escape_label(&container.name),
+            escape_label(&container.image)
+        )
+    };
+    let container_samples = |value: &dyn Fn(&ContainerMetrics) -> String| {
+        containers.iter().map(|container| (labels(container), value(container))).collect::<Vec<_>>()
+    };
+    let network_samples = |value: &dyn Fn(&NetworkMetrics) -> u64| {
+        containers
+            .iter()
+            .flat_map(|container| {
+                container.networks.iter().map(move |network| {
+                    (format!("{},network=\"{}\"", labels(container), escape_label(&network.network)), value(network).to_string())
+                })
+            })
+            .collect::<Vec<_>>()
+    };
+
+    family("cort_containers_running", "gauge", "Number of running containers.", vec![(String::new(), containers.len().to_string())]);
+    family("cort_container_cpu_seconds_total", "counter", "Total CPU time used by the container.", container_samples(&|container| container.usage.cpu_seconds.to_string()));
+    family("cort_container_memory_usage_bytes", "gauge", "Current memory usage of the container, including the page cache.", container_samples(&|container| container.memory_usage.to_string()));
+    family("cort_container_memory_peak_bytes", "gauge", "Peak memory usage of the container.", container_samples(&|container| container.usage.memory_peak.to_string()));
+    family("cort_container_io_read_bytes_total", "counter", "Bytes read from block devices by the container.", container_samples(&|container| container.usage.io_read_bytes.to_string()));
+    family("cort_container_io_write_bytes_total", "counter", "Bytes written to block devices by the container.", container_samples(&|container| container.usage.io_write_bytes.to_string()));
+    family("cort_container_network_receive_bytes_total", "counter", "Bytes received by the container on a network.", network_samples(&|network| network.receive_bytes));
+    family("cort_container_network_transmit_bytes_total", "counter", "Bytes transmitted by the container on a network.", network_samples(&|network| network.transmit_bytes));
+
+    output
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Serves the metrics of the running containers at /metrics until the process is stopped.
+pub fn serve(runtime: &ContainerRuntime, address: SocketAddr) -> ContainerRuntimeResult<()> {
+    let listener = TcpListener::bind(address).map_err(|err| ContainerRuntimeError::Metrics(format!("Failed to listen on {}: {}", address, err)))?;
+    info!("Serving metrics at http://{}/metrics", listener.local_addr()?);
+
+    for stream in listener.incoming() {
+        let result = stream
+            .map_err(ContainerRuntimeError::from)
+            .and_then(|stream| handle_request(runtime, stream));
+
+        if let Err(err) = result {
+            warn!("Failed to handle metrics request: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Requests are handled one at a time, so a client that stops sending (or reading) is dropped rather than stalling the others.
+fn handle_request(runtime: &ContainerRuntime, mut stream: TcpStream) -> ContainerRuntimeResult<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // The headers are not used, but are read so that the client does not see a reset connection
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim() != "" {
+        header.clear();
+    }
+
+    let (status, body) = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["GET", "/metrics", _] => {
+            let mut containers = Vec::new();
+            for state in runtime.list()? {
+                match ContainerMetrics::collect(&state) {
+                    Ok(metrics) => containers.push(metrics),
+                    // The container might have exited since it was listed
+                    Err(err) => warn!("Failed to collect metrics of {}: {}", state.name, err)
+                }
+            }
+
+            ("200 OK", render(&containers))
+        }
+        ["GET", _, _] => ("404 Not Found", "Not found\n".to_owned()),
+        _ => ("405 Method Not Allowed", "Method not allowed\n".to_owned())
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+#[test]
+fn test_render() {
+    let containers = vec![
+        ContainerMetrics {
+            id: "28170ceb".to_owned(),
+            name: "brave_otter".to_owned(),
+            image: "ubuntu".to_owned(),
+            usage: ResourceUsage {
+                cpu_seconds: 1.5,
+                memory_peak: 2048,
+                io_read_bytes: 10,
+                io_write_bytes: 20
+            },
+            memory_usage: 1024,
+            networks: vec![
+                NetworkMetrics {
+                    network: "default".to_owned(),
+                    receive_bytes: 100,
+                    transmit_bytes: 200
+                }
+            ]
+        }
+    ];
+
+    let output = render(&containers);
+    assert!(output.contains("cort_containers_running 1\n"));
+    assert!(output.contains("# TYPE cort_container_cpu_seconds_total counter\n"));
+    assert!(output.contains("cort_container_cpu_seconds_total{id=\"28170ceb\",name=\"brave_otter\",image=\"ubuntu\"} 1.5\n"));
+    assert!(output.contains("cort_container_network_receive_bytes_total{id=\"28170ceb\",name=\"brave_otter\",image=\"ubuntu\",network=\"default\"} 100\n"));
+    assert_eq!("a\\\"b\\\\c", escape_label("a\"b\\c"));
+}
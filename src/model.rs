@@ -4,41 +4,88 @@ use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::spec::UserSpec;
 
 #[derive(Error, Debug)]
 pub enum ContainerRuntimeError {
     #[error("{0}")]
     Input(String),
+    #[error("Invalid container spec: {}", .0.join("; "))]
+    InvalidSpec(Vec<String>),
+    #[error("Invalid config: {0}")]
+    Config(String),
 
     #[error("Failed to create network bridge: {0}")]
     CreateNetworkBridge(String),
-    #[error("Failed to create network namespace: {0}")]
-    CreateNetworkNamespace(String),
-    #[error("Failed to destroy network namespace: {0}")]
-    DestroyNetworkNamespace(String),
+    #[error("Failed to create container network: {0}")]
+    CreateContainerNetwork(String),
 
     #[error("Failed to setup cpu cgroup: {0}")]
     SetupCpuCgroup(String),
     #[error("Failed to setup memory cgroup: {0}")]
     SetupMemoryCgroup(String),
+    #[error("Failed to update resources: {0}")]
+    UpdateResources(String),
     #[error("Failed to setup network stack: {0}")]
     SetupNetwork(String),
     #[error("Failed to setup DNS: {0}")]
     SetupDNS(String),
+    #[error("Failed to setup hosts: {0}")]
+    SetupHosts(String),
+    #[error("Failed to setup time namespace: {0}")]
+    SetupTimeNamespace(String),
+    #[error("Failed to setup security labels: {0}")]
+    SetupSecurity(String),
     #[error("Failed to setup user: {0}")]
     SetupUser(String),
+    #[error("Failed to setup resource limits: {0}")]
+    SetupResourceLimits(String),
+    #[error("Failed to setup sysctl: {0}")]
+    SetupSysctl(String),
+    #[error("Failed to setup scheduling: {0}")]
+    SetupScheduling(String),
     #[error("Failed to setup container root: {0}")]
     SetupContainerRoot(String),
     #[error("Failed to setup mounts: {0}")]
     SetupMounts(String),
     #[error("Failed to setup devices: {0}")]
     SetupDevices(String),
+    #[error("Failed to setup GPUs: {0}")]
+    SetupGpus(String),
+    #[error("Failed to setup secrets: {0}")]
+    SetupSecrets(String),
+    #[error("Storage driver failure: {0}")]
+    StorageDriver(String),
+    #[error("Container setup failed: {0}")]
+    ContainerSetup(String),
+    #[error("Container setup failed: {}", .0.message)]
+    ContainerProcess(ErrorReport),
 
-    #[error("User not found: {0:?}")]
-    InvalidUser(UserSpec),
+    #[error("Failed to pause or resume container: {0}")]
+    Freezer(String),
+    #[error("Container not found: {0}")]
+    ContainerNotFound(String),
+    #[error("Network not found: {0}")]
+    NetworkNotFound(String),
+    #[error("Failed to attach network: {0}")]
+    AttachNetwork(String),
+    #[error("Container already exists: {0}")]
+    ContainerAlreadyExists(String),
+    #[error("Container is running: {0}")]
+    ContainerRunning(String),
+    #[error("Pod not found: {0}")]
+    PodNotFound(String),
+    #[error("Pod already exists: {0}")]
+    PodAlreadyExists(String),
+    #[error("Failed to access container state: {0}")]
+    State(String),
+    #[error("Image not found: {0}")]
+    ImageNotFound(String),
+
+    #[error("Invalid user: {0}")]
+    InvalidUser(String),
     #[error("No free IP address found in network")]
     NetworkIsFull,
     #[error("Failed to determine internet interface: {0}")]
@@ -48,28 +95,352 @@ pub enum ContainerRuntimeError {
     IPCommand(String),
     #[error("IPTables command failure: {0}")]
     IPTablesCommand(String),
+    #[error("TC command failure: {0}")]
+    TCCommand(String),
+    #[error("DHCP failure: {0}")]
+    Dhcp(String),
+    #[error("CNI failure: {0}")]
+    Cni(String),
+    #[error("Network diagnostics failed: {0}")]
+    Diagnostics(String),
     #[error("Failed to mount: {0}")]
-    Mount(String),
+    Mount(#[source] std::io::Error),
+    #[error("Command not found: {command}")]
+    CommandNotFound { command: String },
     #[error("Failed to execute: {0}")]
-    Execute(String),
+    Execute(#[source] std::io::Error),
+    #[error("Failed to copy: {0}")]
+    Copy(String),
+    #[error("Failed to export container: {0}")]
+    Export(String),
+    #[error("Failed to import image: {0}")]
+    Import(String),
+    #[error("Failed to pull image: {0}")]
+    Pull(String),
+    #[error("Failed to push image: {0}")]
+    Push(String),
+    #[error("Registry failure: {0}")]
+    Registry(String),
+    #[error("Credentials failure: {0}")]
+    Credentials(String),
+    #[error("Platform mismatch: {0}")]
+    Platform(String),
+    #[error("Emulation failure: {0}")]
+    Emulation(String),
+    #[error("systemd failure: {0}")]
+    Systemd(String),
+    #[error("Signature verification failed: {0}")]
+    Signature(String),
+    #[error("Rootfs integrity check failed: {0}")]
+    RootfsIntegrity(String),
+    #[error("Lazy pulling failed: {0}")]
+    LazyPull(String),
+    #[error("Metrics endpoint failure: {0}")]
+    Metrics(String),
+    #[error("Hook failed: {0}")]
+    Hook(String),
+    #[error("Timed out: {0}")]
+    Timeout(String),
+    #[error("Failed to restore containers: {0}")]
+    Restore(String),
 
     #[error("I/O error: {0}")]
     IO(#[from] std::io::Error),
+    #[error("JSON error: {0}")]
+    JSON(#[from] serde_json::Error),
     #[error("Libc error: {0}")]
-    Libc(String)
+    Libc(#[source] std::io::Error)
 }
 
 pub type ContainerRuntimeResult<T> = Result<T, ContainerRuntimeError>;
 
-#[derive(Debug, Clone)]
+/// Exit code when the runtime itself fails.
+pub const EXIT_CODE_RUNTIME_ERROR: i32 = 125;
+/// Exit code when the command exists but cannot be executed.
+pub const EXIT_CODE_NOT_EXECUTABLE: i32 = 126;
+/// Exit code when the command does not exist.
+pub const EXIT_CODE_NOT_FOUND: i32 = 127;
+
+impl ContainerRuntimeError {
+    /// Stable identifier of the kind of error, for scripts.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ContainerRuntimeError::Input(_) => "input",
+            ContainerRuntimeError::InvalidSpec(_) => "invalid_spec",
+            ContainerRuntimeError::Config(_) => "config",
+            ContainerRuntimeError::CreateNetworkBridge(_) => "create_network_bridge",
+            ContainerRuntimeError::CreateContainerNetwork(_) => "create_container_network",
+            ContainerRuntimeError::SetupCpuCgroup(_) => "setup_cpu_cgroup",
+            ContainerRuntimeError::SetupMemoryCgroup(_) => "setup_memory_cgroup",
+            ContainerRuntimeError::UpdateResources(_) => "update_resources",
+            ContainerRuntimeError::SetupNetwork(_) => "setup_network",
+            ContainerRuntimeError::SetupDNS(_) => "setup_dns",
+            ContainerRuntimeError::SetupHosts(_) => "setup_hosts",
+            ContainerRuntimeError::SetupTimeNamespace(_) => "setup_time_namespace",
+            ContainerRuntimeError::SetupSecurity(_) => "setup_security",
+            ContainerRuntimeError::SetupUser(_) => "setup_user",
+            ContainerRuntimeError::SetupResourceLimits(_) => "setup_resource_limits",
+            ContainerRuntimeError::SetupSysctl(_) => "setup_sysctl",
+            ContainerRuntimeError::SetupScheduling(_) => "setup_scheduling",
+            ContainerRuntimeError::SetupContainerRoot(_) => "setup_container_root",
+            ContainerRuntimeError::SetupMounts(_) => "setup_mounts",
+            ContainerRuntimeError::SetupDevices(_) => "setup_devices",
+            ContainerRuntimeError::SetupGpus(_) => "setup_gpus",
+            ContainerRuntimeError::SetupSecrets(_) => "setup_secrets",
+            ContainerRuntimeError::StorageDriver(_) => "storage_driver",
+            ContainerRuntimeError::ContainerSetup(_) => "container_setup",
+            ContainerRuntimeError::ContainerProcess(_) => "container_process",
+            ContainerRuntimeError::Freezer(_) => "freezer",
+            ContainerRuntimeError::ContainerNotFound(_) => "container_not_found",
+            ContainerRuntimeError::NetworkNotFound(_) => "network_not_found",
+            ContainerRuntimeError::AttachNetwork(_) => "attach_network",
+            ContainerRuntimeError::ContainerAlreadyExists(_) => "container_already_exists",
+            ContainerRuntimeError::ContainerRunning(_) => "container_running",
+            ContainerRuntimeError::PodNotFound(_) => "pod_not_found",
+            ContainerRuntimeError::PodAlreadyExists(_) => "pod_already_exists",
+            ContainerRuntimeError::State(_) => "state",
+            ContainerRuntimeError::ImageNotFound(_) => "image_not_found",
+            ContainerRuntimeError::InvalidUser(_) => "invalid_user",
+            ContainerRuntimeError::NetworkIsFull => "network_is_full",
+            ContainerRuntimeError::FailedToDetermineInternetInterface(_) => "internet_interface",
+            ContainerRuntimeError::IPCommand(_) => "ip_command",
+            ContainerRuntimeError::IPTablesCommand(_) => "iptables_command",
+            ContainerRuntimeError::TCCommand(_) => "tc_command",
+            ContainerRuntimeError::Dhcp(_) => "dhcp",
+            ContainerRuntimeError::Cni(_) => "cni",
+            ContainerRuntimeError::Diagnostics(_) => "diagnostics",
+            ContainerRuntimeError::Mount(_) => "mount",
+            ContainerRuntimeError::CommandNotFound { .. } => "command_not_found",
+            ContainerRuntimeError::Execute(_) => "execute",
+            ContainerRuntimeError::Copy(_) => "copy",
+            ContainerRuntimeError::Export(_) => "export",
+            ContainerRuntimeError::Import(_) => "import",
+            ContainerRuntimeError::Pull(_) => "pull",
+            ContainerRuntimeError::Push(_) => "push",
+            ContainerRuntimeError::Registry(_) => "registry",
+            ContainerRuntimeError::Credentials(_) => "credentials",
+            ContainerRuntimeError::Platform(_) => "platform",
+            ContainerRuntimeError::Emulation(_) => "emulation",
+            ContainerRuntimeError::Systemd(_) => "systemd",
+            ContainerRuntimeError::Signature(_) => "signature",
+            ContainerRuntimeError::RootfsIntegrity(_) => "rootfs_integrity",
+            ContainerRuntimeError::LazyPull(_) => "lazy_pull",
+            ContainerRuntimeError::Metrics(_) => "metrics",
+            ContainerRuntimeError::Hook(_) => "hook",
+            ContainerRuntimeError::Timeout(_) => "timeout",
+            ContainerRuntimeError::Restore(_) => "restore",
+            ContainerRuntimeError::IO(_) => "io",
+            ContainerRuntimeError::JSON(_) => "json",
+            ContainerRuntimeError::Libc(_) => "libc"
+        }
+    }
+
+    /// The underlying OS error number, if any.
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            ContainerRuntimeError::IO(err)
+            | ContainerRuntimeError::Mount(err)
+            | ContainerRuntimeError::Execute(err)
+            | ContainerRuntimeError::Libc(err) => err.raw_os_error(),
+            ContainerRuntimeError::ContainerProcess(report) => report.errno,
+            _ => None
+        }
+    }
+
+    /// The exit code to use for the error, following the conventions of shells for commands that cannot be run.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ContainerRuntimeError::Execute(err) => {
+                match err.raw_os_error() {
+                    Some(libc::ENOENT) => EXIT_CODE_NOT_FOUND,
+                    Some(libc::EACCES | libc::ENOEXEC | libc::EPERM) => EXIT_CODE_NOT_EXECUTABLE,
+                    _ => EXIT_CODE_RUNTIME_ERROR
+                }
+            }
+            ContainerRuntimeError::CommandNotFound { .. } => EXIT_CODE_NOT_FOUND,
+            ContainerRuntimeError::ContainerProcess(report) => report.exit_code,
+            _ => EXIT_CODE_RUNTIME_ERROR
+        }
+    }
+
+    pub fn report(&self) -> ErrorReport {
+        if let ContainerRuntimeError::ContainerProcess(report) = self {
+            // Keep what the container reported, but with the context that it happened in the container
+            return ErrorReport {
+                message: self.to_string(),
+                ..report.clone()
+            };
+        }
+
+        let mut causes = Vec::new();
+        let mut source = std::error::Error::source(self);
+        while let Some(err) = source {
+            causes.push(err.to_string());
+            source = err.source();
+        }
+
+        ErrorReport {
+            code: self.code().to_owned(),
+            message: self.to_string(),
+            exit_code: self.exit_code(),
+            errno: self.errno(),
+            causes
+        }
+    }
+}
+
+/// Machine-readable form of an error, used for the JSON error output and to send errors from the container process.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorReport {
+    pub code: String,
+    pub message: String,
+    pub exit_code: i32,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub errno: Option<i32>,
+    #[serde(default, skip_serializing_if="Vec::is_empty")]
+    pub causes: Vec<String>
+}
+
+/// What running a container would do, as the steps of each stage in the order they would be done.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RunPlan {
+    pub stages: Vec<PlanStage>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlanStage {
+    pub name: String,
+    pub steps: Vec<String>
+}
+
+impl RunPlan {
+    /// Adds the stage, unless there is nothing to do in it.
+    pub fn add(&mut self, name: &str, steps: Vec<String>) {
+        if !steps.is_empty() {
+            self.stages.push(PlanStage { name: name.to_owned(), steps });
+        }
+    }
+}
+
+impl std::fmt::Display for RunPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, stage) in self.stages.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+
+            writeln!(f, "{}:", stage.name)?;
+            for step in &stage.steps {
+                writeln!(f, "  {}", step)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// How the process of a container ended, or that it was stopped, decoded from the status returned by `waitpid`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag="type", rename_all="snake_case")]
+pub enum ContainerExitStatus {
+    Exited { code: i32 },
+    Signaled { signal: i32, core_dumped: bool },
+    Stopped { signal: i32 }
+}
+
+impl ContainerExitStatus {
+    pub fn from_wait_status(status: i32) -> ContainerExitStatus {
+        if libc::WIFEXITED(status) {
+            ContainerExitStatus::Exited { code: libc::WEXITSTATUS(status) }
+        } else if libc::WIFSIGNALED(status) {
+            ContainerExitStatus::Signaled { signal: libc::WTERMSIG(status), core_dumped: libc::WCOREDUMP(status) }
+        } else if libc::WIFSTOPPED(status) {
+            ContainerExitStatus::Stopped { signal: libc::WSTOPSIG(status) }
+        } else {
+            // Continued processes are only reported when waiting with WCONTINUED, which is never done
+            unreachable!("Unexpected wait status {}", status)
+        }
+    }
+
+    /// The exit code of the process, using the shell convention of 128 + signal for processes that were killed or stopped.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ContainerExitStatus::Exited { code } => *code,
+            ContainerExitStatus::Signaled { signal, .. } | ContainerExitStatus::Stopped { signal } => 128 + signal
+        }
+    }
+
+    pub fn success(&self) -> bool {
+        *self == ContainerExitStatus::Exited { code: 0 }
+    }
+
+    /// Stopped processes are still alive and can be continued.
+    pub fn has_terminated(&self) -> bool {
+        !matches!(self, ContainerExitStatus::Stopped { .. })
+    }
+}
+
+impl std::fmt::Display for ContainerExitStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContainerExitStatus::Exited { code } => write!(f, "exited with code {}", code),
+            ContainerExitStatus::Signaled { signal, core_dumped } => {
+                write!(f, "killed by {}", signal_name(*signal))?;
+                if *core_dumped {
+                    write!(f, " (core dumped)")?;
+                }
+
+                Ok(())
+            }
+            ContainerExitStatus::Stopped { signal } => write!(f, "stopped by {}", signal_name(*signal))
+        }
+    }
+}
+
+const SIGNAL_NAMES: [&str; 31] = [
+    "SIGHUP", "SIGINT", "SIGQUIT", "SIGILL", "SIGTRAP", "SIGABRT", "SIGBUS", "SIGFPE", "SIGKILL", "SIGUSR1",
+    "SIGSEGV", "SIGUSR2", "SIGPIPE", "SIGALRM", "SIGTERM", "SIGSTKFLT", "SIGCHLD", "SIGCONT", "SIGSTOP", "SIGTSTP",
+    "SIGTTIN", "SIGTTOU", "SIGURG", "SIGXCPU", "SIGXFSZ", "SIGVTALRM", "SIGPROF", "SIGWINCH", "SIGIO", "SIGPWR",
+    "SIGSYS"
+];
+
+/// The name of the signal (such as SIGTERM), or its number for real-time signals.
+pub fn signal_name(signal: i32) -> String {
+    match usize::try_from(signal - 1).ok().and_then(|index| SIGNAL_NAMES.get(index)) {
+        Some(name) => (*name).to_owned(),
+        None => format!("signal {}", signal)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub username: String,
     pub id: i32,
     pub group_id: Option<i32>,
-    pub home_folder: PathBuf
+    pub home_folder: PathBuf,
+    #[serde(default = "User::default_shell")]
+    pub shell: PathBuf
 }
 
+/// The search path of processes that switch user, rather than the one of the runtime (which runs as root).
+pub const USER_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
 impl User {
+    pub fn default_shell() -> PathBuf {
+        PathBuf::from("/bin/sh")
+    }
+
+    /// The environment variables of a login as the user, as many entrypoints expect them to match the user.
+    pub fn environment(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("HOME", self.home_folder.to_str().unwrap().to_owned()),
+            ("USER", self.username.clone()),
+            ("LOGNAME", self.username.clone()),
+            ("SHELL", self.shell.to_str().unwrap().to_owned()),
+            ("PATH", USER_PATH.to_owned())
+        ]
+    }
+
     pub fn from_passwd_file(passwd_path: &Path) -> ContainerRuntimeResult<HashMap<i32, User>> {
         let mut users = HashMap::new();
 
@@ -78,13 +449,14 @@ impl User {
             file.read_to_string(&mut content)?;
 
             for line in content.lines() {
-                let parts = line.split(":").collect::<Vec<_>>();
+                let parts = line.split(':').collect::<Vec<_>>();
 
                 if parts.len() >= 6 {
                     let username = parts[0].to_owned();
                     let user_id = i32::from_str(parts[2]).unwrap();
                     let group_id = i32::from_str(parts[3]).unwrap();
                     let home_folder = Path::new(parts[5]).to_owned();
+                    let shell = parts.get(6).filter(|shell| !shell.is_empty()).map(PathBuf::from).unwrap_or_else(User::default_shell);
 
                     users.insert(
                         user_id,
@@ -92,7 +464,8 @@ impl User {
                             username,
                             id: user_id,
                             group_id: Some(group_id),
-                            home_folder
+                            home_folder,
+                            shell
                         }
                     );
                 }
@@ -101,4 +474,80 @@ impl User {
 
         Ok(users)
     }
-}
\ No newline at end of file
+
+    /// The ids of the groups in a group file (such as /etc/group) by name, which are none if the file does not exist.
+    pub fn groups_from_group_file(group_path: &Path) -> ContainerRuntimeResult<HashMap<String, i32>> {
+        let content = match std::fs::read_to_string(group_path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(err) => return Err(err.into())
+        };
+
+        Ok(
+            content
+                .lines()
+                .filter_map(|line| {
+                    let parts = line.split(':').collect::<Vec<_>>();
+                    let group_id = i32::from_str(parts.get(2)?).ok()?;
+                    Some((parts[0].to_owned(), group_id))
+                })
+                .collect()
+        )
+    }
+}
+
+#[test]
+fn test_error_report() {
+    let not_found = ContainerRuntimeError::Execute(std::io::Error::from_raw_os_error(libc::ENOENT));
+    assert_eq!(EXIT_CODE_NOT_FOUND, not_found.exit_code());
+    let report = not_found.report();
+    assert_eq!("execute", report.code);
+    assert_eq!(Some(libc::ENOENT), report.errno);
+    assert_eq!(1, report.causes.len());
+
+    let not_executable = ContainerRuntimeError::Execute(std::io::Error::from_raw_os_error(libc::EACCES));
+    assert_eq!(EXIT_CODE_NOT_EXECUTABLE, not_executable.exit_code());
+
+    let from_container = ContainerRuntimeError::ContainerProcess(not_found.report());
+    assert_eq!(EXIT_CODE_NOT_FOUND, from_container.exit_code());
+    assert_eq!("execute", from_container.report().code);
+    assert_eq!("Container setup failed: Failed to execute: No such file or directory (os error 2)", from_container.report().message);
+
+    assert_eq!(EXIT_CODE_NOT_FOUND, ContainerRuntimeError::CommandNotFound { command: "test".to_owned() }.exit_code());
+    assert_eq!(EXIT_CODE_RUNTIME_ERROR, ContainerRuntimeError::ContainerNotFound("test".to_owned()).exit_code());
+}
+
+#[test]
+fn test_container_exit_status() {
+    let exited = ContainerExitStatus::from_wait_status(3 << 8);
+    assert_eq!(ContainerExitStatus::Exited { code: 3 }, exited);
+    assert_eq!(3, exited.exit_code());
+    assert!(!exited.success());
+    assert!(ContainerExitStatus::from_wait_status(0).success());
+
+    let killed = ContainerExitStatus::from_wait_status(libc::SIGKILL);
+    assert_eq!(ContainerExitStatus::Signaled { signal: libc::SIGKILL, core_dumped: false }, killed);
+    assert_eq!(137, killed.exit_code());
+    assert_eq!("killed by SIGKILL", killed.to_string());
+    assert_eq!("killed by SIGSEGV (core dumped)", ContainerExitStatus::from_wait_status(libc::SIGSEGV | 0x80).to_string());
+
+    let stopped = ContainerExitStatus::from_wait_status((libc::SIGSTOP << 8) | 0x7f);
+    assert_eq!(ContainerExitStatus::Stopped { signal: libc::SIGSTOP }, stopped);
+    assert!(!stopped.has_terminated());
+    assert_eq!("stopped by SIGSTOP", stopped.to_string());
+}
+
+#[test]
+fn test_user_environment() {
+    let passwd_path = std::env::temp_dir().join(format!("cort-test-passwd-{}", std::process::id()));
+    std::fs::write(&passwd_path, "root:x:0:0:root:/root:/bin/bash\nsync:x:4:65534:sync:/bin:\n").unwrap();
+    let users = User::from_passwd_file(&passwd_path).unwrap();
+    std::fs::remove_file(&passwd_path).unwrap();
+
+    let environment = users[&0].environment();
+    assert!(environment.contains(&("USER", "root".to_owned())));
+    assert!(environment.contains(&("LOGNAME", "root".to_owned())));
+    assert!(environment.contains(&("SHELL", "/bin/bash".to_owned())));
+    assert!(environment.contains(&("PATH", USER_PATH.to_owned())));
+    assert_eq!(Path::new("/bin/sh"), users[&4].shell);
+}
@@ -0,0 +1,54 @@
+use crate::model::{ContainerRuntimeError, ContainerRuntimeResult};
+
+const ADJECTIVES: [&str; 40] = [
+    "agile", "amber", "bold", "brave", "bright", "calm", "clever", "cosmic", "crisp", "curious",
+    "daring", "eager", "fancy", "fearless", "gentle", "happy", "humble", "jolly", "keen", "lively",
+    "lucky", "mellow", "merry", "nimble", "noble", "patient", "plucky", "proud", "quiet", "quirky",
+    "rapid", "serene", "sharp", "shiny", "silent", "sleepy", "steady", "swift", "witty", "zesty"
+];
+
+const NOUNS: [&str; 40] = [
+    "badger", "beacon", "bison", "canyon", "comet", "condor", "coral", "cypress", "dolphin", "ember",
+    "falcon", "fjord", "glacier", "harbor", "heron", "island", "jaguar", "lagoon", "lynx", "meadow",
+    "meteor", "moose", "nebula", "otter", "panda", "pelican", "pine", "quasar", "raven", "reef",
+    "river", "salmon", "sparrow", "summit", "tundra", "valley", "walrus", "willow", "wolf", "zephyr"
+];
+
+/// Generates a random name of the form adjective_noun.
+pub fn generate_name() -> String {
+    let random = uuid::Uuid::new_v4().as_u128();
+    let adjective = ADJECTIVES[(random % ADJECTIVES.len() as u128) as usize];
+    let noun = NOUNS[((random >> 64) % NOUNS.len() as u128) as usize];
+    format!("{}_{}", adjective, noun)
+}
+
+/// Names must start with a letter or digit and only contain letters, digits, '_', '.' and '-'.
+pub fn validate_name(name: &str) -> ContainerRuntimeResult<()> {
+    let mut chars = name.chars();
+    let is_valid = chars.next().map(|first| first.is_ascii_alphanumeric()).unwrap_or(false)
+        && chars.all(|char| char.is_ascii_alphanumeric() || matches!(char, '_' | '.' | '-'));
+
+    if !is_valid {
+        return Err(ContainerRuntimeError::Input(format!("Invalid container name '{}'", name)));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_generate_name() {
+    let name = generate_name();
+    let (adjective, noun) = name.split_once('_').unwrap();
+    assert!(ADJECTIVES.contains(&adjective));
+    assert!(NOUNS.contains(&noun));
+    assert!(validate_name(&name).is_ok());
+}
+
+#[test]
+fn test_validate_name() {
+    assert!(validate_name("web-1.example_app").is_ok());
+    assert!(validate_name("").is_err());
+    assert!(validate_name("-web").is_err());
+    assert!(validate_name("../web").is_err());
+    assert!(validate_name("web/1").is_err());
+}
@@ -0,0 +1,253 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, mpsc};
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use tracing::{info, Span, trace, warn};
+
+use crate::container::resolv_conf;
+use crate::linux::{lock_file, open_in_root, route_monitor_socket};
+use crate::model::ContainerRuntimeResult;
+use crate::network::{self, Ipv4Net};
+use crate::spec::{DNSSpec, NetworkSpec, RunContainerSpec};
+use crate::sys::SysApi;
+
+/// How often the network of the host is checked for changes, which is also how long a change of the routes must have settled.
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// Follows the network of the host while the container runs, so that it keeps working when the host roams between networks:
+/// the resolv.conf of the container is written again when the one of the host changes (if it is copied from the host), and the rules
+/// that masquerade the traffic of the bridge are moved when the default route of the host moves to another interface.
+pub struct HostNetworkWatcher {
+    stop_sender: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl HostNetworkWatcher {
+    /// Starts following the network of the host for the container with the PID, unless there is nothing of the host that it uses.
+    pub fn start(sys: &Arc<dyn SysApi>, spec: &RunContainerSpec, pid: i32) -> Option<HostNetworkWatcher> {
+        let mut resolv_conf_watch = match &spec.dns {
+            DNSSpec::CopyFromHost => ResolvConfWatch::new(spec, pid),
+            DNSSpec::Server(_) => None
+        };
+
+        let mut bridge_watch = match &spec.network {
+            NetworkSpec::Bridged(bridged) => {
+                let network_lock_path = spec.containers_base_dir.with_file_name("network.lock");
+                match BridgeWatch::new(sys.clone(), &bridged.bridge_interface, bridged.bridge_ip_address, network_lock_path) {
+                    Ok(bridge_watch) => Some(bridge_watch),
+                    Err(err) => {
+                        warn!("Failed to follow the routes of the host: {}", err);
+                        None
+                    }
+                }
+            }
+            _ => None
+        };
+
+        if resolv_conf_watch.is_none() && bridge_watch.is_none() {
+            return None;
+        }
+
+        let (stop_sender, stop_receiver) = mpsc::channel::<()>();
+        let span = Span::current();
+        let thread = std::thread::spawn(move || {
+            let _span = span.entered();
+            while stop_receiver.recv_timeout(CHECK_INTERVAL) == Err(RecvTimeoutError::Timeout) {
+                if let Some(watch) = resolv_conf_watch.as_mut() {
+                    if !watch.check() {
+                        resolv_conf_watch = None;
+                    }
+                }
+
+                if let Some(watch) = bridge_watch.as_mut() {
+                    watch.check();
+                }
+            }
+        });
+
+        Some(
+            HostNetworkWatcher {
+                stop_sender: Some(stop_sender),
+                thread: Some(thread)
+            }
+        )
+    }
+}
+
+impl Drop for HostNetworkWatcher {
+    fn drop(&mut self) {
+        drop(self.stop_sender.take());
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+struct ResolvConfWatch {
+    /// The root directory of the container, as seen from the host
+    root: PathBuf,
+    search: Vec<String>,
+    options: Vec<String>,
+    host_content: Option<String>,
+    written: String
+}
+
+impl ResolvConfWatch {
+    fn new(spec: &RunContainerSpec, pid: i32) -> Option<ResolvConfWatch> {
+        let root = PathBuf::from(format!("/proc/{}/root", pid));
+        match read_in_root(&root, Path::new(RESOLV_CONF)) {
+            Ok(written) => {
+                Some(
+                    ResolvConfWatch {
+                        root,
+                        search: spec.dns_search.clone(),
+                        options: spec.dns_options.clone(),
+                        host_content: None,
+                        written
+                    }
+                )
+            }
+            Err(err) => {
+                warn!("Failed to follow the resolv.conf of the host: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Writes the resolv.conf of the container again if the one of the host has changed. Returns false when it should no longer be followed.
+    fn check(&mut self) -> bool {
+        // Replaced rather than changed in place by most network managers, so it might briefly be missing
+        let host_content = match std::fs::read_to_string(RESOLV_CONF) {
+            Ok(host_content) => host_content,
+            Err(_) => return true
+        };
+
+        if self.host_content.as_ref() == Some(&host_content) {
+            return true;
+        }
+
+        let content = resolv_conf(&host_content, &self.search, &self.options);
+        self.host_content = Some(host_content);
+        if content == self.written {
+            return true;
+        }
+
+        match self.write(&content) {
+            Ok(true) => {
+                info!("Updated the resolv.conf of the container as the one of the host has changed.");
+                trace!("Update DNS - content: {}", content.replace('\n', " "));
+                self.written = content;
+                true
+            }
+            Ok(false) => {
+                info!("No longer following the resolv.conf of the host, as the one of the container has been changed within it.");
+                false
+            }
+            Err(err) => {
+                warn!("Failed to update the resolv.conf of the container: {}", err);
+                false
+            }
+        }
+    }
+
+    /// Writes the content unless the file has been changed since it was last written.
+    fn write(&self, content: &str) -> ContainerRuntimeResult<bool> {
+        let path = Path::new(RESOLV_CONF);
+        if read_in_root(&self.root, path)? != self.written {
+            return Ok(false);
+        }
+
+        // Written in a single call, as the file might be a bind mount that cannot be replaced
+        open_in_root(&self.root, path, libc::O_WRONLY | libc::O_TRUNC)?.write_all(content.as_bytes())?;
+        Ok(true)
+    }
+}
+
+fn read_in_root(root: &Path, path: &Path) -> ContainerRuntimeResult<String> {
+    let mut content = String::new();
+    open_in_root(root, path, libc::O_RDONLY)?.read_to_string(&mut content)?;
+    Ok(content)
+}
+
+struct BridgeWatch {
+    sys: Arc<dyn SysApi>,
+    socket: File,
+    bridge_interface: String,
+    bridge_ip_address: Ipv4Net,
+    network_lock_path: PathBuf,
+    pending: bool,
+    failed: bool
+}
+
+impl BridgeWatch {
+    fn new(sys: Arc<dyn SysApi>, bridge_interface: &str, bridge_ip_address: Ipv4Net, network_lock_path: PathBuf) -> ContainerRuntimeResult<BridgeWatch> {
+        Ok(
+            BridgeWatch {
+                sys,
+                socket: route_monitor_socket()?,
+                bridge_interface: bridge_interface.to_owned(),
+                bridge_ip_address,
+                network_lock_path,
+                // The host might have roamed before the container was started
+                pending: true,
+                failed: false
+            }
+        )
+    }
+
+    /// Moves the rules of the bridge to the interface of the default route once the routes have stopped changing.
+    fn check(&mut self) {
+        if self.has_changed() {
+            self.pending = true;
+            return;
+        }
+
+        if !self.pending {
+            return;
+        }
+
+        match self.update() {
+            Ok(()) => {
+                self.pending = false;
+                self.failed = false;
+            }
+            Err(err) => {
+                // Such as when the host has no network, which is tried again until it has
+                if !self.failed {
+                    warn!("Failed to move the network bridge to the interface of the default route: {}", err);
+                    self.failed = true;
+                }
+            }
+        }
+    }
+
+    fn update(&self) -> ContainerRuntimeResult<()> {
+        let physical_interface = network::default_route_interface()?;
+
+        // The other containers using the bridge are following the routes as well
+        let _lock = lock_file(&self.network_lock_path)?;
+        network::move_physical_interface(self.sys.as_ref(), &self.bridge_interface, self.bridge_ip_address, &physical_interface)?;
+        Ok(())
+    }
+
+    /// Reads the pending messages of the socket, returning if there were any.
+    fn has_changed(&mut self) -> bool {
+        let mut buffer = [0; 8192];
+        let mut changed = false;
+        loop {
+            match self.socket.read(&mut buffer) {
+                Ok(0) => return changed,
+                Ok(_) => changed = true,
+                // Messages were dropped as they were not read in time, which are changes as well
+                Err(err) if err.raw_os_error() == Some(libc::ENOBUFS) => changed = true,
+                Err(_) => return changed
+            }
+        }
+    }
+}
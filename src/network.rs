@@ -1,34 +1,60 @@
 use std::ffi::OsStr;
 use std::fmt::{Display};
 use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
 use std::process::Command;
 use std::str::FromStr;
+use std::time::Duration;
 
-use log::{error, info};
+use tracing::{info, warn};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
 
+use crate::dhcp;
+use crate::dhcp::DhcpLease;
+use crate::helpers::{COMMAND_TIMEOUT, command_output, Rollback};
+use crate::linux;
 use crate::model::{ContainerRuntimeError, ContainerRuntimeResult};
-use crate::spec::{BridgedNetworkSpec, BridgeSpec};
+use crate::spec::{BandwidthSpec, BridgedNetworkSpec, BridgeSpec};
+use crate::sys::SysApi;
 
-pub fn create_bridge(bridge: &BridgeSpec) -> ContainerRuntimeResult<bool> {
-    if ip_command(["link", "show", &bridge.interface]).is_err() {
+pub fn create_bridge(sys: &dyn SysApi, bridge: &BridgeSpec) -> ContainerRuntimeResult<bool> {
+    if sys.ip(&["link", "show", &bridge.interface]).is_err() {
         let inner = || -> ContainerRuntimeResult<bool> {
-            ip_command(["link", "add", "name", &bridge.interface, "type", "bridge"])?;
-            ip_command(["link", "set", "dev", &bridge.interface, "up"])?;
-            ip_command(["addr", "add", &bridge.ip_address.to_string(), "dev", &bridge.interface])?;
+            let mut rollback = Rollback::new();
 
-            std::fs::write("/proc/sys/net/ipv4/ip_forward", "1")?;
+            sys.ip(&["link", "add", "name", &bridge.interface, "type", "bridge"])?;
+            let interface = bridge.interface.clone();
+            rollback.add("delete bridge", move || sys.ip(&["link", "del", &interface]).map(|_| ()));
 
-            iptables_command(["-P", "FORWARD", "DROP"])?;
-            iptables_command(["-F", "FORWARD"])?;
-            iptables_command(["-A", "FORWARD", "-i", &bridge.interface, "-o", &bridge.interface, "-j", "ACCEPT"])?;
+            sys.ip(&["link", "set", "dev", &bridge.interface, "up"])?;
+            sys.ip(&["addr", "add", &bridge.ip_address.to_string(), "dev", &bridge.interface])?;
+
+            sys.write_sysctl("net/ipv4/ip_forward", "1")?;
+
+            sys.iptables(&["-P", "FORWARD", "DROP"])?;
+            if bridge.inter_container_communication {
+                add_iptables_rule(sys, &mut rollback, &["-A", "FORWARD", "-i", &bridge.interface, "-o", &bridge.interface, "-j", "ACCEPT"])?;
+            } else {
+                // Traffic between ports of the same bridge only passes through iptables with br_netfilter
+                enable_bridge_netfilter(sys)?;
+                add_iptables_rule(sys, &mut rollback, &["-A", "FORWARD", "-i", &bridge.interface, "-o", &bridge.interface, "-j", "DROP"])?;
+            }
 
             if let Some(physical_interface) = &bridge.physical_interface {
-                iptables_command(["-t", "nat", "-F"])?;
-                iptables_command(["-t", "nat", "-A", "POSTROUTING", "-s", &bridge.ip_address.to_string(), "-o", physical_interface, "-j", "MASQUERADE"])?;
-                iptables_command(["-A", "FORWARD", "-i", physical_interface, "-o", &bridge.interface, "-j", "ACCEPT"])?;
-                iptables_command(["-A", "FORWARD", "-o", physical_interface, "-i", &bridge.interface, "-j", "ACCEPT"])?;
+                add_iptables_rule(sys, &mut rollback, &["-t", "nat", "-A", "POSTROUTING", "-s", &bridge.ip_address.to_string(), "-o", physical_interface, "-j", "MASQUERADE"])?;
+                add_iptables_rule(sys, &mut rollback, &["-A", "FORWARD", "-i", physical_interface, "-o", &bridge.interface, "-j", "ACCEPT"])?;
+                add_iptables_rule(sys, &mut rollback, &["-A", "FORWARD", "-o", physical_interface, "-i", &bridge.interface, "-j", "ACCEPT"])?;
+            }
+
+            if bridge.hairpin {
+                // Replies to a container reaching itself through the host must go back through the host
+                let subnet = bridge.ip_address.to_string();
+                add_iptables_rule(sys, &mut rollback, &["-t", "nat", "-A", "POSTROUTING", "-s", &subnet, "-d", &subnet, "-o", &bridge.interface, "-j", "MASQUERADE"])?;
             }
 
+            rollback.commit();
+
             let physical_interface = bridge.physical_interface.clone().unwrap_or_else(|| "N/A".to_owned());
             info!("Created network bridge '{}' with IP {} using physical interface {}.", bridge.interface, bridge.ip_address, physical_interface);
             Ok(true)
@@ -40,86 +66,393 @@ pub fn create_bridge(bridge: &BridgeSpec) -> ContainerRuntimeResult<bool> {
     }
 }
 
-pub struct NetworkNamespace {
-    name: String
+fn enable_bridge_netfilter(sys: &dyn SysApi) -> ContainerRuntimeResult<()> {
+    match sys.write_sysctl("net/bridge/bridge-nf-call-iptables", "1") {
+        Err(ContainerRuntimeError::IO(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+            Err(ContainerRuntimeError::CreateNetworkBridge("The br_netfilter module is required to disable inter-container communication".to_owned()))
+        }
+        result => result
+    }
+}
+
+fn add_iptables_rule<'a>(sys: &'a dyn SysApi, rollback: &mut Rollback<'a>, rule: &[&str]) -> ContainerRuntimeResult<()> {
+    sys.iptables(rule)?;
+
+    let delete_rule = rule
+        .iter()
+        .map(|part| if *part == "-A" { "-D".to_owned() } else { part.to_string() })
+        .collect::<Vec<_>>();
+    rollback.add("delete iptables rule", move || sys.iptables(&delete_rule.iter().map(|part| part.as_str()).collect::<Vec<_>>()).map(|_| ()));
+
+    Ok(())
+}
+
+/// Veth pair connecting a container to the bridge, where only the host end is attached to the bridge.
+pub struct VethPair {
+    pub host_interface: String,
+    pub container_interface: String
+}
+
+impl VethPair {
+    pub fn for_container(container_id: &str) -> VethPair {
+        // Interface names are limited to 15 characters
+        let id = container_id.replace('-', "");
+        VethPair {
+            host_interface: format!("veth{}", &id[..11]),
+            container_interface: format!("vc{}", &id[..11])
+        }
+    }
+
+    /// Veth pair connecting the container to an additional network.
+    pub fn for_attachment(container_id: &str, network: &str) -> VethPair {
+        let hash = hex::encode(Sha256::digest(format!("{}/{}", container_id, network)));
+        VethPair {
+            host_interface: format!("veth{}", &hash[..11]),
+            container_interface: format!("vc{}", &hash[..11])
+        }
+    }
+}
+
+/// Network connected to a running container in addition to the one it was started with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkAttachment {
+    pub network: String,
+    pub host_interface: String,
+    pub interface: String,
+    pub ip_address: Ipv4Net
+}
+
+pub const CONTAINER_INTERFACE: &str = "eth0";
+const DHCP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Creates the veth pair from the host and moves one end into the network namespace of the container process.
+pub fn create_container_network(sys: &dyn SysApi, bridge: &BridgedNetworkSpec, container_id: &str, pid: i32) -> ContainerRuntimeResult<()> {
+    let inner = || -> ContainerRuntimeResult<()> {
+        let mut rollback = Rollback::new();
+        create_veth_pair(sys, &mut rollback, bridge, &VethPair::for_container(container_id), pid)?;
+        rollback.commit();
+        Ok(())
+    };
+
+    inner().map_err(|err| ContainerRuntimeError::CreateContainerNetwork(err.to_string()))
+}
+
+fn create_veth_pair<'a>(sys: &'a dyn SysApi, rollback: &mut Rollback<'a>, bridge: &BridgedNetworkSpec, veth: &VethPair, pid: i32) -> ContainerRuntimeResult<()> {
+    // Never take over (and later delete) an interface belonging to another container
+    for interface in [&veth.host_interface, &veth.container_interface] {
+        if sys.ip(&["link", "show", interface]).is_ok() {
+            return Err(ContainerRuntimeError::CreateContainerNetwork(format!("Interface {} already exists", interface)));
+        }
+    }
+
+    sys.ip(&["link", "add", &veth.host_interface, "type", "veth", "peer", "name", &veth.container_interface])?;
+    let interface = veth.host_interface.clone();
+    rollback.add("delete veth pair", move || sys.ip(&["link", "del", &interface]).map(|_| ()));
+
+    sys.ip(&["link", "set", "dev", &veth.host_interface, "master", &bridge.bridge_interface])?;
+    if bridge.hairpin {
+        sys.ip(&["link", "set", "dev", &veth.host_interface, "type", "bridge_slave", "hairpin", "on"])?;
+    }
+    if let Some(ingress_limit) = bridge.ingress_limit {
+        // What the host end sends is received by the container
+        limit_bandwidth(sys, &veth.host_interface, ingress_limit)?;
+    }
+    sys.ip(&["link", "set", "dev", &veth.host_interface, "up"])?;
+    sys.ip(&["link", "set", "dev", &veth.container_interface, "netns", &pid.to_string()])?;
+    Ok(())
 }
 
-impl NetworkNamespace {
-    pub fn create(name: String, network: &BridgedNetworkSpec) -> ContainerRuntimeResult<NetworkNamespace> {
-        create_network_namespace(network, &name)?;
+/// Connects a running container to an additional network, where the new interface only gets a route to its subnet.
+pub fn attach_container_network(sys: &dyn SysApi, bridge: &BridgedNetworkSpec, network: &str, container_id: &str, pid: i32) -> ContainerRuntimeResult<NetworkAttachment> {
+    let inner = || -> ContainerRuntimeResult<NetworkAttachment> {
+        if !has_own_network_namespace(pid)? {
+            return Err(ContainerRuntimeError::AttachNetwork("Containers using the host network cannot be connected to networks".to_owned()));
+        }
+
+        let mut rollback = Rollback::new();
+        let veth = VethPair::for_attachment(container_id, network);
+        create_veth_pair(sys, &mut rollback, bridge, &veth, pid)?;
+
+        let container_interface = veth.container_interface.clone();
+        let container_ip_address = bridge.container_ip_address;
+        let (interface, ip_address) = in_network_namespace(pid, move || {
+            let interfaces = interface_names(&sys.ip(&["-o", "link", "show"])?);
+            let interface = (1..)
+                .map(|index| format!("eth{}", index))
+                .find(|name| !interfaces.contains(name))
+                .unwrap();
+
+            sys.ip(&["link", "set", "dev", &container_interface, "name", &interface])?;
+            sys.ip(&["link", "set", "dev", &interface, "up"])?;
+            let ip_address = match container_ip_address {
+                Some(ip_address) => ip_address,
+                None => dhcp::request_lease(&interface, DHCP_TIMEOUT)?.address
+            };
+            sys.ip(&["addr", "add", &ip_address.to_string(), "dev", &interface])?;
+
+            Ok((interface, ip_address))
+        })?;
+
+        rollback.commit();
+        info!("Connected container {} to network {} as {} with IP {}.", container_id, network, interface, ip_address);
 
         Ok(
-            NetworkNamespace {
-                name
+            NetworkAttachment {
+                network: network.to_owned(),
+                host_interface: veth.host_interface,
+                interface,
+                ip_address
             }
         )
+    };
+
+    inner().map_err(|err| ContainerRuntimeError::AttachNetwork(err.to_string()))
+}
+
+/// Disconnects the network, where deleting the host end also removes the interface within the container.
+pub fn detach_container_network(sys: &dyn SysApi, attachment: &NetworkAttachment) -> ContainerRuntimeResult<()> {
+    if sys.ip(&["link", "show", &attachment.host_interface]).is_ok() {
+        sys.ip(&["link", "del", &attachment.host_interface])?;
+    }
+
+    Ok(())
+}
+
+/// Runs the function on a thread within the network namespace of the process, as namespaces are per thread.
+/// The thread is scoped, so that the function can borrow from the caller.
+pub fn in_network_namespace<T, F>(pid: i32, function: F) -> ContainerRuntimeResult<T>
+    where T: Send, F: FnOnce() -> ContainerRuntimeResult<T> + Send
+{
+    let span = tracing::Span::current();
+    std::thread::scope(|scope| {
+        scope.spawn(move || {
+            let _span = span.entered();
+            linux::enter_network_namespace(pid)?;
+            function()
+        })
+            .join()
+            .map_err(|_| ContainerRuntimeError::SetupNetwork("Network namespace thread panicked".to_owned()))?
+    })
+}
+
+fn has_own_network_namespace(pid: i32) -> ContainerRuntimeResult<bool> {
+    Ok(std::fs::read_link(format!("/proc/{}/ns/net", pid))? != std::fs::read_link("/proc/self/ns/net")?)
+}
+
+fn interface_names(links: &str) -> Vec<String> {
+    links
+        .lines()
+        .filter_map(|line| line.split(": ").nth(1))
+        .map(|name| name.split('@').next().unwrap().to_owned())
+        .collect()
+}
+
+/// Removes the host end of the veth pair once the container has exited.
+/// The kernel also removes it when the network namespace is destroyed, but that happens asynchronously.
+pub fn remove_container_network(sys: &dyn SysApi, container_id: &str) -> ContainerRuntimeResult<()> {
+    let veth = VethPair::for_container(container_id);
+    if sys.ip(&["link", "show", &veth.host_interface]).is_ok() {
+        sys.ip(&["link", "del", &veth.host_interface])?;
     }
+
+    Ok(())
 }
 
-impl Drop for NetworkNamespace {
-    fn drop(&mut self) {
-        if let Err(err) = destroy_network_namespace(&self.name) {
-            error!("Failed to destroy network namespace: {}", err.to_string());
+/// Where `ip netns` keeps named network namespaces, of which those of the runtime are prefixed.
+const NETWORK_NAMESPACE_DIR: &str = "/run/netns";
+const NETWORK_NAMESPACE_PREFIX: &str = "cort-";
+
+/// Removes the veth interfaces attached to the bridges that are not in use, such as those left when the runtime crashed
+/// before moving the container end into the namespace. Returns the removed interfaces.
+pub fn remove_unused_veth_interfaces(sys: &dyn SysApi, bridge_interfaces: &[String], used_interfaces: &[String]) -> ContainerRuntimeResult<Vec<String>> {
+    let mut removed = Vec::new();
+    for bridge_interface in bridge_interfaces {
+        if sys.ip(&["link", "show", bridge_interface]).is_err() {
+            continue;
+        }
+
+        let ports = interface_names(&sys.ip(&["-o", "link", "show", "master", bridge_interface])?);
+        for port in ports {
+            if port.starts_with("veth") && !used_interfaces.contains(&port) {
+                sys.ip(&["link", "del", &port])?;
+                removed.push(port);
+            }
         }
     }
+
+    Ok(removed)
 }
 
-fn create_network_namespace(bridge: &BridgedNetworkSpec, network_namespace: &str) -> ContainerRuntimeResult<()> {
-    let inner = || -> ContainerRuntimeResult<()> {
-        let host_interface = format!("{}-host", network_namespace);
-        let namespace_interface = format!("{}-ns", network_namespace);
+/// Removes the named network namespaces of the runtime (`/run/netns/cort-<id>`) whose container is not running.
+pub fn remove_unused_network_namespaces(sys: &dyn SysApi, running_container_ids: &[String]) -> ContainerRuntimeResult<Vec<String>> {
+    let entries = match std::fs::read_dir(NETWORK_NAMESPACE_DIR) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into())
+    };
 
-        ip_command(["netns", "add", network_namespace])?;
+    let mut removed = Vec::new();
+    for entry in entries {
+        let name = entry?.file_name().to_str().unwrap().to_owned();
+        let Some(container_id) = name.strip_prefix(NETWORK_NAMESPACE_PREFIX) else {
+            continue;
+        };
 
-        ip_command(["link", "add", &host_interface, "type", "veth", "peer", "name", &namespace_interface])?;
-        ip_command(["link", "set", "dev", &host_interface, "master", &bridge.bridge_interface])?;
-        ip_command(["link", "set", "dev", &namespace_interface, "master", &bridge.bridge_interface])?;
+        if !running_container_ids.iter().any(|id| id == container_id) {
+            sys.ip(&["netns", "del", &name])?;
+            removed.push(name);
+        }
+    }
 
-        ip_command(["link", "set", "dev", &host_interface, "up"])?;
+    Ok(removed)
+}
 
-        ip_command(["link", "set", &namespace_interface, "netns", network_namespace])?;
-        ip_command(["netns", "exec", network_namespace, "ip", "addr", "add", &bridge.container_ip_address.to_string(), "dev", &namespace_interface])?;
-        ip_command(["netns", "exec", network_namespace, "ip", "link", "set", "dev", &namespace_interface, "up"])?;
-        ip_command(["netns", "exec", network_namespace, "ip", "link", "set", "dev", "lo", "up"])?;
-        ip_command(["-n", network_namespace, "route", "add", "default", "via", &bridge.bridge_ip_address.address.to_string()])?;
-        Ok(())
+/// Removes the rules added for bridges that no longer exist, which would otherwise be added again when the bridge is created.
+/// Returns the removed rules.
+pub fn remove_stale_iptables_rules(sys: &dyn SysApi, bridge_interfaces: &[String]) -> ContainerRuntimeResult<Vec<String>> {
+    let existing_interfaces = interface_names(&sys.ip(&["-o", "link", "show"])?);
+    let is_stale_interface = |interface: &str| {
+        bridge_interfaces.iter().any(|bridge_interface| bridge_interface == interface)
+            && !existing_interfaces.iter().any(|existing| existing == interface)
     };
 
-    inner().map_err(|err| ContainerRuntimeError::CreateNetworkNamespace(err.to_string()))
+    let mut removed = Vec::new();
+    for table in ["filter", "nat"] {
+        let rules = sys.iptables(&["-t", table, "-S"])?;
+        for rule in stale_iptables_rules(&rules, is_stale_interface) {
+            let mut delete_rule = vec!["-t".to_owned(), table.to_owned()];
+            delete_rule.extend(rule.iter().map(|part| if part == "-A" { "-D".to_owned() } else { part.clone() }));
+            sys.iptables(&delete_rule.iter().map(|part| part.as_str()).collect::<Vec<_>>())?;
+            removed.push(format!("-t {} {}", table, rule.join(" ")));
+        }
+    }
+
+    Ok(removed)
 }
 
-fn destroy_network_namespace(network_namespace: &str) -> ContainerRuntimeResult<()> {
-    let inner = || -> ContainerRuntimeResult<()> {
-        ip_command(["netns", "del", network_namespace])?;
-        ip_command(["link", "del", &format!("{}-host", network_namespace)])?;
-        Ok(())
+/// Finds the rules in the output of `iptables -S` with an input or output interface that is stale.
+fn stale_iptables_rules(rules: &str, is_stale_interface: impl Fn(&str) -> bool) -> Vec<Vec<String>> {
+    rules
+        .lines()
+        .map(|rule| rule.split_whitespace().map(|part| part.to_owned()).collect::<Vec<_>>())
+        .filter(|rule| rule.first().map(|part| part == "-A").unwrap_or(false))
+        .filter(|rule| {
+            rule.windows(2).any(|pair| (pair[0] == "-i" || pair[0] == "-o") && is_stale_interface(&pair[1]))
+        })
+        .collect()
+}
+
+/// Configures the interface moved into the namespace by the host, called from within the container network namespace.
+/// Returns the lease if the address was leased with DHCP.
+pub fn setup_loopback_interface(sys: &dyn SysApi) -> ContainerRuntimeResult<()> {
+    sys.ip(&["link", "set", "dev", "lo", "up"])?;
+    Ok(())
+}
+
+pub fn setup_container_interface(sys: &dyn SysApi, bridge: &BridgedNetworkSpec, container_id: &str) -> ContainerRuntimeResult<Option<DhcpLease>> {
+    let veth = VethPair::for_container(container_id);
+
+    sys.ip(&["link", "set", "dev", &veth.container_interface, "name", CONTAINER_INTERFACE])?;
+    if let Some(egress_limit) = bridge.egress_limit {
+        limit_bandwidth(sys, CONTAINER_INTERFACE, egress_limit)?;
+    }
+    sys.ip(&["link", "set", "dev", CONTAINER_INTERFACE, "up"])?;
+    sys.ip(&["link", "set", "dev", "lo", "up"])?;
+
+    match bridge.container_ip_address {
+        Some(container_ip_address) => {
+            sys.ip(&["addr", "add", &container_ip_address.to_string(), "dev", CONTAINER_INTERFACE])?;
+            sys.ip(&["route", "add", "default", "via", &bridge.bridge_ip_address.address.to_string()])?;
+            Ok(None)
+        }
+        None => {
+            let lease = dhcp::request_lease(CONTAINER_INTERFACE, DHCP_TIMEOUT)?;
+            sys.ip(&["addr", "add", &lease.address.to_string(), "dev", CONTAINER_INTERFACE])?;
+            if let Some(router) = lease.router {
+                sys.ip(&["route", "add", "default", "via", &router.to_string()])?;
+            }
+            Ok(Some(lease))
+        }
+    }
+}
+
+/// Shapes the traffic sent on the interface using a HTB class, with fq_codel to keep the latency down when the limit is reached.
+fn limit_bandwidth(sys: &dyn SysApi, interface: &str, rate: BandwidthSpec) -> ContainerRuntimeResult<()> {
+    let rate = rate.to_string();
+    sys.tc(&["qdisc", "add", "dev", interface, "root", "handle", "1:", "htb", "default", "10"])?;
+    sys.tc(&["class", "add", "dev", interface, "parent", "1:", "classid", "1:10", "htb", "rate", &rate, "ceil", &rate])?;
+    if let Err(err) = sys.tc(&["qdisc", "add", "dev", interface, "parent", "1:10", "handle", "10:", "fq_codel"]) {
+        warn!("Could not add fq_codel to {}, using the default queue: {}", interface, err);
+    }
+
+    Ok(())
+}
+
+/// The bytes received and transmitted by the interface.
+pub fn interface_statistics(interface: &str) -> ContainerRuntimeResult<(u64, u64)> {
+    let read_value = |name: &str| -> ContainerRuntimeResult<u64> {
+        let content = std::fs::read_to_string(format!("/sys/class/net/{}/statistics/{}", interface, name))?;
+        content.trim().parse().map_err(|err| ContainerRuntimeError::State(format!("Invalid {} of {}: {}", name, interface, err)))
     };
 
-    inner().map_err(|err| ContainerRuntimeError::DestroyNetworkNamespace(err.to_string()))
+    Ok((read_value("rx_bytes")?, read_value("tx_bytes")?))
 }
 
-pub fn find_free_ip_address(base_ip_address: Ipv4Net) -> ContainerRuntimeResult<Ipv4Net> {
-    let network_namespaces = find_container_network_namespaces()?;
-    let check_is_ip_address_used = |ip_address: Ipv4Net| -> ContainerRuntimeResult<bool> {
-        if is_ip_address_used(&ip_address, None)? {
-            return Ok(true);
+/// Addresses assigned to containers whose state does not hold them yet, stored as a file per address with the PID of the process that
+/// assigned it. The state of a container is saved while the process still runs, so reservations whose process is gone are no longer needed.
+pub struct AddressReservations {
+    dir: PathBuf
+}
+
+impl AddressReservations {
+    pub fn new(dir: PathBuf) -> AddressReservations {
+        AddressReservations {
+            dir
         }
+    }
 
-        for namespace in &network_namespaces {
-            if is_ip_address_used(&ip_address, Some(namespace))? {
-                return Ok(true);
+    pub fn reserve(&self, address: Ipv4Net) -> ContainerRuntimeResult<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(self.dir.join(address.address.to_string()), format!("{}\n{}\n", std::process::id(), address))?;
+        Ok(())
+    }
+
+    /// The reserved addresses, removing the reservations whose process is gone.
+    pub fn reserved(&self) -> ContainerRuntimeResult<Vec<Ipv4Net>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into())
+        };
+
+        let mut reserved = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let content = std::fs::read_to_string(&path)?;
+            let mut lines = content.lines();
+            let owner_pid = lines.next().and_then(|pid| i32::from_str(pid).ok());
+            let address = lines.next().and_then(|address| Ipv4Net::from_str(address).ok());
+
+            match (owner_pid, address) {
+                (Some(owner_pid), Some(address)) if linux::is_process_alive(owner_pid) => reserved.push(address),
+                _ => std::fs::remove_file(&path)?
             }
         }
 
-        Ok(false)
+        Ok(reserved)
+    }
+}
+
+pub fn find_free_ip_address(base_ip_address: Ipv4Net, used_ip_addresses: &[Ipv4Net]) -> ContainerRuntimeResult<Ipv4Net> {
+    let host_addresses = ip_command(["addr", "show"])?;
+    let is_ip_address_used = |ip_address: Ipv4Net| {
+        used_ip_addresses.iter().any(|used| used.address == ip_address.address) || host_addresses.contains(&ip_address.to_string())
     };
 
     let mut next_ip_address = base_ip_address;
     for _ in 0..base_ip_address.subnet_size() {
-        if !next_ip_address.is_broadcast() && !next_ip_address.is_network() {
-            if !check_is_ip_address_used(next_ip_address)? {
-                return Ok(next_ip_address);
-            }
+        if !next_ip_address.is_broadcast() && !next_ip_address.is_network() && !is_ip_address_used(next_ip_address) {
+            return Ok(next_ip_address);
         }
 
         next_ip_address = next_ip_address.next();
@@ -128,26 +461,6 @@ pub fn find_free_ip_address(base_ip_address: Ipv4Net) -> ContainerRuntimeResult<
     Err(ContainerRuntimeError::NetworkIsFull)
 }
 
-fn find_container_network_namespaces() -> ContainerRuntimeResult<Vec<String>> {
-    Ok(
-        ip_command(["netns", "list"])?
-            .lines()
-            .map(|line| line.split(" ").next().unwrap().to_owned())
-            .filter(|namespace| namespace.starts_with("cort-"))
-            .collect()
-    )
-}
-
-fn is_ip_address_used(ip_address: &Ipv4Net, namespace: Option<&str>) -> ContainerRuntimeResult<bool> {
-    let arguments = if let Some(namespace) = namespace {
-        vec!["netns", "exec", namespace, "ip", "addr", "show"]
-    } else {
-        vec!["addr", "show"]
-    };
-
-    Ok(ip_command(arguments)?.contains(&ip_address.to_string()))
-}
-
 pub fn find_internet_interface() -> ContainerRuntimeResult<String> {
     let inner = || -> Result<String, String> {
         let hostname = "google.com";
@@ -156,7 +469,7 @@ pub fn find_internet_interface() -> ContainerRuntimeResult<String> {
         for ip in ips {
             if let IpAddr::V4(ip) = ip {
                 let result = ip_command(["route", "get", &ip.to_string()]).map_err(|err| err.to_string())?;
-                let result = result.split(" ");
+                let result = result.split(' ');
                 let mut result = result.skip(4);
                 return result.next().ok_or_else(|| "No interface found".to_owned()).map(|x| x.to_owned());
             }
@@ -165,7 +478,71 @@ pub fn find_internet_interface() -> ContainerRuntimeResult<String> {
         Err("No IPv4 address found for host 'google.com'".to_owned())
     };
 
-    inner().map_err(|err| ContainerRuntimeError::FailedToDetermineInternetInterface(err))
+    inner().map_err(ContainerRuntimeError::FailedToDetermineInternetInterface)
+}
+
+/// The interface of the default route of the host, which unlike `find_internet_interface` does not need a working name server.
+pub fn default_route_interface() -> ContainerRuntimeResult<String> {
+    let routes = std::fs::read_to_string("/proc/net/route")?;
+    default_route_interface_in(&routes)
+        .ok_or_else(|| ContainerRuntimeError::FailedToDetermineInternetInterface("No default route".to_owned()))
+}
+
+/// Finds the interface of the up default route with the lowest metric in the routing table (as in /proc/net/route).
+fn default_route_interface_in(routes: &str) -> Option<String> {
+    routes
+        .lines()
+        .skip(1)
+        .map(|route| route.split_whitespace().collect::<Vec<_>>())
+        .filter(|route| route.len() >= 8 && route[1] == "00000000" && route[7] == "00000000")
+        .filter(|route| u32::from_str_radix(route[3], 16).map(|flags| flags & libc::RTF_UP as u32 != 0).unwrap_or(false))
+        .min_by_key(|route| route[6].parse::<u32>().unwrap_or(u32::MAX))
+        .map(|route| route[0].to_owned())
+}
+
+/// Moves the rules that masquerade the traffic of the bridge to another physical interface, such as when the host has roamed
+/// between networks. The new rules are added before the old are removed, so that the bridge always has a way out.
+/// Returns the interface that was used before, unless the rules already use the interface (or the bridge has none).
+pub fn move_physical_interface(sys: &dyn SysApi, bridge_interface: &str, bridge_ip_address: Ipv4Net, physical_interface: &str) -> ContainerRuntimeResult<Option<String>> {
+    let rules = sys.iptables(&["-t", "nat", "-S", "POSTROUTING"])?;
+    let current_interface = match masquerade_interface(&rules, bridge_ip_address) {
+        Some(current_interface) if current_interface != physical_interface => current_interface,
+        _ => return Ok(None)
+    };
+
+    let subnet = bridge_ip_address.to_string();
+    let mut rollback = Rollback::new();
+    add_iptables_rule(sys, &mut rollback, &["-t", "nat", "-A", "POSTROUTING", "-s", &subnet, "-o", physical_interface, "-j", "MASQUERADE"])?;
+    add_iptables_rule(sys, &mut rollback, &["-A", "FORWARD", "-i", physical_interface, "-o", bridge_interface, "-j", "ACCEPT"])?;
+    add_iptables_rule(sys, &mut rollback, &["-A", "FORWARD", "-o", physical_interface, "-i", bridge_interface, "-j", "ACCEPT"])?;
+    rollback.commit();
+
+    sys.iptables(&["-t", "nat", "-D", "POSTROUTING", "-s", &subnet, "-o", &current_interface, "-j", "MASQUERADE"])?;
+    sys.iptables(&["-D", "FORWARD", "-i", &current_interface, "-o", bridge_interface, "-j", "ACCEPT"])?;
+    sys.iptables(&["-D", "FORWARD", "-o", &current_interface, "-i", bridge_interface, "-j", "ACCEPT"])?;
+
+    info!("Moved network bridge '{}' from physical interface {} to {}.", bridge_interface, current_interface, physical_interface);
+    Ok(Some(current_interface))
+}
+
+/// Finds the output interface of the rule that masquerades the traffic of the bridge in the output of `iptables -t nat -S POSTROUTING`.
+/// The source is listed as the network of the bridge (10.10.0.0/16 rather than 10.10.0.1/16), and the hairpin rule, which also
+/// has a destination, is not the one.
+fn masquerade_interface(rules: &str, bridge_ip_address: Ipv4Net) -> Option<String> {
+    rules
+        .lines()
+        .map(|rule| rule.split_whitespace().collect::<Vec<_>>())
+        .find_map(|rule| match rule.as_slice() {
+            ["-A", "POSTROUTING", "-s", source, "-o", interface, "-j", "MASQUERADE"] => {
+                let source = Ipv4Net::from_str(source).ok()?;
+                if source.subnet_cidr == bridge_ip_address.subnet_cidr && source.contains(bridge_ip_address.address) {
+                    Some(interface.to_string())
+                } else {
+                    None
+                }
+            }
+            _ => None
+        })
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -199,6 +576,12 @@ impl Ipv4Net {
         Ipv4Net::new(next_address, self.subnet_cidr)
     }
 
+    /// Whether the address is in the subnet.
+    pub fn contains(&self, address: Ipv4Addr) -> bool {
+        let subnet_mask = self.subnet_mask();
+        u32::from_be_bytes(address.octets()) & subnet_mask == u32::from_be_bytes(self.address.octets()) & subnet_mask
+    }
+
     pub fn is_network(&self) -> bool {
         let (_, host_part) = self.split();
         host_part == 0
@@ -225,13 +608,26 @@ impl Display for Ipv4Net {
     }
 }
 
+impl Serialize for Ipv4Net {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Ipv4Net {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        let text = String::deserialize(deserializer)?;
+        Ipv4Net::from_str(&text).map_err(serde::de::Error::custom)
+    }
+}
+
 impl FromStr for Ipv4Net {
     type Err = String;
 
     fn from_str(text: &str) -> Result<Self, Self::Err> {
-        let mut parts = text.split("/");
-        let address = parts.next().ok_or_else(|| "Expected IP address.")?;
-        let subnet_size = parts.next().ok_or_else(|| "Expected cidr notation.")?;
+        let mut parts = text.split('/');
+        let address = parts.next().ok_or("Expected IP address.")?;
+        let subnet_size = parts.next().ok_or("Expected cidr notation.")?;
 
         let address = Ipv4Addr::from_str(address).map_err(|err| format!("Failed to parse IP address: {}", err))?;
         let subnet_cidr = u16::from_str(subnet_size).map_err(|err| format!("Failed to parse subnet mask: {}", err))?;
@@ -240,11 +636,9 @@ impl FromStr for Ipv4Net {
     }
 }
 
-fn ip_command<I, S>(args: I) -> ContainerRuntimeResult<String> where I: IntoIterator<Item = S>, S: AsRef<OsStr> {
-    let result = Command::new("ip")
-        .args(args)
-        .output()
-        .unwrap();
+pub(crate) fn ip_command<I, S>(args: I) -> ContainerRuntimeResult<String> where I: IntoIterator<Item = S>, S: AsRef<OsStr> {
+    let result = command_output(Command::new("ip").args(args), COMMAND_TIMEOUT)
+        .map_err(|err| ContainerRuntimeError::IPCommand(err.to_string()))?;
 
     if !result.status.success() {
         return Err(ContainerRuntimeError::IPCommand(String::from_utf8(result.stderr).unwrap()));
@@ -253,11 +647,20 @@ fn ip_command<I, S>(args: I) -> ContainerRuntimeResult<String> where I: IntoIter
     Ok(String::from_utf8(result.stdout).unwrap())
 }
 
-fn iptables_command<I, S>(args: I) -> ContainerRuntimeResult<String> where I: IntoIterator<Item = S>, S: AsRef<OsStr> {
-    let result = Command::new("iptables")
-        .args(args)
-        .output()
-        .unwrap();
+pub(crate) fn tc_command<I, S>(args: I) -> ContainerRuntimeResult<String> where I: IntoIterator<Item = S>, S: AsRef<OsStr> {
+    let result = command_output(Command::new("tc").args(args), COMMAND_TIMEOUT)
+        .map_err(|err| ContainerRuntimeError::TCCommand(err.to_string()))?;
+
+    if !result.status.success() {
+        return Err(ContainerRuntimeError::TCCommand(String::from_utf8(result.stderr).unwrap()));
+    }
+
+    Ok(String::from_utf8(result.stdout).unwrap())
+}
+
+pub(crate) fn iptables_command<I, S>(args: I) -> ContainerRuntimeResult<String> where I: IntoIterator<Item = S>, S: AsRef<OsStr> {
+    let result = command_output(Command::new("iptables").args(args), COMMAND_TIMEOUT)
+        .map_err(|err| ContainerRuntimeError::IPTablesCommand(err.to_string()))?;
 
     if !result.status.success() {
         return Err(ContainerRuntimeError::IPTablesCommand(String::from_utf8(result.stderr).unwrap()));
@@ -267,6 +670,105 @@ fn iptables_command<I, S>(args: I) -> ContainerRuntimeResult<String> where I: In
 }
 
 
+#[test]
+fn test_stale_iptables_rules() {
+    let rules = "-P FORWARD DROP
+-A FORWARD -i cort0 -o cort0 -j ACCEPT
+-A FORWARD -i eth0 -o cort1 -j ACCEPT
+-A FORWARD -o eth0 -i cort1 -j ACCEPT
+-A FORWARD -i docker0 -o docker0 -j ACCEPT
+";
+
+    let stale = stale_iptables_rules(rules, |interface| interface == "cort1");
+    assert_eq!(
+        vec![
+            vec!["-A", "FORWARD", "-i", "eth0", "-o", "cort1", "-j", "ACCEPT"],
+            vec!["-A", "FORWARD", "-o", "eth0", "-i", "cort1", "-j", "ACCEPT"]
+        ],
+        stale
+    );
+}
+
+#[test]
+fn test_default_route_interface() {
+    let routes = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+wlan0\t00000000\t0101A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0
+eth0\t00000000\t0100000A\t0003\t0\t0\t100\t00000000\t0\t0\t0
+cort0\t00000A0A\t00000000\t0001\t0\t0\t0\t0000FFFF\t0\t0\t0
+";
+
+    assert_eq!(Some("eth0".to_owned()), default_route_interface_in(routes));
+    assert_eq!(None, default_route_interface_in(&routes.replace("\t0003\t", "\t0002\t")));
+}
+
+#[test]
+fn test_move_physical_interface() {
+    use crate::sys::RecordingSysApi;
+
+    let rules = "-P POSTROUTING ACCEPT
+-A POSTROUTING -s 10.10.0.0/16 -d 10.10.0.0/16 -o cort0 -j MASQUERADE
+-A POSTROUTING -s 10.10.0.0/16 -o wlan0 -j MASQUERADE
+";
+    let sys = RecordingSysApi::new().respond("iptables -t nat -S", rules);
+    let bridge_ip_address = Ipv4Net::from_str("10.10.0.1/16").unwrap();
+
+    assert_eq!(None, move_physical_interface(&sys, "cort0", bridge_ip_address, "wlan0").unwrap());
+    assert_eq!(Some("wlan0".to_owned()), move_physical_interface(&sys, "cort0", bridge_ip_address, "eth0").unwrap());
+    assert_eq!(
+        vec![
+            "iptables -t nat -S POSTROUTING",
+            "iptables -t nat -S POSTROUTING",
+            "iptables -t nat -A POSTROUTING -s 10.10.0.1/16 -o eth0 -j MASQUERADE",
+            "iptables -A FORWARD -i eth0 -o cort0 -j ACCEPT",
+            "iptables -A FORWARD -o eth0 -i cort0 -j ACCEPT",
+            "iptables -t nat -D POSTROUTING -s 10.10.0.1/16 -o wlan0 -j MASQUERADE",
+            "iptables -D FORWARD -i wlan0 -o cort0 -j ACCEPT",
+            "iptables -D FORWARD -o wlan0 -i cort0 -j ACCEPT"
+        ],
+        sys.command_lines()
+    );
+
+    // Another bridge
+    assert_eq!(None, move_physical_interface(&sys, "cort1", Ipv4Net::from_str("10.20.0.1/16").unwrap(), "eth0").unwrap());
+}
+
+#[test]
+fn test_create_bridge_rollback() {
+    use crate::sys::RecordingSysApi;
+
+    let sys = RecordingSysApi::new()
+        .fail("ip link show cort0", "Device \"cort0\" does not exist.")
+        .fail("iptables -A FORWARD -i eth0", "iptables: No chain/target/match by that name.");
+    let bridge = BridgeSpec {
+        physical_interface: Some("eth0".to_owned()),
+        interface: "cort0".to_owned(),
+        ip_address: Ipv4Net::from_str("10.10.0.1/16").unwrap(),
+        inter_container_communication: true,
+        hairpin: false,
+        ipam: crate::spec::IpamSpec::Static
+    };
+
+    assert!(create_bridge(&sys, &bridge).is_err());
+    assert_eq!(
+        vec![
+            "ip link show cort0",
+            "ip link add name cort0 type bridge",
+            "ip link set dev cort0 up",
+            "ip addr add 10.10.0.1/16 dev cort0",
+            "sysctl net.ipv4.ip_forward=1",
+            "iptables -P FORWARD DROP",
+            "iptables -A FORWARD -i cort0 -o cort0 -j ACCEPT",
+            "iptables -t nat -A POSTROUTING -s 10.10.0.1/16 -o eth0 -j MASQUERADE",
+            "iptables -A FORWARD -i eth0 -o cort0 -j ACCEPT",
+            // What was added before the failure is undone in reverse order
+            "iptables -t nat -D POSTROUTING -s 10.10.0.1/16 -o eth0 -j MASQUERADE",
+            "iptables -D FORWARD -i cort0 -o cort0 -j ACCEPT",
+            "ip link del cort0"
+        ],
+        sys.command_lines()
+    );
+}
+
 #[test]
 fn test_ipv4net_from_str() {
     assert_eq!(Ok(Ipv4Net::new(Ipv4Addr::new(127, 0, 0, 1), 17)), Ipv4Net::from_str("127.0.0.1/17"));
@@ -290,5 +792,41 @@ fn test_ipv4net_next_address() {
     }
 
     assert_eq!(Ipv4Net::new(Ipv4Addr::new(127, 41, 12, 0), 24), current);
-    assert_eq!(true, current.is_network());
-}
\ No newline at end of file
+    assert!(current.is_network());
+}
+
+#[test]
+fn test_veth_pair_names() {
+    let veth1 = VethPair::for_container("4f3e9a1c-7d2b-4c1a-9e8f-0123456789ab");
+    let veth2 = VethPair::for_container("4f3e1111-7d2b-4c1a-9e8f-0123456789ab");
+    assert_eq!("veth4f3e9a1c7d2", veth1.host_interface);
+    assert!(veth1.host_interface.len() <= 15 && veth1.container_interface.len() <= 15);
+    assert_ne!(veth1.host_interface, veth2.host_interface);
+}
+
+#[test]
+fn test_interface_names() {
+    let links = "1: lo: <LOOPBACK,UP,LOWER_UP> mtu 65536 qdisc noqueue state UNKNOWN mode DEFAULT group default qlen 1000\\    link/loopback 00:00:00:00:00:00 brd 00:00:00:00:00:00\n\
+                 39: eth0@if40: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 qdisc noqueue state UP mode DEFAULT group default qlen 1000\\    link/ether 82:e8:5b:f6:f3:40 brd ff:ff:ff:ff:ff:ff link-netnsid 0\n";
+    assert_eq!(vec!["lo".to_owned(), "eth0".to_owned()], interface_names(links));
+
+    let veth = VethPair::for_attachment("28170ceb-d89f-4dc3-862a-3f0657f5a9fe", "backend");
+    assert!(veth.host_interface.len() <= 15);
+    assert_ne!(VethPair::for_container("28170ceb-d89f-4dc3-862a-3f0657f5a9fe").host_interface, veth.host_interface);
+}
+
+#[test]
+fn test_address_reservations() {
+    let dir = std::env::temp_dir().join(format!("cort-addresses-{}", uuid::Uuid::new_v4()));
+    let _remove_dir = crate::helpers::RemoveDirGuard::new(dir.clone());
+    let reservations = AddressReservations::new(dir.clone());
+    assert_eq!(Vec::<Ipv4Net>::new(), reservations.reserved().unwrap());
+
+    let address = Ipv4Net::from_str("10.10.1.2/16").unwrap();
+    reservations.reserve(address).unwrap();
+    std::fs::write(dir.join("10.10.1.3"), format!("{}\n10.10.1.3/16\n", i32::MAX)).unwrap();
+
+    // The reservation of a process that is gone is removed
+    assert_eq!(vec![address], reservations.reserved().unwrap());
+    assert!(!dir.join("10.10.1.3").exists());
+}
@@ -0,0 +1,133 @@
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::model::{ContainerRuntimeError, ContainerRuntimeResult};
+use crate::spec::serde_as_text;
+
+/// Containers that share the network, UTS and IPC namespaces (and /dev/shm) of the pod, which are created by its infra container
+/// (or by the first container run in it when it has none).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PodState {
+    pub name: String,
+    pub hostname: String,
+    pub network: PodNetwork,
+    /// The container that keeps the namespaces of the pod alive, unless the pod was created without one
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub infra: Option<String>,
+    /// The ids of the containers added to the pod, in the order they are started
+    #[serde(default)]
+    pub containers: Vec<String>
+}
+
+impl PodState {
+    pub fn new(name: &str, hostname: &str, network: PodNetwork) -> PodState {
+        PodState {
+            name: name.to_owned(),
+            hostname: hostname.to_owned(),
+            network,
+            infra: None,
+            containers: Vec::new()
+        }
+    }
+
+    /// The infra container followed by the other containers, in the order they are started.
+    pub fn container_ids(&self) -> impl DoubleEndedIterator<Item=&String> {
+        self.infra.iter().chain(self.containers.iter())
+    }
+
+    /// The empty root filesystem of the infra container.
+    pub fn rootfs(pod_dir: &Path) -> PathBuf {
+        pod_dir.join("rootfs")
+    }
+
+    /// The directory that the tmpfs mounted as /dev/shm in the containers of the pod is mounted on.
+    pub fn shm_dir(pod_dir: &Path) -> PathBuf {
+        pod_dir.join("shm")
+    }
+
+    pub fn exists(pod_dir: &Path) -> bool {
+        state_path(pod_dir).exists()
+    }
+
+    pub fn load(pod_dir: &Path) -> ContainerRuntimeResult<PodState> {
+        let content = std::fs::read_to_string(state_path(pod_dir))?;
+        serde_json::from_str(&content).map_err(|err| ContainerRuntimeError::State(err.to_string()))
+    }
+
+    pub fn save(&self, pod_dir: &Path) -> ContainerRuntimeResult<()> {
+        let content = serde_json::to_string_pretty(self).map_err(|err| ContainerRuntimeError::State(err.to_string()))?;
+        std::fs::write(state_path(pod_dir), content)?;
+        Ok(())
+    }
+
+    pub fn load_all(pods_dir: &Path) -> ContainerRuntimeResult<Vec<PodState>> {
+        let mut pods = Vec::new();
+        if !pods_dir.exists() {
+            return Ok(pods);
+        }
+
+        for entry in std::fs::read_dir(pods_dir)? {
+            let entry = entry?;
+            if state_path(&entry.path()).exists() {
+                pods.push(PodState::load(&entry.path())?);
+            }
+        }
+
+        pods.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(pods)
+    }
+}
+
+fn state_path(pod_dir: &Path) -> PathBuf {
+    pod_dir.join("pod.json")
+}
+
+/// The network of a pod, which cannot be the one of the host, as the pod would then have no namespaces to share.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PodNetwork {
+    Bridge,
+    Cni(String)
+}
+
+impl FromStr for PodNetwork {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "bridge" => Ok(PodNetwork::Bridge),
+            _ => match text.strip_prefix("cni:") {
+                Some(name) if !name.is_empty() => Ok(PodNetwork::Cni(name.to_owned())),
+                _ => Err("Invalid network of pod (expected bridge or cni:<name>).".to_owned())
+            }
+        }
+    }
+}
+
+impl Display for PodNetwork {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PodNetwork::Bridge => write!(f, "bridge"),
+            PodNetwork::Cni(name) => write!(f, "cni:{}", name)
+        }
+    }
+}
+
+serde_as_text!(PodNetwork);
+
+#[test]
+fn test_pod_network() {
+    assert_eq!(Ok(PodNetwork::Bridge), PodNetwork::from_str("bridge"));
+    assert_eq!(Ok(PodNetwork::Cni("lan".to_owned())), PodNetwork::from_str("cni:lan"));
+    assert!(PodNetwork::from_str("host").is_err());
+    assert!(PodNetwork::from_str("cni:").is_err());
+
+    let mut pod = PodState::new("web", "web", PodNetwork::Cni("lan".to_owned()));
+    pod.infra = Some("infra".to_owned());
+    pod.containers.push("app".to_owned());
+    let pod: PodState = serde_json::from_str(&serde_json::to_string(&pod).unwrap()).unwrap();
+    assert_eq!(PodNetwork::Cni("lan".to_owned()), pod.network);
+    assert_eq!(vec!["infra", "app"], pod.container_ids().collect::<Vec<_>>());
+}
@@ -0,0 +1,122 @@
+use std::io::Read;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// How often the progress of a stage is reported at most, as reads are much more frequent than what is useful to show.
+const REPORT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What is being done with a blob or layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all="snake_case")]
+pub enum ProgressStage {
+    Download,
+    Extract,
+    Upload,
+    Import
+}
+
+impl ProgressStage {
+    pub fn name(&self) -> &str {
+        match self {
+            ProgressStage::Download => "Downloading",
+            ProgressStage::Extract => "Extracting",
+            ProgressStage::Upload => "Uploading",
+            ProgressStage::Import => "Importing"
+        }
+    }
+}
+
+/// An event of a long operation (such as a pull), about a stage of a blob or layer identified by its digest (or the image when imported).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag="event", rename_all="snake_case")]
+pub enum ProgressEvent {
+    /// The total number of bytes is None when not known in advance
+    Started { id: String, stage: ProgressStage, total: Option<u64> },
+    Advanced { id: String, stage: ProgressStage, current: u64, total: Option<u64> },
+    Finished { id: String, stage: ProgressStage },
+    /// Nothing had to be done, such as when the blob already exists
+    Skipped { id: String, stage: ProgressStage }
+}
+
+/// Receives the events of long operations, such as to render progress bars or to stream them to clients.
+pub trait ProgressReporter {
+    fn report(&self, event: ProgressEvent);
+}
+
+/// Discards all events.
+pub struct NoProgress;
+
+impl ProgressReporter for NoProgress {
+    fn report(&self, _event: ProgressEvent) {}
+}
+
+impl<F: Fn(ProgressEvent)> ProgressReporter for F {
+    fn report(&self, event: ProgressEvent) {
+        self(event)
+    }
+}
+
+impl ProgressReporter for Sender<ProgressEvent> {
+    fn report(&self, event: ProgressEvent) {
+        // The operation continues even if no one listens anymore
+        self.send(event).ok();
+    }
+}
+
+/// Reports the bytes read through it as the progress of a stage, which is started when created.
+pub(crate) struct ProgressReader<'a, R> {
+    inner: R,
+    reporter: &'a dyn ProgressReporter,
+    id: String,
+    stage: ProgressStage,
+    current: u64,
+    total: Option<u64>,
+    last_report: Instant
+}
+
+impl<'a, R: Read> ProgressReader<'a, R> {
+    pub fn new(inner: R, reporter: &'a dyn ProgressReporter, id: &str, stage: ProgressStage, total: Option<u64>) -> ProgressReader<'a, R> {
+        reporter.report(ProgressEvent::Started { id: id.to_owned(), stage, total });
+
+        ProgressReader {
+            inner,
+            reporter,
+            id: id.to_owned(),
+            stage,
+            current: 0,
+            total,
+            last_report: Instant::now()
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.inner.read(buffer)?;
+        self.current += count as u64;
+
+        // The end is always reported, so that the last event has the full count
+        if (count == 0 && self.current > 0) || self.last_report.elapsed() >= REPORT_INTERVAL {
+            self.last_report = Instant::now();
+            self.reporter.report(ProgressEvent::Advanced { id: self.id.clone(), stage: self.stage, current: self.current, total: self.total });
+        }
+
+        Ok(count)
+    }
+}
+
+#[test]
+fn test_progress_reader() {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let mut reader = ProgressReader::new(&b"layer"[..], &sender, "sha256:1234", ProgressStage::Download, Some(5));
+    std::io::copy(&mut reader, &mut std::io::sink()).unwrap();
+
+    let events = receiver.try_iter().collect::<Vec<_>>();
+    assert_eq!(ProgressEvent::Started { id: "sha256:1234".to_owned(), stage: ProgressStage::Download, total: Some(5) }, events[0]);
+    assert_eq!(
+        Some(&ProgressEvent::Advanced { id: "sha256:1234".to_owned(), stage: ProgressStage::Download, current: 5, total: Some(5) }),
+        events.last()
+    );
+}
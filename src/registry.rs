@@ -0,0 +1,815 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use tracing::{info, trace};
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::pem::PemObject;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::RegistryConfig;
+use crate::credentials::{self, Credentials};
+use crate::model::{ContainerRuntimeError, ContainerRuntimeResult};
+use crate::progress::{ProgressEvent, ProgressReader, ProgressReporter, ProgressStage};
+
+pub const DOCKER_HUB_REGISTRY: &str = "registry-1.docker.io";
+
+pub const MEDIA_TYPE_DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+pub const MEDIA_TYPE_DOCKER_MANIFEST_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+pub const MEDIA_TYPE_DOCKER_CONFIG: &str = "application/vnd.docker.container.image.v1+json";
+pub const MEDIA_TYPE_DOCKER_LAYER_GZIP: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+pub const MEDIA_TYPE_OCI_LAYER_ZSTD: &str = "application/vnd.oci.image.layer.v1.tar+zstd";
+pub const MEDIA_TYPE_OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const MEDIA_TYPE_OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+
+const UPLOAD_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+const UPLOAD_CHUNK_RETRIES: usize = 3;
+
+/// The client id given to OAuth2 token servers, which identifies the client rather than authenticating it.
+const OAUTH_CLIENT_ID: &str = "cort";
+/// How long tokens are valid when the token server does not say, as given by the token authentication spec.
+const DEFAULT_TOKEN_EXPIRY: Duration = Duration::from_secs(60);
+/// Tokens are renewed this long before they expire, so that they do not expire during a request.
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageReference {
+    pub registry: String,
+    pub repository: String,
+    /// The tag, or the digest of the manifest (sha256:...) when pinned by digest
+    pub tag: String
+}
+
+impl ImageReference {
+    pub fn is_digest(&self) -> bool {
+        is_sha256_digest(&self.tag)
+    }
+
+    /// The local image it is pulled to by default: the last part of the repository and the tag,
+    /// where images pulled by digest get a tag from the digest, as a tag cannot contain ':'.
+    pub fn local_name(&self) -> String {
+        format!("{}:{}", self.repository_name(), self.tag.replace(':', "-"))
+    }
+
+    /// The last part of the repository, such as `ubuntu` of `library/ubuntu`.
+    pub fn repository_name(&self) -> &str {
+        self.repository.rsplit('/').next().unwrap()
+    }
+}
+
+impl FromStr for ImageReference {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        if text.is_empty() {
+            return Err("Empty image reference.".to_owned());
+        }
+
+        // A digest pins the manifest, where a tag given along with it is ignored
+        let (text, digest) = match text.split_once('@') {
+            Some((_, digest)) if !is_sha256_digest(digest) => return Err(format!("Invalid digest '{}' (expected sha256:<hex>).", digest)),
+            Some((name, digest)) => (name, Some(digest)),
+            None => (text, None)
+        };
+
+        let (name, tag) = match text.rsplit_once(':') {
+            Some((name, tag)) if !tag.contains('/') => (name, tag.to_owned()),
+            _ => (text, "latest".to_owned())
+        };
+        let tag = digest.map(|digest| digest.to_owned()).unwrap_or(tag);
+
+        let (registry, repository) = match name.split_once('/') {
+            Some((registry, repository)) if registry.contains('.') || registry.contains(':') || registry == "localhost" => {
+                (registry.to_owned(), repository.to_owned())
+            }
+            _ => (DOCKER_HUB_REGISTRY.to_owned(), name.to_owned())
+        };
+
+        let repository = if registry == DOCKER_HUB_REGISTRY && !repository.contains('/') {
+            format!("library/{}", repository)
+        } else {
+            repository
+        };
+
+        if repository.is_empty() || tag.is_empty() {
+            return Err(format!("Invalid image reference '{}'.", text));
+        }
+
+        Ok(ImageReference { registry, repository, tag })
+    }
+}
+
+impl Display for ImageReference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_digest() {
+            write!(f, "{}/{}@{}", self.registry, self.repository, self.tag)
+        } else {
+            write!(f, "{}/{}:{}", self.registry, self.repository, self.tag)
+        }
+    }
+}
+
+/// The registry of the name given by the user, where Docker Hub can also be given as `docker.io`.
+pub fn normalize_registry(name: &str) -> &str {
+    let name = name.trim_start_matches("https://").trim_start_matches("http://").trim_end_matches('/');
+    match name {
+        "docker.io" | "index.docker.io" | "index.docker.io/v1" => DOCKER_HUB_REGISTRY,
+        _ => name
+    }
+}
+
+/// Whether the text is a sha256 digest, such as `sha256:` followed by 64 hex digits.
+pub fn is_sha256_digest(text: &str) -> bool {
+    match text.split_once(':') {
+        Some(("sha256", hex)) => hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        _ => false
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all="camelCase")]
+pub struct Descriptor {
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub platform: Option<Platform>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub annotations: Option<HashMap<String, String>>
+}
+
+/// The platform of an image, which is also how the config of an image gives it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Platform {
+    pub architecture: String,
+    pub os: String,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub variant: Option<String>
+}
+
+impl Platform {
+    pub fn host() -> Platform {
+        Platform {
+            architecture: host_architecture().to_owned(),
+            os: "linux".to_owned(),
+            variant: None
+        }
+    }
+
+    /// Whether an image of the platform can be used where this platform is wanted, where a variant is only compared when wanted.
+    pub fn matches(&self, platform: &Platform) -> bool {
+        self.os == platform.os
+        && self.architecture == platform.architecture
+        && self.variant.as_ref().is_none_or(|variant| Some(variant) == platform.variant.as_ref())
+    }
+}
+
+impl FromStr for Platform {
+    type Err = String;
+
+    /// Parses platforms of the form `<os>/<architecture>[/<variant>]`, such as `linux/arm64` or `linux/arm/v7`.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let parts = text.split('/').collect::<Vec<_>>();
+        if parts.len() < 2 || parts.len() > 3 || parts.iter().any(|part| part.is_empty()) {
+            return Err(format!("Invalid platform '{}' (expected <os>/<architecture>[/<variant>]).", text));
+        }
+
+        Ok(
+            Platform {
+                architecture: parts[1].to_owned(),
+                os: parts[0].to_owned(),
+                variant: parts.get(2).map(|variant| (*variant).to_owned())
+            }
+        )
+    }
+}
+
+impl Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The architecture of the host as named by images, such as `amd64` and `arm64`.
+pub fn host_architecture() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "x86" => "386",
+        "powerpc64" => "ppc64le",
+        architecture => architecture
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all="camelCase")]
+pub struct Manifest {
+    pub schema_version: u32,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub media_type: Option<String>,
+    pub config: Descriptor,
+    pub layers: Vec<Descriptor>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all="camelCase")]
+pub struct ManifestList {
+    pub schema_version: u32,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub media_type: Option<String>,
+    pub manifests: Vec<Descriptor>
+}
+
+/// Cloned to use the same authorization from more than one thread.
+#[derive(Clone)]
+pub struct RegistryClient {
+    agent: ureq::Agent,
+    registry: String,
+    base_url: String,
+    credentials: Option<Credentials>,
+    authorization: Option<String>,
+    /// The token server of registries that use bearer tokens, known after the first challenge
+    token_server: Option<TokenServer>,
+    token_expires: Option<Instant>,
+    scope: String
+}
+
+#[derive(Clone)]
+struct TokenServer {
+    realm: String,
+    service: Option<String>
+}
+
+impl RegistryClient {
+    /// Connects with the settings of the registry, if any, where the credentials default to the stored ones (such as by `cort login`).
+    pub fn new(registry: &str, repository: &str, push: bool, registries: &HashMap<String, RegistryConfig>) -> ContainerRuntimeResult<RegistryClient> {
+        let config = find_registry_config(registries, registry).cloned().unwrap_or_default();
+        let insecure = config.insecure || registry.starts_with("localhost") || registry.starts_with("127.0.0.1");
+
+        let mut agent = ureq::AgentBuilder::new();
+        if let Some(proxy) = &config.proxy {
+            let proxy = ureq::Proxy::new(proxy).map_err(|err| ContainerRuntimeError::Config(format!("Invalid proxy '{}' of registry {}: {}", proxy, registry, err)))?;
+            agent = agent.proxy(proxy);
+        }
+        if let Some(ca_cert) = &config.ca_cert {
+            agent = agent.tls_config(Arc::new(tls_config(ca_cert)?));
+        }
+
+        let credentials = match (config.username, config.password) {
+            (Some(username), Some(password)) => Some(Credentials::Password { username, password }),
+            _ => credentials::find_credentials(registry, config.credential_helper.as_deref())?
+        };
+
+        Ok(
+            RegistryClient {
+                agent: agent.build(),
+                registry: registry.to_owned(),
+                base_url: format!("{}://{}", if insecure { "http" } else { "https" }, registry),
+                credentials,
+                authorization: None,
+                token_server: None,
+                token_expires: None,
+                scope: format!("repository:{}:{}", repository, if push { "pull,push" } else { "pull" })
+            }
+        )
+    }
+
+    /// The registry connected to, such as a mirror of the one of the image.
+    pub fn registry(&self) -> &str {
+        &self.registry
+    }
+
+    pub fn get_manifest(&mut self, repository: &str, reference: &str) -> ContainerRuntimeResult<(String, Vec<u8>)> {
+        self.find_manifest(repository, reference)?
+            .ok_or_else(|| ContainerRuntimeError::Registry(format!("Manifest {} not found in {}", reference, repository)))
+    }
+
+    pub fn find_manifest(&mut self, repository: &str, reference: &str) -> ContainerRuntimeResult<Option<(String, Vec<u8>)>> {
+        let url = format!("{}/v2/{}/manifests/{}", self.base_url, repository, reference);
+        let accept = [MEDIA_TYPE_DOCKER_MANIFEST, MEDIA_TYPE_DOCKER_MANIFEST_LIST, MEDIA_TYPE_OCI_MANIFEST, MEDIA_TYPE_OCI_INDEX].join(", ");
+        let response = self.execute("GET", &url, &[("Accept", &accept)], None)?;
+        if response.status() == 404 {
+            return Ok(None);
+        }
+
+        let response = check_status(response, &[200])?;
+        let content_type = response.header("Content-Type").unwrap_or(MEDIA_TYPE_DOCKER_MANIFEST).to_owned();
+        let mut content = Vec::new();
+        response.into_reader().read_to_end(&mut content)?;
+        Ok(Some((content_type, content)))
+    }
+
+    pub fn put_manifest(&mut self, repository: &str, reference: &str, media_type: &str, content: &[u8]) -> ContainerRuntimeResult<()> {
+        let url = format!("{}/v2/{}/manifests/{}", self.base_url, repository, reference);
+        let response = self.execute("PUT", &url, &[("Content-Type", media_type)], Some(content))?;
+        check_status(response, &[200, 201])?;
+        Ok(())
+    }
+
+    pub fn download_blob(&mut self, repository: &str, digest: &str, destination: &Path, progress: &dyn ProgressReporter) -> ContainerRuntimeResult<()> {
+        let url = format!("{}/v2/{}/blobs/{}", self.base_url, repository, digest);
+        let response = self.execute("GET", &url, &[], None)?;
+        let response = check_status(response, &[200])?;
+        let size = response.header("Content-Length").and_then(|size| u64::from_str(size).ok());
+
+        let mut writer = DigestWriter::new(File::create(destination)?);
+        std::io::copy(&mut ProgressReader::new(response.into_reader(), progress, digest, ProgressStage::Download, size), &mut writer)?;
+
+        let (_, actual_digest, _) = writer.finish();
+        if actual_digest != digest {
+            std::fs::remove_file(destination)?;
+            return Err(ContainerRuntimeError::Registry(format!("Digest mismatch for blob {} (got {})", digest, actual_digest)));
+        }
+
+        progress.report(ProgressEvent::Finished { id: digest.to_owned(), stage: ProgressStage::Download });
+        Ok(())
+    }
+
+    pub fn get_blob(&mut self, repository: &str, digest: &str) -> ContainerRuntimeResult<Vec<u8>> {
+        let url = format!("{}/v2/{}/blobs/{}", self.base_url, repository, digest);
+        let response = check_status(self.execute("GET", &url, &[], None)?, &[200])?;
+
+        let mut content = Vec::new();
+        response.into_reader().read_to_end(&mut content)?;
+
+        let actual_digest = sha256_digest(&content);
+        if actual_digest != digest {
+            return Err(ContainerRuntimeError::Registry(format!("Digest mismatch for blob {} (got {})", digest, actual_digest)));
+        }
+
+        Ok(content)
+    }
+
+    /// Reads part of a blob, which cannot be checked against the digest of the blob as a whole.
+    pub fn get_blob_range(&mut self, repository: &str, digest: &str, offset: u64, length: u64) -> ContainerRuntimeResult<Vec<u8>> {
+        let url = format!("{}/v2/{}/blobs/{}", self.base_url, repository, digest);
+        let range = format!("bytes={}-{}", offset, offset + length - 1);
+        let response = self.execute("GET", &url, &[("Range", &range)], None)?;
+        if response.status() == 200 {
+            return Err(ContainerRuntimeError::Registry(format!("The registry does not support reading part of blob {}", digest)));
+        }
+
+        let response = check_status(response, &[206])?;
+        let mut content = Vec::with_capacity(length as usize);
+        response.into_reader().take(length).read_to_end(&mut content)?;
+        if content.len() as u64 != length {
+            return Err(ContainerRuntimeError::Registry(format!("Expected {} bytes of blob {} but got {}", length, digest, content.len())));
+        }
+
+        Ok(content)
+    }
+
+    pub fn blob_exists(&mut self, repository: &str, digest: &str) -> ContainerRuntimeResult<bool> {
+        let url = format!("{}/v2/{}/blobs/{}", self.base_url, repository, digest);
+        let response = self.execute("HEAD", &url, &[], None)?;
+        Ok(response.status() == 200)
+    }
+
+    pub fn upload_blob(&mut self, repository: &str, digest: &str, source: &Path, progress: &dyn ProgressReporter) -> ContainerRuntimeResult<()> {
+        if self.blob_exists(repository, digest)? {
+            trace!("Blob {} already exists in {}", digest, repository);
+            progress.report(ProgressEvent::Skipped { id: digest.to_owned(), stage: ProgressStage::Upload });
+            return Ok(());
+        }
+
+        let url = format!("{}/v2/{}/blobs/uploads/", self.base_url, repository);
+        let response = check_status(self.execute("POST", &url, &[], Some(&[]))?, &[202])?;
+        let mut location = self.upload_location(&response)?;
+
+        let mut file = File::open(source)?;
+        let size = file.metadata()?.len() as usize;
+        let mut offset = 0;
+        let mut chunk = vec![0u8; UPLOAD_CHUNK_SIZE];
+        progress.report(ProgressEvent::Started { id: digest.to_owned(), stage: ProgressStage::Upload, total: Some(size as u64) });
+
+        while offset < size {
+            let chunk_size = UPLOAD_CHUNK_SIZE.min(size - offset);
+            file.read_exact(&mut chunk[..chunk_size])?;
+
+            let mut attempt = 0;
+            loop {
+                let content_range = format!("{}-{}", offset, offset + chunk_size - 1);
+                let headers = [("Content-Type", "application/octet-stream"), ("Content-Range", content_range.as_str())];
+                let result = self.execute("PATCH", &location, &headers, Some(&chunk[..chunk_size]))
+                    .and_then(|response| check_status(response, &[202]));
+
+                match result {
+                    Ok(response) => {
+                        location = self.upload_location(&response)?;
+                        offset += chunk_size;
+                        break;
+                    }
+                    Err(err) if attempt < UPLOAD_CHUNK_RETRIES => {
+                        attempt += 1;
+                        info!("Upload of chunk {} failed ({}), resuming (attempt {}/{}).", content_range, err, attempt, UPLOAD_CHUNK_RETRIES);
+
+                        // Resume from whatever the registry has received so far
+                        let (new_location, received) = self.upload_status(&location)?;
+                        location = new_location;
+                        if received != offset {
+                            offset = received;
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        return Err(err);
+                    }
+                }
+            }
+
+            file.seek(SeekFrom::Start(offset as u64))?;
+            progress.report(ProgressEvent::Advanced { id: digest.to_owned(), stage: ProgressStage::Upload, current: offset as u64, total: Some(size as u64) });
+        }
+
+        let separator = if location.contains('?') { '&' } else { '?' };
+        let url = format!("{}{}digest={}", location, separator, digest);
+        check_status(self.execute("PUT", &url, &[("Content-Type", "application/octet-stream")], Some(&[]))?, &[201])?;
+        progress.report(ProgressEvent::Finished { id: digest.to_owned(), stage: ProgressStage::Upload });
+        Ok(())
+    }
+
+    fn upload_status(&mut self, location: &str) -> ContainerRuntimeResult<(String, usize)> {
+        let response = check_status(self.execute("GET", location, &[], None)?, &[204])?;
+        let received = response
+            .header("Range")
+            .and_then(|range| range.split('-').nth(1))
+            .and_then(|end| usize::from_str(end).ok())
+            .map(|end| end + 1)
+            .unwrap_or(0);
+
+        Ok((self.upload_location(&response)?, received))
+    }
+
+    fn upload_location(&self, response: &ureq::Response) -> ContainerRuntimeResult<String> {
+        let location = response
+            .header("Location")
+            .ok_or_else(|| ContainerRuntimeError::Registry("Missing upload location".to_owned()))?;
+
+        if location.starts_with("http://") || location.starts_with("https://") {
+            Ok(location.to_owned())
+        } else {
+            Ok(format!("{}{}", self.base_url, location))
+        }
+    }
+
+    fn execute(&mut self, method: &str, url: &str, headers: &[(&str, &str)], body: Option<&[u8]>) -> ContainerRuntimeResult<ureq::Response> {
+        // Long pulls and pushes outlive the tokens, which are renewed rather than failing a request first
+        if self.token_expires.is_some_and(|token_expires| Instant::now() >= token_expires) {
+            trace!("Renewing the token of {}", self.registry);
+            self.request_token()?;
+        }
+
+        for attempt in 0..2 {
+            let mut request = self.agent.request(method, url);
+            for (name, value) in headers {
+                request = request.set(name, value);
+            }
+
+            if let Some(authorization) = &self.authorization {
+                request = request.set("Authorization", authorization);
+            }
+
+            let result = match body {
+                Some(body) => request.send_bytes(body),
+                None => request.call()
+            };
+
+            match result {
+                Ok(response) => {
+                    return Ok(response);
+                }
+                Err(ureq::Error::Status(401, response)) if attempt == 0 => {
+                    self.authenticate(&response)?;
+                }
+                Err(ureq::Error::Status(_, response)) => {
+                    return Ok(response);
+                }
+                Err(err) => {
+                    return Err(ContainerRuntimeError::Registry(format!("{} {} failed: {}", method, url, err)));
+                }
+            }
+        }
+
+        Err(ContainerRuntimeError::Registry(format!("{} {} failed: not authorized", method, url)))
+    }
+
+    fn authenticate(&mut self, response: &ureq::Response) -> ContainerRuntimeResult<()> {
+        let challenge = response
+            .header("WWW-Authenticate")
+            .ok_or_else(|| ContainerRuntimeError::Registry("Missing authentication challenge".to_owned()))?
+            .to_owned();
+
+        if challenge.starts_with("Basic") {
+            let Some(Credentials::Password { username, password }) = &self.credentials else {
+                return Err(ContainerRuntimeError::Registry("Registry requires a username and password".to_owned()));
+            };
+            self.authorization = Some(basic_authorization(username, password));
+            return Ok(());
+        }
+
+        let mut parameters = parse_challenge(challenge.strip_prefix("Bearer").unwrap_or(&challenge));
+        let realm = parameters
+            .remove("realm")
+            .ok_or_else(|| ContainerRuntimeError::Registry(format!("Unsupported authentication challenge: {}", challenge)))?;
+
+        self.token_server = Some(TokenServer { realm, service: parameters.remove("service") });
+        self.request_token()
+    }
+
+    /// Gets a token from the token server, which is exchanged for the identity token if the credentials are one (as OAuth2).
+    fn request_token(&mut self) -> ContainerRuntimeResult<()> {
+        let token_server = self.token_server.clone().ok_or_else(|| ContainerRuntimeError::Registry("No token server".to_owned()))?;
+        let requested = Instant::now();
+
+        let response = match &self.credentials {
+            Some(Credentials::IdentityToken(identity_token)) => {
+                let mut form = vec![("grant_type", "refresh_token"), ("refresh_token", identity_token.as_str()), ("client_id", OAUTH_CLIENT_ID)];
+                if let Some(service) = &token_server.service {
+                    form.push(("service", service));
+                }
+                if !self.scope.is_empty() {
+                    form.push(("scope", &self.scope));
+                }
+
+                self.agent.post(&token_server.realm).send_form(&form)
+            }
+            credentials => {
+                let mut request = self.agent.get(&token_server.realm);
+                if let Some(service) = &token_server.service {
+                    request = request.query("service", service);
+                }
+                if !self.scope.is_empty() {
+                    request = request.query("scope", &self.scope);
+                }
+                if let Some(Credentials::Password { username, password }) = credentials {
+                    request = request.set("Authorization", &basic_authorization(username, password));
+                }
+
+                request.call()
+            }
+        };
+
+        let token: TokenResponse = match response {
+            Ok(response) => response.into_json()?,
+            Err(ureq::Error::Status(401, _)) => return Err(ContainerRuntimeError::Registry(format!("Failed to get token from {}: invalid credentials", token_server.realm))),
+            Err(err) => return Err(ContainerRuntimeError::Registry(format!("Failed to get token: {}", err)))
+        };
+
+        let expires_in = token.expires_in.map(Duration::from_secs).unwrap_or(DEFAULT_TOKEN_EXPIRY);
+        let token = token.token
+            .or(token.access_token)
+            .ok_or_else(|| ContainerRuntimeError::Registry("No token returned".to_owned()))?;
+        self.authorization = Some(format!("Bearer {}", token));
+        self.token_expires = Some(requested + expires_in.saturating_sub(TOKEN_EXPIRY_MARGIN));
+        Ok(())
+    }
+
+    /// Asks the token server for an identity token (an OAuth2 refresh token) for the password, which not all token servers give.
+    fn request_identity_token(&self, username: &str, password: &str) -> Option<String> {
+        let token_server = self.token_server.as_ref()?;
+        let mut form = vec![
+            ("grant_type", "password"),
+            ("username", username),
+            ("password", password),
+            ("client_id", OAUTH_CLIENT_ID),
+            ("access_type", "offline")
+        ];
+        if let Some(service) = &token_server.service {
+            form.push(("service", service));
+        }
+
+        let token: TokenResponse = self.agent.post(&token_server.realm).send_form(&form).ok()?.into_json().ok()?;
+        token.refresh_token
+    }
+}
+
+/// Checks the username and password with the registry, returning the credentials to store: an identity token instead of the password
+/// if the token server of the registry gives one.
+pub fn login(registry: &str, username: &str, password: &str, registries: &HashMap<String, RegistryConfig>) -> ContainerRuntimeResult<Credentials> {
+    let mut client = RegistryClient::new(registry, "", false, registries)?;
+    let mut credentials = Credentials::Password { username: username.to_owned(), password: password.to_owned() };
+    client.credentials = Some(credentials.clone());
+    client.scope = String::new();
+
+    let url = format!("{}/v2/", client.base_url);
+    if let Err(ureq::Error::Status(401, response)) = client.agent.get(&url).call() {
+        client.authenticate(&response)?;
+
+        if let Some(identity_token) = client.request_identity_token(username, password) {
+            credentials = Credentials::IdentityToken(identity_token);
+            client.credentials = Some(credentials.clone());
+            client.request_token()?;
+        }
+    }
+
+    let response = client.execute("GET", &url, &[], None)?;
+    if response.status() == 401 {
+        return Err(ContainerRuntimeError::Registry(format!("Login to {} failed: invalid username or password", registry)));
+    }
+    check_status(response, &[200])?;
+
+    Ok(credentials)
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+    /// The lifetime of the token in seconds
+    expires_in: Option<u64>,
+    refresh_token: Option<String>
+}
+
+fn check_status(response: ureq::Response, expected: &[u16]) -> ContainerRuntimeResult<ureq::Response> {
+    if expected.contains(&response.status()) {
+        Ok(response)
+    } else {
+        let status = response.status();
+        let url = response.get_url().to_owned();
+        let body = response.into_string().unwrap_or_default();
+        Err(ContainerRuntimeError::Registry(format!("{} returned status {}: {}", url, status, body.trim())))
+    }
+}
+
+fn parse_challenge(text: &str) -> HashMap<String, String> {
+    let mut parameters = HashMap::new();
+    let mut rest = text.trim();
+
+    while let Some((key, value)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_owned();
+        let value = value.trim_start();
+
+        let (value, remaining) = if let Some(value) = value.strip_prefix('"') {
+            let end = value.find('"').unwrap_or(value.len());
+            (&value[..end], value.get(end + 1..).unwrap_or(""))
+        } else {
+            let end = value.find(',').unwrap_or(value.len());
+            (&value[..end], &value[end..])
+        };
+
+        parameters.insert(key, value.to_owned());
+        rest = remaining;
+    }
+
+    parameters
+}
+
+fn basic_authorization(username: &str, password: &str) -> String {
+    format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", username, password)))
+}
+
+/// The settings of the registry, where Docker Hub can also be configured as `docker.io`.
+pub fn find_registry_config<'a>(registries: &'a HashMap<String, RegistryConfig>, registry: &str) -> Option<&'a RegistryConfig> {
+    registries
+        .get(registry)
+        .or_else(|| if registry == DOCKER_HUB_REGISTRY { registries.get("docker.io") } else { None })
+}
+
+/// Trusts the certificate authorities of the file along with the public ones.
+fn tls_config(ca_cert: &Path) -> ContainerRuntimeResult<rustls::ClientConfig> {
+    let inner = || -> Result<rustls::ClientConfig, String> {
+        let certificates = CertificateDer::pem_file_iter(ca_cert)
+            .map_err(|err| err.to_string())?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| err.to_string())?;
+        if certificates.is_empty() {
+            return Err("no certificates found".to_owned());
+        }
+
+        let mut roots = rustls::RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+        for certificate in certificates {
+            roots.add(certificate).map_err(|err| err.to_string())?;
+        }
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        Ok(
+            rustls::ClientConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions()
+                .map_err(|err| err.to_string())?
+                .with_root_certificates(roots)
+                .with_no_client_auth()
+        )
+    };
+
+    inner().map_err(|err| ContainerRuntimeError::Config(format!("Failed to load certificates from {}: {}", ca_cert.to_str().unwrap(), err)))
+}
+
+pub fn sha256_digest(content: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(content)))
+}
+
+/// Computes the digest and size of everything written through it.
+pub struct DigestWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+    size: u64
+}
+
+impl<W: Write> DigestWriter<W> {
+    pub fn new(inner: W) -> DigestWriter<W> {
+        DigestWriter {
+            inner,
+            hasher: Sha256::new(),
+            size: 0
+        }
+    }
+
+    pub fn finish(self) -> (W, String, u64) {
+        (self.inner, format!("sha256:{}", hex::encode(self.hasher.finalize())), self.size)
+    }
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn test_image_reference_from_str() {
+    assert_eq!(
+        Ok(ImageReference { registry: DOCKER_HUB_REGISTRY.to_owned(), repository: "library/ubuntu".to_owned(), tag: "latest".to_owned() }),
+        ImageReference::from_str("ubuntu")
+    );
+
+    assert_eq!(
+        Ok(ImageReference { registry: DOCKER_HUB_REGISTRY.to_owned(), repository: "someone/app".to_owned(), tag: "1.0".to_owned() }),
+        ImageReference::from_str("someone/app:1.0")
+    );
+
+    assert_eq!(
+        Ok(ImageReference { registry: "localhost:5000".to_owned(), repository: "team/app".to_owned(), tag: "latest".to_owned() }),
+        ImageReference::from_str("localhost:5000/team/app")
+    );
+
+    let digest = format!("sha256:{}", "ab".repeat(32));
+    let reference = ImageReference::from_str(&format!("alpine:3.18@{}", digest)).unwrap();
+    assert_eq!(("library/alpine", digest.as_str()), (reference.repository.as_str(), reference.tag.as_str()));
+    assert!(reference.is_digest());
+    assert_eq!(format!("{}/library/alpine@{}", DOCKER_HUB_REGISTRY, digest), reference.to_string());
+    assert_eq!(format!("alpine:sha256-{}", "ab".repeat(32)), reference.local_name());
+    assert_eq!("app:latest", ImageReference::from_str("localhost:5000/team/app").unwrap().local_name());
+    assert!(ImageReference::from_str("alpine@sha256:abc").is_err());
+}
+
+#[test]
+fn test_platform() {
+    let platform = Platform::from_str("linux/arm/v7").unwrap();
+    assert_eq!(("linux", "arm", Some("v7")), (platform.os.as_str(), platform.architecture.as_str(), platform.variant.as_deref()));
+    assert_eq!("linux/arm/v7", platform.to_string());
+    assert!(Platform::from_str("linux").is_err());
+    assert!(Platform::from_str("linux//v7").is_err());
+
+    // A variant is only compared when one is wanted
+    let arm64 = Platform::from_str("linux/arm64").unwrap();
+    assert!(arm64.matches(&Platform::from_str("linux/arm64/v8").unwrap()));
+    assert!(!Platform::from_str("linux/arm64/v9").unwrap().matches(&Platform::from_str("linux/arm64/v8").unwrap()));
+    assert!(!arm64.matches(&Platform::from_str("linux/amd64").unwrap()));
+}
+
+#[test]
+fn test_parse_challenge() {
+    let parameters = parse_challenge(r#" realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/ubuntu:pull""#);
+    assert_eq!(Some(&"https://auth.docker.io/token".to_owned()), parameters.get("realm"));
+    assert_eq!(Some(&"registry.docker.io".to_owned()), parameters.get("service"));
+    assert_eq!(Some(&"repository:library/ubuntu:pull".to_owned()), parameters.get("scope"));
+}
+
+#[test]
+fn test_registry_config() {
+    let mut registries = HashMap::new();
+    registries.insert("docker.io".to_owned(), RegistryConfig { mirrors: vec!["mirror.internal".to_owned()], ..RegistryConfig::default() });
+    registries.insert(
+        "mirror.internal".to_owned(),
+        RegistryConfig { insecure: true, username: Some("puller".to_owned()), password: Some("secret".to_owned()), ..RegistryConfig::default() }
+    );
+    registries.insert("private.internal".to_owned(), RegistryConfig { ca_cert: Some(std::path::PathBuf::from("/nonexistent/ca.pem")), ..RegistryConfig::default() });
+
+    assert_eq!(vec!["mirror.internal".to_owned()], find_registry_config(&registries, DOCKER_HUB_REGISTRY).unwrap().mirrors);
+    assert!(find_registry_config(&registries, "quay.io").is_none());
+
+    let client = RegistryClient::new("mirror.internal", "library/alpine", false, &registries).unwrap();
+    assert_eq!("http://mirror.internal", client.base_url);
+    assert_eq!(Some(Credentials::Password { username: "puller".to_owned(), password: "secret".to_owned() }), client.credentials);
+    assert_eq!("https://quay.io", RegistryClient::new("quay.io", "app", false, &registries).unwrap().base_url);
+    assert!(matches!(RegistryClient::new("private.internal", "app", false, &registries), Err(ContainerRuntimeError::Config(_))));
+}
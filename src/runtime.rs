@@ -0,0 +1,1141 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::audit::{AUDIT_LOG, AuditedSysApi, AuditLog};
+use crate::config::{ContainerRuntimeConfig, DEFAULT_NETWORK, NetworkConfig, SignaturePolicy};
+use crate::container;
+use crate::container::ContainerProcess;
+use crate::copy;
+use crate::copy::ContainerFilesystem;
+use crate::credentials;
+use crate::diagnostics;
+use crate::diagnostics::NetworkReport;
+use crate::emulation;
+use crate::helpers;
+use crate::helpers::Rollback;
+use crate::hosts;
+use crate::image;
+use crate::image::{HistoryEntry, ImageMetadata, ImageStore, LocalImageReference, PullOptions};
+use crate::linux::{has_exited, lock_file, pidfd_send_signal};
+use crate::logs;
+use crate::logs::LogEntry;
+use crate::model::{ContainerExitStatus, ContainerRuntimeError, ContainerRuntimeResult, RunPlan};
+use crate::names;
+use crate::network;
+use crate::network::{AddressReservations, Ipv4Net, NetworkAttachment};
+use crate::pod::{PodNetwork, PodState};
+use crate::progress::{NoProgress, ProgressReporter};
+use crate::registry;
+use crate::registry::{ImageReference, Platform};
+use crate::signature::SignatureVerifier;
+use crate::storage;
+use crate::cni::CniNetworkConfig;
+use crate::spec::{BridgedNetworkSpec, BridgeSpec, CniNetworkSpec, DEFAULT_SHM_SIZE, LogDriverSpec, LogSpec, NamespaceMode, NetworkSpec, PodNetworkSpec, PodSpec, PullPolicy, RunContainerSpec, SchedulingSpec, SecuritySpec};
+use crate::state::{ContainerFilter, ContainerState, ExecSession, NameRegistry, ResourceLimits};
+use crate::sys::{HostSysApi, SysApi};
+use crate::systemd;
+use crate::systemd::{ServiceOptions, UnitFile};
+
+pub struct ContainerRuntime {
+    config: ContainerRuntimeConfig,
+    sys: Arc<dyn SysApi>
+}
+
+impl ContainerRuntime {
+    pub fn new(config: ContainerRuntimeConfig) -> ContainerRuntime {
+        ContainerRuntime::with_sys(config, Arc::new(HostSysApi))
+    }
+
+    /// Uses the given API for the calls that change the host and the containers, rather than making them on the host.
+    pub fn with_sys(config: ContainerRuntimeConfig, sys: Arc<dyn SysApi>) -> ContainerRuntime {
+        ContainerRuntime {
+            config,
+            sys
+        }
+    }
+
+    pub fn config(&self) -> &ContainerRuntimeConfig {
+        &self.config
+    }
+
+    /// Audits the changes made outside of containers.
+    fn host_audit_log(&self) -> Arc<AuditLog> {
+        Arc::new(AuditLog::new(self.config.audit_log_path()))
+    }
+
+    /// Audits the changes made to a container that is running, together with the ones made when it was started.
+    fn container_audit_log(&self, container_id: &str) -> Arc<AuditLog> {
+        Arc::new(AuditLog::new(self.config.containers_base_dir().join(container_id).join(AUDIT_LOG)))
+    }
+
+    fn audited_sys(&self, audit_log: Arc<AuditLog>) -> Arc<dyn SysApi> {
+        AuditedSysApi::wrap(&self.sys, audit_log)
+    }
+
+    pub fn image_store(&self) -> ImageStore {
+        ImageStore::new(&self.config.image_base_dir())
+    }
+
+    pub fn bridged_network(&self, hostname: Option<String>) -> ContainerRuntimeResult<NetworkSpec> {
+        let bridged = self.allocate_bridged_network(&self.config.network)?.with_hostname(hostname);
+        Ok(NetworkSpec::Bridged(bridged))
+    }
+
+    /// Creates the bridge of the network if missing and assigns a free address on it, which stays reserved while this process runs.
+    fn allocate_bridged_network(&self, network_config: &NetworkConfig) -> ContainerRuntimeResult<BridgedNetworkSpec> {
+        let _lock = lock_file(&self.config.network_lock_path())?;
+        let bridge = BridgeSpec::create(network_config)?;
+        network::create_bridge(self.audited_sys(self.host_audit_log()).as_ref(), &bridge)?;
+
+        let reservations = AddressReservations::new(self.config.addresses_dir());
+        let mut used_ip_addresses = self.used_ip_addresses()?;
+        used_ip_addresses.extend(reservations.reserved()?);
+
+        let bridged = BridgedNetworkSpec::from_bridge(&bridge, &used_ip_addresses)?;
+        if let Some(container_ip_address) = bridged.container_ip_address {
+            reservations.reserve(container_ip_address)?;
+        }
+
+        Ok(bridged)
+    }
+
+    /// A network set up by the CNI plugins of the network configuration with the name.
+    pub fn cni_network(&self, name: &str, hostname: Option<String>) -> ContainerRuntimeResult<NetworkSpec> {
+        // Fails early if the configuration does not exist
+        CniNetworkConfig::load(&self.config.cni.config_dir, name)?;
+
+        Ok(
+            NetworkSpec::Cni(CniNetworkSpec {
+                name: name.to_owned(),
+                config_dir: self.config.cni.config_dir.clone(),
+                plugin_dirs: self.config.cni.plugin_dirs.clone(),
+                hostname
+            })
+        )
+    }
+
+    fn used_ip_addresses(&self) -> ContainerRuntimeResult<Vec<Ipv4Net>> {
+        Ok(
+            self.list()?
+                .into_iter()
+                .flat_map(|state| state.ip_address.into_iter().chain(state.networks.into_iter().map(|attachment| attachment.ip_address)))
+                .collect()
+        )
+    }
+
+    pub fn create_run_spec(&self, image: &str, command: Vec<String>, network: NetworkSpec) -> RunContainerSpec {
+        let id = Uuid::new_v4().to_string();
+        let dns = network.default_dns();
+        let name = self.generate_name();
+
+        RunContainerSpec {
+            image_base_dir: self.config.image_base_dir(),
+            containers_base_dir: self.config.containers_base_dir(),
+            id,
+            name,
+            image: image.to_owned(),
+            pull_policy: self.config.images.pull_policy,
+            platform: None,
+            emulation: None,
+            rootfs: None,
+            labels: BTreeMap::new(),
+            command,
+            network,
+            dns,
+            dns_search: Vec::new(),
+            dns_options: Vec::new(),
+            user: None,
+            cpu_shares: self.config.limits.cpu_shares,
+            cpus: self.config.limits.cpus,
+            memory: self.config.limits.memory,
+            memory_swap: self.config.limits.memory_swap,
+            memory_reservation: self.config.limits.memory_reservation,
+            memory_swappiness: self.config.limits.memory_swappiness,
+            kernel_memory: self.config.limits.kernel_memory,
+            bind_mounts: Vec::new(),
+            secrets: Vec::new(),
+            timezone: None,
+            read_only: false,
+            shm_size: DEFAULT_SHM_SIZE,
+            ipc_namespace: NamespaceMode::default(),
+            cgroup_namespace: NamespaceMode::default(),
+            time_namespace: None,
+            interactive: false,
+            init: false,
+            prewarm: false,
+            time_startup: false,
+            timeout: None,
+            ulimits: Vec::new(),
+            sysctls: BTreeMap::new(),
+            scheduling: SchedulingSpec::default(),
+            gpus: None,
+            security: SecuritySpec::default(),
+            log: LogSpec {
+                driver: self.config.log_driver,
+                ..LogSpec::default()
+            },
+            hooks: self.config.hooks.clone(),
+            pod: None,
+            infra: false,
+            storage_driver: self.config.storage_driver,
+            cgroup_driver: self.config.cgroup_driver,
+            hosts_file: self.config.hosts_file.clone(),
+            overlay: self.config.overlay,
+            verify_signature: false,
+            verify_rootfs: false,
+            registries: self.config.registries.clone(),
+            listen_fds: None
+        }
+    }
+
+    pub fn run(&self, spec: &RunContainerSpec) -> ContainerRuntimeResult<ContainerExitStatus> {
+        let spec = &self.pull_for_run(spec)?;
+        let _release_name = self.prepare_run(spec)?;
+        container::run(&self.sys, spec)
+    }
+
+    /// What running the container would do to the host and within the container, without doing it.
+    pub fn plan_run(&self, spec: &RunContainerSpec) -> ContainerRuntimeResult<RunPlan> {
+        spec.validate()?;
+
+        let bridge = match &spec.network {
+            NetworkSpec::Bridged(bridged) => {
+                let network_config = std::iter::once(&self.config.network)
+                    .chain(self.config.networks.values())
+                    .find(|network_config| network_config.bridge_interface == bridged.bridge_interface);
+                network_config.map(BridgeSpec::create).transpose()?
+            }
+            _ => None
+        };
+
+        container::plan(spec, bridge.as_ref())
+    }
+
+    /// Creates the container without starting it, where it is kept ready to execute its command by a supervisor in the background.
+    pub fn create(&self, spec: &RunContainerSpec) -> ContainerRuntimeResult<()> {
+        if spec.interactive {
+            return Err(ContainerRuntimeError::Input("Created containers cannot be interactive, as the stdin of the runtime is not kept".to_owned()));
+        }
+
+        let spec = &self.pull_for_run(spec)?;
+        let release_name = self.prepare_run(spec)?;
+        let supervisor_pid = container::create(&self.sys, spec, release_name)?;
+
+        // The name stays reserved for as long as the supervisor runs, rather than this process
+        self.name_registry().transfer(&spec.id, supervisor_pid)
+    }
+
+    /// Executes the command of a container created by `create`.
+    pub fn start(&self, container: &str) -> ContainerRuntimeResult<()> {
+        let state = self.find(container)?;
+        let _span = container::span(&state.id, &state.name).entered();
+        if !state.created {
+            return Err(ContainerRuntimeError::ContainerRunning(format!("{} (already started)", state.name)));
+        }
+
+        container::start(&self.config.containers_base_dir().join(&state.id))?;
+        info!("Started container {}.", state.name);
+        Ok(())
+    }
+
+    /// Pulls the image of the container as its pull policy says. The image can also be given as a reference in a registry
+    /// (such as quay.io/org/app:1.0), where the returned spec refers to the local image it is pulled to instead.
+    fn pull_for_run(&self, spec: &RunContainerSpec) -> ContainerRuntimeResult<RunContainerSpec> {
+        let mut spec = spec.clone();
+        if spec.rootfs.is_some() || spec.image.is_empty() {
+            return Ok(spec);
+        }
+
+        let image_store = self.image_store();
+        let is_local = LocalImageReference::from_str(&spec.image).is_ok();
+        if is_local {
+            image_store.import_archive(&spec.image)?;
+            if spec.pull_policy != PullPolicy::Always && image_store.exists(&spec.image) {
+                return Ok(spec);
+            }
+        }
+
+        let reference = ImageReference::from_str(&spec.image).map_err(ContainerRuntimeError::Input)?;
+        let pull_image = reference.local_name();
+        if !is_local {
+            // Images pinned by digest are found by the digest, also when pulled under another tag
+            spec.image = if reference.is_digest() {
+                format!("{}@{}", reference.repository_name(), reference.tag)
+            } else {
+                pull_image.clone()
+            };
+        }
+
+        let is_present = image_store.exists(&spec.image);
+        let should_pull = match spec.pull_policy {
+            PullPolicy::Never => false,
+            PullPolicy::Missing => !is_present,
+            // An image pinned by digest never changes, so it is not pulled again
+            PullPolicy::Always => !(is_present && reference.is_digest())
+        };
+
+        if should_pull {
+            let mut options = self.pull_options(false, spec.platform.clone());
+            options.skip_unchanged = true;
+            self.pull_with_options(&reference, &pull_image, spec.verify_signature, &options, &NoProgress)?;
+        }
+
+        Ok(spec)
+    }
+
+    /// Checks that the container can be run and reserves its name, which is released when the returned rollback is dropped.
+    fn prepare_run(&self, spec: &RunContainerSpec) -> ContainerRuntimeResult<Rollback<'static>> {
+        spec.validate()?;
+        if self.list()?.iter().any(|state| state.id == spec.id) {
+            return Err(ContainerRuntimeError::ContainerAlreadyExists(spec.id.clone()));
+        }
+
+        // The empty root of the infra container of a pod is created by the runtime
+        if spec.rootfs.is_some() && !spec.infra && self.config.signatures.policy == SignaturePolicy::Required {
+            return Err(ContainerRuntimeError::Signature("Running from a rootfs directory is not allowed when signatures are required".to_owned()));
+        }
+
+        if spec.rootfs.is_none() && self.requires_signature(spec.verify_signature) {
+            self.verify(&spec.image)?;
+        }
+
+        if spec.rootfs.is_none() && !spec.image.is_empty() {
+            self.check_platform(spec)?;
+        }
+
+        let name_registry = self.name_registry();
+        name_registry.reserve(&spec.name, &spec.id)?;
+        let mut release_name = Rollback::new();
+        let container_id = spec.id.clone();
+        release_name.add("release container name", move || name_registry.release(&container_id));
+
+        // The infra container is added when the pod is created
+        if let Some(pod) = spec.pod.as_ref().filter(|_| !spec.infra) {
+            self.add_to_pod(&pod.name, &spec.id)?;
+        }
+
+        Ok(release_name)
+    }
+
+    /// Images of other architectures than the one of the host are only run when emulation is enabled, by a binfmt_misc handler of the host,
+    /// or when the container is run with emulation, which registers the handler of its emulator if needed.
+    fn check_platform(&self, spec: &RunContainerSpec) -> ContainerRuntimeResult<()> {
+        let image_store = self.image_store();
+        let platform = image_store.platform(&image_store.load(&spec.image)?)?;
+        if let Some(wanted_platform) = spec.platform.as_ref().filter(|wanted_platform| !wanted_platform.matches(&platform)) {
+            return Err(ContainerRuntimeError::Platform(format!("Image '{}' is for platform {}, not {}", spec.image, platform, wanted_platform)));
+        }
+
+        let host_platform = Platform::host();
+        if host_platform.matches(&platform) {
+            return Ok(());
+        }
+
+        if spec.emulation.is_some() {
+            info!("Running image '{}' for platform {} with emulation.", spec.image, platform);
+            return Ok(());
+        }
+
+        if !self.config.images.emulation {
+            return Err(
+                ContainerRuntimeError::Platform(
+                    format!("Image '{}' is for platform {} rather than {} of the host (run it with --emulate, or set emulation under [images])", spec.image, platform, host_platform)
+                )
+            );
+        }
+
+        let handler = emulation::handler_path(&platform.architecture);
+        let handler_content = std::fs::read_to_string(&handler).map_err(|_| {
+            ContainerRuntimeError::Platform(format!("No binfmt_misc handler for {} at {} (such as from qemu-user-static)", platform.architecture, handler.to_str().unwrap()))
+        })?;
+
+        if handler_content.lines().next() != Some("enabled") {
+            return Err(ContainerRuntimeError::Platform(format!("The binfmt_misc handler {} is disabled", handler.to_str().unwrap())));
+        }
+
+        // Without the fix binary flag, the interpreter is opened when the command is executed, where it is not in the image
+        let fixes_binary = handler_content.lines().any(|line| line.strip_prefix("flags: ").is_some_and(|flags| flags.contains('F')));
+        if !fixes_binary {
+            warn!("The binfmt_misc handler {} does not have the F flag, so its interpreter must be in the image.", handler.to_str().unwrap());
+        }
+
+        info!("Running image '{}' for platform {} with emulation.", spec.image, platform);
+        Ok(())
+    }
+
+    pub fn rename(&self, container: &str, new_name: &str) -> ContainerRuntimeResult<()> {
+        names::validate_name(new_name)?;
+        let mut state = self.find(container)?;
+        self.name_registry().rename(&state.id, new_name)?;
+
+        info!("Renamed container {} to {}.", state.name, new_name);
+        state.name = new_name.to_owned();
+        state.save(&self.config.containers_base_dir().join(&state.id))?;
+        self.update_hosts_file();
+        Ok(())
+    }
+
+    /// Updates the names of the running containers in the hosts file of the config, if any, where a failure only affects lookups from the host.
+    fn update_hosts_file(&self) {
+        if let Some(hosts_file) = &self.config.hosts_file {
+            if let Err(err) = hosts::update_hosts_file(hosts_file, &self.config.containers_base_dir()) {
+                warn!("Failed to update the hosts file {}: {}", hosts_file.path.to_str().unwrap(), err);
+            }
+        }
+    }
+
+    fn name_registry(&self) -> NameRegistry {
+        NameRegistry::new(self.config.names_dir())
+    }
+
+    /// Generates a random name, avoiding the names in use.
+    fn generate_name(&self) -> String {
+        let name_registry = self.name_registry();
+        for _ in 0..10 {
+            let name = names::generate_name();
+            if !name_registry.is_reserved(&name) {
+                return name;
+            }
+        }
+
+        // The number of combinations is small, so add a suffix when they are used up
+        format!("{}_{}", names::generate_name(), &Uuid::new_v4().simple().to_string()[..8])
+    }
+
+    pub fn images(&self) -> ContainerRuntimeResult<Vec<ImageMetadata>> {
+        self.image_store().list()
+    }
+
+    pub fn image_history(&self, image: &str) -> ContainerRuntimeResult<Vec<HistoryEntry>> {
+        let image_store = self.image_store();
+        image_store.history(&image_store.load(image)?)
+    }
+
+    /// The config of the image, such as its environment, entrypoint and history.
+    pub fn inspect_image(&self, image: &str) -> ContainerRuntimeResult<serde_json::Value> {
+        let image_store = self.image_store();
+        image_store.config(&image_store.load(image)?)
+    }
+
+    pub fn list(&self) -> ContainerRuntimeResult<Vec<ContainerState>> {
+        Ok(
+            ContainerState::load_all(&self.config.containers_base_dir())?
+                .into_iter()
+                .filter(|state| state.is_running())
+                .collect()
+        )
+    }
+
+    /// The running containers along with the exited ones that are kept until pruned.
+    pub fn list_all(&self) -> ContainerRuntimeResult<Vec<ContainerState>> {
+        Ok(
+            ContainerState::load_all(&self.config.containers_base_dir())?
+                .into_iter()
+                .filter(|state| state.has_exited() || state.is_running())
+                .collect()
+        )
+    }
+
+    pub fn find(&self, container: &str) -> ContainerRuntimeResult<ContainerState> {
+        self.list()?
+            .into_iter()
+            .find(|state| state.id == container || state.name == container)
+            .ok_or_else(|| ContainerRuntimeError::ContainerNotFound(container.to_owned()))
+    }
+
+    /// Finds a running container, or the most recently exited one when none is running.
+    pub fn inspect(&self, container: &str) -> ContainerRuntimeResult<ContainerState> {
+        if let Ok(state) = self.find(container) {
+            return Ok(state);
+        }
+
+        let containers_base_dir = self.config.containers_base_dir();
+        let mut exited = ContainerState::load_all(&containers_base_dir)?
+            .into_iter()
+            .filter(|state| state.has_exited() && (state.id == container || state.name == container))
+            .map(|state| {
+                let modified = std::fs::metadata(containers_base_dir.join(&state.id)).and_then(|metadata| metadata.modified()).ok();
+                (modified, state)
+            })
+            .collect::<Vec<_>>();
+
+        exited.sort_by_key(|(modified, _)| *modified);
+        exited.pop()
+            .map(|(_, state)| state)
+            .ok_or_else(|| ContainerRuntimeError::ContainerNotFound(container.to_owned()))
+    }
+
+    /// Generates the units that run the container as a service of systemd, from the spec that it was run with.
+    /// The spec is written to the units directory, and each start of the service runs a new container from it.
+    pub fn generate_systemd_units(&self, container: &str, options: &ServiceOptions) -> ContainerRuntimeResult<Vec<UnitFile>> {
+        let state = self.inspect(container)?;
+        let mut spec = RunContainerSpec::load(&self.config.containers_base_dir().join(&state.id))
+            .map_err(|err| ContainerRuntimeError::Input(format!("The spec of container {} is not known: {}", state.name, err)))?;
+        spec.name = state.name;
+        if spec.pod.is_some() {
+            return Err(ContainerRuntimeError::Input(format!("Container {} belongs to a pod, which cannot be run as a service", spec.name)));
+        }
+
+        // Belong to each run, where the network is given by the options of the service
+        let mut fields = serde_json::to_value(&spec)?;
+        for name in ["image_base_dir", "containers_base_dir", "id", "name", "network"] {
+            fields.as_object_mut().unwrap().remove(name);
+        }
+
+        let units_dir = self.config.units_dir();
+        std::fs::create_dir_all(&units_dir)?;
+        let spec_path = units_dir.join(format!("{}.json", systemd::unit_name(&spec.name)));
+        std::fs::write(&spec_path, serde_json::to_string_pretty(&fields)?)?;
+
+        Ok(systemd::generate_units(&spec, &spec_path, options))
+    }
+
+    /// The output of a running or exited container, which is only kept by the json-file log driver.
+    pub fn logs(&self, container: &str) -> ContainerRuntimeResult<Vec<LogEntry>> {
+        let state = self.json_file_logged(container)?;
+        logs::read_json_file(&self.config.containers_base_dir().join(&state.id))
+    }
+
+    /// Calls the callback with the output of the container, including new output until the container exits.
+    pub fn follow_logs<F: FnMut(LogEntry)>(&self, container: &str, on_entry: F) -> ContainerRuntimeResult<()> {
+        let state = self.json_file_logged(container)?;
+        let is_running = || !state.has_exited() && self.find(&state.id).is_ok();
+        logs::follow_json_file(&self.config.containers_base_dir().join(&state.id), is_running, on_entry)
+    }
+
+    fn json_file_logged(&self, container: &str) -> ContainerRuntimeResult<ContainerState> {
+        let state = self.inspect(container)?;
+        if state.log_driver != LogDriverSpec::JsonFile {
+            return Err(ContainerRuntimeError::Input(format!("Logs of {} are not kept by the {} log driver", state.name, state.log_driver.name())));
+        }
+
+        Ok(state)
+    }
+
+    pub fn top(&self, container: &str) -> ContainerRuntimeResult<Vec<ContainerProcess>> {
+        let state = self.find(container)?;
+        container::list_processes(&state.id, state.pid)
+    }
+
+    /// Executes the command in the running container, returning the exit status of the command.
+    pub fn exec(&self, container: &str, command: &[String], interactive: bool) -> ContainerRuntimeResult<ContainerExitStatus> {
+        let state = self.find(container)?;
+        let _span = container::span(&state.id, &state.name).entered();
+        if state.created {
+            return Err(ContainerRuntimeError::Input(format!("Container {} has not been started", state.name)));
+        }
+
+        // The command would be frozen along with the container as it enters its cgroups
+        if self.is_paused(&state.id) {
+            return Err(ContainerRuntimeError::Input(format!("Container {} is paused", state.name)));
+        }
+
+        let spec = RunContainerSpec::load(&self.config.containers_base_dir().join(&state.id))
+            .map_err(|err| ContainerRuntimeError::Input(format!("The spec of container {} is not known: {}", state.name, err)))?;
+        let exec_id = Uuid::new_v4().simple().to_string()[..12].to_owned();
+        container::exec_command(&spec, &state, &exec_id, command, interactive)
+    }
+
+    /// The commands executed in a running container, oldest first, where the ones that have exited are kept until the container exits.
+    pub fn exec_sessions(&self, container: &str) -> ContainerRuntimeResult<Vec<ExecSession>> {
+        let state = self.find(container)?;
+        ExecSession::load_all(&self.config.containers_base_dir().join(&state.id))
+    }
+
+    pub fn inspect_network(&self, container: &str) -> ContainerRuntimeResult<NetworkReport> {
+        let state = self.find(container)?;
+        diagnostics::inspect_network(&state, &self.config.network.bridge_interface)
+    }
+
+    pub fn connect_network(&self, network: &str, container: &str) -> ContainerRuntimeResult<NetworkAttachment> {
+        let network_config = self.config.find_network(network)?;
+        let mut state = self.find(container)?;
+        let _span = container::span(&state.id, &state.name).entered();
+
+        let is_connected = state.networks.iter().any(|attachment| attachment.network == network)
+            || (network == DEFAULT_NETWORK && state.ip_address.is_some());
+        if is_connected {
+            return Err(ContainerRuntimeError::AttachNetwork(format!("Container {} is already connected to {}", state.name, network)));
+        }
+
+        let bridged = self.allocate_bridged_network(network_config)?;
+        let attachment = network::attach_container_network(self.audited_sys(self.container_audit_log(&state.id)).as_ref(), &bridged, network, &state.id, state.pid)?;
+        state.networks.push(attachment.clone());
+        state.save(&self.config.containers_base_dir().join(&state.id))?;
+        self.update_hosts_file();
+        Ok(attachment)
+    }
+
+    pub fn disconnect_network(&self, network: &str, container: &str) -> ContainerRuntimeResult<()> {
+        let mut state = self.find(container)?;
+        let _span = container::span(&state.id, &state.name).entered();
+        let index = state.networks
+            .iter()
+            .position(|attachment| attachment.network == network)
+            .ok_or_else(|| ContainerRuntimeError::AttachNetwork(format!("Container {} is not connected to {} (the network it was started with cannot be disconnected)", state.name, network)))?;
+
+        network::detach_container_network(self.audited_sys(self.container_audit_log(&state.id)).as_ref(), &state.networks[index])?;
+        state.networks.remove(index);
+        state.save(&self.config.containers_base_dir().join(&state.id))?;
+        self.update_hosts_file();
+        info!("Disconnected container {} from network {}.", state.name, network);
+        Ok(())
+    }
+
+    pub fn stop(&self, container: &str, timeout: Duration) -> ContainerRuntimeResult<()> {
+        let state = self.find(container)?;
+        let _span = container::span(&state.id, &state.name).entered();
+
+        self.stop_containers(std::slice::from_ref(&state), timeout)?;
+        info!("Stopped container {}.", state.name);
+        Ok(())
+    }
+
+    /// Adopts the running containers whose runtime has exited, such as when it was killed or to upgrade it, each by a supervisor in the
+    /// background that follows its output again, records its exit and cleans up after it. A container that cannot be adopted does not
+    /// stop the others from being adopted. Returns the names of the adopted containers, and the number that could not be adopted.
+    pub fn restore(&self) -> ContainerRuntimeResult<(Vec<String>, usize)> {
+        let mut adopted = Vec::new();
+        let mut failed = 0;
+        for state in self.list()?.into_iter().filter(|state| state.is_orphaned()) {
+            let _span = container::span(&state.id, &state.name).entered();
+            match self.adopt(&state) {
+                Ok(()) => {
+                    info!("Adopted container {}.", state.name);
+                    adopted.push(state.name);
+                }
+                Err(err) => {
+                    error!("Failed to adopt container {}: {}", state.name, err);
+                    failed += 1;
+                }
+            }
+        }
+
+        Ok((adopted, failed))
+    }
+
+    fn adopt(&self, state: &ContainerState) -> ContainerRuntimeResult<()> {
+        let spec = RunContainerSpec::load(&self.config.containers_base_dir().join(&state.id))?;
+
+        // Released by the supervisor once the container has exited, as by the runtime it replaces
+        let name_registry = self.name_registry();
+        let mut release_name = Rollback::new();
+        let container_id = state.id.clone();
+        release_name.add("release container name", move || name_registry.release(&container_id));
+
+        let supervisor_pid = container::adopt(&self.sys, &spec, release_name)?;
+        self.name_registry().transfer(&state.id, supervisor_pid)
+    }
+
+    /// Stops all running containers, such as before the host shuts down. The containers of pods are stopped before their infra
+    /// containers, where each of the two groups is given the timeout to stop before what is left of it is killed.
+    /// Returns the names of the stopped containers.
+    pub fn shutdown(&self, timeout: Duration) -> ContainerRuntimeResult<Vec<String>> {
+        let infra_ids = self.list_pods()?.into_iter().filter_map(|pod| pod.infra).collect::<Vec<_>>();
+        let (infra, containers): (Vec<_>, Vec<_>) = self.list()?.into_iter().partition(|state| infra_ids.contains(&state.id));
+
+        let mut stopped = Vec::new();
+        for group in [containers, infra] {
+            self.stop_containers(&group, timeout)?;
+            stopped.extend(group);
+        }
+
+        self.wait_for_exits(&stopped, timeout)?;
+        info!("Stopped {} containers.", stopped.len());
+        Ok(stopped.into_iter().map(|state| state.name).collect())
+    }
+
+    /// Sends SIGTERM to all the containers at once, and kills those that have not stopped within the timeout.
+    /// The containers are signaled through pidfds, so that a container that exits meanwhile is never mistaken for a later process with its PID.
+    fn stop_containers(&self, states: &[ContainerState], timeout: Duration) -> ContainerRuntimeResult<()> {
+        let mut processes = Vec::new();
+        for state in states {
+            // The container might have exited since it was listed
+            let process = match state.open_process() {
+                Ok(process) => process,
+                Err(_) => continue
+            };
+
+            if let Err(err) = pidfd_send_signal(&process, libc::SIGTERM) {
+                if !has_exited(&process) {
+                    return Err(err);
+                }
+            }
+
+            // Signals are only delivered once the processes are resumed
+            if self.is_paused(&state.id) {
+                container::thaw(&state.id)?;
+            }
+
+            processes.push((state, process));
+        }
+
+        let start = Instant::now();
+        while processes.iter().any(|(_, process)| !has_exited(process)) {
+            if start.elapsed() >= timeout {
+                for (state, process) in processes.iter().filter(|(_, process)| !has_exited(process)) {
+                    info!("Container {} did not stop within {:.1} seconds, killing.", state.name, timeout.as_secs_f64());
+                    pidfd_send_signal(process, libc::SIGKILL)?;
+                }
+                break;
+            }
+
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        Ok(())
+    }
+
+    /// Waits (at most the timeout) for the exits of the stopped containers to be recorded, which is done by their runtimes shortly
+    /// after they have exited.
+    fn wait_for_exits(&self, stopped: &[ContainerState], timeout: Duration) -> ContainerRuntimeResult<()> {
+        let start = Instant::now();
+        let is_exiting = |state: &ContainerState| stopped.iter().any(|stopped| stopped.id == state.id) && !state.has_exited();
+        while start.elapsed() < timeout && ContainerState::load_all(&self.config.containers_base_dir())?.iter().any(is_exiting) {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        Ok(())
+    }
+
+    /// Removes the exited containers with the id or name, which fails if such a container is running.
+    pub fn remove(&self, container: &str) -> ContainerRuntimeResult<Vec<ContainerState>> {
+        if let Ok(state) = self.find(container) {
+            return Err(ContainerRuntimeError::ContainerRunning(format!("{} (stop it before removing it)", state.name)));
+        }
+
+        let exited = self.list_exited(|state| state.id == container || state.name == container)?;
+        if exited.is_empty() {
+            return Err(ContainerRuntimeError::ContainerNotFound(container.to_owned()));
+        }
+
+        self.remove_exited(exited)
+    }
+
+    /// Removes the exited containers that match all filters, where running containers are left as they are.
+    pub fn remove_filtered(&self, filters: &[ContainerFilter]) -> ContainerRuntimeResult<Vec<ContainerState>> {
+        let exited = self.list_exited(|state| ContainerFilter::matches_all(filters, state))?;
+        self.remove_exited(exited)
+    }
+
+    fn list_exited<F: Fn(&ContainerState) -> bool>(&self, predicate: F) -> ContainerRuntimeResult<Vec<ContainerState>> {
+        Ok(
+            ContainerState::load_all(&self.config.containers_base_dir())?
+                .into_iter()
+                .filter(|state| state.has_exited() && predicate(state))
+                .collect()
+        )
+    }
+
+    fn remove_exited(&self, exited: Vec<ContainerState>) -> ContainerRuntimeResult<Vec<ContainerState>> {
+        for state in &exited {
+            let _span = container::span(&state.id, &state.name).entered();
+            // The cgroups are normally removed when the container exits
+            container::remove_cgroups(&state.id)?;
+            helpers::remove_dir_all_unmounted(&self.config.containers_base_dir().join(&state.id))?;
+            info!("Removed container {} ({}).", state.name, state.id);
+        }
+
+        Ok(exited)
+    }
+
+    /// Creates a pod, along with its infra container unless created without one. Containers are added by creating them in the pod,
+    /// and are started together with `start_pod`.
+    pub fn create_pod(&self, name: &str, hostname: Option<String>, network: PodNetwork, infra: bool) -> ContainerRuntimeResult<PodState> {
+        names::validate_name(name)?;
+        let pod_dir = self.pod_dir(name);
+        let mut pod = PodState::new(name, &hostname.unwrap_or_else(|| name.to_owned()), network);
+
+        let mut rollback = Rollback::new();
+        {
+            let _lock = lock_file(&self.pods_lock_path())?;
+            if PodState::exists(&pod_dir) {
+                return Err(ContainerRuntimeError::PodAlreadyExists(name.to_owned()));
+            }
+
+            // The root of the infra container only has what the runtime mounts and writes
+            for dir in ["dev", "etc", "proc", "sys"] {
+                std::fs::create_dir_all(PodState::rootfs(&pod_dir).join(dir))?;
+            }
+            std::fs::create_dir_all(PodState::shm_dir(&pod_dir))?;
+            let cleanup_pod_dir = pod_dir.clone();
+            rollback.add("remove pod directory", move || Ok(helpers::remove_dir_all_unmounted(&cleanup_pod_dir)?));
+
+            let options = format!("mode=1777,size={}", DEFAULT_SHM_SIZE);
+            let shm_flags = libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC;
+            self.audited_sys(self.host_audit_log()).mount(Some("shm"), &PodState::shm_dir(&pod_dir), Some("tmpfs"), shm_flags, Some(&options))?;
+            pod.save(&pod_dir)?;
+        }
+
+        if infra {
+            let mut spec = self.create_run_spec("", vec!["pause".to_owned()], self.pod_own_network(&pod)?);
+            spec.name = format!("{}-infra", name);
+            spec.rootfs = Some(PodState::rootfs(&pod_dir));
+            spec.pod = Some(self.pod_spec(&pod));
+            spec.infra = true;
+
+            pod.infra = Some(spec.id.clone());
+            pod.save(&pod_dir)?;
+            self.create(&spec)?;
+        }
+
+        rollback.commit();
+        info!("Created pod {}.", name);
+        Ok(pod)
+    }
+
+    /// The pod of a container that is added to it, along with its network. The container joins the namespaces of a container
+    /// of the pod that has been created or is running, or creates them if there is none and the pod has no infra container.
+    pub fn join_pod(&self, pod: &str) -> ContainerRuntimeResult<(PodSpec, NetworkSpec)> {
+        let pod = self.find_pod(pod)?;
+        let running = self.list()?;
+        let namespaces_owner = pod.container_ids().find_map(|container_id| running.iter().find(|state| &state.id == container_id));
+
+        let network = match namespaces_owner {
+            Some(owner) => {
+                NetworkSpec::Pod(PodNetworkSpec {
+                    pod: pod.name.clone(),
+                    pid: owner.pid,
+                    hostname: pod.hostname.clone(),
+                    ip_address: owner.ip_address
+                })
+            }
+            None if pod.infra.is_some() => {
+                return Err(ContainerRuntimeError::Input(format!("The infra container of pod {} has exited (remove the pod and create it again)", pod.name)));
+            }
+            None => self.pod_own_network(&pod)?
+        };
+
+        Ok((self.pod_spec(&pod), network))
+    }
+
+    /// Starts the created containers of the pod, its infra container first, returning their names.
+    pub fn start_pod(&self, pod: &str) -> ContainerRuntimeResult<Vec<String>> {
+        let pod = self.find_pod(pod)?;
+        let created = self.list()?.into_iter().filter(|state| state.created).collect::<Vec<_>>();
+
+        let mut started = Vec::new();
+        for container_id in pod.container_ids() {
+            if let Some(state) = created.iter().find(|state| &state.id == container_id) {
+                self.start(&state.id)?;
+                started.push(state.name.clone());
+            }
+        }
+
+        info!("Started pod {}.", pod.name);
+        Ok(started)
+    }
+
+    /// Stops the containers of the pod in the reverse order that they are started, so that its infra container is stopped last.
+    pub fn stop_pod(&self, pod: &str, timeout: Duration) -> ContainerRuntimeResult<Vec<String>> {
+        let pod = self.find_pod(pod)?;
+        let running = self.list()?;
+
+        let mut stopped = Vec::new();
+        for container_id in pod.container_ids().rev() {
+            if let Some(state) = running.iter().find(|state| &state.id == container_id) {
+                self.stop(&state.id, timeout)?;
+                stopped.push(state.clone());
+            }
+        }
+
+        // The pod can only be removed once the exits are recorded
+        self.wait_for_exits(&stopped, timeout)?;
+
+        info!("Stopped pod {}.", pod.name);
+        Ok(stopped.into_iter().map(|state| state.name).collect())
+    }
+
+    /// Removes the pod along with its exited containers, which fails if any of its containers is running.
+    pub fn remove_pod(&self, pod: &str) -> ContainerRuntimeResult<Vec<ContainerState>> {
+        let pod = self.find_pod(pod)?;
+        let _lock = lock_file(&self.pods_lock_path())?;
+        let belongs_to_pod = |state: &ContainerState| pod.container_ids().any(|container_id| container_id == &state.id);
+
+        if let Some(state) = self.list()?.into_iter().find(belongs_to_pod) {
+            return Err(ContainerRuntimeError::ContainerRunning(format!("{} (stop pod {} before removing it)", state.name, pod.name)));
+        }
+
+        let removed = self.remove_exited(self.list_exited(belongs_to_pod)?)?;
+        helpers::remove_dir_all_unmounted(&self.pod_dir(&pod.name))?;
+        info!("Removed pod {}.", pod.name);
+        Ok(removed)
+    }
+
+    pub fn list_pods(&self) -> ContainerRuntimeResult<Vec<PodState>> {
+        PodState::load_all(&self.config.pods_dir())
+    }
+
+    pub fn find_pod(&self, pod: &str) -> ContainerRuntimeResult<PodState> {
+        let pod_dir = self.pod_dir(pod);
+        if !PodState::exists(&pod_dir) {
+            return Err(ContainerRuntimeError::PodNotFound(pod.to_owned()));
+        }
+
+        PodState::load(&pod_dir)
+    }
+
+    fn pod_dir(&self, pod: &str) -> PathBuf {
+        self.config.pods_dir().join(pod)
+    }
+
+    /// Held while pods are created or removed and while containers are added to them.
+    fn pods_lock_path(&self) -> PathBuf {
+        self.config.pods_dir().join(".lock")
+    }
+
+    fn pod_spec(&self, pod: &PodState) -> PodSpec {
+        PodSpec {
+            name: pod.name.clone(),
+            shm_dir: PodState::shm_dir(&self.pod_dir(&pod.name))
+        }
+    }
+
+    /// The network of the container that creates the namespaces of the pod.
+    fn pod_own_network(&self, pod: &PodState) -> ContainerRuntimeResult<NetworkSpec> {
+        match &pod.network {
+            PodNetwork::Bridge => self.bridged_network(Some(pod.hostname.clone())),
+            PodNetwork::Cni(name) => self.cni_network(name, Some(pod.hostname.clone()))
+        }
+    }
+
+    fn add_to_pod(&self, pod: &str, container_id: &str) -> ContainerRuntimeResult<()> {
+        let _lock = lock_file(&self.pods_lock_path())?;
+        let mut pod = self.find_pod(pod)?;
+        pod.containers.push(container_id.to_owned());
+        pod.save(&self.pod_dir(&pod.name))
+    }
+
+    pub fn pause(&self, container: &str) -> ContainerRuntimeResult<()> {
+        let state = self.find(container)?;
+        let _span = container::span(&state.id, &state.name).entered();
+        container::freeze(&state.id)?;
+        info!("Paused container {}.", state.name);
+        Ok(())
+    }
+
+    pub fn unpause(&self, container: &str) -> ContainerRuntimeResult<()> {
+        let state = self.find(container)?;
+        let _span = container::span(&state.id, &state.name).entered();
+        container::thaw(&state.id)?;
+        info!("Resumed container {}.", state.name);
+        Ok(())
+    }
+
+    /// Changes the resource limits of a running container without restarting it, where the limits not set in the update are kept.
+    pub fn update(&self, container: &str, update: &ResourceLimits) -> ContainerRuntimeResult<ContainerState> {
+        let mut state = self.find(container)?;
+        let _span = container::span(&state.id, &state.name).entered();
+        let limits = state.limits.updated(update);
+        let violations = limits.violations();
+        if !violations.is_empty() {
+            return Err(ContainerRuntimeError::Input(violations.join(", ")));
+        }
+
+        container::update_cgroups(&self.container_audit_log(&state.id), &state.id, update)?;
+        state.limits = limits;
+        state.save(&self.config.containers_base_dir().join(&state.id))?;
+        info!("Updated the resource limits of container {}.", state.name);
+        Ok(state)
+    }
+
+    /// Containers without a freezer cgroup cannot be paused, and are never paused.
+    pub fn is_paused(&self, container_id: &str) -> bool {
+        container::is_frozen(container_id).unwrap_or(false)
+    }
+
+    pub fn copy_to_container(&self, container: &str, host_path: &Path, container_path: &Path) -> ContainerRuntimeResult<()> {
+        copy::copy_to_container(&self.container_filesystem(container, "cp")?, host_path, container_path)
+    }
+
+    pub fn copy_from_container(&self, container: &str, container_path: &Path, host_path: &Path) -> ContainerRuntimeResult<()> {
+        copy::copy_from_container(&self.container_filesystem(container, "cp")?, container_path, host_path)
+    }
+
+    pub fn export<W: Write>(&self, container: &str, writer: W) -> ContainerRuntimeResult<()> {
+        copy::export_container(&self.container_filesystem(container, "export")?, writer)
+    }
+
+    /// The mount namespace of a running container, or else the root that an exited container left behind, which is mounted again
+    /// by its storage driver from the same layers.
+    fn container_filesystem(&self, container: &str, operation: &str) -> ContainerRuntimeResult<ContainerFilesystem> {
+        if let Ok(state) = self.find(container) {
+            return Ok(ContainerFilesystem::Running(state.pid));
+        }
+
+        let state = self.inspect(container)?;
+        let container_root = self.config.containers_base_dir().join(&state.id);
+        let spec = RunContainerSpec::load(&container_root).ok().filter(|_| container_root.join("rootfs").exists()).ok_or_else(|| {
+            ContainerRuntimeError::Input(format!("{} needs a running container, as the filesystem of {} is no longer kept", operation, state.name))
+        })?;
+
+        let layers = match &spec.rootfs {
+            Some(rootfs) => vec![rootfs.clone()],
+            None => {
+                let image_store = self.image_store();
+                state.layers
+                    .iter()
+                    .map(|digest| {
+                        image_store.extracted_layer(digest)?.ok_or_else(|| {
+                            ContainerRuntimeError::Input(format!("{} needs a running container, as layer {} of {} is not extracted", operation, digest, state.name))
+                        })
+                    })
+                    .collect::<ContainerRuntimeResult<Vec<_>>>()?
+            }
+        };
+
+        Ok(ContainerFilesystem::Stopped {
+            storage_driver: storage::create_storage_driver(
+                &storage::select_storage_driver(spec.storage_driver, &spec.containers_base_dir),
+                &spec.overlay,
+                &spec.image_base_dir
+            ),
+            layers,
+            container_root
+        })
+    }
+
+    pub fn import<R: Read>(&self, image: &str, reader: R, progress: &dyn ProgressReporter) -> ContainerRuntimeResult<()> {
+        image::import_image(&self.image_store(), image, reader, progress)
+    }
+
+    pub fn pull(&self, reference: &ImageReference, image: &str, verify_signature: bool, lazy: bool, platform: Option<Platform>, progress: &dyn ProgressReporter) -> ContainerRuntimeResult<()> {
+        self.pull_with_options(reference, image, verify_signature, &self.pull_options(lazy, platform), progress)
+    }
+
+    fn pull_with_options(&self, reference: &ImageReference, image: &str, verify_signature: bool, options: &PullOptions, progress: &dyn ProgressReporter) -> ContainerRuntimeResult<()> {
+        let verifier = if self.requires_signature(verify_signature) {
+            Some(SignatureVerifier::load(&self.config.signatures.public_keys)?)
+        } else {
+            None
+        };
+
+        image::pull_image(&self.image_store(), reference, image, verifier.as_ref(), options, progress)
+    }
+
+    fn pull_options(&self, lazy: bool, platform: Option<Platform>) -> PullOptions {
+        PullOptions {
+            lazy,
+            max_concurrent_downloads: self.config.images.max_concurrent_downloads,
+            skip_unchanged: false,
+            registries: self.config.registries.clone(),
+            platform
+        }
+    }
+
+    pub fn verify(&self, image: &str) -> ContainerRuntimeResult<()> {
+        let verifier = SignatureVerifier::load(&self.config.signatures.public_keys)?;
+        self.image_store().verify_image(image, &verifier)
+    }
+
+    fn requires_signature(&self, verify_signature: bool) -> bool {
+        verify_signature || self.config.signatures.policy == SignaturePolicy::Required
+    }
+
+    pub fn tag(&self, image: &str, target: &str) -> ContainerRuntimeResult<()> {
+        self.image_store().tag(image, target)
+    }
+
+    pub fn push(&self, image: &str, reference: &ImageReference, progress: &dyn ProgressReporter) -> ContainerRuntimeResult<()> {
+        image::push_image(&self.image_store(), image, reference, &self.config.registries, progress)
+    }
+
+    /// Checks the credentials with the registry and stores them for pulling and pushing, returning where they were stored.
+    pub fn login(&self, registry: &str, username: &str, password: &str) -> ContainerRuntimeResult<PathBuf> {
+        let credentials = registry::login(registry, username, password, &self.config.registries)?;
+        credentials::store_credentials(registry, &credentials, self.credential_helper(registry))
+    }
+
+    /// Removes the stored credentials of the registry, returning if there were any.
+    pub fn logout(&self, registry: &str) -> ContainerRuntimeResult<bool> {
+        credentials::erase_credentials(registry, self.credential_helper(registry))
+    }
+
+    fn credential_helper(&self, registry: &str) -> Option<&str> {
+        registry::find_registry_config(&self.config.registries, registry).and_then(|config| config.credential_helper.as_deref())
+    }
+
+    pub fn prune(&self) -> ContainerRuntimeResult<Vec<String>> {
+        let running = self.list()?;
+        let containers_base_dir = self.config.containers_base_dir();
+        // Containers being set up (or whose exit is being recorded) are kept as well, as their runtime is alive
+        let is_in_use = |container_id: &str| {
+            running.iter().any(|state| state.id == container_id) || container::has_live_runtime(&containers_base_dir.join(container_id))
+        };
+        let mut removed = Vec::new();
+
+        if containers_base_dir.exists() {
+            for entry in std::fs::read_dir(&containers_base_dir)? {
+                let container_root = entry?.path();
+                if !is_in_use(container_root.file_name().unwrap().to_str().unwrap()) {
+                    match helpers::remove_dir_all_unmounted(&container_root) {
+                        Ok(()) => removed.push(format!("container root {}", container_root.to_str().unwrap())),
+                        Err(err) => error!("Failed to remove container root {}: {}", container_root.to_str().unwrap(), err)
+                    }
+                }
+            }
+        }
+
+        let audit_log = self.host_audit_log();
+        for container_id in container::find_cgroup_container_ids()? {
+            if !is_in_use(&container_id) {
+                match audit_log.record(&format!("remove cgroups of {}", container_id), container::remove_cgroups(&container_id)) {
+                    Ok(()) => removed.push(format!("cgroups of {}", container_id)),
+                    Err(err) => error!("Failed to remove cgroups of {}: {}", container_id, err)
+                }
+            }
+        }
+
+        self.prune_network(&running, &mut removed);
+        // Removes the containers that exited without their entries being removed, such as when the runtime was killed
+        self.update_hosts_file();
+        let container_layers = ContainerState::load_all(&containers_base_dir)?.into_iter().map(|state| state.layers).collect::<Vec<_>>();
+        removed.extend(self.image_store().prune(&container_layers)?);
+
+        Ok(removed)
+    }
+
+    /// Removes what the network of containers that crashed (or of a crashed runtime) left on the host.
+    fn prune_network(&self, running: &[ContainerState], removed: &mut Vec<String>) {
+        let _lock = match lock_file(&self.config.network_lock_path()) {
+            Ok(lock) => lock,
+            Err(err) => {
+                error!("Failed to lock the network: {}", err);
+                return;
+            }
+        };
+
+        let mut bridge_interfaces = vec![self.config.network.bridge_interface.clone()];
+        bridge_interfaces.extend(self.config.networks.values().map(|network| network.bridge_interface.clone()));
+
+        let sys = self.audited_sys(self.host_audit_log());
+        let running_container_ids = running.iter().map(|state| state.id.clone()).collect::<Vec<_>>();
+        let mut used_interfaces = Vec::new();
+        for state in running {
+            used_interfaces.push(network::VethPair::for_container(&state.id).host_interface);
+            used_interfaces.extend(state.networks.iter().map(|attachment| attachment.host_interface.clone()));
+        }
+
+        match network::remove_unused_veth_interfaces(sys.as_ref(), &bridge_interfaces, &used_interfaces) {
+            Ok(interfaces) => removed.extend(interfaces.into_iter().map(|interface| format!("interface {}", interface))),
+            Err(err) => error!("Failed to remove unused veth interfaces: {}", err)
+        }
+
+        match network::remove_unused_network_namespaces(sys.as_ref(), &running_container_ids) {
+            Ok(namespaces) => removed.extend(namespaces.into_iter().map(|namespace| format!("network namespace {}", namespace))),
+            Err(err) => error!("Failed to remove unused network namespaces: {}", err)
+        }
+
+        match network::remove_stale_iptables_rules(sys.as_ref(), &bridge_interfaces) {
+            Ok(rules) => removed.extend(rules.into_iter().map(|rule| format!("iptables rule '{}'", rule))),
+            Err(err) => error!("Failed to remove stale iptables rules: {}", err)
+        }
+    }
+}
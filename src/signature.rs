@@ -0,0 +1,170 @@
+use std::path::PathBuf;
+
+use base64::Engine;
+use p256::ecdsa::{Signature, VerifyingKey};
+use p256::ecdsa::signature::Verifier;
+use p256::pkcs8::DecodePublicKey;
+use serde::{Deserialize, Serialize};
+
+use crate::model::{ContainerRuntimeError, ContainerRuntimeResult};
+use crate::registry::{Manifest, RegistryClient};
+
+pub const MEDIA_TYPE_COSIGN_SIMPLE_SIGNING: &str = "application/vnd.dev.cosign.simplesigning.v1+json";
+pub const COSIGN_SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+
+/// Cosign signature of an image manifest, with both parts base64 encoded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageSignature {
+    pub payload: String,
+    pub signature: String
+}
+
+#[derive(Deserialize)]
+struct SimpleSigningPayload {
+    critical: SimpleSigningCritical
+}
+
+#[derive(Deserialize)]
+struct SimpleSigningCritical {
+    image: SimpleSigningImage,
+    #[serde(rename="type")]
+    signature_type: String
+}
+
+#[derive(Deserialize)]
+struct SimpleSigningImage {
+    #[serde(rename="docker-manifest-digest")]
+    docker_manifest_digest: String
+}
+
+pub struct SignatureVerifier {
+    public_keys: Vec<VerifyingKey>
+}
+
+impl SignatureVerifier {
+    pub fn load(public_key_paths: &[PathBuf]) -> ContainerRuntimeResult<SignatureVerifier> {
+        if public_key_paths.is_empty() {
+            return Err(ContainerRuntimeError::Signature("No public keys configured".to_owned()));
+        }
+
+        let mut public_keys = Vec::new();
+        for path in public_key_paths {
+            let content = std::fs::read_to_string(path)?;
+            let public_key = VerifyingKey::from_public_key_pem(&content)
+                .map_err(|err| ContainerRuntimeError::Signature(format!("Invalid public key {}: {}", path.to_str().unwrap(), err)))?;
+            public_keys.push(public_key);
+        }
+
+        Ok(
+            SignatureVerifier {
+                public_keys
+            }
+        )
+    }
+
+    /// Verifies the signature against the public keys and returns the manifest digest it signs.
+    pub fn verify(&self, signature: &ImageSignature) -> ContainerRuntimeResult<String> {
+        let engine = base64::engine::general_purpose::STANDARD;
+        let payload = engine.decode(&signature.payload)
+            .map_err(|err| ContainerRuntimeError::Signature(format!("Invalid payload: {}", err)))?;
+        let signature = engine.decode(&signature.signature)
+            .map_err(|err| ContainerRuntimeError::Signature(format!("Invalid signature: {}", err)))?;
+        let signature = Signature::from_der(&signature)
+            .map_err(|err| ContainerRuntimeError::Signature(format!("Invalid signature: {}", err)))?;
+
+        if !self.public_keys.iter().any(|public_key| public_key.verify(&payload, &signature).is_ok()) {
+            return Err(ContainerRuntimeError::Signature("Signature does not match any public key".to_owned()));
+        }
+
+        let payload: SimpleSigningPayload = serde_json::from_slice(&payload)?;
+        if payload.critical.signature_type != "cosign container image signature" {
+            return Err(ContainerRuntimeError::Signature(format!("Unsupported signature type '{}'", payload.critical.signature_type)));
+        }
+
+        Ok(payload.critical.image.docker_manifest_digest)
+    }
+
+    /// Returns the signatures that are valid for any of the given manifest digests.
+    pub fn verify_any(&self, signatures: &[ImageSignature], digests: &[String]) -> ContainerRuntimeResult<Vec<ImageSignature>> {
+        let verified = signatures
+            .iter()
+            .filter(|signature| {
+                self.verify(signature)
+                    .map(|digest| digests.contains(&digest))
+                    .unwrap_or(false)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if verified.is_empty() {
+            return Err(ContainerRuntimeError::Signature(format!("No valid signature found for {}", digests.join(", "))));
+        }
+
+        Ok(verified)
+    }
+}
+
+pub fn signature_tag(digest: &str) -> String {
+    format!("{}.sig", digest.replace(':', "-"))
+}
+
+pub fn fetch_signatures(client: &mut RegistryClient, repository: &str, digest: &str) -> ContainerRuntimeResult<Vec<ImageSignature>> {
+    let content = match client.find_manifest(repository, &signature_tag(digest))? {
+        Some((_, content)) => content,
+        None => return Ok(Vec::new())
+    };
+
+    let manifest: Manifest = serde_json::from_slice(&content)?;
+    let mut signatures = Vec::new();
+    for layer in &manifest.layers {
+        let signature = layer.annotations
+            .as_ref()
+            .and_then(|annotations| annotations.get(COSIGN_SIGNATURE_ANNOTATION));
+
+        if let (MEDIA_TYPE_COSIGN_SIMPLE_SIGNING, Some(signature)) = (layer.media_type.as_str(), signature) {
+            let payload = client.get_blob(repository, &layer.digest)?;
+            signatures.push(ImageSignature {
+                payload: base64::engine::general_purpose::STANDARD.encode(payload),
+                signature: signature.clone()
+            });
+        }
+    }
+
+    Ok(signatures)
+}
+
+#[test]
+fn test_verify_signature() {
+    use p256::ecdsa::SigningKey;
+    use p256::ecdsa::signature::Signer;
+
+    let signing_key = SigningKey::from_bytes(&[7u8; 32].into()).unwrap();
+    let verifier = SignatureVerifier {
+        public_keys: vec![*signing_key.verifying_key()]
+    };
+
+    let digest = format!("sha256:{}", "ab".repeat(32));
+    let payload = serde_json::json!({
+        "critical": {
+            "identity": { "docker-reference": "localhost:5000/test" },
+            "image": { "docker-manifest-digest": digest },
+            "type": "cosign container image signature"
+        },
+        "optional": null
+    }).to_string();
+    let signature: Signature = signing_key.sign(payload.as_bytes());
+
+    let engine = base64::engine::general_purpose::STANDARD;
+    let mut image_signature = ImageSignature {
+        payload: engine.encode(&payload),
+        signature: engine.encode(signature.to_der().as_bytes())
+    };
+
+    assert_eq!(digest, verifier.verify(&image_signature).unwrap());
+    let signatures = vec![image_signature.clone()];
+    assert!(verifier.verify_any(&signatures, &[digest]).is_ok());
+    assert!(verifier.verify_any(&signatures, &["sha256:other".to_owned()]).is_err());
+
+    image_signature.payload = engine.encode(payload.replace("ab", "cd"));
+    assert!(verifier.verify(&image_signature).is_err());
+}
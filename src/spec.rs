@@ -1,152 +1,457 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::config::{NetworkConfig, RegistryConfig};
 use crate::model::{ContainerRuntimeError, ContainerRuntimeResult, User};
+use crate::image::ImageStore;
+use crate::names;
 use crate::network;
 use crate::network::Ipv4Net;
+use crate::registry::Platform;
+use crate::state::ResourceLimits;
+
+/// The size of /dev/shm unless given, the same as Docker uses.
+pub const DEFAULT_SHM_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Implements Serialize and Deserialize as the text of Display and FromStr, for specs that are given as text on the command line.
+macro_rules! serde_as_text {
+    ($type:ty) => {
+        impl Serialize for $type {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+                serializer.serialize_str(&self.to_string())
+            }
+        }
 
-#[derive(Debug, Clone)]
+        impl<'de> Deserialize<'de> for $type {
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+                let text = String::deserialize(deserializer)?;
+                <$type>::from_str(&text).map_err(serde::de::Error::custom)
+            }
+        }
+    };
+}
+
+pub(crate) use serde_as_text;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RunContainerSpec {
     pub image_base_dir: PathBuf,
     pub containers_base_dir: PathBuf,
     pub id: String,
     pub name: String,
     pub image: String,
+    /// When the image is pulled from its registry before the container is run
+    pub pull_policy: PullPolicy,
+    /// The platform of the image to pull and run, which is the one of the host if not given
+    #[serde(default)]
+    pub platform: Option<Platform>,
+    /// Runs images of other architectures with QEMU user mode emulation, registering its binfmt_misc handler if needed
+    #[serde(default)]
+    pub emulation: Option<EmulationSpec>,
+    pub rootfs: Option<PathBuf>,
+    /// Metadata of the container, which is kept in its state and used to filter containers
+    pub labels: BTreeMap<String, String>,
     pub command: Vec<String>,
     pub network: NetworkSpec,
     pub dns: DNSSpec,
+    /// Replaces the search domains of the resolver
+    pub dns_search: Vec<String>,
+    /// Options of the resolver (such as ndots:2), replacing options with the same name
+    pub dns_options: Vec<String>,
     pub user: Option<UserSpec>,
     pub cpu_shares: Option<i64>,
+    /// The number of CPUs worth of time the container can use (such as 1.5), enforced as a quota of each scheduling period
+    pub cpus: Option<f64>,
     pub memory: Option<i64>,
     pub memory_swap: Option<i64>,
-    pub bind_mounts: Vec<BindMountSpec>
+    /// The soft limit of the memory, which the container is reduced to when the host is low on memory
+    pub memory_reservation: Option<i64>,
+    /// How willing the kernel is to swap out memory of the container, from 0 to 100
+    pub memory_swappiness: Option<u8>,
+    /// The limit of the kernel memory used by the container, which is only accounted by some kernels
+    pub kernel_memory: Option<i64>,
+    pub bind_mounts: Vec<BindMountSpec>,
+    /// Files of the host given to the container at /run/secrets/<id>, on a tmpfs rather than in the root filesystem
+    #[serde(default)]
+    pub secrets: Vec<SecretSpec>,
+    pub timezone: Option<TimezoneSpec>,
+    /// Mounts the root filesystem as read-only, with writable tmpfs mounts on /tmp and /run
+    pub read_only: bool,
+    /// The size in bytes of the tmpfs at /dev/shm, which is the one of the host when sharing its IPC namespace
+    pub shm_size: u64,
+    pub ipc_namespace: NamespaceMode,
+    pub cgroup_namespace: NamespaceMode,
+    /// Runs the container in its own time namespace when set
+    pub time_namespace: Option<TimeNamespaceSpec>,
+    /// Copies the stdin of the runtime to the command, which otherwise reads from /dev/null
+    pub interactive: bool,
+    /// Runs the command under a minimal init, which reaps orphaned processes and forwards signals
+    pub init: bool,
+    /// Reads the executable files of the container root into the page cache before the command is executed
+    pub prewarm: bool,
+    /// Logs how long each phase of starting the container took
+    pub time_startup: bool,
+    /// Kills the command when it has run for this many seconds
+    pub timeout: Option<u64>,
+    pub ulimits: Vec<UlimitSpec>,
+    /// Kernel parameters of the namespaces of the container, such as net.ipv4.ip_unprivileged_port_start=0
+    pub sysctls: BTreeMap<String, String>,
+    pub scheduling: SchedulingSpec,
+    /// Passes through these GPUs of the host, along with their driver libraries
+    pub gpus: Option<GpuSpec>,
+    pub security: SecuritySpec,
+    pub log: LogSpec,
+    pub hooks: HooksSpec,
+    /// The pod that the container belongs to, which it shares /dev/shm with
+    #[serde(default)]
+    pub pod: Option<PodSpec>,
+    /// Only keeps the namespaces of its pod alive rather than executing the command, which makes the container the infra container of the pod
+    #[serde(default)]
+    pub infra: bool,
+    pub storage_driver: StorageDriverSpec,
+    /// Whether the runtime writes the cgroups itself or has systemd create them, which comes from the config
+    #[serde(default)]
+    pub cgroup_driver: CgroupDriverSpec,
+    /// The hosts file of the host that the container is added to while it runs, which comes from the config
+    #[serde(default)]
+    pub hosts_file: Option<HostsFileSpec>,
+    /// The optional features of the overlay storage driver, which come from the config
+    #[serde(default)]
+    pub overlay: OverlayOptionsSpec,
+    pub verify_signature: bool,
+    /// Refuses to run the image if the files of its layers have been changed on disk since they were extracted
+    pub verify_rootfs: bool,
+    /// The settings of the registries that lazily pulled layers are read from, which come from the config rather than the spec file
+    #[serde(skip)]
+    pub registries: HashMap<String, RegistryConfig>,
+    /// The listening sockets that systemd passed to the runtime, which are passed on to the command
+    #[serde(skip)]
+    pub listen_fds: Option<ListenFdsSpec>
 }
 
+/// The spec that a container was run with, which is kept in its root after it has exited.
+pub const SPEC_FILE: &str = "spec.json";
+
 impl RunContainerSpec {
-    pub fn image_root(&self) -> PathBuf {
-        self.image_base_dir.join("rootfs").join(&self.image)
+    pub fn container_root(&self) -> PathBuf {
+        self.containers_base_dir.join(&self.id)
     }
 
-    pub fn image_archive(&self) -> PathBuf {
-        self.image_base_dir.join(self.image.clone() + ".tar")
+    pub fn load(container_root: &Path) -> ContainerRuntimeResult<RunContainerSpec> {
+        let content = std::fs::read_to_string(container_root.join(SPEC_FILE))?;
+        serde_json::from_str(&content).map_err(|err| ContainerRuntimeError::State(err.to_string()))
     }
 
-    pub fn container_root(&self) -> PathBuf {
-        self.containers_base_dir.join(&self.id)
+    pub fn save(&self, container_root: &Path) -> ContainerRuntimeResult<()> {
+        let content = serde_json::to_string_pretty(self).map_err(|err| ContainerRuntimeError::State(err.to_string()))?;
+        std::fs::write(container_root.join(SPEC_FILE), content)?;
+        Ok(())
     }
 
-    pub fn hostname(&self) -> Option<String> {
-        match &self.network {
-            NetworkSpec::Host => None,
-            NetworkSpec::Bridged(bridged) => {
-                Some(bridged.hostname.clone().unwrap_or_else(|| self.name.clone()))
+    /// Replaces the fields of the spec with those given in a YAML (or JSON) spec file, where the rest are kept.
+    /// The id and the directories always belong to the runtime.
+    pub fn with_file(self, path: &Path) -> ContainerRuntimeResult<RunContainerSpec> {
+        let content = std::fs::read_to_string(path)?;
+        self.with_content(&content).map_err(|err| ContainerRuntimeError::Input(format!("Invalid spec file {}: {}", path.to_str().unwrap(), err)))
+    }
+
+    fn with_content(self, content: &str) -> Result<RunContainerSpec, String> {
+        let fields = match serde_yaml::from_str::<serde_json::Value>(content).map_err(|err| err.to_string())? {
+            serde_json::Value::Object(fields) => fields,
+            serde_json::Value::Null => serde_json::Map::new(),
+            _ => return Err("expected a mapping of fields".to_owned())
+        };
+
+        let mut spec = serde_json::to_value(&self).map_err(|err| err.to_string())?;
+        for (name, value) in fields {
+            if spec.get(&name).is_none() {
+                return Err(format!("unknown field '{}'", name));
             }
+
+            spec[name] = value;
         }
+
+        let mut spec = serde_json::from_value::<RunContainerSpec>(spec).map_err(|err| err.to_string())?;
+        spec.image_base_dir = self.image_base_dir;
+        spec.containers_base_dir = self.containers_base_dir;
+        spec.id = self.id;
+        spec.registries = self.registries;
+
+        if spec.image.is_empty() {
+            // Like on the command line, a container run from a root filesystem is shown with the path as the image
+            match &spec.rootfs {
+                Some(rootfs) => spec.image = rootfs.to_str().unwrap().to_owned(),
+                None => return Err("expected an image or a rootfs".to_owned())
+            }
+        }
+
+        Ok(spec)
     }
 
-    pub fn user<'a, T: Iterator<Item=&'a User>>(&'a self, users: T) -> Option<ContainerRuntimeResult<User>> {
-        let user = self.user.as_ref()?;
+    /// Checks the spec before anything is set up for the container, reporting all violations together.
+    pub fn validate(&self) -> ContainerRuntimeResult<()> {
+        let mut violations = Vec::new();
 
-        Some(
-            user
-                .find_user(users)
-                .ok_or_else(|| ContainerRuntimeError::InvalidUser(user.clone()))
-        )
+        if let Err(err) = names::validate_name(&self.name) {
+            violations.push(err.to_string());
+        }
+
+        if self.command.is_empty() {
+            violations.push("No command given".to_owned());
+        }
+
+        if self.shm_size == 0 {
+            violations.push("The size of /dev/shm must be larger than 0".to_owned());
+        }
+
+        match &self.rootfs {
+            Some(rootfs) if !rootfs.is_dir() => violations.push(format!("Rootfs {} is not a directory", rootfs.to_str().unwrap())),
+            Some(_) if self.verify_rootfs => violations.push("Only the layers of an image can be verified, not a rootfs directory".to_owned()),
+            Some(_) => {}
+            None => {
+                if let Err(err) = ImageStore::new(&self.image_base_dir).layers(&self.image) {
+                    violations.push(err.to_string());
+                }
+            }
+        }
+
+        for bind_mount in &self.bind_mounts {
+            if !bind_mount.source.exists() {
+                violations.push(format!("Source of bind mount {} does not exist", bind_mount.source.to_str().unwrap()));
+            }
+
+            if !bind_mount.target.is_absolute() {
+                violations.push(format!("Target of bind mount {} is not an absolute path", bind_mount.target.to_str().unwrap()));
+            }
+        }
+
+        for (index, secret) in self.secrets.iter().enumerate() {
+            if !secret.source.is_file() {
+                violations.push(format!("Source of secret {} is not a file: {}", secret.id, secret.source.to_str().unwrap()));
+            }
+
+            if self.secrets[..index].iter().any(|other| other.id == secret.id) {
+                violations.push(format!("Secret {} is given more than once", secret.id));
+            }
+        }
+
+        violations.extend(ResourceLimits::from_spec(self).violations());
+        violations.extend(self.log.violations());
+
+        for name in self.sysctls.keys() {
+            if let Err(err) = validate_sysctl(name, &self.network, self.ipc_namespace) {
+                violations.push(err);
+            }
+        }
+
+        let scheduling = &self.scheduling;
+        if let Err(err) = SchedulingSpec::new(scheduling.oom_score_adj, scheduling.nice, scheduling.rt_priority) {
+            violations.push(err.to_string());
+        }
+
+        if let NetworkSpec::Pod(pod) = &self.network {
+            if self.ipc_namespace == NamespaceMode::Host {
+                violations.push(format!("The IPC namespace of the host cannot be shared by a container of pod {}, which shares the one of the pod", pod.pod));
+            }
+        }
+
+        if let NetworkSpec::Bridged(bridged) = &self.network {
+            if let Some(container_ip_address) = bridged.container_ip_address {
+                let bridge_ip_address = bridged.bridge_ip_address;
+                let address = Ipv4Net::new(container_ip_address.address, bridge_ip_address.subnet_cidr);
+                if !bridge_ip_address.contains(container_ip_address.address) {
+                    violations.push(format!("IP address {} is not in the subnet of the bridge ({})", container_ip_address.address, bridge_ip_address));
+                } else if address.is_network() || address.is_broadcast() || address.address == bridge_ip_address.address {
+                    violations.push(format!("IP address {} is reserved in the subnet of the bridge ({})", container_ip_address.address, bridge_ip_address));
+                }
+            }
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(ContainerRuntimeError::InvalidSpec(violations))
+        }
     }
 
-    pub fn network_namespace(&self) -> Option<String> {
+    pub fn hostname(&self) -> Option<String> {
         match &self.network {
             NetworkSpec::Host => None,
-            NetworkSpec::Bridged(_) => Some(format!("cort-{}", &self.id[..4]))
+            NetworkSpec::Bridged(bridged) => {
+                Some(bridged.hostname.clone().unwrap_or_else(|| self.name.clone()))
+            }
+            NetworkSpec::Cni(cni) => {
+                Some(cni.hostname.clone().unwrap_or_else(|| self.name.clone()))
+            }
+            NetworkSpec::Pod(pod) => Some(pod.hostname.clone())
         }
     }
+
+    /// The user to run as, found in the users (by id) and groups (by name) of the image, or none to run as root.
+    pub fn user(&self, users: &HashMap<i32, User>, groups: &HashMap<String, i32>) -> Option<ContainerRuntimeResult<User>> {
+        Some(self.user.as_ref()?.find_user(users, groups))
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum UserSpec {
     Name(String),
     Id(i32),
-    IdAndGroupId(i32, i32)
+    IdAndGroupId(i32, i32),
+    /// A user (by name or id) and a group where either is a name, such as `www-data:www-data` or `1000:staff`
+    WithGroup(String, String)
+}
+
+impl FromStr for UserSpec {
+    type Err = String;
+
+    /// Parses users of the form `<name>`, `<uid>` or `<user>:<group>`, where the user and group are names or ids.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid user '{}' (expected <name>, <uid> or <user>:<group>).", text);
+        match text.split_once(':') {
+            Some((user, group)) if user.is_empty() || group.is_empty() || group.contains(':') => Err(invalid()),
+            Some((user, group)) => {
+                match (i32::from_str(user), i32::from_str(group)) {
+                    (Ok(user_id), Ok(group_id)) => Ok(UserSpec::IdAndGroupId(user_id, group_id)),
+                    _ => Ok(UserSpec::WithGroup(user.to_owned(), group.to_owned()))
+                }
+            }
+            None if text.is_empty() => Err(invalid()),
+            None => Ok(i32::from_str(text).map(UserSpec::Id).unwrap_or_else(|_| UserSpec::Name(text.to_owned())))
+        }
+    }
 }
 
+impl Display for UserSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UserSpec::Name(name) => write!(f, "{}", name),
+            UserSpec::Id(id) => write!(f, "{}", id),
+            UserSpec::IdAndGroupId(user_id, group_id) => write!(f, "{}:{}", user_id, group_id),
+            UserSpec::WithGroup(user, group) => write!(f, "{}:{}", user, group)
+        }
+    }
+}
+
+serde_as_text!(UserSpec);
+
 impl UserSpec {
-    pub fn find_user<'a, T: Iterator<Item=&'a User>>(&'a self, users: T) -> Option<User> {
+    /// Users given by id need not exist in the image, while users and groups given by name must.
+    pub fn find_user(&self, users: &HashMap<i32, User>, groups: &HashMap<String, i32>) -> ContainerRuntimeResult<User> {
         match self {
             UserSpec::Name(name) => {
-                for user in users {
-                    if &user.username == name {
-                        return Some(user.clone());
-                    }
-                }
-
-                None
+                users
+                    .values()
+                    .find(|user| &user.username == name)
+                    .cloned()
+                    .ok_or_else(|| {
+                        let mut names = users.values().map(|user| (user.id, user.username.as_str())).collect::<Vec<_>>();
+                        names.sort();
+                        let names = names.into_iter().map(|(_, name)| name).collect::<Vec<_>>();
+                        ContainerRuntimeError::InvalidUser(format!("no user named '{}' in the image (its users are: {})", name, names_or_none(&names)))
+                    })
             }
             UserSpec::Id(id) => {
-                for user in users {
-                    if &user.id == id {
-                        return Some(user.clone());
-                    }
-                }
-
-                Some(
-                    User {
+                Ok(
+                    users.get(id).cloned().unwrap_or_else(|| User {
                         username: "unknown".to_string(),
                         id: *id,
                         group_id: None,
-                        home_folder: Path::new("/root").to_owned()
-                    }
+                        home_folder: Path::new("/root").to_owned(),
+                        shell: User::default_shell()
+                    })
                 )
             }
             UserSpec::IdAndGroupId(user_id, group_id) => {
-                for user in users {
-                    if &user.id == user_id && &user.group_id == &Some(*group_id) {
-                        return Some(user.clone());
+                let mut user = UserSpec::Id(*user_id).find_user(users, groups)?;
+                user.group_id = Some(*group_id);
+                Ok(user)
+            }
+            UserSpec::WithGroup(user, group) => {
+                let group_id = match i32::from_str(group) {
+                    Ok(group_id) => group_id,
+                    Err(_) => {
+                        *groups.get(group).ok_or_else(|| {
+                            let mut names = groups.iter().map(|(name, group_id)| (*group_id, name.as_str())).collect::<Vec<_>>();
+                            names.sort();
+                            let names = names.into_iter().map(|(_, name)| name).collect::<Vec<_>>();
+                            ContainerRuntimeError::InvalidUser(format!("no group named '{}' in the image (its groups are: {})", group, names_or_none(&names)))
+                        })?
                     }
-                }
+                };
 
-                Some(
-                    User {
-                        username: "unknown".to_string(),
-                        id: *user_id,
-                        group_id: Some(*group_id),
-                        home_folder: Path::new("/root").to_owned()
-                    }
-                )
+                let mut user = UserSpec::from_str(user).map_err(ContainerRuntimeError::Input)?.find_user(users, groups)?;
+                user.group_id = Some(group_id);
+                Ok(user)
             }
         }
     }
 }
 
-#[derive(Debug, Clone)]
+fn names_or_none(names: &[&str]) -> String {
+    if names.is_empty() {
+        "none".to_owned()
+    } else {
+        names.join(", ")
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BridgeSpec {
     pub physical_interface: Option<String>,
     pub interface: String,
-    pub ip_address: Ipv4Net
+    pub ip_address: Ipv4Net,
+    pub inter_container_communication: bool,
+    pub hairpin: bool,
+    pub ipam: IpamSpec
 }
 
 impl BridgeSpec {
-    pub fn default() -> ContainerRuntimeResult<BridgeSpec> {
+    pub fn create(network_config: &NetworkConfig) -> ContainerRuntimeResult<BridgeSpec> {
         Ok(
             BridgeSpec {
                 physical_interface: Some(network::find_internet_interface()?),
-                interface: "cort0".to_string(),
-                ip_address: Ipv4Net::from_str("10.10.1.1/16").unwrap()
+                interface: network_config.bridge_interface.clone(),
+                ip_address: network_config.bridge_ip_address,
+                inter_container_communication: network_config.inter_container_communication,
+                hairpin: network_config.hairpin,
+                ipam: network_config.ipam
             }
         )
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all="snake_case")]
 pub enum NetworkSpec {
     Host,
-    Bridged(BridgedNetworkSpec)
+    Bridged(BridgedNetworkSpec),
+    /// The network is set up by CNI plugins instead of the runtime
+    Cni(CniNetworkSpec),
+    /// Joins the network, UTS and IPC namespaces of a pod, which are set up by the container that created them
+    Pod(PodNetworkSpec)
 }
 
 impl NetworkSpec {
     pub fn is_host(&self) -> bool {
+        matches!(self, NetworkSpec::Host)
+    }
+
+    pub fn with_bandwidth_limits(self, ingress: Option<BandwidthSpec>, egress: Option<BandwidthSpec>) -> ContainerRuntimeResult<NetworkSpec> {
         match self {
-            NetworkSpec::Host => true,
-            _ => false
+            NetworkSpec::Host | NetworkSpec::Cni(_) | NetworkSpec::Pod(_) if ingress.is_some() || egress.is_some() => {
+                Err(ContainerRuntimeError::Input("Bandwidth limits require a bridged network".to_owned()))
+            }
+            NetworkSpec::Host | NetworkSpec::Cni(_) | NetworkSpec::Pod(_) => Ok(self),
+            NetworkSpec::Bridged(mut bridged) => {
+                bridged.ingress_limit = ingress;
+                bridged.egress_limit = egress;
+                Ok(NetworkSpec::Bridged(bridged))
+            }
         }
     }
 
@@ -159,22 +464,60 @@ impl NetworkSpec {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CniNetworkSpec {
+    /// The name of the network configuration
+    pub name: String,
+    pub config_dir: PathBuf,
+    pub plugin_dirs: Vec<PathBuf>,
+    pub hostname: Option<String>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PodNetworkSpec {
+    /// The name of the pod
+    pub pod: String,
+    /// A process of the pod whose namespaces are joined, which is its infra container unless created without one
+    pub pid: i32,
+    pub hostname: String,
+    pub ip_address: Option<Ipv4Net>
+}
+
+/// The pod of a container, where its /dev/shm is a tmpfs of the host that all containers of the pod mount.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PodSpec {
+    pub name: String,
+    pub shm_dir: PathBuf
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BridgedNetworkSpec {
     pub bridge_interface: String,
     pub bridge_ip_address: Ipv4Net,
-    pub container_ip_address: Ipv4Net,
-    pub hostname: Option<String>
+    /// None when the address is leased with DHCP
+    pub container_ip_address: Option<Ipv4Net>,
+    pub hairpin: bool,
+    pub hostname: Option<String>,
+    /// Limit of the traffic received by the container
+    pub ingress_limit: Option<BandwidthSpec>,
+    /// Limit of the traffic sent by the container
+    pub egress_limit: Option<BandwidthSpec>
 }
 
 impl BridgedNetworkSpec {
-    pub fn from_bridge(bridge: &BridgeSpec) -> ContainerRuntimeResult<BridgedNetworkSpec> {
+    pub fn from_bridge(bridge: &BridgeSpec, used_ip_addresses: &[Ipv4Net]) -> ContainerRuntimeResult<BridgedNetworkSpec> {
         Ok(
             BridgedNetworkSpec {
                 bridge_interface: bridge.interface.clone(),
-                bridge_ip_address: bridge.ip_address.clone(),
-                container_ip_address: network::find_free_ip_address(bridge.ip_address)?,
-                hostname: None
+                bridge_ip_address: bridge.ip_address,
+                container_ip_address: match bridge.ipam {
+                    IpamSpec::Static => Some(network::find_free_ip_address(bridge.ip_address, used_ip_addresses)?),
+                    IpamSpec::Dhcp => None
+                },
+                hairpin: bridge.hairpin,
+                hostname: None,
+                ingress_limit: None,
+                egress_limit: None
             }
         )
     }
@@ -185,7 +528,56 @@ impl BridgedNetworkSpec {
     }
 }
 
-#[derive(Debug, Clone)]
+/// How the addresses of bridged containers are assigned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum IpamSpec {
+    /// The runtime assigns a free address in the bridge subnet
+    #[default]
+    Static,
+    /// The container leases an address from a DHCP server reachable through the bridge
+    Dhcp
+}
+
+/// Bandwidth in bits per second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BandwidthSpec(pub u64);
+
+impl FromStr for BandwidthSpec {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.to_lowercase();
+        let digits_end = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+        let (value, unit) = text.split_at(digits_end);
+
+        let value = u64::from_str(value).map_err(|_| "Expected bandwidth such as 10mbit.".to_owned())?;
+        let multiplier = match unit {
+            "" | "bit" => 1,
+            "kbit" => 1000,
+            "mbit" => 1000 * 1000,
+            "gbit" => 1000 * 1000 * 1000,
+            _ => return Err(format!("Invalid bandwidth unit '{}' (expected bit, kbit, mbit or gbit).", unit))
+        };
+
+        if value == 0 {
+            return Err("Bandwidth must be positive.".to_owned());
+        }
+
+        Ok(BandwidthSpec(value * multiplier))
+    }
+}
+
+impl Display for BandwidthSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}bit", self.0)
+    }
+}
+
+serde_as_text!(BandwidthSpec);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all="snake_case")]
 pub enum DNSSpec {
     Server(Vec<String>),
     CopyFromHost
@@ -197,30 +589,1203 @@ impl Default for DNSSpec {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BindMountSpec {
     pub source: PathBuf,
     pub target: PathBuf,
-    pub is_readonly: bool
+    #[serde(default)]
+    pub is_readonly: bool,
+    #[serde(default)]
+    pub propagation: MountPropagation
 }
 
 impl BindMountSpec {
+    /// Creates bind mounts from source and target pairs, where the target can end with a propagation mode (such as `/media:slave`).
     pub fn from_paths(paths: Vec<PathBuf>) -> ContainerRuntimeResult<Vec<BindMountSpec>> {
         let mut bind_mounts = Vec::new();
-        if paths.len() > 0 {
-            if paths.len() % 2 != 0 {
+        if !paths.is_empty() {
+            if !paths.len().is_multiple_of(2) {
                 return Err(ContainerRuntimeError::Input("Expected bind mounts in pairs".to_owned()));
             }
 
             for pair in paths.chunks(2) {
+                let target = pair[1].to_str().unwrap();
+                let (target, propagation) = match target.rsplit_once(':') {
+                    Some((target, propagation)) => {
+                        let propagation = MountPropagation::from_str(propagation).map_err(ContainerRuntimeError::Input)?;
+                        (target, propagation)
+                    }
+                    None => (target, MountPropagation::default())
+                };
+
                 bind_mounts.push(BindMountSpec {
                     source: pair[0].to_owned(),
-                    target: pair[1].to_owned(),
-                    is_readonly: false
+                    target: PathBuf::from(target),
+                    is_readonly: false,
+                    propagation
                 });
             }
         }
 
         Ok(bind_mounts)
     }
-}
\ No newline at end of file
+}
+
+/// Controls if mount events propagate between the host and a bind mount.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum MountPropagation {
+    /// Mount events do not propagate in either direction
+    #[default]
+    Private,
+    /// Mounts on the host become visible in the container
+    Slave,
+    /// Like slave, but mounts within the container also propagate to other mounts of the same source in the container
+    Shared
+}
+
+impl MountPropagation {
+    pub fn flags(&self) -> libc::c_ulong {
+        match self {
+            MountPropagation::Private => libc::MS_PRIVATE,
+            MountPropagation::Slave => libc::MS_SLAVE,
+            MountPropagation::Shared => libc::MS_SHARED
+        }
+    }
+}
+
+impl FromStr for MountPropagation {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "private" => Ok(MountPropagation::Private),
+            "slave" => Ok(MountPropagation::Slave),
+            "shared" => Ok(MountPropagation::Shared),
+            _ => Err(format!("Invalid mount propagation '{}' (expected shared, slave or private).", text))
+        }
+    }
+}
+
+const ULIMIT_RESOURCES: [(&str, libc::__rlimit_resource_t); 16] = [
+    ("as", libc::RLIMIT_AS), ("core", libc::RLIMIT_CORE), ("cpu", libc::RLIMIT_CPU), ("data", libc::RLIMIT_DATA),
+    ("fsize", libc::RLIMIT_FSIZE), ("locks", libc::RLIMIT_LOCKS), ("memlock", libc::RLIMIT_MEMLOCK), ("msgqueue", libc::RLIMIT_MSGQUEUE),
+    ("nice", libc::RLIMIT_NICE), ("nofile", libc::RLIMIT_NOFILE), ("nproc", libc::RLIMIT_NPROC), ("rss", libc::RLIMIT_RSS),
+    ("rtprio", libc::RLIMIT_RTPRIO), ("rttime", libc::RLIMIT_RTTIME), ("sigpending", libc::RLIMIT_SIGPENDING), ("stack", libc::RLIMIT_STACK)
+];
+
+/// A resource limit (as by ulimit) of the container process, instead of inheriting the limits of the runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UlimitSpec {
+    pub name: &'static str,
+    pub resource: libc::__rlimit_resource_t,
+    pub soft: libc::rlim_t,
+    pub hard: libc::rlim_t
+}
+
+impl FromStr for UlimitSpec {
+    type Err = String;
+
+    /// Parses limits of the form `<name>=<soft>[:<hard>]`, where the hard limit is the same as the soft when omitted.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (name, limits) = text.split_once('=').ok_or_else(|| format!("Invalid ulimit '{}' (expected <name>=<soft>[:<hard>]).", text))?;
+        let (name, resource) = ULIMIT_RESOURCES
+            .iter()
+            .find(|(resource_name, _)| *resource_name == name)
+            .copied()
+            .ok_or_else(|| format!("Unknown ulimit '{}'.", name))?;
+
+        let parse_limit = |limit: &str| -> Result<libc::rlim_t, String> {
+            match limit {
+                "unlimited" | "-1" => Ok(libc::RLIM_INFINITY),
+                _ => libc::rlim_t::from_str(limit).map_err(|_| format!("Invalid limit '{}' for ulimit {}.", limit, name))
+            }
+        };
+
+        let (soft, hard) = match limits.split_once(':') {
+            Some((soft, hard)) => (parse_limit(soft)?, parse_limit(hard)?),
+            None => (parse_limit(limits)?, parse_limit(limits)?)
+        };
+
+        if soft > hard {
+            return Err(format!("The soft limit of ulimit {} cannot exceed the hard limit.", name));
+        }
+
+        Ok(
+            UlimitSpec {
+                name,
+                resource,
+                soft,
+                hard
+            }
+        )
+    }
+}
+
+impl Display for UlimitSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let format_limit = |limit: libc::rlim_t| if limit == libc::RLIM_INFINITY { "unlimited".to_owned() } else { limit.to_string() };
+        write!(f, "{}={}:{}", self.name, format_limit(self.soft), format_limit(self.hard))
+    }
+}
+
+serde_as_text!(UlimitSpec);
+
+/// The directory that secrets are mounted at in the container.
+pub const SECRETS_DIR: &str = "/run/secrets";
+
+/// A file of the host given to the container as /run/secrets/<id>, readable only by the user of the container.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecretSpec {
+    pub id: String,
+    pub source: PathBuf
+}
+
+impl SecretSpec {
+    pub fn target(&self) -> PathBuf {
+        Path::new(SECRETS_DIR).join(&self.id)
+    }
+}
+
+impl FromStr for SecretSpec {
+    type Err = String;
+
+    /// Parses secrets of the form `id=<id>,src=<path>` (or `source=<path>`).
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut id = None;
+        let mut source = None;
+        for option in text.split(',') {
+            match option.split_once('=') {
+                Some(("id", value)) => id = Some(value),
+                Some(("src" | "source", value)) => source = Some(value),
+                _ => return Err(format!("Invalid secret option '{}' (expected id=<id>,src=<path>).", option))
+            }
+        }
+
+        let (Some(id), Some(source)) = (id, source) else {
+            return Err(format!("Invalid secret '{}' (expected id=<id>,src=<path>).", text));
+        };
+
+        if id.is_empty() || id.contains('/') || id == "." || id == ".." {
+            return Err(format!("Invalid secret id '{}'.", id));
+        }
+
+        if source.is_empty() {
+            return Err(format!("No source given for secret '{}'.", id));
+        }
+
+        Ok(
+            SecretSpec {
+                id: id.to_owned(),
+                source: PathBuf::from(source)
+            }
+        )
+    }
+}
+
+impl Display for SecretSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "id={},src={}", self.id, self.source.to_str().unwrap())
+    }
+}
+
+serde_as_text!(SecretSpec);
+
+/// How the process of the container is prioritized by the kernel, when it is out of memory and when scheduling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SchedulingSpec {
+    /// Between -1000 (never killed) and 1000 (killed first) when out of memory
+    pub oom_score_adj: Option<i32>,
+    /// Between -20 (highest priority) and 19
+    pub nice: Option<i32>,
+    /// Runs the process with the SCHED_RR real-time policy at this priority, between 1 and 99
+    pub rt_priority: Option<i32>
+}
+
+impl SchedulingSpec {
+    pub fn new(oom_score_adj: Option<i32>, nice: Option<i32>, rt_priority: Option<i32>) -> ContainerRuntimeResult<SchedulingSpec> {
+        let check_range = |name: &str, value: Option<i32>, min: i32, max: i32| {
+            match value {
+                Some(value) if value < min || value > max => Err(ContainerRuntimeError::Input(format!("{} must be between {} and {}", name, min, max))),
+                _ => Ok(())
+            }
+        };
+
+        check_range("OOM score adjustment", oom_score_adj, -1000, 1000)?;
+        check_range("Nice value", nice, -20, 19)?;
+        check_range("Real-time priority", rt_priority, 1, 99)?;
+
+        Ok(
+            SchedulingSpec {
+                oom_score_adj,
+                nice,
+                rt_priority
+            }
+        )
+    }
+}
+
+/// Parses labels of the form `<key>=<value>`, where the value is empty when omitted.
+pub fn parse_labels(labels: &[String]) -> ContainerRuntimeResult<BTreeMap<String, String>> {
+    let mut parsed = BTreeMap::new();
+    for label in labels {
+        let (key, value) = label.split_once('=').unwrap_or((label, ""));
+        if key.is_empty() {
+            return Err(ContainerRuntimeError::Input(format!("Invalid label '{}' (expected <key>=<value>)", label)));
+        }
+
+        parsed.insert(key.to_owned(), value.to_owned());
+    }
+
+    Ok(parsed)
+}
+
+pub fn parse_sysctls(sysctls: &[String]) -> ContainerRuntimeResult<BTreeMap<String, String>> {
+    let mut parsed = BTreeMap::new();
+    for sysctl in sysctls {
+        match sysctl.split_once('=') {
+            Some((name, value)) if !name.is_empty() => {
+                parsed.insert(name.to_owned(), value.to_owned());
+            }
+            _ => return Err(ContainerRuntimeError::Input(format!("Invalid sysctl '{}' (expected <name>=<value>)", sysctl)))
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// The sysctls of the IPC namespace, along with those under fs.mqueue.
+const IPC_SYSCTLS: [&str; 8] = [
+    "kernel.msgmax", "kernel.msgmnb", "kernel.msgmni", "kernel.sem", "kernel.shmall", "kernel.shmmax", "kernel.shmmni", "kernel.shm_rmid_forced"
+];
+
+/// Checks that the sysctl belongs to a namespace that the container has of its own, as it would otherwise change the host.
+fn validate_sysctl(name: &str, network: &NetworkSpec, ipc_namespace: NamespaceMode) -> Result<(), String> {
+    let is_valid_name = name.split('.').all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-')));
+    if !is_valid_name {
+        return Err(format!("Invalid sysctl name '{}'", name));
+    }
+
+    if name.starts_with("net.") {
+        match network {
+            NetworkSpec::Host => return Err(format!("Sysctl {} cannot be set when using the host network", name)),
+            NetworkSpec::Pod(pod) => return Err(format!("Sysctl {} cannot be set by a container of pod {}, as it shares the network of the pod", name, pod.pod)),
+            _ => {}
+        }
+    } else if IPC_SYSCTLS.contains(&name) || name.starts_with("fs.mqueue.") {
+        if ipc_namespace == NamespaceMode::Host {
+            return Err(format!("Sysctl {} cannot be set when sharing the IPC namespace of the host", name));
+        }
+        if let NetworkSpec::Pod(pod) = network {
+            return Err(format!("Sysctl {} cannot be set by a container of pod {}, as it shares the IPC namespace of the pod", name, pod.pod));
+        }
+    } else {
+        return Err(format!("Sysctl {} is not namespaced (only net.*, fs.mqueue.* and the IPC sysctls of kernel.* are)", name));
+    }
+
+    Ok(())
+}
+
+/// QEMU user mode emulation of images of other architectures than the one of the host.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmulationSpec {
+    /// Where the emulators (qemu-<architecture>-static) are found, which come from the config
+    pub qemu_dir: PathBuf
+}
+
+/// The GPUs passed through to the container, identified by the number of their device node (/dev/nvidiaN or /dev/dri/cardN).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GpuSpec {
+    All,
+    Devices(Vec<u32>)
+}
+
+impl FromStr for GpuSpec {
+    type Err = String;
+
+    /// Parses either `all` or a comma separated list of GPUs, such as `0,2`.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        if text == "all" {
+            return Ok(GpuSpec::All);
+        }
+
+        let mut devices = Vec::new();
+        for device in text.split(',') {
+            let device = u32::from_str(device.trim()).map_err(|_| format!("Invalid GPU '{}' (expected all or a list of numbers).", device))?;
+            if !devices.contains(&device) {
+                devices.push(device);
+            }
+        }
+
+        Ok(GpuSpec::Devices(devices))
+    }
+}
+
+impl Display for GpuSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GpuSpec::All => write!(f, "all"),
+            GpuSpec::Devices(devices) => write!(f, "{}", devices.iter().map(|device| device.to_string()).collect::<Vec<_>>().join(","))
+        }
+    }
+}
+
+serde_as_text!(GpuSpec);
+
+/// Mandatory access control labels applied to the container process, and the capabilities it keeps as a non-root user.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SecuritySpec {
+    pub apparmor_profile: Option<String>,
+    pub selinux_label: Option<String>,
+    /// Capabilities that a non-root user keeps (as ambient capabilities), where all others are dropped
+    pub keep_capabilities: Vec<CapabilitySpec>
+}
+
+impl SecuritySpec {
+    /// Parses options of the form `apparmor=<profile>` and `label=<selinux context>`.
+    pub fn from_options(options: &[String]) -> ContainerRuntimeResult<SecuritySpec> {
+        let mut spec = SecuritySpec::default();
+        for option in options {
+            match option.split_once('=') {
+                Some(("apparmor", profile)) if !profile.is_empty() => spec.apparmor_profile = Some(profile.to_owned()),
+                Some(("label", label)) if !label.is_empty() => spec.selinux_label = Some(label.to_owned()),
+                _ => return Err(ContainerRuntimeError::Input(format!("Invalid security option '{}' (expected apparmor=<profile> or label=<label>)", option)))
+            }
+        }
+
+        Ok(spec)
+    }
+}
+
+/// The capabilities by number, as in linux/capability.h.
+const CAPABILITIES: [&str; 41] = [
+    "CAP_CHOWN", "CAP_DAC_OVERRIDE", "CAP_DAC_READ_SEARCH", "CAP_FOWNER", "CAP_FSETID", "CAP_KILL", "CAP_SETGID", "CAP_SETUID",
+    "CAP_SETPCAP", "CAP_LINUX_IMMUTABLE", "CAP_NET_BIND_SERVICE", "CAP_NET_BROADCAST", "CAP_NET_ADMIN", "CAP_NET_RAW", "CAP_IPC_LOCK", "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE", "CAP_SYS_RAWIO", "CAP_SYS_CHROOT", "CAP_SYS_PTRACE", "CAP_SYS_PACCT", "CAP_SYS_ADMIN", "CAP_SYS_BOOT", "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE", "CAP_SYS_TIME", "CAP_SYS_TTY_CONFIG", "CAP_MKNOD", "CAP_LEASE", "CAP_AUDIT_WRITE", "CAP_AUDIT_CONTROL", "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE", "CAP_MAC_ADMIN", "CAP_SYSLOG", "CAP_WAKE_ALARM", "CAP_BLOCK_SUSPEND", "CAP_AUDIT_READ", "CAP_PERFMON", "CAP_BPF",
+    "CAP_CHECKPOINT_RESTORE"
+];
+
+/// A capability (such as CAP_NET_BIND_SERVICE) of the container process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapabilitySpec {
+    pub name: &'static str,
+    pub number: u32
+}
+
+impl FromStr for CapabilitySpec {
+    type Err = String;
+
+    /// Parses capabilities by name, with or without the `CAP_` prefix and in any case, such as `net_bind_service`.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let upper = text.to_uppercase();
+        let name = if upper.starts_with("CAP_") { upper } else { format!("CAP_{}", upper) };
+        CAPABILITIES
+            .iter()
+            .position(|capability| *capability == name)
+            .map(|number| CapabilitySpec { name: CAPABILITIES[number], number: number as u32 })
+            .ok_or_else(|| format!("Unknown capability '{}'.", text))
+    }
+}
+
+impl Display for CapabilitySpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+serde_as_text!(CapabilitySpec);
+
+/// An executable run by the runtime at a point in the lifecycle of a container, with the state of the container as JSON on stdin.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HookSpec {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// The hook is killed if it runs for longer than this many seconds
+    #[serde(default)]
+    pub timeout: Option<u64>
+}
+
+impl HookSpec {
+    pub fn new(path: &Path) -> HookSpec {
+        HookSpec {
+            path: path.to_owned(),
+            args: Vec::new(),
+            timeout: None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HooksSpec {
+    /// Run once the namespaces, cgroups and network of the container exist, but before its root is set up
+    pub prestart: Vec<HookSpec>,
+    /// Run once the command of the container has started
+    pub poststart: Vec<HookSpec>,
+    /// Run once the container has exited and been cleaned up
+    pub poststop: Vec<HookSpec>
+}
+
+impl HooksSpec {
+    /// Adds hooks from options of the form `<prestart|poststart|poststop>=<path>`.
+    pub fn add_options(&mut self, options: &[String]) -> ContainerRuntimeResult<()> {
+        for option in options {
+            let (hooks, path) = match option.split_once('=') {
+                Some(("prestart", path)) if !path.is_empty() => (&mut self.prestart, path),
+                Some(("poststart", path)) if !path.is_empty() => (&mut self.poststart, path),
+                Some(("poststop", path)) if !path.is_empty() => (&mut self.poststop, path),
+                _ => return Err(ContainerRuntimeError::Input(format!("Invalid hook '{}' (expected prestart=<path>, poststart=<path> or poststop=<path>)", option)))
+            };
+
+            hooks.push(HookSpec::new(Path::new(path)));
+        }
+
+        Ok(())
+    }
+}
+
+/// Where the output of the container is sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all="kebab-case")]
+pub enum LogDriverSpec {
+    /// The output is not captured, and the container writes directly to the stdout and stderr of the runtime
+    #[default]
+    None,
+    JsonFile,
+    Journald,
+    Syslog
+}
+
+impl LogDriverSpec {
+    pub fn name(&self) -> &str {
+        match self {
+            LogDriverSpec::None => "none",
+            LogDriverSpec::JsonFile => "json-file",
+            LogDriverSpec::Journald => "journald",
+            LogDriverSpec::Syslog => "syslog"
+        }
+    }
+}
+
+impl FromStr for LogDriverSpec {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "none" => Ok(LogDriverSpec::None),
+            "json-file" => Ok(LogDriverSpec::JsonFile),
+            "journald" => Ok(LogDriverSpec::Journald),
+            "syslog" => Ok(LogDriverSpec::Syslog),
+            _ => Err("Invalid log driver (expected none, json-file, journald or syslog).".to_owned())
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogSpec {
+    pub driver: LogDriverSpec,
+    /// The json-file log is rotated when it would exceed this size in bytes
+    pub max_size: Option<u64>,
+    /// The number of json-file logs kept when rotating, including the current one
+    pub max_file: Option<u32>,
+    /// The identifier used by journald and syslog, by default the name of the container
+    pub tag: Option<String>,
+    /// The socket of the syslog daemon, by default /dev/log
+    pub syslog_address: Option<PathBuf>
+}
+
+impl LogSpec {
+    /// Parses options of the form `max-size=<size>` and `max-file=<count>` (json-file), `tag=<tag>` (journald and syslog) and `syslog-address=<path>` (syslog).
+    pub fn from_options(driver: LogDriverSpec, options: &[String]) -> ContainerRuntimeResult<LogSpec> {
+        let mut spec = LogSpec {
+            driver,
+            ..LogSpec::default()
+        };
+
+        for option in options {
+            match (driver, option.split_once('=')) {
+                (LogDriverSpec::JsonFile, Some(("max-size", size))) => {
+                    spec.max_size = Some(parse_size(size).map_err(ContainerRuntimeError::Input)?);
+                }
+                (LogDriverSpec::JsonFile, Some(("max-file", count))) => {
+                    let count = u32::from_str(count).ok().filter(|count| *count > 0)
+                        .ok_or_else(|| ContainerRuntimeError::Input(format!("Invalid max-file '{}' (expected a positive number)", count)))?;
+                    spec.max_file = Some(count);
+                }
+                (LogDriverSpec::Journald | LogDriverSpec::Syslog, Some(("tag", tag))) if !tag.is_empty() => {
+                    spec.tag = Some(tag.to_owned());
+                }
+                (LogDriverSpec::Syslog, Some(("syslog-address", address))) => {
+                    let address = address.strip_prefix("unix://").unwrap_or(address);
+                    spec.syslog_address = Some(PathBuf::from(address));
+                }
+                _ => return Err(ContainerRuntimeError::Input(format!("Invalid option '{}' for the {} log driver", option, driver.name())))
+            }
+        }
+
+        if let Some(violation) = spec.violations().into_iter().next() {
+            return Err(ContainerRuntimeError::Input(violation));
+        }
+
+        Ok(spec)
+    }
+
+    /// The rules of the options, which are also checked for specs that are not given as options, such as with --spec.
+    pub fn violations(&self) -> Vec<String> {
+        let mut violations = Vec::new();
+
+        if self.max_file == Some(0) {
+            violations.push("The max-file log option must be a positive number".to_owned());
+        }
+
+        if self.max_file.is_some() && self.max_size.is_none() {
+            violations.push("The max-file log option requires max-size".to_owned());
+        }
+
+        violations
+    }
+}
+
+/// Parses a size in bytes with an optional k, m or g suffix (powers of 1024).
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    let text = text.to_lowercase();
+    let digits_end = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (value, unit) = text.split_at(digits_end);
+
+    let value = u64::from_str(value).map_err(|_| "Expected size such as 10m.".to_owned())?;
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" => 1024,
+        "m" => 1024 * 1024,
+        "g" => 1024 * 1024 * 1024,
+        _ => return Err(format!("Invalid size unit '{}' (expected k, m or g).", unit))
+    };
+
+    if value == 0 {
+        return Err("Size must be positive.".to_owned());
+    }
+
+    Ok(value * multiplier)
+}
+
+/// If the container gets its own namespace or shares it with the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum NamespaceMode {
+    #[default]
+    Private,
+    Host
+}
+
+impl FromStr for NamespaceMode {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "private" => Ok(NamespaceMode::Private),
+            "host" => Ok(NamespaceMode::Host),
+            _ => Err("Invalid namespace mode (expected private or host).".to_owned())
+        }
+    }
+}
+
+/// Offsets of the clocks in a time namespace, relative to the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimeNamespaceSpec {
+    /// Offset of CLOCK_MONOTONIC in seconds
+    pub monotonic_offset: i64,
+    /// Offset of CLOCK_BOOTTIME (and the uptime) in seconds
+    pub boottime_offset: i64
+}
+
+impl TimeNamespaceSpec {
+    pub fn from_offsets(monotonic_offset: Option<i64>, boottime_offset: Option<i64>) -> Option<TimeNamespaceSpec> {
+        if monotonic_offset.is_none() && boottime_offset.is_none() {
+            return None;
+        }
+
+        Some(
+            TimeNamespaceSpec {
+                monotonic_offset: monotonic_offset.unwrap_or(0),
+                boottime_offset: boottime_offset.unwrap_or(0)
+            }
+        )
+    }
+
+    /// The content of /proc/[pid]/timens_offsets.
+    pub fn offsets_content(&self) -> String {
+        format!("monotonic {} 0\nboottime {} 0\n", self.monotonic_offset, self.boottime_offset)
+    }
+}
+
+/// The timezone used for /etc/localtime in the container.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimezoneSpec {
+    /// Uses the timezone of the host
+    Host,
+    /// Uses a timezone from the host's zoneinfo database, such as Europe/Stockholm
+    Zone(String)
+}
+
+impl TimezoneSpec {
+    /// The file on the host that is mounted as /etc/localtime.
+    pub fn localtime_path(&self) -> ContainerRuntimeResult<PathBuf> {
+        let path = match self {
+            TimezoneSpec::Host => PathBuf::from("/etc/localtime"),
+            TimezoneSpec::Zone(zone) => Path::new("/usr/share/zoneinfo").join(zone)
+        };
+
+        if !path.is_file() {
+            return Err(ContainerRuntimeError::Input(format!("Timezone file {} not found", path.to_str().unwrap())));
+        }
+
+        Ok(path)
+    }
+}
+
+impl FromStr for TimezoneSpec {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        if text == "host" {
+            return Ok(TimezoneSpec::Host);
+        }
+
+        let is_valid = !text.is_empty()
+            && !text.starts_with('/')
+            && Path::new(text).components().all(|component| matches!(component, std::path::Component::Normal(_)));
+        if !is_valid {
+            return Err(format!("Invalid timezone '{}'.", text));
+        }
+
+        Ok(TimezoneSpec::Zone(text.to_owned()))
+    }
+}
+
+impl Display for TimezoneSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TimezoneSpec::Host => write!(f, "host"),
+            TimezoneSpec::Zone(zone) => write!(f, "{}", zone)
+        }
+    }
+}
+
+serde_as_text!(TimezoneSpec);
+
+/// When the image of a container is pulled before it is run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum PullPolicy {
+    /// Pulls the image unless the local image has the same manifest (or is pinned by a digest that is already present)
+    Always,
+    /// Pulls the image only when there is no local image
+    #[default]
+    Missing,
+    /// Never pulls, where the image must be present locally
+    Never
+}
+
+impl FromStr for PullPolicy {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "always" => Ok(PullPolicy::Always),
+            "missing" => Ok(PullPolicy::Missing),
+            "never" => Ok(PullPolicy::Never),
+            _ => Err("Invalid pull policy (expected always, missing or never).".to_owned())
+        }
+    }
+}
+
+/// A hosts file that the names and addresses of the running containers are kept in, so that the host can reach them by name,
+/// such as `/etc/hosts` (which systemd-resolved also reads) or a file read by the hosts plugin of a DNS server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostsFileSpec {
+    pub path: PathBuf,
+    /// Appended to the names of the containers, such as `cort` for `web.cort`, so that they do not shadow other hosts
+    #[serde(default)]
+    pub domain: Option<String>
+}
+
+/// When systemd restarts the unit of a container after it has exited, named as in Docker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RestartPolicy {
+    #[default]
+    No,
+    /// Restarts when the container exits with a non-zero exit code, at most the given number of times
+    OnFailure(Option<u32>),
+    Always,
+    /// Like always, as stopping the unit is what stops the container
+    UnlessStopped
+}
+
+impl FromStr for RestartPolicy {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.split_once(':') {
+            None if text == "no" => Ok(RestartPolicy::No),
+            None if text == "on-failure" => Ok(RestartPolicy::OnFailure(None)),
+            None if text == "always" => Ok(RestartPolicy::Always),
+            None if text == "unless-stopped" => Ok(RestartPolicy::UnlessStopped),
+            Some(("on-failure", max_retries)) => {
+                let max_retries = max_retries.parse().map_err(|_| format!("Invalid number of retries '{}'.", max_retries))?;
+                Ok(RestartPolicy::OnFailure(Some(max_retries)))
+            }
+            _ => Err("Invalid restart policy (expected no, on-failure[:<max retries>], always or unless-stopped).".to_owned())
+        }
+    }
+}
+
+/// The listening sockets passed by systemd socket activation, which are the file descriptors from 3 on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenFdsSpec {
+    pub count: i32,
+    /// The names of the sockets separated by colons (FileDescriptorName= of the socket unit)
+    pub names: Option<String>
+}
+
+impl ListenFdsSpec {
+    pub const START: i32 = 3;
+
+    /// Takes the sockets passed to this process, where the variables are removed so that the container does not inherit them.
+    pub fn from_env() -> Option<ListenFdsSpec> {
+        let listen_fds = ListenFdsSpec::from_variables(
+            std::env::var("LISTEN_PID").ok().as_deref(),
+            std::env::var("LISTEN_FDS").ok().as_deref(),
+            std::env::var("LISTEN_FDNAMES").ok(),
+            std::process::id()
+        );
+
+        for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            std::env::remove_var(name);
+        }
+
+        listen_fds
+    }
+
+    /// The sockets are only for the process that systemd executed, not for processes that inherited the variables.
+    fn from_variables(listen_pid: Option<&str>, listen_fds: Option<&str>, names: Option<String>, pid: u32) -> Option<ListenFdsSpec> {
+        if listen_pid?.parse::<u32>().ok()? != pid {
+            return None;
+        }
+
+        let count = listen_fds?.parse::<i32>().ok().filter(|count| *count > 0)?;
+        Some(ListenFdsSpec { count, names })
+    }
+
+    pub fn fds(&self) -> std::ops::Range<i32> {
+        ListenFdsSpec::START..(ListenFdsSpec::START + self.count)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum StorageDriverSpec {
+    #[default]
+    Overlay,
+    Vfs,
+    Btrfs
+}
+
+impl FromStr for StorageDriverSpec {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "overlay" => Ok(StorageDriverSpec::Overlay),
+            "vfs" => Ok(StorageDriverSpec::Vfs),
+            "btrfs" => Ok(StorageDriverSpec::Btrfs),
+            _ => Err("Invalid storage driver.".to_owned())
+        }
+    }
+}
+
+/// Who creates the cgroups of containers: the runtime under `container_runtime` in each controller,
+/// or systemd as a transient scope in `cort.slice` (on hosts where systemd owns the cgroup tree).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum CgroupDriverSpec {
+    #[default]
+    Cgroupfs,
+    Systemd
+}
+
+impl FromStr for CgroupDriverSpec {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "cgroupfs" => Ok(CgroupDriverSpec::Cgroupfs),
+            "systemd" => Ok(CgroupDriverSpec::Systemd),
+            _ => Err("Invalid cgroup driver (expected cgroupfs or systemd).".to_owned())
+        }
+    }
+}
+
+/// Optional features of overlay that make starting containers and copying up files faster, used when supported by the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OverlayOptionsSpec {
+    /// Skips syncing the upper directory to disk, which is lost anyway when the container is removed (Linux 5.10)
+    pub volatile: bool,
+    /// Copies up only the metadata of a file when only the metadata changes, such as by chown (`metacopy=on`)
+    pub metacopy: bool,
+    /// Keeps hard links of lower files intact when copied up (`index=on`)
+    pub index: bool
+}
+
+#[test]
+fn test_bandwidth_from_str() {
+    assert_eq!(Ok(BandwidthSpec(10_000_000)), BandwidthSpec::from_str("10mbit"));
+    assert_eq!(Ok(BandwidthSpec(1_000_000_000)), BandwidthSpec::from_str("1Gbit"));
+    assert_eq!(Ok(BandwidthSpec(500)), BandwidthSpec::from_str("500"));
+    assert_eq!("64000bit", BandwidthSpec::from_str("64kbit").unwrap().to_string());
+    assert!(BandwidthSpec::from_str("10mb").is_err());
+    assert!(BandwidthSpec::from_str("0mbit").is_err());
+    assert!(BandwidthSpec::from_str("mbit").is_err());
+}
+
+#[test]
+fn test_bind_mounts_from_paths() {
+    let bind_mounts = BindMountSpec::from_paths(vec![
+        PathBuf::from("/data"), PathBuf::from("/data"),
+        PathBuf::from("/media"), PathBuf::from("/media:slave")
+    ]).unwrap();
+
+    assert_eq!(2, bind_mounts.len());
+    assert_eq!(MountPropagation::Private, bind_mounts[0].propagation);
+    assert_eq!(Path::new("/media"), bind_mounts[1].target);
+    assert_eq!(MountPropagation::Slave, bind_mounts[1].propagation);
+
+    assert!(BindMountSpec::from_paths(vec![PathBuf::from("/media"), PathBuf::from("/media:rslave")]).is_err());
+}
+
+#[test]
+fn test_timezone_from_str() {
+    assert_eq!(Ok(TimezoneSpec::Host), TimezoneSpec::from_str("host"));
+    assert_eq!(Ok(TimezoneSpec::Zone("Europe/Stockholm".to_owned())), TimezoneSpec::from_str("Europe/Stockholm"));
+    assert!(TimezoneSpec::from_str("../../etc/shadow").is_err());
+    assert!(TimezoneSpec::from_str("/etc/localtime").is_err());
+    assert!(TimezoneSpec::from_str("").is_err());
+}
+
+#[test]
+fn test_time_namespace_offsets() {
+    assert_eq!(None, TimeNamespaceSpec::from_offsets(None, None));
+
+    let spec = TimeNamespaceSpec::from_offsets(None, Some(86400)).unwrap();
+    assert_eq!("monotonic 0 0\nboottime 86400 0\n", spec.offsets_content());
+}
+
+#[test]
+fn test_security_from_options() {
+    let spec = SecuritySpec::from_options(&["apparmor=cort-default".to_owned(), "label=system_u:system_r:container_t:s0".to_owned()]).unwrap();
+    assert_eq!(Some("cort-default".to_owned()), spec.apparmor_profile);
+    assert_eq!(Some("system_u:system_r:container_t:s0".to_owned()), spec.selinux_label);
+
+    assert_eq!(SecuritySpec::default(), SecuritySpec::from_options(&[]).unwrap());
+    assert!(SecuritySpec::from_options(&["seccomp=unconfined".to_owned()]).is_err());
+    assert!(SecuritySpec::from_options(&["apparmor=".to_owned()]).is_err());
+}
+
+#[test]
+fn test_log_spec_from_options() {
+    let spec = LogSpec::from_options(LogDriverSpec::JsonFile, &["max-size=10m".to_owned(), "max-file=3".to_owned()]).unwrap();
+    assert_eq!(Some(10 * 1024 * 1024), spec.max_size);
+    assert_eq!(Some(3), spec.max_file);
+
+    let spec = LogSpec::from_options(LogDriverSpec::Syslog, &["tag=web".to_owned(), "syslog-address=unix:///run/log".to_owned()]).unwrap();
+    assert_eq!(Some("web".to_owned()), spec.tag);
+    assert_eq!(Some(PathBuf::from("/run/log")), spec.syslog_address);
+
+    assert!(LogSpec::from_options(LogDriverSpec::Journald, &["max-size=10m".to_owned()]).is_err());
+    assert!(LogSpec::from_options(LogDriverSpec::JsonFile, &["max-size=10x".to_owned()]).is_err());
+    assert!(LogSpec::from_options(LogDriverSpec::JsonFile, &["max-file=3".to_owned()]).is_err());
+    assert!(LogSpec::from_options(LogDriverSpec::JsonFile, &["max-size=10m".to_owned(), "max-file=0".to_owned()]).is_err());
+}
+
+#[test]
+fn test_hooks_add_options() {
+    let mut hooks = HooksSpec::default();
+    hooks.add_options(&["prestart=/usr/local/bin/setup".to_owned(), "poststop=/usr/local/bin/teardown".to_owned()]).unwrap();
+    assert_eq!(vec![HookSpec::new(Path::new("/usr/local/bin/setup"))], hooks.prestart);
+    assert!(hooks.poststart.is_empty());
+    assert_eq!(vec![HookSpec::new(Path::new("/usr/local/bin/teardown"))], hooks.poststop);
+
+    assert!(hooks.add_options(&["createRuntime=/bin/true".to_owned()]).is_err());
+    assert!(hooks.add_options(&["prestart=".to_owned()]).is_err());
+}
+
+#[test]
+fn test_ulimit_from_str() {
+    let ulimit = UlimitSpec::from_str("nofile=1024:4096").unwrap();
+    assert_eq!(("nofile", libc::RLIMIT_NOFILE, 1024, 4096), (ulimit.name, ulimit.resource, ulimit.soft, ulimit.hard));
+
+    let ulimit = UlimitSpec::from_str("core=unlimited").unwrap();
+    assert_eq!((libc::RLIM_INFINITY, libc::RLIM_INFINITY), (ulimit.soft, ulimit.hard));
+    assert_eq!(libc::RLIM_INFINITY, UlimitSpec::from_str("memlock=64:-1").unwrap().hard);
+
+    assert!(UlimitSpec::from_str("nofile").is_err());
+    assert!(UlimitSpec::from_str("files=1024").is_err());
+    assert!(UlimitSpec::from_str("nofile=many").is_err());
+    assert!(UlimitSpec::from_str("nofile=4096:1024").is_err());
+}
+
+#[test]
+fn test_secret_from_str() {
+    let secret = SecretSpec::from_str("id=db_password,src=./secret.txt").unwrap();
+    assert_eq!(("db_password", Path::new("./secret.txt")), (secret.id.as_str(), secret.source.as_path()));
+    assert_eq!(Path::new("/run/secrets/db_password"), secret.target());
+    assert_eq!(secret, SecretSpec::from_str(&secret.to_string()).unwrap());
+    assert_eq!(Path::new("/etc/key"), SecretSpec::from_str("source=/etc/key,id=key").unwrap().source);
+
+    assert!(SecretSpec::from_str("id=key").is_err());
+    assert!(SecretSpec::from_str("id=../key,src=/etc/key").is_err());
+    assert!(SecretSpec::from_str("id=key,src=/etc/key,mode=0444").is_err());
+}
+
+#[test]
+fn test_capability_from_str() {
+    let capability = CapabilitySpec::from_str("net_bind_service").unwrap();
+    assert_eq!(("CAP_NET_BIND_SERVICE", 10), (capability.name, capability.number));
+    assert_eq!(capability, CapabilitySpec::from_str("CAP_NET_BIND_SERVICE").unwrap());
+    assert_eq!(40, CapabilitySpec::from_str("checkpoint_restore").unwrap().number);
+    assert!(CapabilitySpec::from_str("net_bind").is_err());
+}
+
+#[test]
+fn test_parse_labels() {
+    let labels = parse_labels(&["env=prod".to_owned(), "url=http://host/?a=b".to_owned(), "debug".to_owned()]).unwrap();
+    assert_eq!(Some("prod"), labels.get("env").map(|value| value.as_str()));
+    assert_eq!(Some("http://host/?a=b"), labels.get("url").map(|value| value.as_str()));
+    assert_eq!(Some(""), labels.get("debug").map(|value| value.as_str()));
+    assert!(parse_labels(&["=prod".to_owned()]).is_err());
+}
+
+#[test]
+fn test_gpu_spec_from_str() {
+    assert_eq!(GpuSpec::All, GpuSpec::from_str("all").unwrap());
+    assert_eq!(GpuSpec::Devices(vec![0, 2]), GpuSpec::from_str("0, 2,0").unwrap());
+    assert!(GpuSpec::from_str("").is_err());
+    assert!(GpuSpec::from_str("gpu0").is_err());
+}
+
+#[test]
+fn test_scheduling_spec() {
+    assert_eq!(Some(-500), SchedulingSpec::new(Some(-500), Some(10), Some(50)).unwrap().oom_score_adj);
+    assert!(SchedulingSpec::new(Some(1001), None, None).is_err());
+    assert!(SchedulingSpec::new(None, Some(-21), None).is_err());
+    assert!(SchedulingSpec::new(None, None, Some(0)).is_err());
+}
+
+#[test]
+fn test_spec_with_file_content() {
+    let runtime = crate::ContainerRuntime::new(crate::ContainerRuntimeConfig::from_base_dir(Path::new("/tmp/cort-test")));
+    let spec = runtime.create_run_spec("alpine", vec!["sh".to_owned()], NetworkSpec::Host);
+    let id = spec.id.clone();
+
+    let spec = spec.clone().with_content(r#"
+image: ubuntu
+command: [/bin/echo, hello]
+memory: 268435456
+bind_mounts:
+  - source: /data
+    target: /data
+    propagation: slave
+ulimits: [nofile=1024:2048]
+gpus: 0,1
+dns: copy_from_host
+"#).unwrap();
+    assert_eq!(id, spec.id);
+    assert_eq!("ubuntu", spec.image);
+    assert_eq!(vec!["/bin/echo", "hello"], spec.command);
+    assert_eq!(Some(268435456), spec.memory);
+    assert_eq!(MountPropagation::Slave, spec.bind_mounts[0].propagation);
+    assert_eq!(UlimitSpec::from_str("nofile=1024:2048").unwrap(), spec.ulimits[0]);
+    assert_eq!(Some(GpuSpec::Devices(vec![0, 1])), spec.gpus);
+    assert!(spec.network.is_host());
+
+    assert!(spec.clone().with_content("memroy: 1024").is_err());
+    assert!(spec.clone().with_content("image: ''").is_err());
+    assert_eq!("/srv/rootfs", spec.with_content("image: ''\nrootfs: /srv/rootfs").unwrap().image);
+}
+
+#[test]
+fn test_user_spec_from_str() {
+    assert_eq!(UserSpec::Name("nobody".to_owned()), UserSpec::from_str("nobody").unwrap());
+    assert_eq!(UserSpec::Id(1000), UserSpec::from_str("1000").unwrap());
+    assert_eq!(UserSpec::IdAndGroupId(1000, 100), UserSpec::from_str("1000:100").unwrap());
+    assert_eq!(UserSpec::WithGroup("nobody".to_owned(), "100".to_owned()), UserSpec::from_str("nobody:100").unwrap());
+    assert_eq!(UserSpec::WithGroup("1000".to_owned(), "staff".to_owned()), UserSpec::from_str("1000:staff").unwrap());
+    assert!(UserSpec::from_str("nobody:").is_err());
+    assert!(UserSpec::from_str(":staff").is_err());
+    assert!(UserSpec::from_str("").is_err());
+}
+
+#[test]
+fn test_user_spec_find_user() {
+    let user = |username: &str, id: i32, group_id: i32| User { username: username.to_owned(), id, group_id: Some(group_id), home_folder: Path::new("/home").join(username), shell: User::default_shell() };
+    let users = HashMap::from([(0, user("root", 0, 0)), (33, user("www-data", 33, 33)), (1000, user("alice", 1000, 1000))]);
+    let groups = HashMap::from([("root".to_owned(), 0), ("staff".to_owned(), 50)]);
+    let find_user = |text: &str| UserSpec::from_str(text).unwrap().find_user(&users, &groups);
+
+    assert_eq!(33, find_user("www-data").unwrap().id);
+    let alice = find_user("alice:staff").unwrap();
+    assert_eq!((1000, Some(50), Path::new("/home/alice")), (alice.id, alice.group_id, alice.home_folder.as_path()));
+    assert_eq!(Some(100), find_user("1000:100").unwrap().group_id);
+    assert_eq!("unknown", find_user("2000").unwrap().username);
+
+    assert_eq!(
+        "Invalid user: no user named 'bob' in the image (its users are: root, www-data, alice)",
+        find_user("bob").unwrap_err().to_string()
+    );
+    assert_eq!(
+        "Invalid user: no group named 'wheel' in the image (its groups are: root, staff)",
+        find_user("alice:wheel").unwrap_err().to_string()
+    );
+}
+
+#[test]
+fn test_spec_serde_roundtrip() {
+    let runtime = crate::ContainerRuntime::new(crate::ContainerRuntimeConfig::from_base_dir(Path::new("/tmp/cort-test")));
+    let mut spec = runtime.create_run_spec("alpine", vec!["sh".to_owned()], NetworkSpec::Host);
+    spec.user = Some(UserSpec::IdAndGroupId(1000, 100));
+    spec.timezone = Some(TimezoneSpec::Host);
+    spec.gpus = Some(GpuSpec::All);
+    spec.ulimits = vec![UlimitSpec::from_str("core=unlimited").unwrap()];
+    spec.network = NetworkSpec::Bridged(
+        BridgedNetworkSpec {
+            bridge_interface: "cort0".to_owned(),
+            bridge_ip_address: Ipv4Net::from_str("10.10.1.1/16").unwrap(),
+            container_ip_address: Some(Ipv4Net::from_str("10.10.1.2/16").unwrap()),
+            hairpin: false,
+            hostname: None,
+            ingress_limit: Some(BandwidthSpec(10_000_000)),
+            egress_limit: None
+        }
+    );
+
+    let json = serde_json::to_value(&spec).unwrap();
+    assert_eq!("1000:100", json["user"]);
+    assert_eq!("10.10.1.2/16", json["network"]["bridged"]["container_ip_address"]);
+    assert_eq!("core=unlimited:unlimited", json["ulimits"][0]);
+
+    let roundtrip: RunContainerSpec = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(json, serde_json::to_value(&roundtrip).unwrap());
+    assert_eq!(Some(UserSpec::IdAndGroupId(1000, 100)), roundtrip.user);
+}
+
+#[test]
+fn test_spec_validate() {
+    let runtime = crate::ContainerRuntime::new(crate::ContainerRuntimeConfig::from_base_dir(Path::new("/tmp/cort-test")));
+    let mut spec = runtime.create_run_spec("alpine", vec!["sh".to_owned()], NetworkSpec::Host);
+    spec.rootfs = Some(std::env::temp_dir());
+    assert!(spec.validate().is_ok());
+
+    spec.name = "-web".to_owned();
+    spec.command = Vec::new();
+    spec.memory = Some(1024);
+    spec.memory_swap = Some(512);
+    spec.bind_mounts = BindMountSpec::from_paths(vec![PathBuf::from("/does/not/exist"), PathBuf::from("/data")]).unwrap();
+    spec.network = NetworkSpec::Bridged(
+        BridgedNetworkSpec {
+            bridge_interface: "cort0".to_owned(),
+            bridge_ip_address: Ipv4Net::from_str("10.10.1.1/16").unwrap(),
+            container_ip_address: Some(Ipv4Net::from_str("10.11.0.2/16").unwrap()),
+            hairpin: false,
+            hostname: None,
+            ingress_limit: None,
+            egress_limit: None
+        }
+    );
+
+    match spec.validate() {
+        Err(ContainerRuntimeError::InvalidSpec(violations)) => assert_eq!(5, violations.len(), "{:?}", violations),
+        result => panic!("Expected an invalid spec but got {:?}", result)
+    }
+
+    spec.rootfs = None;
+    spec.image = "does-not-exist".to_owned();
+    assert!(matches!(spec.validate(), Err(ContainerRuntimeError::InvalidSpec(violations)) if violations.len() == 6));
+}
+
+#[test]
+fn test_validate_log() {
+    let runtime = crate::ContainerRuntime::new(crate::ContainerRuntimeConfig::from_base_dir(Path::new("/tmp/cort-test")));
+    let mut spec = runtime.create_run_spec("alpine", vec!["sh".to_owned()], NetworkSpec::Host);
+    spec.rootfs = Some(std::env::temp_dir());
+
+    // As given by --spec, which does not go through the checks of the options
+    spec.log = serde_yaml::from_str("driver: json-file\nmax_file: 3").unwrap();
+    assert!(matches!(spec.validate(), Err(ContainerRuntimeError::InvalidSpec(violations)) if violations.len() == 1));
+
+    spec.log = serde_yaml::from_str("driver: json-file\nmax_size: 1024\nmax_file: 0").unwrap();
+    assert!(matches!(spec.validate(), Err(ContainerRuntimeError::InvalidSpec(violations)) if violations.len() == 1));
+
+    spec.log.max_file = Some(1);
+    assert!(spec.validate().is_ok());
+}
+
+#[test]
+fn test_validate_sysctl() {
+    let bridged = NetworkSpec::Bridged(
+        BridgedNetworkSpec {
+            bridge_interface: "cort0".to_owned(),
+            bridge_ip_address: Ipv4Net::from_str("10.10.1.1/16").unwrap(),
+            container_ip_address: None,
+            hairpin: false,
+            hostname: None,
+            ingress_limit: None,
+            egress_limit: None
+        }
+    );
+
+    assert!(validate_sysctl("net.ipv4.ip_unprivileged_port_start", &bridged, NamespaceMode::Private).is_ok());
+    assert!(validate_sysctl("net.ipv4.ip_unprivileged_port_start", &NetworkSpec::Host, NamespaceMode::Private).is_err());
+    assert!(validate_sysctl("kernel.shmmax", &NetworkSpec::Host, NamespaceMode::Private).is_ok());
+    assert!(validate_sysctl("fs.mqueue.msg_max", &NetworkSpec::Host, NamespaceMode::Host).is_err());
+    assert!(validate_sysctl("kernel.pid_max", &bridged, NamespaceMode::Private).is_err());
+    assert!(validate_sysctl("net.ipv4/../../kernel.pid_max", &bridged, NamespaceMode::Private).is_err());
+
+    let pod = NetworkSpec::Pod(PodNetworkSpec { pod: "web".to_owned(), pid: 1, hostname: "web".to_owned(), ip_address: None });
+    assert!(validate_sysctl("net.ipv4.ip_unprivileged_port_start", &pod, NamespaceMode::Private).is_err());
+    assert!(validate_sysctl("kernel.shmmax", &pod, NamespaceMode::Private).is_err());
+
+    assert_eq!(
+        BTreeMap::from([("net.ipv4.ping_group_range".to_owned(), "0 2147483647".to_owned())]),
+        parse_sysctls(&["net.ipv4.ping_group_range=0 2147483647".to_owned()]).unwrap()
+    );
+    assert!(parse_sysctls(&["net.ipv4.ip_forward".to_owned()]).is_err());
+}
+
+#[test]
+fn test_restart_policy_from_str() {
+    assert_eq!(RestartPolicy::No, RestartPolicy::from_str("no").unwrap());
+    assert_eq!(RestartPolicy::OnFailure(None), RestartPolicy::from_str("on-failure").unwrap());
+    assert_eq!(RestartPolicy::OnFailure(Some(5)), RestartPolicy::from_str("on-failure:5").unwrap());
+    assert_eq!(RestartPolicy::UnlessStopped, RestartPolicy::from_str("unless-stopped").unwrap());
+    assert!(RestartPolicy::from_str("on-failure:many").is_err());
+    assert!(RestartPolicy::from_str("always:5").is_err());
+}
+
+#[test]
+fn test_listen_fds_from_variables() {
+    let listen_fds = ListenFdsSpec::from_variables(Some("1234"), Some("2"), Some("http:https".to_owned()), 1234).unwrap();
+    assert_eq!(3..5, listen_fds.fds());
+    assert_eq!(Some("http:https"), listen_fds.names.as_deref());
+
+    // Inherited from a parent that was socket activated
+    assert_eq!(None, ListenFdsSpec::from_variables(Some("1"), Some("2"), None, 1234));
+    assert_eq!(None, ListenFdsSpec::from_variables(Some("1234"), Some("0"), None, 1234));
+    assert_eq!(None, ListenFdsSpec::from_variables(None, None, None, 1234));
+}
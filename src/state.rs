@@ -0,0 +1,671 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::cni::CniResult;
+use crate::dhcp::DhcpLease;
+use crate::linux::{is_process_alive, lock_file, pidfd_open, process_start_time};
+use crate::model::{ContainerExitStatus, ContainerRuntimeError, ContainerRuntimeResult};
+use crate::network::{Ipv4Net, NetworkAttachment};
+use crate::spec::{serde_as_text, LogDriverSpec, NetworkSpec, RunContainerSpec};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContainerState {
+    pub id: String,
+    pub name: String,
+    pub image: String,
+    pub command: Vec<String>,
+    pub pid: i32,
+    /// The start time of the process of the container (in clock ticks since boot), which tells it apart from a later process with the same PID
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub start_time: Option<u64>,
+    /// The PID of the runtime of the container, which records its exit and cleans up after it
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub runtime_pid: Option<i32>,
+    /// The digests of the image layers that the root of the container was created from, top-most first (none with --rootfs)
+    #[serde(default, skip_serializing_if="Vec::is_empty")]
+    pub layers: Vec<String>,
+    #[serde(default, skip_serializing_if="BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// The hostname of the container, unless it uses the network of the host
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub ip_address: Option<Ipv4Net>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub dhcp_lease: Option<DhcpLease>,
+    #[serde(default, skip_serializing_if="Vec::is_empty")]
+    pub networks: Vec<NetworkAttachment>,
+    /// The result of adding the container to its CNI network, which the plugins are given again when it is removed
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub cni_result: Option<CniResult>,
+    #[serde(default)]
+    pub log_driver: LogDriverSpec,
+    /// The resource limits, which are changed by updating the container while it runs
+    #[serde(default)]
+    pub limits: ResourceLimits,
+    /// Set while the container has been created but not started
+    #[serde(default, skip_serializing_if="std::ops::Not::not")]
+    pub created: bool,
+    /// Set when the container was adopted by another runtime after its own had exited, which cannot know its exit status
+    #[serde(default, skip_serializing_if="std::ops::Not::not")]
+    pub adopted: bool,
+    /// Set when the container has exited, which is -1 when its exit status is not known
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub exit_code: Option<i32>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub exit_status: Option<ContainerExitStatus>,
+    /// The resources used by the container, recorded when it exits
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub usage: Option<ResourceUsage>,
+    /// How long each phase of starting the container took, recorded when it executes its command
+    #[serde(default, skip_serializing_if="Vec::is_empty")]
+    pub startup_timings: Vec<StartupTiming>
+}
+
+/// The time that a phase of starting a container (such as setting up its cgroups) took.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StartupTiming {
+    pub phase: String,
+    pub milliseconds: f64
+}
+
+/// Resource usage of a container over its lifetime, as accounted by its cgroups.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    /// The total CPU time in seconds
+    pub cpu_seconds: f64,
+    /// The peak memory usage in bytes
+    pub memory_peak: u64,
+    pub io_read_bytes: u64,
+    pub io_write_bytes: u64
+}
+
+/// The limits of the resources of a container that can be changed while it runs, where unset limits are not changed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResourceLimits {
+    pub cpu_shares: Option<i64>,
+    /// The number of CPUs worth of time the container can use, such as 1.5
+    pub cpus: Option<f64>,
+    pub memory: Option<i64>,
+    pub memory_swap: Option<i64>,
+    pub memory_reservation: Option<i64>,
+    pub memory_swappiness: Option<u8>
+}
+
+impl ResourceLimits {
+    pub fn from_spec(spec: &RunContainerSpec) -> ResourceLimits {
+        ResourceLimits {
+            cpu_shares: spec.cpu_shares,
+            cpus: spec.cpus,
+            memory: spec.memory,
+            memory_swap: spec.memory_swap,
+            memory_reservation: spec.memory_reservation,
+            memory_swappiness: spec.memory_swappiness
+        }
+    }
+
+    /// These limits with the ones set in the update replacing them.
+    pub fn updated(&self, update: &ResourceLimits) -> ResourceLimits {
+        ResourceLimits {
+            cpu_shares: update.cpu_shares.or(self.cpu_shares),
+            cpus: update.cpus.or(self.cpus),
+            memory: update.memory.or(self.memory),
+            memory_swap: update.memory_swap.or(self.memory_swap),
+            memory_reservation: update.memory_reservation.or(self.memory_reservation),
+            memory_swappiness: update.memory_swappiness.or(self.memory_swappiness)
+        }
+    }
+
+    pub fn violations(&self) -> Vec<String> {
+        let mut violations = Vec::new();
+
+        if matches!(self.cpus, Some(cpus) if cpus.is_nan() || cpus < 0.01) {
+            violations.push("The number of CPUs must be at least 0.01".to_owned());
+        }
+
+        // The swap limit is of the memory and swap combined, where -1 is unlimited
+        if let (Some(memory), Some(memory_swap)) = (self.memory, self.memory_swap) {
+            if memory_swap != -1 && memory_swap < memory {
+                violations.push(format!("Memory and swap limit ({}) must be at least the memory limit ({})", memory_swap, memory));
+            }
+        }
+
+        if let (Some(memory), Some(memory_reservation)) = (self.memory, self.memory_reservation) {
+            if memory_reservation > memory {
+                violations.push(format!("Memory reservation ({}) must not exceed the memory limit ({})", memory_reservation, memory));
+            }
+        }
+
+        if matches!(self.memory_swappiness, Some(swappiness) if swappiness > 100) {
+            violations.push("Memory swappiness must be between 0 and 100".to_owned());
+        }
+
+        violations
+    }
+}
+
+impl ContainerState {
+    pub fn new(spec: &RunContainerSpec, pid: i32) -> ContainerState {
+        ContainerState {
+            id: spec.id.clone(),
+            name: spec.name.clone(),
+            image: spec.image.clone(),
+            command: spec.command.clone(),
+            pid,
+            start_time: process_start_time(pid).ok(),
+            runtime_pid: Some(std::process::id() as i32),
+            layers: Vec::new(),
+            labels: spec.labels.clone(),
+            hostname: spec.hostname(),
+            ip_address: match &spec.network {
+                NetworkSpec::Host | NetworkSpec::Cni(_) => None,
+                NetworkSpec::Bridged(bridged) => bridged.container_ip_address,
+                NetworkSpec::Pod(pod) => pod.ip_address
+            },
+            dhcp_lease: None,
+            networks: Vec::new(),
+            cni_result: None,
+            log_driver: spec.log.driver,
+            limits: ResourceLimits::from_spec(spec),
+            created: false,
+            adopted: false,
+            exit_code: None,
+            exit_status: None,
+            usage: None,
+            startup_timings: Vec::new()
+        }
+    }
+
+    pub fn has_exited(&self) -> bool {
+        self.exit_code.is_some()
+    }
+
+    /// If the container has not exited, and its process is still the one that was started (rather than a later process with the same PID).
+    pub fn is_running(&self) -> bool {
+        !self.has_exited() && is_process_alive(self.pid) && is_same_process(self.pid, self.start_time)
+    }
+
+    /// Opens a pidfd of the process of the running container (see `linux::pidfd_open`), through which it can be waited for and signaled
+    /// without reaching another process should it exit meanwhile.
+    pub fn open_process(&self) -> ContainerRuntimeResult<File> {
+        let not_running = || ContainerRuntimeError::ContainerNotFound(format!("{} (has exited)", self.name));
+        if self.has_exited() {
+            return Err(not_running());
+        }
+
+        let process = pidfd_open(self.pid).map_err(|_| not_running())?;
+        // Checked once opened, as the pidfd then refers to the process also if it exits and its PID is reused
+        if !is_same_process(self.pid, self.start_time) {
+            return Err(not_running());
+        }
+
+        Ok(process)
+    }
+
+    /// If the runtime of the running container has exited, such as when it was killed or crashed, where the container can be adopted by another.
+    pub fn is_orphaned(&self) -> bool {
+        !self.has_exited() && self.runtime_pid.map(|runtime_pid| !is_process_alive(runtime_pid)).unwrap_or(false)
+    }
+
+    pub fn load(container_root: &Path) -> ContainerRuntimeResult<ContainerState> {
+        let content = std::fs::read_to_string(state_path(container_root))?;
+        serde_json::from_str(&content).map_err(|err| ContainerRuntimeError::State(err.to_string()))
+    }
+
+    pub fn save(&self, container_root: &Path) -> ContainerRuntimeResult<()> {
+        let content = serde_json::to_string_pretty(self).map_err(|err| ContainerRuntimeError::State(err.to_string()))?;
+        std::fs::write(state_path(container_root), content)?;
+        Ok(())
+    }
+
+    pub fn load_all(containers_base_dir: &Path) -> ContainerRuntimeResult<Vec<ContainerState>> {
+        let mut states = Vec::new();
+        if !containers_base_dir.exists() {
+            return Ok(states);
+        }
+
+        for entry in std::fs::read_dir(containers_base_dir)? {
+            let entry = entry?;
+            if state_path(&entry.path()).exists() {
+                states.push(ContainerState::load(&entry.path())?);
+            }
+        }
+
+        states.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(states)
+    }
+}
+
+fn state_path(container_root: &Path) -> PathBuf {
+    container_root.join("state.json")
+}
+
+fn is_same_process(pid: i32, start_time: Option<u64>) -> bool {
+    match start_time {
+        Some(start_time) => process_start_time(pid).map(|process_start_time| process_start_time == start_time).unwrap_or(false),
+        // Started before the start time was kept
+        None => true
+    }
+}
+
+/// The number of exited exec sessions that are kept for each container, the oldest are removed when a new session starts.
+const MAX_EXITED_EXEC_SESSIONS: usize = 16;
+
+/// A command executed in a running container by `cort exec`, kept in the container root (as `execs/<id>.json`) until the container exits.
+/// Each session has its own file, as it is written by the process executing it rather than the runtime of the container.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExecSession {
+    pub id: String,
+    /// The PID of the command, as seen from the host
+    pub pid: i32,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub start_time: Option<u64>,
+    pub command: Vec<String>,
+    /// The terminal of the session (such as /dev/pts/2), when it was started from one
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub tty: Option<String>,
+    /// When the session started (RFC 3339)
+    pub started: String,
+    /// Set when the command has exited, which is -1 when the session was ended before its exit status was recorded
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub exit_code: Option<i32>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub exit_status: Option<ContainerExitStatus>
+}
+
+impl ExecSession {
+    pub fn new(id: &str, command: &[String], pid: i32, tty: Option<String>) -> ExecSession {
+        ExecSession {
+            id: id.to_owned(),
+            pid,
+            start_time: process_start_time(pid).ok(),
+            command: command.to_vec(),
+            tty,
+            started: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
+            exit_code: None,
+            exit_status: None
+        }
+    }
+
+    pub fn has_exited(&self) -> bool {
+        self.exit_code.is_some()
+    }
+
+    /// If the command has not exited, and its process is still the one that was started.
+    pub fn is_running(&self) -> bool {
+        !self.has_exited() && is_process_alive(self.pid) && is_same_process(self.pid, self.start_time)
+    }
+
+    /// If the session ended without its exit being recorded, such as when `cort exec` was killed.
+    pub fn is_lost(&self) -> bool {
+        !self.has_exited() && !self.is_running()
+    }
+
+    pub fn exited(&mut self, exit_status: ContainerExitStatus) {
+        self.exit_code = Some(exit_status.exit_code());
+        self.exit_status = Some(exit_status);
+    }
+
+    /// Saves the session, which fails once the container has exited, as its root (besides the state and logs) has then been removed.
+    pub fn save(&self, container_root: &Path) -> ContainerRuntimeResult<()> {
+        if !container_root.exists() {
+            return Err(ContainerRuntimeError::ContainerNotFound(container_root.to_str().unwrap().to_owned()));
+        }
+
+        let execs_dir = execs_dir(container_root);
+        std::fs::create_dir_all(&execs_dir)?;
+        let content = serde_json::to_string_pretty(self).map_err(|err| ContainerRuntimeError::State(err.to_string()))?;
+        std::fs::write(execs_dir.join(format!("{}.json", self.id)), content)?;
+        Ok(())
+    }
+
+    /// The sessions of the container, oldest first.
+    pub fn load_all(container_root: &Path) -> ContainerRuntimeResult<Vec<ExecSession>> {
+        let mut sessions = Vec::new();
+        let execs_dir = execs_dir(container_root);
+        if !execs_dir.exists() {
+            return Ok(sessions);
+        }
+
+        for entry in std::fs::read_dir(&execs_dir)? {
+            let path = entry?.path();
+            // Skips sessions that are being written or removed meanwhile
+            let session = std::fs::read_to_string(&path).ok().and_then(|content| serde_json::from_str::<ExecSession>(&content).ok());
+            if let Some(session) = session {
+                sessions.push(session);
+            }
+        }
+
+        sessions.sort_by(|a, b| a.started.cmp(&b.started).then_with(|| a.id.cmp(&b.id)));
+        Ok(sessions)
+    }
+
+    /// Removes the oldest of the exited (or lost) sessions, keeping at most `MAX_EXITED_EXEC_SESSIONS`.
+    pub fn prune(container_root: &Path) -> ContainerRuntimeResult<()> {
+        let mut ended = ExecSession::load_all(container_root)?
+            .into_iter()
+            .filter(|session| !session.is_running())
+            .collect::<Vec<_>>();
+
+        let remove_count = ended.len().saturating_sub(MAX_EXITED_EXEC_SESSIONS);
+        for session in ended.drain(..remove_count) {
+            std::fs::remove_file(execs_dir(container_root).join(format!("{}.json", session.id))).ok();
+        }
+
+        Ok(())
+    }
+}
+
+fn execs_dir(container_root: &Path) -> PathBuf {
+    container_root.join("execs")
+}
+
+/// Selects containers by their labels, name or id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContainerFilter {
+    /// Containers with the label, and the value when given
+    Label { key: String, value: Option<String> },
+    Name(String),
+    Id(String)
+}
+
+impl ContainerFilter {
+    pub fn matches(&self, state: &ContainerState) -> bool {
+        match self {
+            ContainerFilter::Label { key, value } => {
+                match (state.labels.get(key), value) {
+                    (Some(label_value), Some(value)) => label_value == value,
+                    (Some(_), None) => true,
+                    (None, _) => false
+                }
+            }
+            ContainerFilter::Name(name) => &state.name == name,
+            ContainerFilter::Id(id) => state.id.starts_with(id.as_str())
+        }
+    }
+
+    /// A container is selected only if it matches all filters.
+    pub fn matches_all(filters: &[ContainerFilter], state: &ContainerState) -> bool {
+        filters.iter().all(|filter| filter.matches(state))
+    }
+}
+
+impl FromStr for ContainerFilter {
+    type Err = String;
+
+    /// Parses filters of the form `label=<key>[=<value>]`, `name=<name>` or `id=<id prefix>`.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.split_once('=') {
+            Some(("label", label)) if !label.is_empty() => {
+                let (key, value) = match label.split_once('=') {
+                    Some((key, value)) => (key, Some(value.to_owned())),
+                    None => (label, None)
+                };
+
+                Ok(ContainerFilter::Label { key: key.to_owned(), value })
+            }
+            Some(("name", name)) if !name.is_empty() => Ok(ContainerFilter::Name(name.to_owned())),
+            Some(("id", id)) if !id.is_empty() => Ok(ContainerFilter::Id(id.to_owned())),
+            _ => Err(format!("Invalid filter '{}' (expected label=<key>[=<value>], name=<name> or id=<id>).", text))
+        }
+    }
+}
+
+impl Display for ContainerFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContainerFilter::Label { key, value: Some(value) } => write!(f, "label={}={}", key, value),
+            ContainerFilter::Label { key, value: None } => write!(f, "label={}", key),
+            ContainerFilter::Name(name) => write!(f, "name={}", name),
+            ContainerFilter::Id(id) => write!(f, "id={}", id)
+        }
+    }
+}
+
+serde_as_text!(ContainerFilter);
+
+/// Keeps container names unique by storing a file per name, holding the id of the container and the PID of the process running it.
+/// Names whose process is gone (such as after a crash) are free to be reused.
+pub struct NameRegistry {
+    dir: PathBuf
+}
+
+impl NameRegistry {
+    pub fn new(dir: PathBuf) -> NameRegistry {
+        NameRegistry {
+            dir
+        }
+    }
+
+    /// Reserves the name for a container run by the current process.
+    pub fn reserve(&self, name: &str, container_id: &str) -> ContainerRuntimeResult<()> {
+        let _lock = self.lock()?;
+        self.reserve_locked(name, container_id, std::process::id() as i32)
+    }
+
+    /// Moves the reservation of the container to a new name.
+    pub fn rename(&self, container_id: &str, new_name: &str) -> ContainerRuntimeResult<()> {
+        let _lock = self.lock()?;
+        let (current_name, owner_pid) = self.reservations()?
+            .into_iter()
+            .find(|(_, (id, _))| id == container_id)
+            .map(|(name, (_, owner_pid))| (name, owner_pid))
+            .ok_or_else(|| ContainerRuntimeError::ContainerNotFound(container_id.to_owned()))?;
+
+        self.reserve_locked(new_name, container_id, owner_pid)?;
+        if current_name != new_name {
+            std::fs::remove_file(self.dir.join(current_name))?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves the reservations of the container to another process, such as the supervisor of a created container.
+    pub fn transfer(&self, container_id: &str, owner_pid: i32) -> ContainerRuntimeResult<()> {
+        let _lock = self.lock()?;
+        for (name, (id, _)) in self.reservations()? {
+            if id == container_id {
+                self.reserve_locked(&name, container_id, owner_pid)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Releases the names reserved by the container.
+    pub fn release(&self, container_id: &str) -> ContainerRuntimeResult<()> {
+        let _lock = self.lock()?;
+        for (name, (id, _)) in self.reservations()? {
+            if id == container_id {
+                std::fs::remove_file(self.dir.join(name))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn is_reserved(&self, name: &str) -> bool {
+        self.owner(name).map(|(_, owner_pid)| is_process_alive(owner_pid)).unwrap_or(false)
+    }
+
+    fn reserve_locked(&self, name: &str, container_id: &str, owner_pid: i32) -> ContainerRuntimeResult<()> {
+        if let Some((id, existing_owner_pid)) = self.owner(name) {
+            if id != container_id && is_process_alive(existing_owner_pid) {
+                return Err(ContainerRuntimeError::ContainerAlreadyExists(name.to_owned()));
+            }
+        }
+
+        std::fs::write(self.dir.join(name), format!("{}\n{}\n", container_id, owner_pid))?;
+        Ok(())
+    }
+
+    fn owner(&self, name: &str) -> Option<(String, i32)> {
+        let content = std::fs::read_to_string(self.dir.join(name)).ok()?;
+        let mut lines = content.lines();
+        let container_id = lines.next()?.to_owned();
+        let owner_pid = lines.next()?.parse().ok()?;
+        Some((container_id, owner_pid))
+    }
+
+    fn reservations(&self) -> ContainerRuntimeResult<Vec<(String, (String, i32))>> {
+        let mut reservations = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let name = entry?.file_name().to_str().unwrap().to_owned();
+            if let Some(owner) = self.owner(&name) {
+                reservations.push((name, owner));
+            }
+        }
+
+        Ok(reservations)
+    }
+
+    /// Serializes changes between processes, the lock is released when the file is closed.
+    fn lock(&self) -> ContainerRuntimeResult<File> {
+        lock_file(&self.dir.join(".lock"))
+    }
+}
+
+#[test]
+fn test_container_filter() {
+    let state = ContainerState {
+        id: "0f4e2b9c".to_owned(),
+        name: "web".to_owned(),
+        image: "nginx".to_owned(),
+        command: vec!["nginx".to_owned()],
+        pid: 1,
+        start_time: None,
+        runtime_pid: None,
+        layers: Vec::new(),
+        labels: BTreeMap::from([("env".to_owned(), "prod".to_owned())]),
+        hostname: None,
+        ip_address: None,
+        dhcp_lease: None,
+        networks: Vec::new(),
+        cni_result: None,
+        log_driver: LogDriverSpec::default(),
+        limits: ResourceLimits::default(),
+        created: false,
+        adopted: false,
+        exit_code: None,
+        exit_status: None,
+        usage: None,
+        startup_timings: Vec::new()
+    };
+
+    let filters = ["label=env=prod", "label=env", "name=web", "id=0f4e"].map(|filter| ContainerFilter::from_str(filter).unwrap());
+    assert!(ContainerFilter::matches_all(&filters, &state));
+    assert_eq!("[\"label=env=prod\",\"label=env\",\"name=web\",\"id=0f4e\"]", serde_json::to_string(&filters).unwrap());
+    assert!(!ContainerFilter::from_str("label=env=dev").unwrap().matches(&state));
+    assert!(!ContainerFilter::from_str("label=tier").unwrap().matches(&state));
+    assert!(ContainerFilter::from_str("label=").is_err());
+    assert!(ContainerFilter::from_str("status=running").is_err());
+}
+
+#[test]
+fn test_container_process() {
+    let runtime = crate::ContainerRuntime::new(crate::ContainerRuntimeConfig::from_base_dir(Path::new("/tmp/cort-test")));
+    let spec = runtime.create_run_spec("alpine", vec!["sh".to_owned()], NetworkSpec::Host);
+
+    // Run by this process
+    let mut state = ContainerState::new(&spec, std::process::id() as i32);
+    assert!(!state.is_orphaned());
+    assert!(state.is_running());
+    assert!(state.open_process().is_ok());
+    state.start_time = state.start_time.map(|start_time| start_time + 1);
+    assert!(!state.is_running());
+    assert!(state.open_process().is_err());
+
+    // No such process, as PIDs are far below the maximum
+    state.runtime_pid = Some(i32::MAX);
+    assert!(state.is_orphaned());
+    state.exit_code = Some(0);
+    assert!(!state.is_orphaned());
+
+    // Run before the runtime was kept
+    let state: ContainerState = serde_json::from_str(r#"{"id": "a", "name": "a", "image": "alpine", "command": ["sh"], "pid": 1}"#).unwrap();
+    assert!(!state.is_orphaned());
+}
+
+#[test]
+fn test_resource_limits_updated() {
+    let limits = ResourceLimits { cpu_shares: Some(256), memory: Some(1 << 30), memory_swap: Some(2 << 30), ..ResourceLimits::default() };
+    let updated = limits.updated(&ResourceLimits { cpus: Some(2.0), memory: Some(2 << 30), ..ResourceLimits::default() });
+    assert_eq!(ResourceLimits { cpu_shares: Some(256), cpus: Some(2.0), memory: Some(2 << 30), memory_swap: Some(2 << 30), ..ResourceLimits::default() }, updated);
+    assert!(updated.violations().is_empty());
+
+    // Raising the memory limit above the swap limit is refused
+    assert_eq!(1, limits.updated(&ResourceLimits { memory: Some(3 << 30), ..ResourceLimits::default() }).violations().len());
+    assert_eq!(1, limits.updated(&ResourceLimits { cpus: Some(0.0), ..ResourceLimits::default() }).violations().len());
+}
+
+#[test]
+fn test_name_registry() {
+    let dir = std::env::temp_dir().join(format!("cort-names-{}", uuid::Uuid::new_v4()));
+    let _remove_dir = crate::helpers::RemoveDirGuard::new(dir.clone());
+    let registry = NameRegistry::new(dir.clone());
+
+    registry.reserve("web", "1").unwrap();
+    assert!(registry.is_reserved("web"));
+    assert!(registry.reserve("web", "2").is_err());
+
+    registry.transfer("1", i32::MAX).unwrap();
+    assert!(!registry.is_reserved("web"));
+    registry.transfer("1", std::process::id() as i32).unwrap();
+
+    registry.rename("1", "frontend").unwrap();
+    assert!(!registry.is_reserved("web"));
+    assert!(registry.is_reserved("frontend"));
+
+    // Reservations of processes that no longer exist are reused
+    std::fs::write(dir.join("db"), format!("3\n{}\n", i32::MAX)).unwrap();
+    registry.reserve("db", "4").unwrap();
+
+    registry.release("1").unwrap();
+    registry.release("4").unwrap();
+    assert!(!registry.is_reserved("frontend"));
+    assert!(!registry.is_reserved("db"));
+}
+
+#[test]
+fn test_exec_sessions() {
+    let container_root = std::env::temp_dir().join(format!("cort-execs-{}", uuid::Uuid::new_v4()));
+    let _remove_dir = crate::helpers::RemoveDirGuard::new(container_root.clone());
+    let command = vec!["sh".to_owned()];
+
+    // Not recorded once the container root has been removed
+    assert!(ExecSession::new("0", &command, std::process::id() as i32, None).save(&container_root).is_err());
+    std::fs::create_dir_all(&container_root).unwrap();
+
+    let mut running = ExecSession::new("running", &command, std::process::id() as i32, Some("/dev/pts/1".to_owned()));
+    running.save(&container_root).unwrap();
+    assert!(running.is_running());
+    ExecSession::new("lost", &command, i32::MAX, None).save(&container_root).unwrap();
+    for index in 0..MAX_EXITED_EXEC_SESSIONS {
+        let mut session = ExecSession::new(&format!("exited-{:02}", index), &command, std::process::id() as i32, None);
+        session.exited(ContainerExitStatus::Exited { code: 1 });
+        session.save(&container_root).unwrap();
+    }
+
+    let sessions = ExecSession::load_all(&container_root).unwrap();
+    assert_eq!(MAX_EXITED_EXEC_SESSIONS + 2, sessions.len());
+    assert_eq!(vec!["running", "lost"], sessions[..2].iter().map(|session| session.id.as_str()).collect::<Vec<_>>());
+    assert!(sessions[1].is_lost());
+    assert_eq!(Some(1), sessions[2].exit_code);
+
+    // The oldest of the sessions that have ended are removed
+    ExecSession::prune(&container_root).unwrap();
+    let sessions = ExecSession::load_all(&container_root).unwrap();
+    assert_eq!(MAX_EXITED_EXEC_SESSIONS + 1, sessions.len());
+    assert_eq!("running", sessions[0].id);
+    assert_eq!("exited-00", sessions[1].id);
+
+    running.exited(ContainerExitStatus::Signaled { signal: libc::SIGKILL, core_dumped: false });
+    running.save(&container_root).unwrap();
+    assert_eq!(Some(128 + libc::SIGKILL), ExecSession::load_all(&container_root).unwrap()[0].exit_code);
+}
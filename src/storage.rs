@@ -0,0 +1,424 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use tracing::{trace, warn};
+
+use crate::helpers::{COMMAND_TIMEOUT, command_output, Rollback};
+use crate::image::{ImageStore, OVERLAY_OPAQUE_XATTR, remove_path};
+use crate::linux::{BTRFS_SUPER_MAGIC, filesystem_type, OVERLAYFS_SUPER_MAGIC, get_xattr, is_process_alive, lchown, mknod, mount, readahead};
+use crate::model::{ContainerRuntimeError, ContainerRuntimeResult};
+use crate::registry::sha256_digest;
+use crate::spec::{OverlayOptionsSpec, StorageDriverSpec};
+
+/// The entries of a container root that hold the filesystem of the container (for any of the drivers), which are kept when the
+/// container exits so that its filesystem can be mounted again by the driver, such as to copy files from it.
+pub const CONTAINER_STORAGE: [&str; 3] = ["rootfs", "cow_rw", "cow_workdir"];
+
+pub trait StorageDriver {
+    fn name(&self) -> &str;
+    /// Creates the root filesystem of a container from the image layers (top-most layer first).
+    fn create_container_root(&self, image_layers: &[PathBuf], container_root: &Path) -> ContainerRuntimeResult<PathBuf>;
+}
+
+pub fn create_storage_driver(spec: &StorageDriverSpec, overlay_options: &OverlayOptionsSpec, image_base_dir: &Path) -> Box<dyn StorageDriver> {
+    match spec {
+        StorageDriverSpec::Overlay => Box::new(OverlayStorageDriver::new(*overlay_options)),
+        StorageDriverSpec::Vfs => Box::new(VfsStorageDriver),
+        StorageDriverSpec::Btrfs => {
+            let image_store = ImageStore::new(image_base_dir);
+            Box::new(BtrfsStorageDriver::new(image_store.subvolumes_dir(), image_store.layers_dir()))
+        }
+    }
+}
+
+/// Falls back to the vfs driver when the overlay driver cannot be used, such as when running inside another container.
+pub fn select_storage_driver(spec: StorageDriverSpec, container_root: &Path) -> StorageDriverSpec {
+    if spec == StorageDriverSpec::Overlay && !OverlayStorageDriver::is_supported(container_root) {
+        warn!("Overlay cannot be used on top of overlay at {}, using the vfs storage driver instead.", container_root.to_str().unwrap());
+        return StorageDriverSpec::Vfs;
+    }
+
+    spec
+}
+
+pub struct OverlayStorageDriver {
+    options: OverlayOptionsSpec
+}
+
+impl OverlayStorageDriver {
+    pub fn new(options: OverlayOptionsSpec) -> OverlayStorageDriver {
+        OverlayStorageDriver {
+            options
+        }
+    }
+
+    /// The upper and work directories cannot be on an overlay filesystem.
+    pub fn is_supported(path: &Path) -> bool {
+        filesystem_type(path).map(|fs_type| fs_type != OVERLAYFS_SUPER_MAGIC).unwrap_or(true)
+    }
+}
+
+/// The optional features of overlay that the kernel supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct OverlayFeatures {
+    volatile: bool,
+    metacopy: bool,
+    index: bool
+}
+
+impl OverlayFeatures {
+    fn detect() -> OverlayFeatures {
+        // The features that can be toggled per mount are also module parameters (giving their default)
+        let has_parameter = |name: &str| Path::new("/sys/module/overlay/parameters").join(name).exists();
+        let kernel_version = std::fs::read_to_string("/proc/sys/kernel/osrelease").ok().and_then(|release| parse_kernel_version(&release));
+
+        OverlayFeatures {
+            volatile: kernel_version.map(|version| version >= (5, 10)).unwrap_or(false),
+            metacopy: has_parameter("metacopy"),
+            index: has_parameter("index")
+        }
+    }
+}
+
+/// The major and minor version of a kernel release, such as 5.15 of `5.15.0-91-generic`.
+fn parse_kernel_version(release: &str) -> Option<(u32, u32)> {
+    let mut parts = release.trim().split(['.', '-']);
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// The mount options of the features that are both requested and supported, where those not supported are skipped with a warning.
+fn overlay_feature_options(options: &OverlayOptionsSpec, features: &OverlayFeatures) -> Vec<&'static str> {
+    let mut feature_options = Vec::new();
+    for (requested, supported, option) in [
+        (options.volatile, features.volatile, "volatile"),
+        (options.metacopy, features.metacopy, "metacopy=on"),
+        (options.index, features.index, "index=on")
+    ] {
+        if requested && supported {
+            feature_options.push(option);
+        } else if requested {
+            warn!("The kernel does not support the overlay option {}, mounting without it.", option);
+        }
+    }
+
+    feature_options
+}
+
+impl StorageDriver for OverlayStorageDriver {
+    fn name(&self) -> &str {
+        "overlay"
+    }
+
+    fn create_container_root(&self, image_layers: &[PathBuf], container_root: &Path) -> ContainerRuntimeResult<PathBuf> {
+        trace!("Create container root (overlay) - image layers: {}, container root: {}", image_layers.len(), container_root.to_str().unwrap());
+
+        let inner = || -> ContainerRuntimeResult<PathBuf> {
+            let container_cow_rw = container_root.join("cow_rw");
+            let container_cow_workdir = container_root.join("cow_workdir");
+            let container_rootfs = container_root.join("rootfs");
+
+            for path in [&container_cow_rw, &container_cow_workdir, &container_rootfs] {
+                if !path.exists() {
+                    std::fs::create_dir_all(path)?;
+                }
+            }
+
+            let base_options = format!(
+                "lowerdir={},upperdir={},workdir={}",
+                image_layers.iter().map(|layer| layer.to_str().unwrap()).collect::<Vec<_>>().join(":"),
+                container_cow_rw.to_str().unwrap(),
+                container_cow_workdir.to_str().unwrap()
+            );
+
+            let feature_options = if self.options == OverlayOptionsSpec::default() {
+                Vec::new()
+            } else {
+                overlay_feature_options(&self.options, &OverlayFeatures::detect())
+            };
+
+            if feature_options.is_empty() {
+                mount(Some("overlay"), &container_rootfs, Some("overlay"), libc::MS_NODEV, Some(&base_options))?;
+            } else {
+                // Features can still be refused for the filesystems of the layers (such as index=on without file handles)
+                let options = format!("{},{}", base_options, feature_options.join(","));
+                if let Err(err) = mount(Some("overlay"), &container_rootfs, Some("overlay"), libc::MS_NODEV, Some(&options)) {
+                    warn!("Failed to mount overlay with {} ({}), mounting without them.", feature_options.join(","), err);
+                    mount(Some("overlay"), &container_rootfs, Some("overlay"), libc::MS_NODEV, Some(&base_options))?;
+                }
+            }
+
+            Ok(container_rootfs)
+        };
+
+        inner().map_err(|err| ContainerRuntimeError::StorageDriver(err.to_string()))
+    }
+}
+
+/// Creates the container root by copying the full image, which works on any filesystem at the cost of disk space and startup time.
+pub struct VfsStorageDriver;
+
+impl StorageDriver for VfsStorageDriver {
+    fn name(&self) -> &str {
+        "vfs"
+    }
+
+    fn create_container_root(&self, image_layers: &[PathBuf], container_root: &Path) -> ContainerRuntimeResult<PathBuf> {
+        trace!("Create container root (vfs) - image layers: {}, container root: {}", image_layers.len(), container_root.to_str().unwrap());
+
+        let inner = || -> ContainerRuntimeResult<PathBuf> {
+            let container_rootfs = container_root.join("rootfs");
+            if !container_rootfs.exists() {
+                flatten_layers(image_layers, &container_rootfs)?;
+            }
+
+            // pivot_root requires the new root to be a mount point
+            mount(
+                Some(container_rootfs.to_str().unwrap()),
+                &container_rootfs,
+                None,
+                libc::MS_BIND | libc::MS_REC,
+                None
+            )?;
+
+            Ok(container_rootfs)
+        };
+
+        inner().map_err(|err| ContainerRuntimeError::StorageDriver(err.to_string()))
+    }
+}
+
+/// Creates the container root as a snapshot of a per-image subvolume, making container creation close to instant.
+pub struct BtrfsStorageDriver {
+    subvolumes_dir: PathBuf,
+    image_layers_dir: PathBuf
+}
+
+impl BtrfsStorageDriver {
+    pub fn new(subvolumes_dir: PathBuf, image_layers_dir: PathBuf) -> BtrfsStorageDriver {
+        BtrfsStorageDriver {
+            subvolumes_dir,
+            image_layers_dir
+        }
+    }
+
+    pub fn is_supported(path: &Path) -> bool {
+        filesystem_type(path).map(|fs_type| fs_type == BTRFS_SUPER_MAGIC).unwrap_or(false)
+    }
+
+    fn image_subvolume(&self, image_layers: &[PathBuf]) -> ContainerRuntimeResult<PathBuf> {
+        let layers_key = BtrfsStorageDriver::layers_key(image_layers);
+        let image_subvolume = self.subvolumes_dir.join(&layers_key);
+        if image_subvolume.exists() {
+            return Ok(image_subvolume);
+        }
+
+        // Built under a name of its own, so that a failed build is never used and runs of the same image can build it at the same time
+        std::fs::create_dir_all(&self.subvolumes_dir)?;
+        let building_subvolume = self.subvolumes_dir.join(format!(".{}.{}.tmp", layers_key, std::process::id()));
+        btrfs_command(["subvolume".as_ref(), "create".as_ref(), building_subvolume.as_os_str()])?;
+
+        let mut cleanup = Rollback::new();
+        cleanup.add("delete image subvolume being built", || {
+            btrfs_command(["subvolume".as_ref(), "delete".as_ref(), building_subvolume.as_os_str()])?;
+            Ok(())
+        });
+
+        flatten_layers(image_layers, &building_subvolume)?;
+        if let Err(err) = std::fs::rename(&building_subvolume, &image_subvolume) {
+            // Built by another run meanwhile, whose subvolume is used instead
+            if !image_subvolume.exists() {
+                return Err(err.into());
+            }
+
+            trace!("Image subvolume {} was created by another run", image_subvolume.to_str().unwrap());
+            return Ok(image_subvolume);
+        }
+
+        cleanup.commit();
+        trace!("Created image subvolume {}", image_subvolume.to_str().unwrap());
+        Ok(image_subvolume)
+    }
+
+    /// Images with the same layers share the subvolume, which is named by the digest of the paths of the layers.
+    fn layers_key(image_layers: &[PathBuf]) -> String {
+        let layers_key = image_layers.iter().map(|layer| layer.to_str().unwrap()).collect::<Vec<_>>().join("\n");
+        sha256_digest(layers_key.as_bytes()).trim_start_matches("sha256:").to_owned()
+    }
+
+    /// Deletes the image subvolumes that none of the sets of layers (top-most first) are flattened into, along with those left behind by
+    /// runs that exited while building them.
+    pub fn prune_image_subvolumes(&self, layer_sets: &[Vec<PathBuf>]) -> ContainerRuntimeResult<Vec<String>> {
+        let mut removed = Vec::new();
+        if !self.subvolumes_dir.exists() {
+            return Ok(removed);
+        }
+
+        let in_use = layer_sets.iter().map(|layers| BtrfsStorageDriver::layers_key(layers)).collect::<HashSet<_>>();
+        for entry in std::fs::read_dir(&self.subvolumes_dir)? {
+            let subvolume = entry?.path();
+            let name = subvolume.file_name().unwrap().to_str().unwrap();
+            let is_in_use = match name.strip_prefix('.').and_then(|name| name.strip_suffix(".tmp")) {
+                // Being built, as `.<key>.<runtime PID>.tmp`
+                Some(building) => building.rsplit_once('.').and_then(|(_, pid)| pid.parse().ok()).map(is_process_alive).unwrap_or(false),
+                None => in_use.contains(name)
+            };
+
+            if !is_in_use {
+                btrfs_command(["subvolume".as_ref(), "delete".as_ref(), subvolume.as_os_str()])?;
+                removed.push(format!("image subvolume {}", subvolume.to_str().unwrap()));
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+impl StorageDriver for BtrfsStorageDriver {
+    fn name(&self) -> &str {
+        "btrfs"
+    }
+
+    fn create_container_root(&self, image_layers: &[PathBuf], container_root: &Path) -> ContainerRuntimeResult<PathBuf> {
+        trace!("Create container root (btrfs) - image layers: {}, container root: {}", image_layers.len(), container_root.to_str().unwrap());
+
+        let inner = || -> ContainerRuntimeResult<PathBuf> {
+            std::fs::create_dir_all(&self.subvolumes_dir)?;
+            if !BtrfsStorageDriver::is_supported(&self.subvolumes_dir) || !BtrfsStorageDriver::is_supported(container_root) {
+                return Err(ContainerRuntimeError::StorageDriver("Images and containers must be stored on a btrfs filesystem".to_owned()));
+            }
+
+            let container_rootfs = container_root.join("rootfs");
+            if !container_rootfs.exists() {
+                // Only layers from the image store are immutable, other directories can't be shared through a snapshot
+                if image_layers.iter().all(|layer| layer.starts_with(&self.image_layers_dir)) {
+                    let image_subvolume = self.image_subvolume(image_layers)?;
+                    btrfs_command(["subvolume".as_ref(), "snapshot".as_ref(), image_subvolume.as_os_str(), container_rootfs.as_os_str()])?;
+                } else {
+                    btrfs_command(["subvolume".as_ref(), "create".as_ref(), container_rootfs.as_os_str()])?;
+                    flatten_layers(image_layers, &container_rootfs)?;
+                }
+            }
+
+            // pivot_root requires the new root to be a mount point
+            mount(
+                Some(container_rootfs.to_str().unwrap()),
+                &container_rootfs,
+                None,
+                libc::MS_BIND | libc::MS_REC,
+                None
+            )?;
+
+            Ok(container_rootfs)
+        };
+
+        inner().map_err(|err| ContainerRuntimeError::StorageDriver(err.to_string()))
+    }
+}
+
+/// Reads the executable files below the root into the page cache, returning the number of files and their total size.
+/// Special filesystems are not mounted yet, and symlinks are not followed, so only the files of the image are read.
+pub fn prewarm(root: &Path) -> ContainerRuntimeResult<(usize, u64)> {
+    let mut files = 0;
+    let mut size = 0;
+    let mut dirs = vec![root.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            } else if metadata.is_file() && metadata.mode() & 0o111 != 0 && metadata.len() > 0 {
+                readahead(&File::open(entry.path())?, metadata.len())?;
+                files += 1;
+                size += metadata.len();
+            }
+        }
+    }
+
+    Ok((files, size))
+}
+
+fn btrfs_command<I, S>(args: I) -> ContainerRuntimeResult<String> where I: IntoIterator<Item = S>, S: AsRef<OsStr> {
+    let result = command_output(Command::new("btrfs").args(args), COMMAND_TIMEOUT)?;
+
+    if !result.status.success() {
+        return Err(ContainerRuntimeError::StorageDriver(String::from_utf8(result.stderr).unwrap()));
+    }
+
+    Ok(String::from_utf8(result.stdout).unwrap())
+}
+
+fn flatten_layers(image_layers: &[PathBuf], destination: &Path) -> ContainerRuntimeResult<()> {
+    std::fs::create_dir_all(destination)?;
+    for layer in image_layers.iter().rev() {
+        copy_tree(layer, destination)?;
+    }
+
+    Ok(())
+}
+
+fn copy_tree(source: &Path, destination: &Path) -> ContainerRuntimeResult<()> {
+    let metadata = std::fs::symlink_metadata(source)?;
+    let file_type = metadata.file_type();
+
+    // Whiteouts hide the file from lower layers
+    if file_type.is_char_device() && metadata.rdev() == 0 {
+        return remove_path(destination);
+    }
+
+    if file_type.is_dir() {
+        let is_opaque = get_xattr(source, OVERLAY_OPAQUE_XATTR)?.map(|value| value == b"y").unwrap_or(false);
+        match std::fs::symlink_metadata(destination) {
+            Ok(existing) if !existing.is_dir() || is_opaque => remove_path(destination)?,
+            _ => {}
+        }
+
+        std::fs::create_dir_all(destination)?;
+        for entry in std::fs::read_dir(source)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &destination.join(entry.file_name()))?;
+        }
+    } else if file_type.is_symlink() {
+        remove_path(destination)?;
+        std::os::unix::fs::symlink(std::fs::read_link(source)?, destination)?;
+    } else if file_type.is_file() {
+        remove_path(destination)?;
+        std::fs::copy(source, destination)?;
+    } else if file_type.is_char_device() || file_type.is_block_device() || file_type.is_fifo() {
+        remove_path(destination)?;
+        mknod(destination, metadata.mode(), metadata.rdev())?;
+    } else {
+        trace!("Skipping unsupported file: {}", source.to_str().unwrap());
+        return Ok(());
+    }
+
+    // Changing owner clears the setuid/setgid bits, so the mode is applied afterwards
+    lchown(destination, metadata.uid() as u64, metadata.gid() as u64)?;
+    if !file_type.is_symlink() {
+        std::fs::set_permissions(destination, std::fs::Permissions::from_mode(metadata.mode()))?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_overlay_feature_options() {
+    assert_eq!(Some((5, 15)), parse_kernel_version("5.15.0-91-generic\n"));
+    assert_eq!(Some((6, 1)), parse_kernel_version("6.1"));
+    assert_eq!(None, parse_kernel_version("unknown"));
+
+    let options = OverlayOptionsSpec { volatile: true, metacopy: true, index: false };
+    let all = OverlayFeatures { volatile: true, metacopy: true, index: true };
+    assert_eq!(vec!["volatile", "metacopy=on"], overlay_feature_options(&options, &all));
+
+    // Unsupported features are skipped
+    let old_kernel = OverlayFeatures { volatile: false, metacopy: true, index: true };
+    assert_eq!(vec!["metacopy=on"], overlay_feature_options(&options, &old_kernel));
+}
+
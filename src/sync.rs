@@ -0,0 +1,132 @@
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::dhcp::DhcpLease;
+use crate::network::Ipv4Net;
+use crate::model::{ContainerRuntimeError, ContainerRuntimeResult, ErrorReport};
+
+/// Messages exchanged between the runtime and the container while the container is being set up.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SyncMessage {
+    /// The parent has finished its part of the setup (cgroups), the container can set up its root
+    ParentReady,
+    /// The container is set up and waits for permission to exec
+    ChildReady,
+    /// The container may exec its command
+    Exec,
+    /// How long the phases of the setup in the container took, sent before ChildReady when timing the startup
+    StartupTimes(Vec<(String, Duration)>),
+    /// The container has leased its address with DHCP
+    Leased(DhcpLease),
+    /// The parent has added the container to its CNI network, with the address assigned by the plugins
+    CniAdded(Option<Ipv4Net>),
+    /// The container has been created and waits to be started (sent by the supervisor of a created container)
+    Created,
+    /// The created container has executed its command
+    Started,
+    /// The container has been taken over (sent by the supervisor adopting it)
+    Adopted,
+    /// The setup failed on the sending side
+    Error(ErrorReport)
+}
+
+/// One end of a socketpair, where each message is sent as a line of JSON.
+pub struct SyncChannel {
+    stream: UnixStream
+}
+
+impl SyncChannel {
+    pub fn pair() -> ContainerRuntimeResult<(SyncChannel, SyncChannel)> {
+        let (first, second) = UnixStream::pair()?;
+        Ok((SyncChannel { stream: first }, SyncChannel { stream: second }))
+    }
+
+    pub fn from_stream(stream: UnixStream) -> SyncChannel {
+        SyncChannel { stream }
+    }
+
+    pub fn send(&mut self, message: &SyncMessage) -> ContainerRuntimeResult<()> {
+        let mut content = serde_json::to_vec(message)?;
+        content.push(b'\n');
+        self.stream.write_all(&content)?;
+        Ok(())
+    }
+
+    /// Makes receiving fail with a timeout error if no message arrives within the timeout.
+    pub fn set_receive_timeout(&self, timeout: Option<Duration>) -> ContainerRuntimeResult<()> {
+        self.stream.set_read_timeout(timeout)?;
+        Ok(())
+    }
+
+    /// Returns the next message, or none if the other side has closed the channel.
+    pub fn receive(&mut self) -> ContainerRuntimeResult<Option<SyncMessage>> {
+        // Read byte by byte so that nothing after the message is consumed
+        let mut line = Vec::new();
+        let mut byte = [0u8; 1];
+        loop {
+            let count = match self.stream.read(&mut byte) {
+                Ok(count) => count,
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    let timeout = self.stream.read_timeout()?.unwrap_or_default();
+                    return Err(ContainerRuntimeError::Timeout(format!("Container setup did not progress within {} seconds", timeout.as_secs())));
+                }
+                Err(err) => return Err(err.into())
+            };
+
+            if count == 0 {
+                if line.is_empty() {
+                    return Ok(None);
+                }
+
+                return Err(ContainerRuntimeError::ContainerSetup("Channel closed in the middle of a message".to_owned()));
+            }
+
+            if byte[0] == b'\n' {
+                break;
+            }
+
+            line.push(byte[0]);
+        }
+
+        Ok(Some(serde_json::from_slice(&line)?))
+    }
+
+    pub fn wait_for(&mut self, expected: SyncMessage) -> ContainerRuntimeResult<()> {
+        match self.receive()? {
+            Some(message) if message == expected => Ok(()),
+            Some(SyncMessage::Error(report)) => Err(ContainerRuntimeError::ContainerProcess(report)),
+            Some(message) => Err(ContainerRuntimeError::ContainerSetup(format!("Expected {:?} but got {:?}", expected, message))),
+            None => Err(ContainerRuntimeError::ContainerSetup(format!("Channel closed while waiting for {:?}", expected)))
+        }
+    }
+}
+
+impl AsRawFd for SyncChannel {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+#[test]
+fn test_sync_channel() {
+    let (mut parent, mut child) = SyncChannel::pair().unwrap();
+
+    parent.send(&SyncMessage::ParentReady).unwrap();
+    child.wait_for(SyncMessage::ParentReady).unwrap();
+
+    child.send(&SyncMessage::Error(ContainerRuntimeError::SetupMounts("No such file or directory".to_owned()).report())).unwrap();
+    assert_eq!(
+        "Container setup failed: Failed to setup mounts: No such file or directory",
+        parent.wait_for(SyncMessage::ChildReady).unwrap_err().to_string()
+    );
+
+    parent.set_receive_timeout(Some(Duration::from_millis(10))).unwrap();
+    assert_eq!("timeout", parent.receive().unwrap_err().code());
+
+    drop(child);
+    assert_eq!(None, parent.receive().unwrap());
+}
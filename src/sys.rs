@@ -0,0 +1,345 @@
+use std::ffi::{c_ulong, CString};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::linux;
+use crate::linux::wrap_libc_error;
+use crate::model::{ContainerRuntimeError, ContainerRuntimeResult};
+use crate::network::{ip_command, iptables_command, tc_command};
+
+/// The calls that change the host or the container, so that the setup logic can run against a fake on machines where they are not permitted.
+pub trait SysApi: Send + Sync {
+    fn mount(&self, src: Option<&str>, target: &Path, fstype: Option<&str>, flags: c_ulong, data: Option<&str>) -> ContainerRuntimeResult<()>;
+    fn unmount(&self, target: &Path) -> ContainerRuntimeResult<()>;
+    fn pivot_root(&self, new_root: &Path, old_root: &Path) -> ContainerRuntimeResult<()>;
+    fn mknod(&self, path: &Path, mode: u32, device: u64) -> ContainerRuntimeResult<()>;
+    fn set_hostname(&self, hostname: &str) -> ContainerRuntimeResult<()>;
+    /// Writes a kernel parameter, such as `net/ipv4/ip_forward`
+    fn write_sysctl(&self, name: &str, value: &str) -> ContainerRuntimeResult<()>;
+
+    fn ip(&self, args: &[&str]) -> ContainerRuntimeResult<String>;
+    fn tc(&self, args: &[&str]) -> ContainerRuntimeResult<String>;
+    fn iptables(&self, args: &[&str]) -> ContainerRuntimeResult<String>;
+
+    /// If the calls are made on the host, rather than recorded by a fake or a dry run, which is what decides if they are audited.
+    fn changes_host(&self) -> bool {
+        true
+    }
+}
+
+/// If the command (of ip, tc or iptables) only queries the host, such as `ip link show` or `iptables -C`.
+pub(crate) fn is_query_command(args: &[&str]) -> bool {
+    args.iter().any(|arg| matches!(*arg, "show" | "get" | "-S" | "-L" | "-C"))
+}
+
+/// Makes the calls on the host.
+pub struct HostSysApi;
+
+impl SysApi for HostSysApi {
+    fn mount(&self, src: Option<&str>, target: &Path, fstype: Option<&str>, flags: c_ulong, data: Option<&str>) -> ContainerRuntimeResult<()> {
+        linux::mount(src, target, fstype, flags, data)
+    }
+
+    fn unmount(&self, target: &Path) -> ContainerRuntimeResult<()> {
+        linux::unmount(target)
+    }
+
+    fn pivot_root(&self, new_root: &Path, old_root: &Path) -> ContainerRuntimeResult<()> {
+        linux::pivot_root(new_root, old_root)
+    }
+
+    fn mknod(&self, path: &Path, mode: u32, device: u64) -> ContainerRuntimeResult<()> {
+        linux::mknod(path, mode, device)
+    }
+
+    fn set_hostname(&self, hostname: &str) -> ContainerRuntimeResult<()> {
+        unsafe {
+            let hostname = CString::new(hostname).unwrap();
+            wrap_libc_error(libc::sethostname(hostname.as_ptr(), hostname.as_bytes().len()))?;
+        }
+
+        Ok(())
+    }
+
+    fn write_sysctl(&self, name: &str, value: &str) -> ContainerRuntimeResult<()> {
+        std::fs::write(Path::new("/proc/sys").join(name), value)?;
+        Ok(())
+    }
+
+    fn ip(&self, args: &[&str]) -> ContainerRuntimeResult<String> {
+        ip_command(args)
+    }
+
+    fn tc(&self, args: &[&str]) -> ContainerRuntimeResult<String> {
+        tc_command(args)
+    }
+
+    fn iptables(&self, args: &[&str]) -> ContainerRuntimeResult<String> {
+        iptables_command(args)
+    }
+}
+
+/// A call made through the API, as recorded by `RecordingSysApi`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SysCall {
+    Mount { src: Option<String>, target: PathBuf, fstype: Option<String>, flags: c_ulong, data: Option<String> },
+    Unmount { target: PathBuf },
+    PivotRoot { new_root: PathBuf, old_root: PathBuf },
+    Mknod { path: PathBuf, mode: u32, device: u64 },
+    SetHostname { hostname: String },
+    WriteSysctl { name: String, value: String },
+    Command { program: String, args: Vec<String> }
+}
+
+impl Display for SysCall {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SysCall::Mount { src, target, fstype, flags, data } => {
+                write!(f, "mount")?;
+                if let Some(fstype) = fstype {
+                    write!(f, " -t {}", fstype)?;
+                }
+                if let Some(data) = data {
+                    write!(f, " -o {}", data)?;
+                }
+                write!(f, " {} {}", src.as_deref().unwrap_or("none"), target.to_str().unwrap())?;
+                if *flags != 0 {
+                    write!(f, " ({})", mount_flag_names(*flags).join(","))?;
+                }
+                Ok(())
+            }
+            SysCall::Unmount { target } => write!(f, "umount {}", target.to_str().unwrap()),
+            SysCall::PivotRoot { new_root, old_root } => write!(f, "pivot_root {} {}", new_root.to_str().unwrap(), old_root.to_str().unwrap()),
+            SysCall::Mknod { path, mode, device } => {
+                let device_type = if mode & libc::S_IFMT == libc::S_IFBLK { "b" } else { "c" };
+                let permissions = mode & !libc::S_IFMT;
+                write!(f, "mknod -m {:o} {} {} {} {}", permissions, path.to_str().unwrap(), device_type, libc::major(*device), libc::minor(*device))
+            }
+            SysCall::SetHostname { hostname } => write!(f, "hostname {}", hostname),
+            SysCall::WriteSysctl { name, value } => write!(f, "sysctl {}={}", name.replace('/', "."), value),
+            SysCall::Command { program, args } => write!(f, "{} {}", program, args.join(" "))
+        }
+    }
+}
+
+/// The names of the mount flags, as used by `mount -o`.
+fn mount_flag_names(flags: c_ulong) -> Vec<&'static str> {
+    let names = [
+        (libc::MS_RDONLY, "ro"), (libc::MS_NOSUID, "nosuid"), (libc::MS_NODEV, "nodev"), (libc::MS_NOEXEC, "noexec"),
+        (libc::MS_REMOUNT, "remount"), (libc::MS_BIND, "bind"), (libc::MS_REC, "rec"), (libc::MS_PRIVATE, "private"),
+        (libc::MS_SLAVE, "slave"), (libc::MS_SHARED, "shared"), (libc::MS_STRICTATIME, "strictatime")
+    ];
+
+    names
+        .iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// Records the calls instead of making them, where all succeed with no output unless a response is given.
+/// Used to unit test the setup logic, and to show what would be done.
+pub struct RecordingSysApi {
+    calls: Mutex<Vec<SysCall>>,
+    /// The output (or error) of the calls whose command line starts with the prefix, where the first matching is used
+    responses: Vec<(String, Result<String, String>)>
+}
+
+impl RecordingSysApi {
+    pub fn new() -> RecordingSysApi {
+        RecordingSysApi {
+            calls: Mutex::new(Vec::new()),
+            responses: Vec::new()
+        }
+    }
+
+    /// Responds to the calls starting with the prefix (such as `ip link show`) with the output.
+    pub fn respond(mut self, prefix: &str, output: &str) -> RecordingSysApi {
+        self.responses.push((prefix.to_owned(), Ok(output.to_owned())));
+        self
+    }
+
+    /// Fails the calls starting with the prefix.
+    pub fn fail(mut self, prefix: &str, error: &str) -> RecordingSysApi {
+        self.responses.push((prefix.to_owned(), Err(error.to_owned())));
+        self
+    }
+
+    pub fn calls(&self) -> Vec<SysCall> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// The calls as command lines.
+    pub fn command_lines(&self) -> Vec<String> {
+        self.calls().iter().map(|call| call.to_string()).collect()
+    }
+
+    fn record(&self, call: SysCall) -> Result<String, String> {
+        let command_line = call.to_string();
+        self.calls.lock().unwrap().push(call);
+
+        self.responses
+            .iter()
+            .find(|(prefix, _)| command_line.starts_with(prefix.as_str()))
+            .map(|(_, response)| response.clone())
+            .unwrap_or_else(|| Ok(String::new()))
+    }
+
+    fn record_syscall(&self, call: SysCall) -> ContainerRuntimeResult<()> {
+        self.record(call)
+            .map(|_| ())
+            .map_err(|err| ContainerRuntimeError::Libc(std::io::Error::other(err)))
+    }
+
+    fn record_command(&self, program: &str, args: &[&str]) -> Result<String, String> {
+        self.record(SysCall::Command { program: program.to_owned(), args: args.iter().map(|arg| arg.to_string()).collect() })
+    }
+}
+
+impl Default for RecordingSysApi {
+    fn default() -> Self {
+        RecordingSysApi::new()
+    }
+}
+
+impl SysApi for RecordingSysApi {
+    fn mount(&self, src: Option<&str>, target: &Path, fstype: Option<&str>, flags: c_ulong, data: Option<&str>) -> ContainerRuntimeResult<()> {
+        let call = SysCall::Mount {
+            src: src.map(|src| src.to_owned()),
+            target: target.to_owned(),
+            fstype: fstype.map(|fstype| fstype.to_owned()),
+            flags,
+            data: data.map(|data| data.to_owned())
+        };
+
+        self.record(call)
+            .map(|_| ())
+            .map_err(|err| ContainerRuntimeError::Mount(std::io::Error::other(err)))
+    }
+
+    fn unmount(&self, target: &Path) -> ContainerRuntimeResult<()> {
+        self.record_syscall(SysCall::Unmount { target: target.to_owned() })
+    }
+
+    fn pivot_root(&self, new_root: &Path, old_root: &Path) -> ContainerRuntimeResult<()> {
+        self.record_syscall(SysCall::PivotRoot { new_root: new_root.to_owned(), old_root: old_root.to_owned() })
+    }
+
+    fn mknod(&self, path: &Path, mode: u32, device: u64) -> ContainerRuntimeResult<()> {
+        self.record_syscall(SysCall::Mknod { path: path.to_owned(), mode, device })
+    }
+
+    fn set_hostname(&self, hostname: &str) -> ContainerRuntimeResult<()> {
+        self.record_syscall(SysCall::SetHostname { hostname: hostname.to_owned() })
+    }
+
+    fn write_sysctl(&self, name: &str, value: &str) -> ContainerRuntimeResult<()> {
+        self.record_syscall(SysCall::WriteSysctl { name: name.to_owned(), value: value.to_owned() })
+    }
+
+    fn ip(&self, args: &[&str]) -> ContainerRuntimeResult<String> {
+        self.record_command("ip", args).map_err(ContainerRuntimeError::IPCommand)
+    }
+
+    fn tc(&self, args: &[&str]) -> ContainerRuntimeResult<String> {
+        self.record_command("tc", args).map_err(ContainerRuntimeError::TCCommand)
+    }
+
+    fn iptables(&self, args: &[&str]) -> ContainerRuntimeResult<String> {
+        self.record_command("iptables", args).map_err(ContainerRuntimeError::IPTablesCommand)
+    }
+
+    fn changes_host(&self) -> bool {
+        false
+    }
+}
+
+/// Makes the calls that only query the host (such as `ip link show`), so that the decisions are the ones of a real run,
+/// and records the others instead of making them.
+pub struct DryRunSysApi {
+    recording: RecordingSysApi
+}
+
+impl DryRunSysApi {
+    pub fn new() -> DryRunSysApi {
+        DryRunSysApi {
+            recording: RecordingSysApi::new()
+        }
+    }
+
+    /// The calls that would have changed the host, as command lines.
+    pub fn command_lines(&self) -> Vec<String> {
+        self.recording.command_lines()
+    }
+}
+
+impl Default for DryRunSysApi {
+    fn default() -> Self {
+        DryRunSysApi::new()
+    }
+}
+
+impl SysApi for DryRunSysApi {
+    fn mount(&self, src: Option<&str>, target: &Path, fstype: Option<&str>, flags: c_ulong, data: Option<&str>) -> ContainerRuntimeResult<()> {
+        self.recording.mount(src, target, fstype, flags, data)
+    }
+
+    fn unmount(&self, target: &Path) -> ContainerRuntimeResult<()> {
+        self.recording.unmount(target)
+    }
+
+    fn pivot_root(&self, new_root: &Path, old_root: &Path) -> ContainerRuntimeResult<()> {
+        self.recording.pivot_root(new_root, old_root)
+    }
+
+    fn mknod(&self, path: &Path, mode: u32, device: u64) -> ContainerRuntimeResult<()> {
+        self.recording.mknod(path, mode, device)
+    }
+
+    fn set_hostname(&self, hostname: &str) -> ContainerRuntimeResult<()> {
+        self.recording.set_hostname(hostname)
+    }
+
+    fn write_sysctl(&self, name: &str, value: &str) -> ContainerRuntimeResult<()> {
+        self.recording.write_sysctl(name, value)
+    }
+
+    fn ip(&self, args: &[&str]) -> ContainerRuntimeResult<String> {
+        if is_query_command(args) { HostSysApi.ip(args) } else { self.recording.ip(args) }
+    }
+
+    fn tc(&self, args: &[&str]) -> ContainerRuntimeResult<String> {
+        if is_query_command(args) { HostSysApi.tc(args) } else { self.recording.tc(args) }
+    }
+
+    fn iptables(&self, args: &[&str]) -> ContainerRuntimeResult<String> {
+        if is_query_command(args) { HostSysApi.iptables(args) } else { self.recording.iptables(args) }
+    }
+
+    fn changes_host(&self) -> bool {
+        false
+    }
+}
+
+#[test]
+fn test_recording_sys_api() {
+    let sys = RecordingSysApi::new()
+        .respond("ip -o link show", "1: lo: <LOOPBACK,UP,LOWER_UP> mtu 65536\n")
+        .fail("ip link show", "Device does not exist");
+
+    assert!(sys.ip(&["-o", "link", "show"]).unwrap().contains("lo"));
+    assert!(sys.ip(&["link", "show", "cort0"]).is_err());
+    sys.mount(Some("tmpfs"), Path::new("/tmp/root/dev"), Some("tmpfs"), libc::MS_NOSUID, Some("mode=755")).unwrap();
+    sys.write_sysctl("net/ipv4/ip_forward", "1").unwrap();
+
+    assert_eq!(
+        vec![
+            "ip -o link show".to_owned(),
+            "ip link show cort0".to_owned(),
+            "mount -t tmpfs -o mode=755 tmpfs /tmp/root/dev (nosuid)".to_owned(),
+            "sysctl net.ipv4.ip_forward=1".to_owned()
+        ],
+        sys.command_lines()
+    );
+}
@@ -0,0 +1,310 @@
+//! The systemd cgroup driver, where systemd creates the cgroups of each container as a transient scope unit in `cort.slice`,
+//! started over D-Bus (with busctl). Containers then show up in `systemd-cgls`, and limits set on the slice apply to all of them.
+//!
+//! Also generates the units that run a container as a service of systemd, optionally activated by a socket unit.
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use tracing::trace;
+
+use crate::helpers::{COMMAND_TIMEOUT, command_output};
+use crate::model::{ContainerRuntimeError, ContainerRuntimeResult};
+use crate::spec::{NetworkSpec, RestartPolicy, RunContainerSpec};
+use crate::state::ResourceLimits;
+
+/// The slice of the scopes of all containers.
+pub const SLICE: &str = "cort.slice";
+
+/// The controllers that systemd creates the cgroups of units in on cgroup v1, where the others are created by the runtime.
+pub const CONTROLLERS: [&str; 5] = ["cpu", "cpuacct", "memory", "blkio", "devices"];
+
+/// How long starting the scope may take before the container is given up on.
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+const DESTINATION: &str = "org.freedesktop.systemd1";
+const OBJECT_PATH: &str = "/org/freedesktop/systemd1";
+const MANAGER_INTERFACE: &str = "org.freedesktop.systemd1.Manager";
+
+pub fn scope_name(container_id: &str) -> String {
+    format!("cort-{}.scope", container_id)
+}
+
+/// Starts the scope of the container with the process in it and the limits set, waiting until the process has been moved into it.
+/// The scope is delegated, so that the runtime can write the limits that systemd does not manage to its cgroups.
+pub fn start_scope(container_id: &str, name: &str, pid: i32, limits: &ResourceLimits) -> ContainerRuntimeResult<()> {
+    let scope = scope_name(container_id);
+    trace!("Start systemd scope - scope: {}, pid: {}", scope, pid);
+
+    let mut properties = vec![
+        ("Description", "s", format!("cort container {}", name)),
+        ("Slice", "s", SLICE.to_owned()),
+        ("PIDs", "au", format!("1 {}", pid)),
+        ("Delegate", "b", "true".to_owned()),
+        // A scope whose container failed is removed as well, rather than kept until reset
+        ("CollectMode", "s", "inactive-or-failed".to_owned())
+    ];
+    properties.extend(limit_properties(limits));
+
+    let mut args = vec![scope.clone(), "fail".to_owned()];
+    append_properties(&mut args, &properties);
+    // No auxiliary units
+    args.push("0".to_owned());
+    busctl("StartTransientUnit", "ssa(sv)a(sa(sv))", &args)?;
+
+    // The job is only queued when the call returns
+    let start = Instant::now();
+    loop {
+        let cgroups = std::fs::read_to_string(format!("/proc/{}/cgroup", pid))?;
+        if cgroups.lines().any(|line| line.ends_with(&format!("/{}/{}", SLICE, scope))) {
+            return Ok(());
+        }
+
+        if start.elapsed() >= START_TIMEOUT {
+            return Err(ContainerRuntimeError::Systemd(format!("The process was not moved into {} within {} seconds", scope, START_TIMEOUT.as_secs())));
+        }
+
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Changes the limits of the scope of a running container, so that systemd does not restore the previous ones.
+pub fn set_scope_limits(container_id: &str, limits: &ResourceLimits) -> ContainerRuntimeResult<()> {
+    let properties = limit_properties(limits);
+    if properties.is_empty() {
+        return Ok(());
+    }
+
+    // Only for the runtime of the unit, as the scope does not outlive the container
+    let mut args = vec![scope_name(container_id), "true".to_owned()];
+    append_properties(&mut args, &properties);
+    busctl("SetUnitProperties", "sba(sv)", &args)?;
+    Ok(())
+}
+
+/// The limits as properties of the unit, where those that systemd does not have on cgroup v1 (such as swap) are only written to the cgroups.
+fn limit_properties(limits: &ResourceLimits) -> Vec<(&'static str, &'static str, String)> {
+    let mut properties = Vec::new();
+    if let Some(cpu_shares) = limits.cpu_shares {
+        properties.push(("CPUShares", "t", cpu_shares.to_string()));
+    }
+
+    if let Some(cpus) = limits.cpus {
+        properties.push(("CPUQuotaPerSecUSec", "t", ((cpus * 1_000_000.0) as u64).to_string()));
+    }
+
+    if let Some(memory) = limits.memory {
+        properties.push(("MemoryLimit", "t", memory.to_string()));
+    }
+
+    properties
+}
+
+fn append_properties(args: &mut Vec<String>, properties: &[(&str, &str, String)]) {
+    args.push(properties.len().to_string());
+    for (name, signature, value) in properties {
+        args.push(name.to_string());
+        args.push(signature.to_string());
+        // Arrays are given as their length followed by the elements, each as an argument of its own
+        if signature.starts_with('a') {
+            args.extend(value.split(' ').map(|part| part.to_owned()));
+        } else {
+            args.push(value.clone());
+        }
+    }
+}
+
+/// Calls the method of the manager of systemd, with the arguments given in the format of busctl.
+fn busctl(method: &str, signature: &str, args: &[String]) -> ContainerRuntimeResult<String> {
+    let result = command_output(
+        Command::new("busctl").args(["call", "--system", DESTINATION, OBJECT_PATH, MANAGER_INTERFACE, method, signature]).args(args),
+        COMMAND_TIMEOUT
+    )
+        .map_err(|err| ContainerRuntimeError::Systemd(format!("Failed to run busctl: {}", err)))?;
+
+    if !result.status.success() {
+        return Err(ContainerRuntimeError::Systemd(format!("{} failed: {}", method, String::from_utf8_lossy(&result.stderr).trim())));
+    }
+
+    Ok(String::from_utf8_lossy(&result.stdout).into_owned())
+}
+
+/// A unit file, by the name of its file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnitFile {
+    pub name: String,
+    pub content: String
+}
+
+/// How the service of a container is run and stopped.
+#[derive(Debug, Clone)]
+pub struct ServiceOptions {
+    /// The cort executable along with its global options (such as the data directory)
+    pub cort: Vec<String>,
+    pub restart: RestartPolicy,
+    /// The seconds the container is given to stop before it is killed
+    pub stop_timeout: u64,
+    /// The addresses that a socket unit listens on (as ListenStream= of systemd), which activates the service when connected to
+    pub listen: Vec<String>
+}
+
+pub fn unit_name(container_name: &str) -> String {
+    format!("cort-{}", container_name)
+}
+
+/// The service that runs the container from its spec file, along with its socket unit when it is socket activated.
+/// The network is given by options rather than the spec file, so that each run gets its own address.
+pub fn generate_units(spec: &RunContainerSpec, spec_path: &Path, options: &ServiceOptions) -> Vec<UnitFile> {
+    let unit_name = unit_name(&spec.name);
+    let cort = |args: &[&str]| -> String {
+        options.cort.iter().map(|arg| arg.as_str()).chain(args.iter().copied()).map(quote_arg).collect::<Vec<_>>().join(" ")
+    };
+
+    let mut run_args = vec!["run", "--name", &spec.name];
+    let (network, hostname, ingress_limit, egress_limit) = match &spec.network {
+        NetworkSpec::Host => ("host".to_owned(), None, None, None),
+        NetworkSpec::Bridged(bridged) => ("bridge".to_owned(), bridged.hostname.clone(), bridged.ingress_limit, bridged.egress_limit),
+        NetworkSpec::Cni(cni_network) => (format!("cni:{}", cni_network.name), cni_network.hostname.clone(), None, None),
+        NetworkSpec::Pod(_) => unreachable!("containers of pods are not run as services")
+    };
+    let ingress_limit = ingress_limit.map(|limit| limit.to_string());
+    let egress_limit = egress_limit.map(|limit| limit.to_string());
+    run_args.extend(["--net", &network]);
+    if let Some(hostname) = &hostname {
+        run_args.extend(["--hostname", hostname]);
+    }
+    if let Some(ingress_limit) = &ingress_limit {
+        run_args.extend(["--network-bw-limit-ingress", ingress_limit]);
+    }
+    if let Some(egress_limit) = &egress_limit {
+        run_args.extend(["--network-bw-limit-egress", egress_limit]);
+    }
+    run_args.extend(["--spec", spec_path.to_str().unwrap()]);
+
+    let mut unit = vec![
+        format!("Description=cort container {}", spec.name),
+        "Wants=network-online.target".to_owned(),
+        "After=network-online.target".to_owned()
+    ];
+    if !options.listen.is_empty() {
+        unit.push(format!("Requires={}.socket", unit_name));
+        unit.push(format!("After={}.socket", unit_name));
+    }
+    if let RestartPolicy::OnFailure(Some(max_retries)) = options.restart {
+        // The first start is not a retry
+        unit.push("StartLimitIntervalSec=infinity".to_owned());
+        unit.push(format!("StartLimitBurst={}", max_retries + 1));
+    }
+
+    let restart = match options.restart {
+        RestartPolicy::No => "no",
+        RestartPolicy::OnFailure(_) => "on-failure",
+        RestartPolicy::Always | RestartPolicy::UnlessStopped => "always"
+    };
+    let service = vec![
+        "Type=simple".to_owned(),
+        // The container of the previous run is kept until the next one, so that it can be inspected
+        format!("ExecStartPre=-{}", cort(&["rm", &spec.name])),
+        format!("ExecStart={}", cort(&run_args)),
+        // The container might already have exited
+        format!("ExecStop=-{}", cort(&["stop", "--timeout", &options.stop_timeout.to_string(), &spec.name])),
+        format!("TimeoutStopSec={}", options.stop_timeout + 10),
+        format!("Restart={}", restart)
+    ];
+
+    // A socket activated service is started by its socket rather than at boot
+    let install = if options.listen.is_empty() { "WantedBy=multi-user.target".to_owned() } else { format!("Also={}.socket", unit_name) };
+    let mut units = vec![
+        UnitFile {
+            name: format!("{}.service", unit_name),
+            content: unit_file(&spec.name, &[("Unit", unit), ("Service", service), ("Install", vec![install])])
+        }
+    ];
+
+    if !options.listen.is_empty() {
+        let socket = options.listen.iter().map(|address| format!("ListenStream={}", address)).collect();
+        units.push(
+            UnitFile {
+                name: format!("{}.socket", unit_name),
+                content: unit_file(
+                    &spec.name,
+                    &[
+                        ("Unit", vec![format!("Description=Socket of cort container {}", spec.name)]),
+                        ("Socket", socket),
+                        ("Install", vec!["WantedBy=sockets.target".to_owned()])
+                    ]
+                )
+            }
+        );
+    }
+
+    units
+}
+
+fn unit_file(container_name: &str, sections: &[(&str, Vec<String>)]) -> String {
+    let mut content = format!("# Generated by cort generate systemd {}\n", container_name);
+    for (name, settings) in sections {
+        content += &format!("\n[{}]\n", name);
+        for setting in settings {
+            content += setting;
+            content += "\n";
+        }
+    }
+
+    content
+}
+
+/// Quotes the argument of a command line of a unit if needed, where systemd also expands % specifiers and $ variables.
+fn quote_arg(arg: &str) -> String {
+    let arg = arg.replace('%', "%%").replace('$', "$$");
+    if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'' || c == '\\' || c == ';') {
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        arg
+    }
+}
+
+#[test]
+fn test_scope_properties() {
+    let limits = ResourceLimits { cpu_shares: Some(512), cpus: Some(1.5), memory: Some(1 << 30), memory_swap: Some(1 << 31), ..Default::default() };
+
+    let mut args = Vec::new();
+    append_properties(&mut args, &[("Description", "s", "cort container web".to_owned()), ("PIDs", "au", "1 42".to_owned())]);
+    append_properties(&mut args, &limit_properties(&limits));
+    assert_eq!(
+        vec![
+            "2", "Description", "s", "cort container web", "PIDs", "au", "1", "42",
+            "3", "CPUShares", "t", "512", "CPUQuotaPerSecUSec", "t", "1500000", "MemoryLimit", "t", "1073741824"
+        ],
+        args
+    );
+
+    assert!(limit_properties(&ResourceLimits::default()).is_empty());
+}
+
+#[test]
+fn test_generate_units() {
+    let runtime = crate::ContainerRuntime::new(crate::ContainerRuntimeConfig::from_base_dir(Path::new("/tmp/cort-test")));
+    let mut spec = runtime.create_run_spec("alpine", vec!["sh".to_owned()], NetworkSpec::Host);
+    spec.name = "web".to_owned();
+
+    let options = ServiceOptions {
+        cort: vec!["/usr/bin/cort".to_owned(), "--data-dir".to_owned(), "/srv/cort data".to_owned()],
+        restart: RestartPolicy::OnFailure(Some(3)),
+        stop_timeout: 5,
+        listen: vec!["8080".to_owned()]
+    };
+    let units = generate_units(&spec, Path::new("/srv/cort data/units/cort-web.json"), &options);
+    assert_eq!(vec!["cort-web.service", "cort-web.socket"], units.iter().map(|unit| unit.name.as_str()).collect::<Vec<_>>());
+
+    let service = units[0].content.lines().collect::<Vec<_>>();
+    assert!(service.contains(&"ExecStart=/usr/bin/cort --data-dir \"/srv/cort data\" run --name web --net host --spec \"/srv/cort data/units/cort-web.json\""));
+    assert!(service.contains(&"ExecStop=-/usr/bin/cort --data-dir \"/srv/cort data\" stop --timeout 5 web"));
+    assert!(service.contains(&"Restart=on-failure"));
+    assert!(service.contains(&"StartLimitBurst=4"));
+    assert!(service.contains(&"Requires=cort-web.socket"));
+    assert!(service.contains(&"Also=cort-web.socket"));
+    assert!(units[1].content.contains("\n[Socket]\nListenStream=8080\n"));
+
+    assert_eq!("\"50%% of $$HOME\"", quote_arg("50% of $HOME"));
+}
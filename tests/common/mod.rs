@@ -0,0 +1,84 @@
+//! Shared setup of the integration tests, which run containers from the root filesystem given by `CORT_TEST_ROOTFS`.
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use container_runtime::{ContainerRuntime, ContainerRuntimeConfig};
+use container_runtime::spec::{BindMountSpec, MountPropagation, NetworkSpec, RunContainerSpec};
+
+pub struct TestContext {
+    pub runtime: ContainerRuntime,
+    base_dir: PathBuf,
+    rootfs: PathBuf
+}
+
+impl TestContext {
+    pub fn new() -> TestContext {
+        let rootfs = std::env::var("CORT_TEST_ROOTFS").expect("CORT_TEST_ROOTFS must point to a root filesystem");
+        let base_dir = std::env::temp_dir().join(format!("cort-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(base_dir.join("results")).unwrap();
+
+        TestContext {
+            runtime: ContainerRuntime::new(ContainerRuntimeConfig::from_base_dir(&base_dir.join("data"))),
+            base_dir,
+            rootfs: PathBuf::from(rootfs)
+        }
+    }
+
+    /// Shared with the containers as /results, where they write what the tests assert on.
+    pub fn results_dir(&self) -> PathBuf {
+        self.base_dir.join("results")
+    }
+
+    pub fn result(&self, name: &str) -> String {
+        std::fs::read_to_string(self.results_dir().join(name)).unwrap_or_else(|err| panic!("Expected result {}: {}", name, err))
+    }
+
+    /// Creates a bridged container that has the results directory mounted at /results.
+    pub fn create_spec(&self, command: &str) -> RunContainerSpec {
+        let network = self.runtime.bridged_network(None).unwrap();
+        self.create_spec_with_network(command, network)
+    }
+
+    /// Creates a container using the network of the host, which needs neither iptables nor internet access.
+    pub fn create_host_spec(&self, command: &str) -> RunContainerSpec {
+        self.create_spec_with_network(command, NetworkSpec::Host)
+    }
+
+    fn create_spec_with_network(&self, command: &str, network: NetworkSpec) -> RunContainerSpec {
+        let mut spec = self.runtime.create_run_spec("", vec!["/bin/sh".to_owned(), "-c".to_owned(), command.to_owned()], network);
+        spec.rootfs = Some(self.rootfs.clone());
+        spec.bind_mounts.push(BindMountSpec {
+            source: self.results_dir(),
+            target: Path::new("/results").to_owned(),
+            is_readonly: false,
+            propagation: MountPropagation::Private
+        });
+        spec
+    }
+
+    pub fn wait_until_running(&self, container_id: &str) {
+        let start = Instant::now();
+        while !self.runtime.list().unwrap().iter().any(|state| state.id == container_id) {
+            assert!(start.elapsed() < Duration::from_secs(10), "Container {} did not start", container_id);
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+impl Drop for TestContext {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.base_dir).ok();
+    }
+}
+
+pub fn host_has_interface(interface: &str) -> bool {
+    Command::new("ip").args(["link", "show", interface]).output().unwrap().status.success()
+}
+
+/// The cgroup directory of the container for the controller (cgroup v1).
+pub fn cgroup_dir(controller: &str, container_id: &str) -> PathBuf {
+    Path::new("/sys/fs/cgroup").join(controller).join("container_runtime").join(container_id)
+}
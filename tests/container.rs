@@ -0,0 +1,179 @@
+//! Integration tests of the namespaces, mounts, cgroups and cleanup of containers. These require root (with cgroup v1) and a root
+//! filesystem containing `sh`, `cat`, `ls` and `sleep` (such as an extracted busybox image), so they are ignored by default:
+//!
+//! `sudo -E CORT_TEST_ROOTFS=/path/to/rootfs cargo test --test container -- --ignored --test-threads=1`
+use std::os::unix::fs::PermissionsExt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use container_runtime::model::ContainerExitStatus;
+use container_runtime::spec::{CapabilitySpec, SecretSpec, UserSpec};
+
+mod common;
+
+use common::{cgroup_dir, TestContext};
+
+#[test]
+#[ignore]
+fn test_exit_status() {
+    let context = TestContext::new();
+
+    let spec = context.create_host_spec("exit 3");
+    assert_eq!(ContainerExitStatus::Exited { code: 3 }, context.runtime.run(&spec).unwrap());
+
+    // Under the init, the command is no longer PID 1 but its exit code is still that of the container
+    let mut spec = context.create_host_spec("test $$ != 1 && exit 5");
+    spec.init = true;
+    assert_eq!(ContainerExitStatus::Exited { code: 5 }, context.runtime.run(&spec).unwrap());
+}
+
+#[test]
+#[ignore]
+fn test_namespaces() {
+    let context = TestContext::new();
+
+    let spec = context.create_host_spec("echo $$ > /results/pid && ls /proc > /results/proc");
+    context.runtime.run(&spec).unwrap();
+
+    // The command is PID 1 of its own PID namespace, where the processes of the host are not visible
+    assert_eq!("1\n", context.result("pid"));
+    let pids = context.result("proc").lines().filter(|name| name.parse::<i32>().is_ok()).count();
+    assert!(pids <= 2, "Expected only the processes of the container, found {}", pids);
+}
+
+#[test]
+#[ignore]
+fn test_mount_layout() {
+    let context = TestContext::new();
+
+    let mut spec = context.create_host_spec(
+        "cat /proc/self/mounts > /results/mounts && ls / > /results/root && ((echo > /readonly) 2> /dev/null || echo > /results/readonly) && echo > /tmp/writable && echo > /results/tmp"
+    );
+    spec.read_only = true;
+    context.runtime.run(&spec).unwrap();
+
+    let mounts = context.result("mounts");
+    let mount_targets = mounts.lines().filter_map(|line| line.split_whitespace().nth(1)).collect::<Vec<_>>();
+    for target in ["/", "/proc", "/sys", "/dev", "/tmp", "/results"] {
+        assert!(mount_targets.contains(&target), "Expected {} to be mounted, got {:?}", target, mount_targets);
+    }
+
+    // The root is the root filesystem of the container rather than that of the host
+    let root = context.result("root");
+    assert!(root.lines().any(|name| name == "results"));
+    assert!(!root.lines().any(|name| name == "home" || name == "boot"), "Unexpected host directories in root: {}", root);
+
+    assert!(context.results_dir().join("readonly").exists(), "Expected the root to be read-only");
+    assert!(context.results_dir().join("tmp").exists(), "Expected /tmp to be writable");
+}
+
+#[test]
+#[ignore]
+fn test_cgroup_limits() {
+    let context = TestContext::new();
+
+    let mut spec = context.create_host_spec("sleep 30");
+    spec.memory = Some(64 * 1024 * 1024);
+    spec.cpu_shares = Some(512);
+
+    std::thread::scope(|scope| {
+        let container = scope.spawn(|| context.runtime.run(&spec));
+        context.wait_until_running(&spec.id);
+
+        let read_cgroup_value = |controller: &str, name: &str| std::fs::read_to_string(cgroup_dir(controller, &spec.id).join(name)).unwrap();
+        assert_eq!("67108864\n", read_cgroup_value("memory", "memory.limit_in_bytes"));
+        assert_eq!("512\n", read_cgroup_value("cpu", "cpu.shares"));
+
+        let state = context.runtime.inspect(&spec.id).unwrap();
+        let tasks = read_cgroup_value("memory", "tasks");
+        assert!(tasks.lines().any(|pid| pid == state.pid.to_string()), "Expected the container in its cgroup, got tasks {}", tasks);
+
+        context.runtime.stop(&spec.id, Duration::from_secs(1)).unwrap();
+        container.join().unwrap().unwrap();
+    });
+}
+
+#[test]
+#[ignore]
+fn test_cleanup() {
+    let context = TestContext::new();
+
+    let mut spec = context.create_host_spec("true");
+    spec.name = "cleanup".to_owned();
+    context.runtime.run(&spec).unwrap();
+
+    // The cgroups and name are released, while the exit status is kept until pruned
+    assert!(!cgroup_dir("memory", &spec.id).exists());
+    assert!(!cgroup_dir("cpu", &spec.id).exists());
+    assert!(context.runtime.list().unwrap().is_empty());
+    assert_eq!(Some(ContainerExitStatus::Exited { code: 0 }), context.runtime.inspect(&spec.id).unwrap().exit_status);
+
+    let mut spec = context.create_host_spec("true");
+    spec.name = "cleanup".to_owned();
+    context.runtime.run(&spec).unwrap();
+
+    context.runtime.prune().unwrap();
+    assert!(context.runtime.inspect(&spec.id).is_err());
+}
+
+#[test]
+#[ignore]
+fn test_cleanup_unmounts_binds() {
+    let context = TestContext::new();
+
+    let spec = context.create_host_spec("echo kept > /results/kept");
+    context.runtime.run(&spec).unwrap();
+
+    // A bind of the host left below the container root is unmounted rather than removed through
+    let mount_point = context.runtime.config().containers_base_dir().join(&spec.id).join("leftover");
+    std::fs::create_dir_all(&mount_point).unwrap();
+    assert!(std::process::Command::new("mount").arg("--bind").arg(context.results_dir()).arg(&mount_point).status().unwrap().success());
+
+    context.runtime.prune().unwrap();
+    assert!(context.runtime.inspect(&spec.id).is_err());
+    assert_eq!("kept\n", context.result("kept"));
+}
+
+#[test]
+#[ignore]
+fn test_non_root_user_drops_capabilities() {
+    let context = TestContext::new();
+
+    let check_capabilities = |expected: &str| format!(
+        "while read name value; do case $name in CapInh:|CapPrm:|CapEff:|CapAmb:) test $value = {} || exit 1;; esac; done < /proc/self/status",
+        expected
+    );
+
+    let mut spec = context.create_host_spec(&check_capabilities("0000000000000000"));
+    spec.user = Some(UserSpec::Id(1000));
+    assert_eq!(ContainerExitStatus::Exited { code: 0 }, context.runtime.run(&spec).unwrap());
+
+    // The kept capabilities are ambient, so they survive the exec of the command by the shell
+    let mut spec = context.create_host_spec(&check_capabilities("0000000000000400"));
+    spec.user = Some(UserSpec::Id(1000));
+    spec.security.keep_capabilities = vec![CapabilitySpec::from_str("NET_BIND_SERVICE").unwrap()];
+    assert_eq!(ContainerExitStatus::Exited { code: 0 }, context.runtime.run(&spec).unwrap());
+}
+
+#[test]
+#[ignore]
+fn test_secrets() {
+    let context = TestContext::new();
+    let source = context.results_dir().join("secret.txt");
+    std::fs::write(&source, "s3cr3t").unwrap();
+    std::fs::set_permissions(context.results_dir(), std::fs::Permissions::from_mode(0o777)).unwrap();
+
+    // The secret is on a read-only tmpfs rather than in the root filesystem, readable only by the user of the container
+    let mut spec = context.create_host_spec(
+        "cat /run/secrets/db > /results/secret && ls -ln /run/secrets/db > /results/mode && grep ' /run/secrets ' /proc/self/mounts > /results/mount"
+    );
+    spec.user = Some(UserSpec::Id(1000));
+    spec.secrets = vec![SecretSpec { id: "db".to_owned(), source }];
+    assert_eq!(ContainerExitStatus::Exited { code: 0 }, context.runtime.run(&spec).unwrap());
+
+    assert_eq!("s3cr3t", context.result("secret"));
+    let mode = context.result("mode");
+    assert_eq!(vec!["-r--------", "1", "1000"], mode.split_whitespace().take(3).collect::<Vec<_>>());
+    let mount = context.result("mount");
+    assert!(mount.starts_with("tmpfs /run/secrets tmpfs ro,"), "Unexpected mount: {}", mount);
+}
@@ -0,0 +1,88 @@
+//! Integration tests for bridged networking. These require root, iptables, internet access and a root filesystem
+//! containing `sh`, `sleep`, `hostname` and `ping` (such as an extracted busybox or alpine image), so they are ignored by default:
+//!
+//! `sudo -E CORT_TEST_ROOTFS=/path/to/rootfs cargo test --test network -- --ignored --test-threads=1`
+use std::process::Command;
+use std::time::Duration;
+
+use container_runtime::network::VethPair;
+use container_runtime::spec::{DNSSpec, NetworkSpec, RunContainerSpec};
+
+mod common;
+
+use common::{host_has_interface, TestContext};
+
+fn container_ip_address(spec: &RunContainerSpec) -> String {
+    match &spec.network {
+        NetworkSpec::Bridged(bridged) => bridged.container_ip_address.expect("Expected static address").address.to_string(),
+        NetworkSpec::Host | NetworkSpec::Cni(_) | NetworkSpec::Pod(_) => panic!("Expected bridged network")
+    }
+}
+
+fn interfaces_with_master(bridge_interface: &str) -> String {
+    let output = Command::new("ip").args(["-o", "link", "show", "master", bridge_interface]).output().unwrap();
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+#[ignore]
+fn test_container_to_internet() {
+    let context = TestContext::new();
+
+    let spec = context.create_spec("ping -c 1 -W 5 8.8.8.8 && touch /results/internet");
+    context.runtime.run(&spec).unwrap();
+
+    assert!(context.results_dir().join("internet").exists());
+    assert!(!host_has_interface(&VethPair::for_container(&spec.id).host_interface));
+}
+
+#[test]
+#[ignore]
+fn test_container_to_container() {
+    let context = TestContext::new();
+    let bridge_interface = context.runtime.config().network.bridge_interface.clone();
+
+    let server_spec = context.create_spec("touch /results/server && sleep 30");
+    std::thread::scope(|scope| {
+        let server = scope.spawn(|| context.runtime.run(&server_spec));
+        context.wait_until_running(&server_spec.id);
+
+        // Only the host end of the pair is attached to the bridge, the other end lives in the container
+        let veth = VethPair::for_container(&server_spec.id);
+        let bridged_interfaces = interfaces_with_master(&bridge_interface);
+        assert!(bridged_interfaces.contains(&veth.host_interface));
+        assert!(!bridged_interfaces.contains(&veth.container_interface));
+        assert!(!host_has_interface(&veth.container_interface));
+
+        let client_spec = context.create_spec(&format!("ping -c 1 -W 5 {} && touch /results/client", container_ip_address(&server_spec)));
+        context.runtime.run(&client_spec).unwrap();
+
+        context.runtime.stop(&server_spec.id, Duration::from_secs(1)).unwrap();
+        server.join().unwrap().unwrap();
+    });
+
+    assert!(context.results_dir().join("server").exists());
+    assert!(context.results_dir().join("client").exists());
+}
+
+#[test]
+#[ignore]
+fn test_container_resolver() {
+    let context = TestContext::new();
+
+    // With ndots:2 the search domain is tried first for dns.google, which must then fall back to the name itself
+    let mut spec = context.create_spec(
+        "cp /etc/resolv.conf /results/resolv.conf && ping -c 1 -W 5 $(hostname) && touch /results/hostname && ping -c 1 -W 5 dns.google && touch /results/dns"
+    );
+    spec.dns = DNSSpec::Server(vec!["8.8.8.8".to_owned(), "8.8.8.8".to_owned()]);
+    spec.dns_search = vec!["svc.invalid".to_owned()];
+    spec.dns_options = vec!["ndots:2".to_owned()];
+    context.runtime.run(&spec).unwrap();
+
+    assert_eq!(
+        "nameserver 8.8.8.8\nsearch svc.invalid\noptions ndots:2\n",
+        context.result("resolv.conf")
+    );
+    assert!(context.results_dir().join("hostname").exists());
+    assert!(context.results_dir().join("dns").exists());
+}